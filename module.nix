@@ -5,7 +5,12 @@
 { pkgs, lib, config, ... }:
 let
   cfg = config.services.nixseparatedebuginfod;
-  url = "127.0.0.1:${toString cfg.port}";
+  # brackets around IPv6 addresses
+  formatAddress = address: if lib.hasInfix ":" address then "[${address}]:${toString cfg.port}" else "${address}:${toString cfg.port}";
+  listens = map formatAddress cfg.listenAddresses;
+  # clients on this machine connect to the first address, or loopback if it is a wildcard
+  firstAddress = builtins.head cfg.listenAddresses;
+  url = formatAddress ({ "0.0.0.0" = "127.0.0.1"; "::" = "::1"; }.${firstAddress} or firstAddress);
   maybeAdd = x: list: if builtins.elem x list then list else list ++ [ x ];
   recentNix = lib.lists.findFirst
     (nix: nix != null && lib.versionAtLeast
@@ -30,18 +35,43 @@ in
         default = 1949;
         type = lib.types.port;
       };
+      listenAddresses = lib.mkOption {
+        description = ''
+          Addresses to listen on. Use `[ "::" ]` to listen on all IPv6 and IPv4 addresses, or
+          `[ "127.0.0.1" "::1" ]` for both loopback addresses.
+        '';
+        default = [ "127.0.0.1" ];
+        example = [ "127.0.0.1" "::1" ];
+        type = lib.types.nonEmptyListOf lib.types.str;
+      };
+      idleTimeout = lib.mkOption {
+        description = ''
+          If not null, start the server on the first connection with socket activation, and stop it
+          after this many seconds without requests nor indexing.
+        '';
+        default = null;
+        type = lib.types.nullOr lib.types.ints.positive;
+      };
     };
   };
   config = lib.mkIf cfg.enable {
+    systemd.sockets.nixseparatedebuginfod = lib.mkIf (cfg.idleTimeout != null) {
+      wantedBy = [ "sockets.target" ];
+      listenStreams = listens;
+    };
+
     systemd.services.nixseparatedebuginfod = {
-      wantedBy = [ "multi-user.target" ];
+      wantedBy = lib.mkIf (cfg.idleTimeout == null) [ "multi-user.target" ];
       wants = [ "nix-daemon.service" ];
       after = [ "nix-daemon.service" ];
-      path = [ recentNix ];
+      # tar extracts the source tarballs libarchive cannot, and creates sources.tar.gz
+      path = [ recentNix pkgs.gnutar pkgs.gzip pkgs.xz pkgs.bzip2 pkgs.zstd ];
       serviceConfig = {
-        ExecStart = [ "${pkgs.nixseparatedebuginfod}/bin/nixseparatedebuginfod -l ${url}" ];
+        ExecStart = [ "${pkgs.nixseparatedebuginfod}/bin/nixseparatedebuginfod ${lib.concatMapStringsSep " " (listen: "-l ${listen}") listens}${lib.optionalString (cfg.idleTimeout != null) " --idle-timeout ${toString cfg.idleTimeout}"}" ];
         Restart = "on-failure";
         CacheDirectory = "nixseparatedebuginfod";
+        # temporary files
+        RuntimeDirectory = "nixseparatedebuginfod";
         # nix does not like DynamicUsers in allowed-users
         User = "nixseparatedebuginfod";
         Group = "nixseparatedebuginfod";
@@ -2,7 +2,7 @@
 #
 # SPDX-License-Identifier: CC0-1.0
 
-{ callPackage, libarchive, pkg-config, lib }:
+{ callPackage, libarchive, pkg-config, lib, nix, withLibstore ? false }:
 let
   customBuildRustCrateForPkgs = pkgs: pkgs.buildRustCrate.override {
     defaultCrateOverrides = pkgs.defaultCrateOverrides // {
@@ -10,10 +10,16 @@ let
         buildInputs = [ libarchive ];
         nativeBuildInputs = [ pkg-config ];
       };
+      # the C API of libstore, for the libstore feature
+      nixseparatedebuginfod = attrs: lib.optionalAttrs withLibstore {
+        buildInputs = [ nix ];
+      };
     };
   };
   generatedBuild = callPackage ./Cargo.nix {
     buildRustCrateForPkgs = customBuildRustCrateForPkgs;
   };
-in generatedBuild.rootCrate.build
+in if withLibstore
+  then generatedBuild.rootCrate.build.override { features = [ "libstore" ]; }
+  else generatedBuild.rootCrate.build
 
@@ -0,0 +1,181 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Socket activation by systemd, and exiting when idle or when the parent process exits.
+//!
+//! On machines where debugging is rare, the server can be started by systemd on the first
+//! connection, and exit after some time without requests so that it does not use memory in
+//! the meantime.
+
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, IntoRawFd};
+use std::os::unix::net::UnixListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+
+/// How often [wait_parent_exit] checks whether the parent process exited
+const PARENT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The first file descriptor passed by systemd
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// Returns how many sockets were passed to this process according to the values of
+/// `LISTEN_PID` and `LISTEN_FDS`.
+fn passed_sockets(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    pid: u32,
+) -> anyhow::Result<usize> {
+    let (listen_pid, listen_fds) = match (listen_pid, listen_fds) {
+        (Some(listen_pid), Some(listen_fds)) => (listen_pid, listen_fds),
+        _ => return Ok(0),
+    };
+    if listen_pid.parse::<u32>().ok() != Some(pid) {
+        // meant for another process
+        return Ok(0);
+    }
+    listen_fds
+        .parse()
+        .map_err(|_| anyhow::anyhow!("invalid LISTEN_FDS={}", listen_fds))
+}
+
+#[test]
+fn test_passed_sockets() {
+    assert_eq!(passed_sockets(None, None, 12).unwrap(), 0);
+    assert_eq!(passed_sockets(Some("12"), Some("1"), 12).unwrap(), 1);
+    assert_eq!(passed_sockets(Some("13"), Some("1"), 12).unwrap(), 0);
+    assert!(passed_sockets(Some("12"), Some("x"), 12).is_err());
+}
+
+/// A listening socket passed by systemd
+pub enum Listener {
+    /// from `ListenStream=127.0.0.1:1949` for example
+    Tcp(TcpListener),
+    /// from `ListenStream=/run/nixseparatedebuginfod.sock` for example
+    Unix(UnixListener),
+}
+
+/// Returns the listening sockets passed by systemd, if this process was socket activated,
+/// for example one IPv4 and one IPv6 socket.
+pub fn listeners_from_systemd() -> anyhow::Result<Vec<Listener>> {
+    let listen_pid = std::env::var("LISTEN_PID").ok();
+    let listen_fds = std::env::var("LISTEN_FDS").ok();
+    let n = passed_sockets(
+        listen_pid.as_deref(),
+        listen_fds.as_deref(),
+        std::process::id(),
+    )?;
+    // do not pass the sockets to our children
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    let mut listeners = Vec::with_capacity(n);
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + n as i32 {
+        // SAFETY: systemd passed us this fd, and nothing else uses it
+        let unix = unsafe { UnixListener::from_raw_fd(fd) };
+        // the address of a socket which is not a unix socket cannot be read as a unix address
+        let listener = if unix.local_addr().is_ok() {
+            unix.set_nonblocking(true)
+                .context("setting socket passed by systemd nonblocking")?;
+            Listener::Unix(unix)
+        } else {
+            // SAFETY: the fd was just released by `unix`
+            let tcp = unsafe { TcpListener::from_raw_fd(unix.into_raw_fd()) };
+            tcp.set_nonblocking(true)
+                .context("setting socket passed by systemd nonblocking")?;
+            Listener::Tcp(tcp)
+        };
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+/// Tracks when the server last received an HTTP request.
+#[derive(Clone)]
+pub struct Activity {
+    /// when the last request finished
+    last: Arc<Mutex<Instant>>,
+    /// how many requests are being answered
+    running: Arc<AtomicUsize>,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Activity {
+            last: Arc::new(Mutex::new(Instant::now())),
+            running: Arc::default(),
+        }
+    }
+}
+
+impl Activity {
+    /// How long ago the last request finished, or `None` if a request is being answered
+    fn idle_for(&self) -> Option<Duration> {
+        if self.running.load(Ordering::SeqCst) > 0 {
+            None
+        } else {
+            Some(self.last.lock().unwrap().elapsed())
+        }
+    }
+
+    /// Returns when the server was idle for `timeout`, according to this tracker and
+    /// `busy`, which tells whether there is work pending other than requests.
+    pub async fn wait_idle(&self, timeout: Duration, busy: impl Fn() -> bool) {
+        loop {
+            let remaining = match self.idle_for() {
+                Some(idle) if idle >= timeout && !busy() => return,
+                Some(idle) if idle < timeout => timeout - idle,
+                _ => timeout,
+            };
+            tokio::time::sleep(remaining.max(Duration::from_secs(1))).await;
+        }
+    }
+}
+
+/// Decrements the number of running requests when dropped, even if the request is cancelled
+struct Running(Activity);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        *self.0.last.lock().unwrap() = Instant::now();
+        self.0.running.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Middleware recording requests in an [Activity]
+pub async fn track_activity(
+    State(activity): State<Activity>,
+    request: Request,
+    next: Next,
+) -> Response {
+    activity.running.fetch_add(1, Ordering::SeqCst);
+    let _running = Running(activity);
+    next.run(request).await
+}
+
+#[test]
+fn test_idle_for() {
+    let activity = Activity::default();
+    activity.running.fetch_add(1, Ordering::SeqCst);
+    let running = Running(activity.clone());
+    assert_eq!(activity.idle_for(), None);
+    drop(running);
+    assert!(activity.idle_for().unwrap() < Duration::from_secs(10));
+}
+
+/// Returns when the parent process of this process exits.
+///
+/// Orphans are reparented to init or a subreaper, so the parent pid changes.
+pub async fn wait_parent_exit() {
+    let parent = std::os::unix::process::parent_id();
+    while std::os::unix::process::parent_id() == parent {
+        tokio::time::sleep(PARENT_POLL_INTERVAL).await;
+    }
+}
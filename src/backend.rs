@@ -0,0 +1,361 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! The package managers whose stores can be indexed: nix, and GNU Guix.
+//!
+//! The daemon of Guix is a fork of the nix daemon: the store is laid out the same way in
+//! `/gnu/store`, valid store paths are listed in the same sqlite db in `/var/guix/db`, and
+//! derivations are written in the same format. Only the commands querying and realising store
+//! paths differ, see the functions of this module which [crate::store] uses with
+//! [Backend::Guix].
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use once_cell::sync::OnceCell;
+use serde_json::json;
+
+use crate::store::{command_output, store_command};
+
+/// A package manager whose store is indexed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    /// nix, with its store in `/nix/store`
+    #[default]
+    Nix,
+    /// GNU Guix, with its store in `/gnu/store`
+    Guix,
+}
+
+impl Backend {
+    /// The directory of the store
+    pub fn store_dir(self) -> &'static str {
+        match self {
+            Backend::Nix => "/nix/store",
+            Backend::Guix => "/gnu/store",
+        }
+    }
+
+    /// The sqlite db listing valid store paths
+    pub fn db(self) -> &'static str {
+        match self {
+            Backend::Nix => "/nix/var/nix/db/db.sqlite",
+            Backend::Guix => "/var/guix/db/db.sqlite",
+        }
+    }
+
+    /// The socket of the daemon
+    pub fn daemon_socket(self) -> &'static str {
+        match self {
+            Backend::Nix => "/nix/var/nix/daemon-socket/socket",
+            Backend::Guix => "/var/guix/daemon-socket/socket",
+        }
+    }
+}
+
+/// The package manager set by [set_backend]
+static BACKEND: OnceCell<Backend> = OnceCell::new();
+
+/// Indexes and serves the store of this package manager instead of nix.
+///
+/// Should be called on startup.
+pub fn set_backend(backend: Backend) {
+    if BACKEND.set(backend).is_err() {
+        tracing::warn!("store backend was already set");
+    }
+}
+
+/// The package manager set by [set_backend], nix by default
+pub fn backend() -> Backend {
+    BACKEND.get().copied().unwrap_or_default()
+}
+
+/// Whether the store is the one of GNU Guix
+pub fn is_guix() -> bool {
+    backend() == Backend::Guix
+}
+
+/// Runs `guix` with these arguments, and returns the store paths it prints, one per line
+fn guix_paths(args: &[&std::ffi::OsStr]) -> anyhow::Result<Vec<PathBuf>> {
+    let mut cmd = store_command("guix");
+    cmd.args(args);
+    tracing::debug!("Running {:?}", &cmd);
+    let out = command_output(&mut cmd)?;
+    if !out.status.success() {
+        anyhow::bail!("{:?} failed: {}", cmd, String::from_utf8_lossy(&out.stderr));
+    }
+    let stdout = String::from_utf8(out.stdout)
+        .with_context(|| format!("{:?} returned non utf8 output", cmd))?;
+    let mut result = Vec::new();
+    for line in stdout.lines().filter(|line| !line.is_empty()) {
+        let path = PathBuf::from(line);
+        if !path.is_absolute() {
+            anyhow::bail!("{:?} returned weird path {}", cmd, line);
+        }
+        result.push(path);
+    }
+    Ok(result)
+}
+
+/// Obtains the derivers of this store path which are in the store.
+///
+/// Corresponds to `guix gc --derivers`
+pub fn guix_derivers(storepath: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    guix_paths(&["gc".as_ref(), "--derivers".as_ref(), storepath.as_os_str()])
+}
+
+/// Obtains the closure of this store path, including itself.
+///
+/// Corresponds to `guix gc --requisites`
+pub fn guix_closure(storepath: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    guix_paths(&[
+        "gc".as_ref(),
+        "--requisites".as_ref(),
+        storepath.as_os_str(),
+    ])
+}
+
+/// Lists all the valid store paths.
+///
+/// Corresponds to `guix gc --list-live` and `guix gc --list-dead` together.
+pub fn guix_list_store_paths() -> anyhow::Result<Vec<PathBuf>> {
+    let mut paths = guix_paths(&["gc".as_ref(), "--list-live".as_ref()])?;
+    paths.extend(guix_paths(&["gc".as_ref(), "--list-dead".as_ref()])?);
+    Ok(paths)
+}
+
+/// The scheme program making the guix daemon substitute this store path if it is missing
+fn ensure_path_program(path: &Path) -> anyhow::Result<String> {
+    let path = path
+        .to_str()
+        .with_context(|| format!("{} is not utf8", path.display()))?;
+    // strings in scheme are escaped like in json
+    Ok(format!(
+        "(use-modules (guix store)) (with-store store (ensure-path store {}))",
+        serde_json::Value::from(path)
+    ))
+}
+
+#[test]
+fn test_ensure_path_program() {
+    assert_eq!(
+        ensure_path_program(Path::new("/gnu/store/aaa-foo-1.0-debug")).unwrap(),
+        r#"(use-modules (guix store)) (with-store store (ensure-path store "/gnu/store/aaa-foo-1.0-debug"))"#
+    );
+}
+
+/// Asks the guix daemon to substitute this store path, with `ensure-path` in `guix repl`, as
+/// `guix build` only accepts derivations and packages.
+///
+/// Returns the output of `guix repl`.
+pub async fn guix_ensure_path(path: &Path) -> anyhow::Result<std::process::Output> {
+    use tokio::io::AsyncWriteExt;
+    let program = ensure_path_program(path)?;
+    let mut command = tokio::process::Command::from(store_command("guix"));
+    command
+        .arg("repl")
+        .arg("/dev/stdin")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true);
+    tracing::info!("Running {:?} for {}", &command, path.display());
+    let mut child = command
+        .spawn()
+        .with_context(|| format!("running {:?}", command))?;
+    let mut stdin = child.stdin.take().context("guix repl has no stdin")?;
+    stdin
+        .write_all(program.as_bytes())
+        .await
+        .context("writing to guix repl")?;
+    drop(stdin);
+    child
+        .wait_with_output()
+        .await
+        .with_context(|| format!("running {:?}", command))
+}
+
+/// A value in the ATerm format of derivation files
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ATerm {
+    /// `"foo"`
+    Str(String),
+    /// `[a,b]`
+    List(Vec<ATerm>),
+    /// `(a,b)`, or `Derive(a,b)` with the constructor ignored
+    Tuple(Vec<ATerm>),
+}
+
+/// Parses an ATerm starting at `input[*pos]`, and advances `pos` past it
+fn parse_aterm(input: &[u8], pos: &mut usize) -> anyhow::Result<ATerm> {
+    // skip the name of constructors like Derive
+    while input.get(*pos).is_some_and(u8::is_ascii_alphabetic) {
+        *pos += 1;
+    }
+    match input.get(*pos) {
+        Some(b'"') => {
+            *pos += 1;
+            let mut result = Vec::new();
+            loop {
+                match input.get(*pos) {
+                    None => anyhow::bail!("unterminated string in derivation"),
+                    Some(b'"') => break,
+                    Some(b'\\') => {
+                        *pos += 1;
+                        result.push(match input.get(*pos) {
+                            Some(b'n') => b'\n',
+                            Some(b'r') => b'\r',
+                            Some(b't') => b'\t',
+                            Some(&c) => c,
+                            None => anyhow::bail!("unterminated string in derivation"),
+                        });
+                    }
+                    Some(&c) => result.push(c),
+                }
+                *pos += 1;
+            }
+            *pos += 1;
+            Ok(ATerm::Str(
+                String::from_utf8(result).context("non utf8 string in derivation")?,
+            ))
+        }
+        Some(&open @ (b'[' | b'(')) => {
+            let close = if open == b'[' { b']' } else { b')' };
+            *pos += 1;
+            let mut items = Vec::new();
+            if input.get(*pos) == Some(&close) {
+                *pos += 1;
+            } else {
+                loop {
+                    items.push(parse_aterm(input, pos)?);
+                    match input.get(*pos) {
+                        Some(b',') => *pos += 1,
+                        Some(&c) if c == close => {
+                            *pos += 1;
+                            break;
+                        }
+                        _ => anyhow::bail!("unexpected character at offset {} in derivation", pos),
+                    }
+                }
+            }
+            Ok(if open == b'[' {
+                ATerm::List(items)
+            } else {
+                ATerm::Tuple(items)
+            })
+        }
+        _ => anyhow::bail!("unexpected character at offset {} in derivation", pos),
+    }
+}
+
+impl ATerm {
+    fn as_str(&self) -> anyhow::Result<&str> {
+        match self {
+            ATerm::Str(s) => Ok(s),
+            _ => anyhow::bail!("expected a string in derivation"),
+        }
+    }
+
+    fn as_items(&self) -> anyhow::Result<&[ATerm]> {
+        match self {
+            ATerm::List(items) | ATerm::Tuple(items) => Ok(items),
+            ATerm::Str(_) => anyhow::bail!("expected a list in derivation"),
+        }
+    }
+}
+
+/// Converts the content of a derivation file to what `nix derivation show` prints for it,
+/// restricted to `outputs`, `inputDrvs` and `env`.
+fn derivation_to_json(drv: &[u8]) -> anyhow::Result<serde_json::Value> {
+    let mut pos = 0;
+    let parsed = parse_aterm(drv, &mut pos)?;
+    let fields = parsed.as_items()?;
+    anyhow::ensure!(fields.len() >= 7, "derivation has too few fields");
+    let mut outputs = serde_json::Map::new();
+    for output in fields[0].as_items()? {
+        match output.as_items()? {
+            [name, path, algo, hash] => {
+                let mut value = json!({ "path": path.as_str()? });
+                if !algo.as_str()?.is_empty() {
+                    value["hashAlgo"] = algo.as_str()?.into();
+                    value["hash"] = hash.as_str()?.into();
+                }
+                outputs.insert(name.as_str()?.to_owned(), value);
+            }
+            _ => anyhow::bail!("weird output in derivation"),
+        }
+    }
+    let mut input_drvs = serde_json::Map::new();
+    for input in fields[1].as_items()? {
+        match input.as_items()? {
+            [path, names] => {
+                let names = names
+                    .as_items()?
+                    .iter()
+                    .map(|name| name.as_str().map(str::to_owned))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                input_drvs.insert(path.as_str()?.to_owned(), json!({ "outputs": names }));
+            }
+            _ => anyhow::bail!("weird input in derivation"),
+        }
+    }
+    let mut env = BTreeMap::new();
+    for binding in fields[6].as_items()? {
+        match binding.as_items()? {
+            [name, value] => {
+                env.insert(name.as_str()?.to_owned(), value.as_str()?.to_owned());
+            }
+            _ => anyhow::bail!("weird environment binding in derivation"),
+        }
+    }
+    Ok(json!({
+        "outputs": outputs,
+        "inputDrvs": input_drvs,
+        "env": env,
+    }))
+}
+
+#[test]
+fn test_derivation_to_json() {
+    let drv = br#"Derive([("debug","/gnu/store/bbb-hello-2.12-debug","",""),("out","/gnu/store/ccc-hello-2.12","","")],[("/gnu/store/ddd-hello-2.12.tar.gz.drv",["out"])],["/gnu/store/eee-builder"],"x86_64-linux","/gnu/store/fff-guile/bin/guile",["--no-auto-compile","-L"],[("out","/gnu/store/ccc-hello-2.12"),("src","/gnu/store/ggg-hello-2.12.tar.gz"),("weird","a\"b\\c\nd")])"#;
+    assert_eq!(
+        derivation_to_json(drv).unwrap(),
+        json!({
+            "outputs": {
+                "debug": {"path": "/gnu/store/bbb-hello-2.12-debug"},
+                "out": {"path": "/gnu/store/ccc-hello-2.12"},
+            },
+            "inputDrvs": {"/gnu/store/ddd-hello-2.12.tar.gz.drv": {"outputs": ["out"]}},
+            "env": {
+                "out": "/gnu/store/ccc-hello-2.12",
+                "src": "/gnu/store/ggg-hello-2.12.tar.gz",
+                "weird": "a\"b\\c\nd",
+            },
+        })
+    );
+    let fixed = br#"Derive([("out","/gnu/store/ggg-hello-2.12.tar.gz","sha256","8d99142afd92576f30b0cd7cb42a8dc6809998bc5d607d88761f512e26c7db20")],[],[],"x86_64-linux","builtin:download",[],[])"#;
+    assert_eq!(
+        derivation_to_json(fixed).unwrap()["outputs"]["out"],
+        json!({
+            "path": "/gnu/store/ggg-hello-2.12.tar.gz",
+            "hashAlgo": "sha256",
+            "hash": "8d99142afd92576f30b0cd7cb42a8dc6809998bc5d607d88761f512e26c7db20",
+        })
+    );
+    assert!(derivation_to_json(b"Derive([").is_err());
+}
+
+/// Reads these derivation files, and returns what `nix derivation show` would print for them,
+/// restricted to `outputs`, `inputDrvs` and `env`.
+pub fn guix_show_derivations(drvs: &[PathBuf]) -> anyhow::Result<serde_json::Value> {
+    let mut result = serde_json::Map::new();
+    for drv in drvs {
+        let content = std::fs::read(drv).with_context(|| format!("reading {}", drv.display()))?;
+        let json =
+            derivation_to_json(&content).with_context(|| format!("parsing {}", drv.display()))?;
+        result.insert(drv.to_string_lossy().into_owned(), json);
+    }
+    Ok(result.into())
+}
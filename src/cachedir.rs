@@ -0,0 +1,138 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Least recently used eviction in the subdirectories of the cache directory where files are
+//! created on demand: extracted sources, files fetched from nars, extracted sections...
+//!
+//! Each entry of such a directory, a file or a directory, is marked as used by its mtime, see
+//! [touch]. Entries whose name starts with a dot are temporary files of a creation in progress,
+//! and are never evicted.
+
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::Context;
+
+/// How much a cache directory may contain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// at most this many entries
+    Entries(usize),
+    /// entries taking at most this many bytes in total
+    Bytes(u64),
+}
+
+/// Marks this entry of a cache directory as recently used
+pub fn touch(path: &Path) -> anyhow::Result<()> {
+    std::fs::File::open(path)
+        .and_then(|file| file.set_modified(SystemTime::now()))
+        .with_context(|| format!("updating mtime of {}", path.display()))
+}
+
+/// Size in bytes of the files of this entry
+fn entry_size(path: &Path, metadata: &std::fs::Metadata) -> u64 {
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    walkdir::WalkDir::new(path)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Removes the least recently used entries of `cache_dir` until it is within `limit`, but
+/// never `keep`, typically the entry which was just created.
+pub fn evict(cache_dir: &Path, limit: Limit, keep: Option<&Path>) -> anyhow::Result<()> {
+    let mut entries = Vec::new();
+    for entry in
+        std::fs::read_dir(cache_dir).with_context(|| format!("listing {}", cache_dir.display()))?
+    {
+        let entry = entry.with_context(|| format!("listing {}", cache_dir.display()))?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            // creation in progress
+            continue;
+        }
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        let mtime = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        let size = match limit {
+            Limit::Entries(_) => 1,
+            Limit::Bytes(_) => entry_size(&entry.path(), &metadata),
+        };
+        entries.push((mtime, size, entry.path()));
+    }
+    let (mut total, max) = match limit {
+        Limit::Entries(max) => (entries.len() as u64, max as u64),
+        Limit::Bytes(max) => (entries.iter().map(|(_, size, _)| size).sum(), max),
+    };
+    entries.sort();
+    for (_, size, path) in entries {
+        if total <= max {
+            break;
+        }
+        if Some(path.as_path()) == keep {
+            continue;
+        }
+        tracing::debug!("evicting {} from the cache", path.display());
+        let removed = if path.is_dir() {
+            std::fs::remove_dir_all(&path)
+        } else {
+            std::fs::remove_file(&path)
+        };
+        match removed {
+            Ok(()) => total -= size,
+            Err(e) => tracing::warn!("removing {}: {:#}", path.display(), e),
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_evict_bytes() {
+    let dir = tempfile::tempdir().unwrap();
+    for (i, name) in ["old", "new", "kept", ".partial"].into_iter().enumerate() {
+        let path = dir.path().join(name);
+        std::fs::write(&path, "0123456789").unwrap();
+        let mtime = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1000 * i as u64);
+        std::fs::File::open(&path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+    }
+    // kept is the newest, but too big alone
+    evict(dir.path(), Limit::Bytes(5), Some(&dir.path().join("kept"))).unwrap();
+    assert!(!dir.path().join("old").exists());
+    assert!(!dir.path().join("new").exists());
+    assert!(dir.path().join("kept").exists());
+    assert!(dir.path().join(".partial").exists());
+}
+
+#[test]
+fn test_evict_directories() {
+    let dir = tempfile::TempDir::new().unwrap();
+    for name in ["a", "b", "c", ".extracting-d"] {
+        std::fs::create_dir(dir.path().join(name)).unwrap();
+        std::fs::write(dir.path().join(name).join("file"), "0123456789").unwrap();
+    }
+    for (name, age) in [("a", 3), ("b", 1), ("c", 2)] {
+        std::fs::File::open(dir.path().join(name))
+            .unwrap()
+            .set_modified(SystemTime::now() - std::time::Duration::from_secs(age * 100))
+            .unwrap();
+    }
+    evict(dir.path(), Limit::Entries(2), None).unwrap();
+    assert!(!dir.path().join("a").exists());
+    assert!(dir.path().join("b").exists());
+    assert!(dir.path().join("c").exists());
+    assert!(dir.path().join(".extracting-d").exists());
+    // directories weigh what their files do
+    evict(dir.path(), Limit::Bytes(15), None).unwrap();
+    assert!(dir.path().join("b").exists());
+    assert!(!dir.path().join("c").exists());
+}
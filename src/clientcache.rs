@@ -0,0 +1,211 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Writing fetched files to the cache of the debuginfod client of elfutils.
+//!
+//! gdb and other tools using `libdebuginfod` look in this cache before querying any server of
+//! `DEBUGINFOD_URLS`, so files written there are found even when this server is not running.
+//! The layout is `<cache>/<buildid>/debuginfo`, `<cache>/<buildid>/executable` and
+//! `<cache>/<buildid>/source#path#to#file.c`.
+//!
+//! The `fetch` subcommand is a client populating this cache, like `debuginfod-find`.
+
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use anyhow::Context;
+use reqwest::Url;
+use tokio::io::AsyncWriteExt;
+
+use crate::federation::{download, file_url, Kind};
+use crate::store::{is_compressed_debuginfo, is_valid_buildid};
+
+/// The cache directory of the debuginfod client: `$DEBUGINFOD_CACHE_PATH`, or
+/// `debuginfod_client` in the user cache directory, like `libdebuginfod` does.
+pub fn client_cache_dir() -> anyhow::Result<PathBuf> {
+    if let Some(path) = std::env::var_os("DEBUGINFOD_CACHE_PATH") {
+        return Ok(PathBuf::from(path));
+    }
+    let dirs = directories::BaseDirs::new().context("cannot determine the cache directory")?;
+    Ok(dirs.cache_dir().join("debuginfod_client"))
+}
+
+/// Writes `file` to the client cache in `cache_dir` as the file of this kind (`debuginfo` or
+/// `executable`) of this buildid, decompressing it if it is compressed debuginfo.
+///
+/// Returns where it was written. A file already in the cache is kept.
+pub async fn install(
+    cache_dir: &Path,
+    buildid: &str,
+    kind: &str,
+    file: &Path,
+) -> anyhow::Result<PathBuf> {
+    let dir = cache_dir.join(buildid);
+    let target = dir.join(kind);
+    if tokio::fs::metadata(&target).await.is_ok() {
+        return Ok(target);
+    }
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .with_context(|| format!("creating {}", dir.display()))?;
+    // other clients must never see a partial file
+    let tmp = tempfile::Builder::new()
+        .prefix(".tmp")
+        .tempfile_in(&dir)
+        .with_context(|| format!("creating temporary file in {}", dir.display()))?
+        .into_temp_path();
+    if is_compressed_debuginfo(file) {
+        let input = tokio::fs::File::open(file)
+            .await
+            .with_context(|| format!("opening {}", file.display()))?;
+        let mut output = tokio::fs::File::create(&tmp)
+            .await
+            .with_context(|| format!("opening {}", tmp.display()))?;
+        compress_tools::tokio_support::uncompress_data(input, &mut output)
+            .await
+            .with_context(|| format!("uncompressing {}", file.display()))?;
+        output
+            .flush()
+            .await
+            .with_context(|| format!("writing {}", tmp.display()))?;
+    } else {
+        tokio::fs::copy(file, &tmp)
+            .await
+            .with_context(|| format!("copying {}", file.display()))?;
+    }
+    tmp.persist(&target)
+        .with_context(|| format!("renaming to {}", target.display()))?;
+    Ok(target)
+}
+
+#[tokio::test]
+async fn test_install() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("foo.debug");
+    std::fs::write(&file, b"debuginfo").unwrap();
+    let cache = dir.path().join("debuginfod_client");
+    let buildid = "483bd7f7229bdb06462222e1e353e4f37e15c293";
+    let target = install(&cache, buildid, "debuginfo", &file).await.unwrap();
+    assert_eq!(target, cache.join(buildid).join("debuginfo"));
+    assert_eq!(std::fs::read(&target).unwrap(), b"debuginfo");
+    // existing files are kept
+    std::fs::write(&file, b"other").unwrap();
+    install(&cache, buildid, "debuginfo", &file).await.unwrap();
+    assert_eq!(std::fs::read(&target).unwrap(), b"debuginfo");
+    assert_eq!(std::fs::read_dir(cache.join(buildid)).unwrap().count(), 1);
+}
+
+/// A file of a buildid, as requested by the `fetch` subcommand
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FetchKind {
+    /// The separate debuginfo
+    Debuginfo,
+    /// The executable or library
+    Executable,
+    /// A source file
+    Source,
+}
+
+/// Where the debuginfod client keeps this file of this buildid, relative to its cache directory
+fn cached_path(buildid: &str, kind: Kind) -> PathBuf {
+    match kind {
+        Kind::Debuginfo => Path::new(buildid).join("debuginfo"),
+        Kind::Executable => Path::new(buildid).join("executable"),
+        // like libdebuginfod, which keeps the whole path in one file name
+        Kind::Source(path) => {
+            let path = if path.starts_with('/') {
+                path.to_owned()
+            } else {
+                format!("/{}", path)
+            };
+            Path::new(buildid).join(format!("source{}", path.replace('/', "#")))
+        }
+    }
+}
+
+#[test]
+fn test_cached_path() {
+    let buildid = "483bd7f7229bdb06462222e1e353e4f37e15c293";
+    assert_eq!(
+        cached_path(buildid, Kind::Debuginfo),
+        Path::new(buildid).join("debuginfo")
+    );
+    assert_eq!(
+        cached_path(buildid, Kind::Source("/build/source/main.c")),
+        Path::new(buildid).join("source#build#source#main.c")
+    );
+    assert_eq!(
+        cached_path(buildid, Kind::Source("src/main.c")),
+        Path::new(buildid).join("source#src#main.c")
+    );
+}
+
+/// Parses the servers of `DEBUGINFOD_URLS`, a space separated list of urls
+fn parse_debuginfod_urls(urls: &str) -> Vec<Url> {
+    let mut result = Vec::new();
+    for url in urls.split_whitespace() {
+        match Url::parse(url) {
+            Ok(url) => result.push(url),
+            Err(e) => tracing::warn!("ignoring invalid url {} in DEBUGINFOD_URLS: {}", url, e),
+        }
+    }
+    result
+}
+
+#[test]
+fn test_parse_debuginfod_urls() {
+    assert_eq!(
+        parse_debuginfod_urls(" http://127.0.0.1:1949  https://debuginfod.elfutils.org/ not a url"),
+        vec![
+            Url::parse("http://127.0.0.1:1949").unwrap(),
+            Url::parse("https://debuginfod.elfutils.org/").unwrap()
+        ]
+    );
+    assert!(parse_debuginfod_urls("").is_empty());
+}
+
+/// Fetches this file of this buildid from the first server of `DEBUGINFOD_URLS` which has it
+/// into the client cache, unless it is already there, and prints where it is.
+///
+/// The exit code is 1 if no server has it.
+pub async fn run_fetch(buildid: &str, kind: Kind<'_>) -> anyhow::Result<ExitCode> {
+    let buildid = &buildid.to_ascii_lowercase();
+    // the buildid becomes a path in the cache directory
+    anyhow::ensure!(is_valid_buildid(buildid), "invalid buildid {:?}", buildid);
+    let target = client_cache_dir()?.join(cached_path(buildid, kind));
+    if tokio::fs::metadata(&target).await.is_ok() {
+        println!("{}", target.display());
+        return Ok(ExitCode::SUCCESS);
+    }
+    let urls = parse_debuginfod_urls(&std::env::var("DEBUGINFOD_URLS").unwrap_or_default());
+    anyhow::ensure!(!urls.is_empty(), "DEBUGINFOD_URLS lists no server");
+    let client = crate::tls::client();
+    let mut error = None;
+    for base in &urls {
+        let url = file_url(base, buildid, kind)?;
+        match download(&client, &url, &target).await {
+            Ok(true) => {
+                println!("{}", target.display());
+                return Ok(ExitCode::SUCCESS);
+            }
+            Ok(false) => tracing::debug!("{} was not found", &url),
+            Err(e) => {
+                tracing::info!("{:#}", e);
+                error = Some(e);
+            }
+        }
+    }
+    match error {
+        // a server which could not be queried may have it
+        Some(e) => Err(e),
+        None => {
+            match kind {
+                Kind::Source(path) => eprintln!("source {} of {} not found", path, buildid),
+                Kind::Debuginfo => eprintln!("debuginfo of {} not found", buildid),
+                Kind::Executable => eprintln!("executable of {} not found", buildid),
+            }
+            Ok(ExitCode::FAILURE)
+        }
+    }
+}
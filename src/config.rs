@@ -10,7 +10,7 @@ pub type NixConfig = HashMap<String, String>;
 ///
 /// Concatenates together the extra-* options
 pub async fn get_nix_config() -> anyhow::Result<NixConfig> {
-    let mut cmd = tokio::process::Command::new("nix");
+    let mut cmd = tokio::process::Command::from(crate::store::store_command("nix"));
     cmd.args([
         "--extra-experimental-features",
         "nix-command",
@@ -34,12 +34,11 @@ fn parse_nix_config(text: &str) -> anyhow::Result<NixConfig> {
         if let Some(cut) = line.find("=") {
             let key = &line[..cut].trim();
             let value = &line[(cut + 1)..].trim();
-            if key.starts_with("extra-") {
-                let key = &key[6..];
+            if let Some(key) = key.strip_prefix("extra-") {
                 let entry = result.entry(key.to_string());
                 entry
                     .and_modify(|before| {
-                        before.push_str(" ");
+                        before.push(' ');
                         before.push_str(value);
                     })
                     .or_insert_with(|| value.to_string());
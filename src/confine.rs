@@ -0,0 +1,97 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Confining the files served for source requests.
+//!
+//! The path of a source request is chosen by the client, and the content of a source tree by
+//! whoever built the package: neither a `..` in the request nor a symlink in the source (or in
+//! an extracted archive) must make the server read a file outside the source it registered.
+
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::store::{demangle, store_dir};
+
+/// Returns the canonical path of `path`, if it is inside `root` once symlinks are resolved.
+///
+/// Blocking.
+pub fn confine(root: &Path, path: &Path) -> anyhow::Result<PathBuf> {
+    let canonical_root = root
+        .canonicalize()
+        .with_context(|| format!("resolving {}", root.display()))?;
+    let canonical = path
+        .canonicalize()
+        .with_context(|| format!("resolving {}", path.display()))?;
+    anyhow::ensure!(
+        canonical.starts_with(&canonical_root),
+        "{} points outside of {}",
+        path.display(),
+        root.display()
+    );
+    Ok(canonical)
+}
+
+#[test]
+fn test_confine() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("source");
+    std::fs::create_dir_all(root.join("src")).unwrap();
+    std::fs::write(root.join("src/main.c"), "content").unwrap();
+    std::fs::write(dir.path().join("secret"), "secret").unwrap();
+    std::os::unix::fs::symlink("main.c", root.join("src/link.c")).unwrap();
+    std::os::unix::fs::symlink("../../secret", root.join("src/escape.c")).unwrap();
+    assert_eq!(
+        confine(&root, &root.join("src/link.c")).unwrap(),
+        root.join("src/main.c").canonicalize().unwrap()
+    );
+    assert!(confine(&root, &root.join("src/escape.c")).is_err());
+    assert!(confine(&root, &root.join("src/../../secret")).is_err());
+    assert!(confine(&root, &root.join("src/missing.c")).is_err());
+}
+
+/// Converts the path of a request for a file of another store path, made relative to `/` like
+/// `nix/store/<hash>-foo/include/foo.h`, to the absolute path of this file, with the hash in
+/// lowercase (see [demangle]).
+///
+/// Fails if the path contains `..` or does not name a file in a store path. The file must
+/// still be checked with [confine_to_store] once realised, as store paths can contain
+/// symlinks.
+pub fn store_request(request: &str) -> anyhow::Result<PathBuf> {
+    let absolute = Path::new("/").join(request);
+    anyhow::ensure!(
+        absolute.components().all(|c| c != Component::ParentDir),
+        "{} contains ..",
+        request
+    );
+    let relative = absolute
+        .strip_prefix(store_dir())
+        .with_context(|| format!("{} is not in {}", request, store_dir()))?;
+    anyhow::ensure!(
+        relative.components().count() > 0,
+        "{} is not a store path",
+        request
+    );
+    Ok(demangle(absolute))
+}
+
+#[test]
+fn test_store_request() {
+    assert_eq!(
+        store_request("nix/store/JW65XNML1FGF4BFGZGISZCK3LFJWXG6L-gcc-12.3.0/include/vector")
+            .unwrap(),
+        PathBuf::from("/nix/store/jw65xnml1fgf4bfgzgiszck3lfjwxg6l-gcc-12.3.0/include/vector")
+    );
+    assert!(store_request("nix/store/../../etc/passwd").is_err());
+    assert!(store_request("nix/store/aaa-foo/../../../etc/passwd").is_err());
+    assert!(store_request("nix/store").is_err());
+    assert!(store_request("etc/passwd").is_err());
+}
+
+/// Like [confine] with the store directory as root.
+///
+/// Blocking.
+pub fn confine_to_store(path: &Path) -> anyhow::Result<PathBuf> {
+    confine(Path::new(store_dir()), path)
+}
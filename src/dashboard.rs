@@ -0,0 +1,272 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! A small html page served at `/` summarizing the state of the server, for users who would
+//! rather not read the json of `/admin/stats`.
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+
+use crate::db::{Coverage, Hit, Miss};
+use crate::index::IndexerHealth;
+use crate::telemetry::IndexerSnapshot;
+
+/// How many requests [RecentRequests] remembers
+const RECENT_REQUESTS: usize = 50;
+
+/// A request, as shown on the dashboard
+#[derive(Debug, Clone)]
+pub struct RequestRecord {
+    /// when the request was received, in seconds since the epoch
+    pub time: u64,
+    /// the requested path
+    pub path: String,
+    /// the status of the response
+    pub status: u16,
+    /// how long it took to answer, in milliseconds
+    pub duration_ms: u64,
+}
+
+/// The last debuginfod requests received
+#[derive(Clone, Default)]
+pub struct RecentRequests(Arc<Mutex<VecDeque<RequestRecord>>>);
+
+impl RecentRequests {
+    /// Remembers this request, forgetting the oldest one if needed
+    fn push(&self, record: RequestRecord) {
+        let mut recent = self.0.lock().unwrap();
+        if recent.len() >= RECENT_REQUESTS {
+            recent.pop_front();
+        }
+        recent.push_back(record);
+    }
+
+    /// Returns the remembered requests, most recent first
+    pub fn list(&self) -> Vec<RequestRecord> {
+        self.0.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+/// Middleware recording debuginfod requests in a [RecentRequests]
+pub async fn record_requests(
+    State(recent): State<RecentRequests>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path().to_owned();
+    if !path.starts_with("/buildid/") {
+        return next.run(request).await;
+    }
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let start = Instant::now();
+    let response = next.run(request).await;
+    recent.push(RequestRecord {
+        time,
+        path,
+        status: response.status().as_u16(),
+        duration_ms: start.elapsed().as_millis() as u64,
+    });
+    response
+}
+
+/// Everything shown on the dashboard
+pub struct Dashboard {
+    /// what the cache knows
+    pub coverage: Option<Coverage>,
+    /// whether the nix db can be read
+    pub health: IndexerHealth,
+    /// indexation throughput and backlog
+    pub indexing: IndexerSnapshot,
+    /// the last requests
+    pub recent: Vec<RequestRecord>,
+    /// the most requested buildids whose debuginfo was not found
+    pub misses: Vec<Miss>,
+    /// the buildids whose files were served the most
+    pub hits: Vec<Hit>,
+    /// the configuration, as (name, value), only shown to requests with the admin token
+    pub config: Option<Vec<(&'static str, String)>>,
+}
+
+/// Escapes text for inclusion in html
+fn escape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+#[test]
+fn test_escape() {
+    assert_eq!(
+        escape("<a href='x'>&</a>"),
+        "&lt;a href=&#39;x&#39;&gt;&amp;&lt;/a&gt;"
+    );
+}
+
+/// Appends a table with these headers and rows
+fn table(html: &mut String, headers: &[&str], rows: impl IntoIterator<Item = Vec<String>>) {
+    html.push_str("<table><tr>");
+    for header in headers {
+        let _ = write!(html, "<th>{}</th>", escape(header));
+    }
+    html.push_str("</tr>");
+    for row in rows {
+        html.push_str("<tr>");
+        for cell in row {
+            let _ = write!(html, "<td>{}</td>", escape(&cell));
+        }
+        html.push_str("</tr>");
+    }
+    html.push_str("</table>");
+}
+
+/// Renders the dashboard as an html page
+pub fn render(dashboard: &Dashboard) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>nixseparatedebuginfod</title>\
+        <style>body{font-family:sans-serif;margin:2em}table{border-collapse:collapse;margin-bottom:1em}\
+        td,th{border:1px solid #ccc;padding:.2em .5em;text-align:left}.bad{color:#b00}</style>\
+        </head><body><h1>nixseparatedebuginfod</h1>",
+    );
+
+    html.push_str("<h2>Index</h2>");
+    match &dashboard.coverage {
+        Some(coverage) => table(
+            &mut html,
+            &["buildids", "with debuginfo", "with source"],
+            [vec![
+                coverage.buildids.to_string(),
+                coverage.with_debuginfo.to_string(),
+                coverage.with_source.to_string(),
+            ]],
+        ),
+        None => html.push_str("<p class=\"bad\">cannot read the cache</p>"),
+    }
+
+    html.push_str("<h2>Indexation</h2>");
+    if let Some(error) = &dashboard.health.last_error {
+        let _ = write!(
+            html,
+            "<p class=\"bad\">cannot read the nix db ({} failures in a row): {}</p>",
+            dashboard.health.consecutive_failures,
+            escape(error)
+        );
+    }
+    let indexing = &dashboard.indexing;
+    let unknown = || "unknown".to_owned();
+    table(
+        &mut html,
+        &["", ""],
+        [
+            vec![
+                "store paths indexed".to_owned(),
+                indexing.paths_indexed.to_string(),
+            ],
+            vec![
+                "store paths per second".to_owned(),
+                format!("{:.1}", indexing.paths_per_second),
+            ],
+            vec![
+                "elf files parsed".to_owned(),
+                indexing.elf_files_parsed.to_string(),
+            ],
+            vec![
+                "entries registered".to_owned(),
+                indexing.entries_registered.to_string(),
+            ],
+            vec!["queue depth".to_owned(), indexing.queue_depth.to_string()],
+            vec![
+                "store paths not indexed yet".to_owned(),
+                indexing.backlog.map_or_else(unknown, |n| n.to_string()),
+            ],
+            vec![
+                "oldest store path not indexed yet".to_owned(),
+                indexing
+                    .backlog_age_secs
+                    .map_or_else(unknown, |age| format!("{}s ago", age)),
+            ],
+            vec![
+                "paused".to_owned(),
+                indexing.paused.clone().unwrap_or_else(|| "no".to_owned()),
+            ],
+        ],
+    );
+
+    html.push_str("<h2>Recent requests</h2>");
+    table(
+        &mut html,
+        &["time", "path", "status", "duration (ms)"],
+        dashboard.recent.iter().map(|request| {
+            vec![
+                request.time.to_string(),
+                request.path.clone(),
+                request.status.to_string(),
+                request.duration_ms.to_string(),
+            ]
+        }),
+    );
+
+    html.push_str("<h2>Missing debuginfo</h2>");
+    table(
+        &mut html,
+        &["requests", "buildid", "package", "executable"],
+        dashboard.misses.iter().map(|miss| {
+            vec![
+                miss.count.to_string(),
+                miss.buildid.clone(),
+                miss.package
+                    .as_ref()
+                    .map_or_else(unknown, |package| package.to_string()),
+                miss.executable.clone().unwrap_or_else(unknown),
+            ]
+        }),
+    );
+
+    html.push_str("<h2>Most served</h2>");
+    table(
+        &mut html,
+        &["files served", "buildid", "package", "debuginfo"],
+        dashboard.hits.iter().map(|hit| {
+            vec![
+                hit.count.to_string(),
+                hit.buildid.clone(),
+                hit.package
+                    .as_ref()
+                    .map_or_else(unknown, |package| package.to_string()),
+                hit.debuginfo.clone().unwrap_or_else(unknown),
+            ]
+        }),
+    );
+
+    if let Some(config) = &dashboard.config {
+        html.push_str("<h2>Configuration</h2>");
+        table(
+            &mut html,
+            &["option", "value"],
+            config
+                .iter()
+                .map(|(name, value)| vec![name.to_string(), value.clone()]),
+        );
+    }
+
+    html.push_str("</body></html>");
+    html
+}
@@ -4,12 +4,33 @@
 
 //! Cache for buildid -> debuginfo as a sqlite database
 
+use std::collections::{HashMap, HashSet};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
 use anyhow::{bail, Context};
 use directories::ProjectDirs;
+use hashlink::LruCache;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
 use sha2::Digest;
-use sqlx::{sqlite::SqlitePool, Row};
+use sqlx::{
+    sqlite::{
+        SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions, SqliteRow,
+        SqliteSynchronous,
+    },
+    Row,
+};
 
+use crate::events::{self, Event};
 use crate::log::ResultExt;
+use crate::store::{package_from_store_path, store_dir, Package};
+use crate::telemetry::INDEXER;
 
 /// id of the row of a store path in `/nix/var/nix/db/db.sqlite`
 pub type Id = u32;
@@ -29,6 +50,23 @@ pub struct Entry {
     pub debuginfo: Option<String>,
     /// store path of the source
     pub source: Option<String>,
+    /// size and mtime of `executable` when it was indexed
+    pub executable_metadata: Option<FileMetadata>,
+    /// size and mtime of `debuginfo` when it was indexed, if it existed at the time
+    pub debuginfo_metadata: Option<FileMetadata>,
+    /// architecture of `executable`, like `x86_64`, see [crate::store::arch_name]
+    pub arch: Option<String>,
+}
+
+/// Size and modification time of a file, as recorded when it was indexed.
+///
+/// This allows answering some requests without realising the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileMetadata {
+    /// size in bytes
+    pub size: u64,
+    /// modification time, in seconds since the epoch
+    pub mtime: i64,
 }
 
 /// A cache storing the executable, debuginfo and source location for each buildid.
@@ -38,9 +76,373 @@ pub struct Entry {
 pub struct Cache {
     /// A connection to a backing sqlite db.
     sqlite: SqlitePool,
+    /// Buildids recently looked up which had no row in the db.
+    ///
+    /// gdb asks for lots of buildids that will never be found (libraries from other distros,
+    /// JIT code...).
+    ///
+    /// They are forgotten after [MISS_TTL], as another process using the same db, like a
+    /// separate indexer, may have registered them in the meantime.
+    misses: Arc<Mutex<LruCache<String, Instant>>>,
+    /// Paths of buildids recently looked up, with when they were read.
+    ///
+    /// The program being debugged and its libraries are looked up again and again.
+    ///
+    /// They are also forgotten after [MISS_TTL], as another process using the same db may have
+    /// registered more files for them, or forgotten them, in the meantime.
+    hits: Arc<Mutex<LruCache<String, (Instant, Paths)>>>,
+    /// Files of buildids which could not be found anywhere, not even by the fallbacks of the
+    /// server, with when this happened and the `generation` at that time.
+    ///
+    /// gdb asks for them again and again, and each lookup may index store paths or query
+    /// binary caches. They are forgotten after [negative_cache_ttl], or once anything is
+    /// registered.
+    unresolved: Arc<Mutex<LruCache<(String, &'static str), Unresolved>>>,
+    /// Incremented each time the content of the db changes, to prevent populating `hits` and
+    /// `misses` with outdated data.
+    generation: Arc<AtomicU64>,
+    /// The store whose nix db ids are tracked by [Cache::get_next_id] and
+    /// [Cache::register_indexed], see [Cache::for_store].
+    store: Arc<str>,
+    /// Notified each time entries are registered, see [Cache::wait_for_buildid]
+    registered: Arc<tokio::sync::Notify>,
+}
+
+/// When a file of a buildid was found to be unresolved, and the `generation` of [Cache] at that
+/// time
+type Unresolved = (Instant, u64);
+
+/// The paths registered for a buildid, as kept in memory by [Cache]
+#[derive(Debug, Clone)]
+struct Paths {
+    executable: Option<String>,
+    debuginfo: Option<String>,
+    source: Option<String>,
+}
+
+/// A buildid whose debuginfo was requested but never found, as recorded by
+/// [Cache::record_miss]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Miss {
+    /// the buildid
+    pub buildid: String,
+    /// the executable of this buildid, if known
+    pub executable: Option<String>,
+    /// the package of the executable, if known
+    pub package: Option<Package>,
+    /// how many times its debuginfo was requested
+    pub count: u64,
+    /// when its debuginfo was first requested, in seconds since the epoch
+    pub first_requested: i64,
+    /// when its debuginfo was last requested, in seconds since the epoch
+    pub last_requested: i64,
+}
+
+/// A buildid whose files were served, as recorded by [Cache::touch]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Hit {
+    /// the buildid
+    pub buildid: String,
+    /// the executable of this buildid, if known
+    pub executable: Option<String>,
+    /// the debuginfo of this buildid, if known
+    pub debuginfo: Option<String>,
+    /// the package of the executable, if known
+    pub package: Option<Package>,
+    /// how many files of this buildid were served
+    pub count: u64,
+    /// when a file of this buildid was first served, in seconds since the epoch
+    pub first_served: i64,
+    /// when a file of this buildid was last served, in seconds since the epoch
+    pub last_served: i64,
+}
+
+/// How many buildids the cache knows, as returned by [Cache::coverage]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Coverage {
+    /// buildids with at least one known file
+    pub buildids: u64,
+    /// buildids whose debuginfo is known
+    pub with_debuginfo: u64,
+    /// buildids whose source is known
+    pub with_source: u64,
+}
+
+/// Which entries [Cache::prune] removes
+#[derive(Debug, Clone, Default)]
+pub struct PrunePolicy {
+    /// remove entries which were not served for that long
+    pub max_age: Option<Duration>,
+    /// keep at most this many entries, removing the least recently served ones
+    pub max_entries: Option<u64>,
+}
+
+impl PrunePolicy {
+    /// Whether this policy would never remove anything
+    pub fn is_empty(&self) -> bool {
+        self.max_age.is_none() && self.max_entries.is_none()
+    }
+}
+
+/// Seconds since the epoch
+fn unix_time_now() -> i64 {
+    unix_time(SystemTime::now())
+}
+
+/// This time in seconds since the epoch
+fn unix_time(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// How many buildids absent from the db are remembered by [Cache]
+const NEGATIVE_CACHE_SIZE: usize = 4096;
+
+/// How many buildids present in the db are remembered by [Cache]
+const POSITIVE_CACHE_SIZE: usize = 1024;
+
+/// How long a buildid absent from, or present in, the db is remembered by [Cache]
+const MISS_TTL: Duration = Duration::from_secs(30);
+
+/// How long a file which could not be found is remembered by [Cache], unless set with
+/// [set_negative_cache_ttl]
+const DEFAULT_NEGATIVE_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// How long to wait for another process (or connection) to release its lock on the db
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many times a transaction failing because the db is locked is attempted again
+const BUSY_RETRIES: u32 = 5;
+
+/// How many entries are inserted by a single sql statement in [Cache::register]
+///
+/// Each entry uses 13 bound variables, and sqlite accepts at most 32766 per statement.
+const INSERT_CHUNK_SIZE: usize = 1000;
+
+/// Converts a buildid as printed by readelf to its representation in the db
+///
+/// Returns None if this is not a valid buildid.
+fn buildid_to_db(buildid: &str) -> Option<Vec<u8>> {
+    match base16::decode(buildid) {
+        Ok(key) if !key.is_empty() => Some(key),
+        _ => None,
+    }
+}
+
+/// Splits a path into the store path it belongs to, relative to the store directory, and the
+/// path relative to this store path.
+///
+/// Store paths are stored once in the `storepaths` table, and referred to by `builds`.
+/// Paths outside the store have no store path.
+fn path_to_db(path: &str) -> (Option<&str>, &str) {
+    match path
+        .strip_prefix(store_dir())
+        .and_then(|rest| rest.strip_prefix('/'))
+    {
+        Some(relative) if !relative.is_empty() => match relative.split_once('/') {
+            Some((storepath, rest)) => (Some(storepath), rest),
+            None => (Some(relative), ""),
+        },
+        _ => (None, path),
+    }
+}
+
+/// Selects the columns read by [entry_from_row]
+const SELECT_ENTRIES: &str = "select builds.buildid,
+        e.path as e_storepath, builds.executable,
+        d.path as d_storepath, builds.debuginfo,
+        s.path as s_storepath, builds.source,
+        builds.executable_size, builds.executable_mtime,
+        builds.debuginfo_size, builds.debuginfo_mtime, builds.arch
+    from builds
+    left join storepaths e on e.id = builds.executable_storepath
+    left join storepaths d on d.id = builds.debuginfo_storepath
+    left join storepaths s on s.id = builds.source_storepath";
+
+/// Converts a row selected with [SELECT_ENTRIES] to an [Entry]
+fn entry_from_row(r: &SqliteRow) -> anyhow::Result<Entry> {
+    let get = |storepath: &str, rest: &str| -> anyhow::Result<Option<String>> {
+        let storepath: Option<String> = r.try_get(storepath)?;
+        let rest: Option<String> = r.try_get(rest)?;
+        Ok(rest.map(|rest| path_from_db(storepath, rest)))
+    };
+    let metadata = |prefix: &str| -> anyhow::Result<Option<FileMetadata>> {
+        let size: Option<i64> = r.try_get(format!("{prefix}_size").as_str())?;
+        let mtime: Option<i64> = r.try_get(format!("{prefix}_mtime").as_str())?;
+        Ok(size.zip(mtime).map(|(size, mtime)| FileMetadata {
+            size: size as u64,
+            mtime,
+        }))
+    };
+    let buildid: Vec<u8> = r.try_get("buildid")?;
+    Ok(Entry {
+        buildid: base16::encode_lower(&buildid),
+        executable: get("e_storepath", "executable")?,
+        executable_metadata: metadata("executable")?,
+        debuginfo: get("d_storepath", "debuginfo")?,
+        debuginfo_metadata: metadata("debuginfo")?,
+        source: get("s_storepath", "source")?,
+        arch: r.try_get("arch")?,
+    })
+}
+
+/// Inverse of [path_to_db]
+fn path_from_db(storepath: Option<String>, rest: String) -> String {
+    match storepath {
+        None => rest,
+        Some(storepath) if rest.is_empty() => format!("{}/{}", store_dir(), storepath),
+        Some(storepath) => format!("{}/{}/{}", store_dir(), storepath, rest),
+    }
+}
+
+#[test]
+fn test_path_to_db() {
+    for path in [
+        "/nix/store/jw65xnml1fgf4bfgzgiszck3lfjwxg6l-gcc-12.3.0/bin/gcc",
+        "/nix/store/jw65xnml1fgf4bfgzgiszck3lfjwxg6l-gcc-12.3.0",
+        "/nix/store",
+        "/nix/storefoo",
+        "/tmp/foo",
+    ] {
+        let (storepath, rest) = path_to_db(path);
+        assert_eq!(
+            path_from_db(storepath.map(|s| s.to_owned()), rest.to_owned()),
+            path
+        );
+    }
+    assert_eq!(
+        path_to_db("/nix/store/jw65xnml1fgf4bfgzgiszck3lfjwxg6l-gcc-12.3.0/bin/gcc"),
+        (
+            Some("jw65xnml1fgf4bfgzgiszck3lfjwxg6l-gcc-12.3.0"),
+            "bin/gcc"
+        )
+    );
+}
+
+/// The cache directory, if set with [set_cache_directory]
+static CACHE_DIRECTORY: OnceCell<PathBuf> = OnceCell::new();
+
+/// Stores downloaded and extracted files in this directory instead of in the cache directory of
+/// the user.
+///
+/// Must be called on startup.
+pub fn set_cache_directory(path: PathBuf) {
+    CACHE_DIRECTORY
+        .set(path)
+        .expect("the cache directory was already set");
+}
+
+/// The directory where nixseparatedebuginfod stores its cache
+pub fn cache_directory() -> anyhow::Result<PathBuf> {
+    if let Some(path) = CACHE_DIRECTORY.get() {
+        return Ok(path.clone());
+    }
+    match ProjectDirs::from("eu", "xlumurb", "nixseparatedebuginfod") {
+        Some(dirs) => Ok(dirs.cache_dir().to_owned()),
+        None => bail!("could not determine cache dir in $HOME"),
+    }
+}
+
+/// The state directory, if set with [set_state_directory]
+static STATE_DIRECTORY: OnceCell<PathBuf> = OnceCell::new();
+
+/// Stores the cache db in this directory instead of in [cache_directory], so that what it
+/// records survives the removal of caches.
+///
+/// Must be called before [Cache::open].
+pub fn set_state_directory(path: PathBuf) {
+    STATE_DIRECTORY
+        .set(path)
+        .expect("the state directory was already set");
+}
+
+/// The directory of the cache db, unless its path was set with [set_cache_db]
+fn state_directory() -> anyhow::Result<PathBuf> {
+    match STATE_DIRECTORY.get() {
+        Some(path) => Ok(path.clone()),
+        None => cache_directory(),
+    }
+}
+
+/// Where the sqlite db backing [Cache] is, if not in the state directory
+static CACHE_DB: OnceCell<PathBuf> = OnceCell::new();
+
+/// Stores the cache db at this path instead of in the state directory, for example to share it
+/// between an indexer and a server running as different users.
+///
+/// Must be called before [Cache::open].
+pub fn set_cache_db(path: PathBuf) {
+    CACHE_DB
+        .set(path)
+        .expect("the cache db path was already set");
+}
+
+/// How long a file which could not be found is remembered, see [set_negative_cache_ttl]
+static NEGATIVE_CACHE_TTL: OnceCell<Duration> = OnceCell::new();
+
+/// Remembers files which could not be found for this long instead of
+/// [DEFAULT_NEGATIVE_CACHE_TTL], see [Cache::record_unresolved].
+pub fn set_negative_cache_ttl(ttl: Duration) {
+    NEGATIVE_CACHE_TTL
+        .set(ttl)
+        .expect("the negative cache ttl was already set");
 }
+
+/// How long a file which could not be found is remembered by [Cache::record_unresolved]
+fn negative_cache_ttl() -> Duration {
+    NEGATIVE_CACHE_TTL
+        .get()
+        .copied()
+        .unwrap_or(DEFAULT_NEGATIVE_CACHE_TTL)
+}
+
+/// A canonical cache db to start from, see [set_seed_db]
+static SEED_DB: OnceCell<PathBuf> = OnceCell::new();
+
+/// When the cache db does not exist yet, starts from a copy of this db, as written by
+/// [Cache::write_canonical], instead of an empty one.
+///
+/// Must be called before [Cache::open].
+pub fn set_seed_db(path: PathBuf) {
+    SEED_DB.set(path).expect("the seed db was already set");
+}
+
+/// Returns whether this error means that the db was locked by another connection
+fn is_busy(e: &anyhow::Error) -> bool {
+    e.chain()
+        .any(|cause| match cause.downcast_ref::<sqlx::Error>() {
+            Some(sqlx::Error::Database(e)) => e
+                .code()
+                .and_then(|code| code.parse::<i32>().ok())
+                // SQLITE_BUSY and SQLITE_LOCKED, and their extended codes
+                .is_some_and(|code| matches!(code & 0xff, 5 | 6)),
+            _ => false,
+        })
+}
+
+/// Runs `transaction`, and runs it again if it failed because the db was locked for longer
+/// than [BUSY_TIMEOUT].
+async fn retry_busy<T, F, Fut>(what: &str, mut transaction: F) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match transaction().await {
+            Err(e) if attempt < BUSY_RETRIES && is_busy(&e) => {
+                attempt += 1;
+                tracing::debug!("cache db busy while {}, retrying: {:#}", what, e);
+                tokio::time::sleep(Duration::from_millis(100) * attempt).await;
+            }
+            res => return res,
+        }
+    }
+}
+
 /// The schema of the sqlite db backing [Cache].
-const SCHEMA: &'static str = include_str!("./schema.sql");
+const SCHEMA: &str = include_str!("./schema.sql");
 
 fn get_schema_version() -> u32 {
     let mut hasher = sha2::Sha256::new();
@@ -83,7 +485,7 @@ async fn populate_pool(pool: &SqlitePool) -> anyhow::Result<()> {
         .execute(&mut *transaction)
         .await
         .context("setting schema default timestamps on cache db")?;
-    sqlx::query("insert into id values (0);")
+    sqlx::query("insert into id values ('', 0);")
         .execute(&mut *transaction)
         .await
         .context("setting schema default next id on cache db")?;
@@ -92,27 +494,70 @@ async fn populate_pool(pool: &SqlitePool) -> anyhow::Result<()> {
 }
 
 impl Cache {
-    /// Attempts to open the cache from disk. Does not try very hard.
-    async fn open_weak() -> anyhow::Result<Cache> {
-        let dirs = ProjectDirs::from("eu", "xlumurb", "nixseparatedebuginfod");
-        let dirs = match dirs {
-            Some(d) => d,
-            None => bail!("could not determine cache dir in $HOME"),
-        };
-        let mut path = dirs.cache_dir().to_owned();
-        std::fs::create_dir_all(&path)
-            .with_context(|| format!("creating cache directory {}", path.display()))?;
-        path.push("cache.sqlite3");
-        let cache_exists = path.exists();
-        let path_utf8 = match path.to_str() {
-            Some(p) => p,
-            None => bail!("cache path {} is not utf8", path.display()),
+    /// Wraps an already populated sqlite pool
+    fn from_pool(sqlite: SqlitePool) -> Cache {
+        Cache {
+            sqlite,
+            misses: Arc::new(Mutex::new(LruCache::new(NEGATIVE_CACHE_SIZE))),
+            hits: Arc::new(Mutex::new(LruCache::new(POSITIVE_CACHE_SIZE))),
+            unresolved: Arc::new(Mutex::new(LruCache::new(NEGATIVE_CACHE_SIZE))),
+            generation: Arc::new(AtomicU64::new(0)),
+            store: Arc::from(""),
+            registered: Arc::new(tokio::sync::Notify::new()),
+        }
+    }
+
+    /// Returns a cache referring to the same sqlite db, but tracking indexation of the nix db
+    /// of the chroot store at this root instead of the system store.
+    pub fn for_store(&self, root: &Path) -> Cache {
+        Cache {
+            store: Arc::from(root.to_string_lossy()),
+            ..self.clone()
+        }
+    }
+
+    /// Attempts to open the cache from disk at this path. Does not try very hard.
+    ///
+    /// Several processes can use the same cache db at the same time.
+    async fn open_path(path: &Path) -> anyhow::Result<Cache> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("creating cache directory {}", dir.display()))?;
+        }
+        if let Some(seed) = SEED_DB.get() {
+            if !path.exists() {
+                tracing::info!("starting from the cache db {}", seed.display());
+                // the seed is typically in the read-only store
+                std::fs::copy(seed, path)
+                    .and_then(|_| {
+                        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o644))
+                    })
+                    .with_context(|| format!("copying {} to {}", seed.display(), path.display()))
+                    .or_warn();
+            }
+        }
+        // readers do not block writers, and writers wait for each other
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .synchronous(SqliteSynchronous::Normal)
+            .busy_timeout(BUSY_TIMEOUT);
+        let connect = || async {
+            SqlitePool::connect_with(options.clone())
+                .await
+                .with_context(|| format!("failed to connect to {} with sqlite3", path.display()))
         };
-        let url = format!("file:{}?mode=rwc", path_utf8);
-        let pool = SqlitePool::connect(&url)
+        let pool = connect().await?;
+        // another process may have created the file but not the schema yet, but the schema is
+        // created in a single transaction
+        let has_schema = sqlx::query("select count(*) as n from sqlite_master")
+            .fetch_one(&pool)
             .await
-            .with_context(|| format!("failed to connect to {} with sqlite3", &url))?;
-        if !cache_exists {
+            .and_then(|row| row.try_get::<i64, _>("n"))
+            .map_or(true, |n| n > 0);
+        if !has_schema {
+            // fails if another process created it in the meantime, which is fine
             populate_pool(&pool)
                 .await
                 .context("populating newly created cache")
@@ -123,138 +568,2247 @@ impl Cache {
             Err(e) => {
                 tracing::warn!("cache {} is invalid, wiping it. {:#}", path.display(), e);
                 pool.close().await;
-                std::fs::remove_file(&path).unwrap_or_else(|e| {
-                    tracing::warn!("error removing corrupted cache {}: {:#}", path.display(), e)
-                });
-                let pool = SqlitePool::connect(&url)
-                    .await
-                    .with_context(|| format!("failed to connect to {} with sqlite3", &url))?;
+                // a stale write-ahead log would be applied to the new db
+                let mut wal = path.as_os_str().to_owned();
+                wal.push("-wal");
+                let mut shm = path.as_os_str().to_owned();
+                shm.push("-shm");
+                for file in [path.as_os_str(), &wal, &shm] {
+                    let file = Path::new(file);
+                    match std::fs::remove_file(file) {
+                        Err(e) if e.kind() != std::io::ErrorKind::NotFound => tracing::warn!(
+                            "error removing corrupted cache {}: {:#}",
+                            file.display(),
+                            e
+                        ),
+                        _ => (),
+                    }
+                }
+                let pool = connect().await?;
                 populate_pool(&pool)
                     .await
                     .context("populating empty cache")?;
                 pool
             }
         };
-        Ok(Cache { sqlite: pool })
+        Ok(Cache::from_pool(pool))
     }
 
     /// Opens a cache, either from disk, or it it fails, in memory.
+    ///
+    /// If the path of the db was set with [set_cache_db], failing to open it is an error.
     pub async fn open() -> anyhow::Result<Cache> {
-        match Cache::open_weak().await {
+        if let Some(path) = CACHE_DB.get() {
+            return Cache::open_path(path).await;
+        }
+        let path = state_directory()?.join("cache.sqlite3");
+        match Cache::open_path(&path).await {
             Err(e) => {
                 tracing::warn!(
                     "could not use on disk cache ({:#}), running cache in memory",
                     e
                 );
-                let pool = SqlitePool::connect(":memory:")
-                    .await
-                    .context("opening in memory sql db")?;
-                populate_pool(&pool)
-                    .await
-                    .context("populating empty cache")?;
-                Ok(Cache { sqlite: pool })
+                Cache::open_in_memory().await
             }
             Ok(cache) => Ok(cache),
         }
     }
 
+    /// Writes the entries of this cache whose files are all in the store to a new db at
+    /// `output`, in a canonical form: the same entries always give the same file, whatever the
+    /// order they were registered in. Rows are sorted by buildid, timestamps are zero,
+    /// indexation progress and statistics are not recorded, and paths are stored relative to
+    /// the store directory, like in any cache db.
+    ///
+    /// Returns how many entries were written.
+    pub async fn write_canonical(&self, output: &Path) -> anyhow::Result<usize> {
+        // ordered by buildid
+        let mut entries = self.get_all_entries().await?;
+        entries.retain(|entry| {
+            [&entry.executable, &entry.debuginfo, &entry.source]
+                .into_iter()
+                .flatten()
+                .all(|path| path_to_db(path).0.is_some())
+        });
+        match std::fs::remove_file(output) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("removing {}", output.display()))
+            }
+            _ => (),
+        }
+        // a single file, without write-ahead log
+        let options = SqliteConnectOptions::new()
+            .filename(output)
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Delete);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options)
+            .await
+            .with_context(|| format!("creating {}", output.display()))?;
+        populate_pool(&pool)
+            .await
+            .context("populating canonical cache")?;
+        let canonical = Cache::from_pool(pool.clone());
+        for chunk in entries.chunks(INSERT_CHUNK_SIZE) {
+            canonical.register(chunk).await?;
+        }
+        sqlx::query("update builds set last_access = 0;")
+            .execute(&pool)
+            .await
+            .context("resetting last access times")?;
+        sqlx::query("vacuum;")
+            .execute(&pool)
+            .await
+            .context("compacting canonical cache")?;
+        pool.close().await;
+        Ok(entries.len())
+    }
+
+    /// Opens an empty cache in memory.
+    pub async fn open_in_memory() -> anyhow::Result<Cache> {
+        // each connection to :memory: is a distinct db
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect(":memory:")
+            .await
+            .context("opening in memory sql db")?;
+        populate_pool(&pool)
+            .await
+            .context("populating empty cache")?;
+        Ok(Cache::from_pool(pool))
+    }
+
+    /// Whether this buildid was recently looked up and had no row in the db
+    fn is_recent_miss(&self, buildid: &str) -> bool {
+        let mut misses = self.misses.lock().unwrap();
+        match misses.get(buildid) {
+            Some(time) if time.elapsed() < MISS_TTL => true,
+            Some(_) => {
+                misses.remove(buildid);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// The paths of this buildid, if it was recently looked up and had a row in the db
+    fn recent_hit(&self, buildid: &str) -> Option<Paths> {
+        let mut hits = self.hits.lock().unwrap();
+        match hits.get(buildid) {
+            Some((time, paths)) if time.elapsed() < MISS_TTL => Some(paths.clone()),
+            Some(_) => {
+                hits.remove(buildid);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Reads the paths registered for this buildid.
+    ///
+    /// Recently looked up buildids are kept in memory so that repeated lookups for them do not
+    /// hit sqlite. This includes buildids that have no row at all.
+    async fn get_paths(&self, buildid: &str) -> anyhow::Result<Option<Paths>> {
+        let key = match buildid_to_db(buildid) {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        if self.is_recent_miss(buildid) {
+            return Ok(None);
+        }
+        if let Some(paths) = self.recent_hit(buildid) {
+            return Ok(Some(paths));
+        }
+        // if register is called while we read the db, what we read may be outdated
+        let generation = self.generation.load(Ordering::SeqCst);
+        let row = sqlx::query(
+            "select e.path as e_storepath, builds.executable,
+                d.path as d_storepath, builds.debuginfo,
+                s.path as s_storepath, builds.source
+            from builds
+            left join storepaths e on e.id = builds.executable_storepath
+            left join storepaths d on d.id = builds.debuginfo_storepath
+            left join storepaths s on s.id = builds.source_storepath
+            where builds.buildid = $1;",
+        )
+        .bind(key)
+        .fetch_optional(&self.sqlite)
+        .await
+        .context("reading paths from cache db")?;
+        let paths = match row {
+            None => None,
+            Some(r) => {
+                let get = |storepath: &str, rest: &str| -> anyhow::Result<Option<String>> {
+                    let storepath: Option<String> = r.try_get(storepath)?;
+                    let rest: Option<String> = r.try_get(rest)?;
+                    Ok(rest.map(|rest| path_from_db(storepath, rest)))
+                };
+                Some(Paths {
+                    executable: get("e_storepath", "executable")?,
+                    debuginfo: get("d_storepath", "debuginfo")?,
+                    source: get("s_storepath", "source")?,
+                })
+            }
+        };
+        let mut misses = self.misses.lock().unwrap();
+        let mut hits = self.hits.lock().unwrap();
+        if generation == self.generation.load(Ordering::SeqCst) {
+            match &paths {
+                None => {
+                    misses.insert(buildid.to_owned(), Instant::now());
+                }
+                Some(paths) => {
+                    hits.insert(buildid.to_owned(), (Instant::now(), paths.clone()));
+                }
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Remembers that the file of this kind (`debuginfo`, `executable`...) of this buildid could
+    /// not be found anywhere, so that looking it up again gives up right away, see
+    /// [Cache::is_unresolved].
+    pub fn record_unresolved(&self, buildid: &str, kind: &'static str) {
+        let generation = self.generation.load(Ordering::SeqCst);
+        self.unresolved
+            .lock()
+            .unwrap()
+            .insert((buildid.to_owned(), kind), (Instant::now(), generation));
+    }
+
+    /// Whether the file of this kind of this buildid was recorded with
+    /// [Cache::record_unresolved] less than [negative_cache_ttl] ago, and nothing was registered
+    /// since.
+    pub fn is_unresolved(&self, buildid: &str, kind: &'static str) -> bool {
+        let key = (buildid.to_owned(), kind);
+        let mut unresolved = self.unresolved.lock().unwrap();
+        match unresolved.get(&key) {
+            Some((time, generation))
+                if time.elapsed() < negative_cache_ttl()
+                    && *generation == self.generation.load(Ordering::SeqCst) =>
+            {
+                true
+            }
+            Some(_) => {
+                unresolved.remove(&key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Forgets what is kept in memory about these buildids
+    fn invalidate<'a>(&self, buildids: impl Iterator<Item = &'a str>) {
+        let mut misses = self.misses.lock().unwrap();
+        let mut hits = self.hits.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        for buildid in buildids {
+            misses.remove(buildid);
+            hits.remove(buildid);
+        }
+    }
+
     /// Get the path of an elf object containing debuginfo for this buildid.
     ///
     /// The path may have been gc-ed, you are responsible to ensure it exists.
     pub async fn get_debuginfo(&self, buildid: &str) -> anyhow::Result<Option<String>> {
-        let row = sqlx::query("select debuginfo from builds where buildid = $1;")
-            .bind(buildid)
-            .fetch_optional(&self.sqlite)
-            .await
-            .context("reading debuginfo from cache db")?;
-        Ok(match row {
-            None => None,
-            Some(r) => r.try_get("debuginfo")?,
-        })
+        Ok(self.get_paths(buildid).await?.and_then(|p| p.debuginfo))
     }
 
     /// Get the path of an elf object containing text for this buildid.
     ///
     /// The path may have been gc-ed, you are responsible to ensure it exists.
     pub async fn get_executable(&self, buildid: &str) -> anyhow::Result<Option<String>> {
-        let row = sqlx::query("select executable from builds where buildid = $1;")
-            .bind(buildid)
-            .fetch_optional(&self.sqlite)
-            .await
-            .context("reading executable from cache db")?;
-        Ok(match row {
-            None => None,
-            Some(r) => r.try_get("executable")?,
-        })
+        Ok(self.get_paths(buildid).await?.and_then(|p| p.executable))
     }
 
     /// Get the store path where the source of this buildid is.
     ///
     /// The path may have been gc-ed, you are responsible to ensure it exists.
     pub async fn get_source(&self, buildid: &str) -> anyhow::Result<Option<String>> {
-        let row = sqlx::query("select source from builds where buildid = $1;")
-            .bind(buildid)
+        Ok(self.get_paths(buildid).await?.and_then(|p| p.source))
+    }
+
+    /// Reads the metadata recorded at indexing time for one of the files of this buildid.
+    ///
+    /// `prefix` is either `executable` or `debuginfo`.
+    async fn get_metadata(
+        &self,
+        buildid: &str,
+        prefix: &str,
+    ) -> anyhow::Result<Option<FileMetadata>> {
+        let key = match buildid_to_db(buildid) {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        if self.is_recent_miss(buildid) {
+            return Ok(None);
+        }
+        let row = sqlx::query(&format!(
+            "select {prefix}_size as size, {prefix}_mtime as mtime from builds where buildid = $1;"
+        ))
+        .bind(key)
+        .fetch_optional(&self.sqlite)
+        .await
+        .with_context(|| format!("reading {} metadata from cache db", prefix))?;
+        let row = match row {
+            None => return Ok(None),
+            Some(row) => row,
+        };
+        let size: Option<i64> = row.try_get("size")?;
+        let mtime: Option<i64> = row.try_get("mtime")?;
+        Ok(match (size, mtime) {
+            (Some(size), Some(mtime)) => Some(FileMetadata {
+                size: size as u64,
+                mtime,
+            }),
+            _ => None,
+        })
+    }
+
+    /// Get the architecture of the executable of this buildid, if it was recorded when it was
+    /// indexed.
+    pub async fn get_arch(&self, buildid: &str) -> anyhow::Result<Option<String>> {
+        let key = match buildid_to_db(buildid) {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        let row = sqlx::query("select arch from builds where buildid = $1;")
+            .bind(key)
             .fetch_optional(&self.sqlite)
             .await
-            .context("reading executable from cache db")?;
-        Ok(match row {
-            None => None,
-            Some(r) => r.try_get("source")?,
-        })
+            .context("reading architecture from cache db")?;
+        match row {
+            None => Ok(None),
+            Some(row) => row
+                .try_get("arch")
+                .context("parsing architecture from cache db"),
+        }
+    }
+
+    /// Get the size and mtime that the debuginfo file of this buildid had when it was indexed.
+    pub async fn get_debuginfo_metadata(
+        &self,
+        buildid: &str,
+    ) -> anyhow::Result<Option<FileMetadata>> {
+        self.get_metadata(buildid, "debuginfo").await
+    }
+
+    /// Get the size and mtime that the executable of this buildid had when it was indexed.
+    pub async fn get_executable_metadata(
+        &self,
+        buildid: &str,
+    ) -> anyhow::Result<Option<FileMetadata>> {
+        self.get_metadata(buildid, "executable").await
     }
 
     /// Register information for a buildid
     ///
     /// Only one of the each entry fields is stored for each buildid, if register is called several times
     /// for a single buildid, only the latest `Some` provided one is retained.
+    ///
+    /// All entries are written in a single transaction, so prefer calling this with a few hundred
+    /// entries at a time.
     pub async fn register(&self, entries: &[Entry]) -> anyhow::Result<()> {
-        if entries.len() == 0 {
+        self.register_indexed(entries, &[]).await
+    }
+
+    /// Like [Cache::register], but also records in the same transaction that the store paths
+    /// of the nix db with these ids are completely indexed.
+    ///
+    /// This allows resuming indexation exactly where it stopped, see [Cache::get_indexed_ids].
+    pub async fn register_indexed(&self, entries: &[Entry], indexed: &[Id]) -> anyhow::Result<()> {
+        if entries.is_empty() && indexed.is_empty() {
             return Ok(());
         }
-        let mut transaction = self.sqlite.begin().await.context("transaction sqlite")?;
+        retry_busy("registering entries", || {
+            self.register_indexed_once(entries, indexed)
+        })
+        .await?;
+        INDEXER.entries_registered(entries.len());
         for entry in entries {
-            sqlx::query(
-                "insert into builds
-                    values ($1, $2, $3, $4)
-                    on conflict(buildid) do update set
+            events::emit(|| Event::EntryRegistered {
+                buildid: entry.buildid.clone(),
+                executable: entry.executable.is_some(),
+                debuginfo: entry.debuginfo.is_some(),
+                source: entry.source.is_some(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Attempts [Cache::register_indexed] once
+    async fn register_indexed_once(&self, entries: &[Entry], indexed: &[Id]) -> anyhow::Result<()> {
+        let mut rows = Vec::with_capacity(entries.len());
+        for entry in entries {
+            match buildid_to_db(&entry.buildid) {
+                Some(key) => rows.push((key, entry)),
+                None => tracing::warn!("not registering invalid buildid {:?}", &entry.buildid),
+            }
+        }
+        let now = unix_time_now();
+        let mut transaction = self.sqlite.begin().await.context("transaction sqlite")?;
+        // intern store paths
+        let mut storepath_ids: HashMap<&str, i64> = HashMap::new();
+        for (_, entry) in rows.iter() {
+            for path in [&entry.executable, &entry.debuginfo, &entry.source] {
+                if let Some((Some(storepath), _)) = path.as_deref().map(path_to_db) {
+                    if storepath_ids.contains_key(storepath) {
+                        continue;
+                    }
+                    let row = sqlx::query(
+                        "insert into storepaths (path) values ($1)
+                            on conflict(path) do update set path = excluded.path
+                            returning id;",
+                    )
+                    .bind(storepath)
+                    .fetch_one(&mut *transaction)
+                    .await
+                    .context("interning store path")?;
+                    storepath_ids.insert(storepath, row.try_get("id")?);
+                }
+            }
+        }
+        let split = |path: &'_ Option<String>| -> (Option<i64>, Option<String>) {
+            match path.as_deref().map(path_to_db) {
+                None => (None, None),
+                Some((storepath, rest)) => (
+                    storepath.and_then(|s| storepath_ids.get(s).copied()),
+                    Some(rest.to_owned()),
+                ),
+            }
+        };
+        for chunk in rows.chunks(INSERT_CHUNK_SIZE) {
+            let mut query = sqlx::QueryBuilder::new(
+                "insert into builds (buildid,
+                    executable_storepath, executable,
+                    debuginfo_storepath, debuginfo,
+                    source_storepath, source,
+                    executable_size, executable_mtime, debuginfo_size, debuginfo_mtime,
+                    arch, last_access) ",
+            );
+            query.push_values(chunk, |mut row, (key, entry)| {
+                let (executable_storepath, executable) = split(&entry.executable);
+                let (debuginfo_storepath, debuginfo) = split(&entry.debuginfo);
+                let (source_storepath, source) = split(&entry.source);
+                row.push_bind(key.as_slice())
+                    .push_bind(executable_storepath)
+                    .push_bind(executable)
+                    .push_bind(debuginfo_storepath)
+                    .push_bind(debuginfo)
+                    .push_bind(source_storepath)
+                    .push_bind(source)
+                    .push_bind(entry.executable_metadata.map(|m| m.size as i64))
+                    .push_bind(entry.executable_metadata.map(|m| m.mtime))
+                    .push_bind(entry.debuginfo_metadata.map(|m| m.size as i64))
+                    .push_bind(entry.debuginfo_metadata.map(|m| m.mtime))
+                    .push_bind(entry.arch.as_deref())
+                    .push_bind(now);
+            });
+            // metadata and store path follow the path they describe
+            query.push(
+                " on conflict(buildid) do update set
+                    executable_size = iif(excluded.executable is null, executable_size, excluded.executable_size),
+                    executable_mtime = iif(excluded.executable is null, executable_mtime, excluded.executable_mtime),
+                    debuginfo_size = iif(excluded.debuginfo is null, debuginfo_size, excluded.debuginfo_size),
+                    debuginfo_mtime = iif(excluded.debuginfo is null, debuginfo_mtime, excluded.debuginfo_mtime),
+                    executable_storepath = iif(excluded.executable is null, executable_storepath, excluded.executable_storepath),
+                    debuginfo_storepath = iif(excluded.debuginfo is null, debuginfo_storepath, excluded.debuginfo_storepath),
+                    source_storepath = iif(excluded.source is null, source_storepath, excluded.source_storepath),
                     executable = coalesce(excluded.executable, executable),
                     debuginfo = coalesce(excluded.debuginfo, debuginfo),
-                    source = coalesce(excluded.source, source)
+                    source = coalesce(excluded.source, source),
+                    arch = coalesce(excluded.arch, arch)
                     ;",
-            )
-            .bind(&entry.buildid)
-            .bind(&entry.executable)
-            .bind(&entry.debuginfo)
-            .bind(&entry.source)
-            .execute(&mut *transaction)
-            .await
-            .context("inserting build")?;
+            );
+            query
+                .build()
+                .execute(&mut *transaction)
+                .await
+                .context("inserting builds")?;
+        }
+        for chunk in indexed.chunks(INSERT_CHUNK_SIZE) {
+            let mut query = sqlx::QueryBuilder::new("insert or ignore into indexed (store, id) ");
+            query.push_values(chunk, |mut row, id| {
+                row.push_bind(&*self.store).push_bind(id);
+            });
+            query
+                .build()
+                .execute(&mut *transaction)
+                .await
+                .context("recording indexed store paths")?;
         }
         transaction
             .commit()
             .await
             .context("committing entry insert")?;
+        self.invalidate(entries.iter().map(|entry| entry.buildid.as_str()));
+        if !entries.is_empty() {
+            self.registered.notify_waiters();
+        }
         Ok(())
     }
 
-    /// Store the next store path id to read from the nix db
-    pub async fn set_next_id(&self, id: Id) -> anyhow::Result<()> {
-        sqlx::query("update id set next = max(next, $1);")
-            .bind(id)
-            .execute(&self.sqlite)
+    /// Waits until this buildid is registered, for at most `timeout`.
+    ///
+    /// Returns whether it is registered. Registrations by other processes using the same db
+    /// are noticed after [MISS_TTL] at most.
+    pub async fn wait_for_buildid(&self, buildid: &str, timeout: Duration) -> anyhow::Result<bool> {
+        let deadline = Instant::now() + timeout;
+        let buildid = [buildid.to_owned()];
+        loop {
+            // listen before looking up, not to miss a registration in between
+            let mut notified = std::pin::pin!(self.registered.notified());
+            notified.as_mut().enable();
+            if !self.get_entries(&buildid).await?.is_empty() {
+                return Ok(true);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(false);
+            }
+            let _ = tokio::time::timeout(remaining.min(MISS_TTL), notified).await;
+        }
+    }
+
+    /// Lists all the entries of the cache, ordered by buildid.
+    pub async fn get_all_entries(&self) -> anyhow::Result<Vec<Entry>> {
+        let rows = sqlx::query(&format!("{SELECT_ENTRIES} order by builds.buildid;"))
+            .fetch_all(&self.sqlite)
             .await
-            .context("advancing next registered id in cache db")?;
-        Ok(())
+            .context("reading all entries from cache db")?;
+        rows.iter().map(entry_from_row).collect()
     }
 
-    /// get the next store path id to read from the nix db
-    pub async fn get_next_id(&self) -> anyhow::Result<Id> {
-        let row = sqlx::query("select next from id")
-            .fetch_one(&self.sqlite)
+    /// Lists at most `limit` entries of the cache with a buildid greater than `after`, ordered
+    /// by buildid, to go through all entries in pages.
+    pub async fn get_entries_after(
+        &self,
+        after: Option<&str>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Entry>> {
+        let after = match after.map(buildid_to_db) {
+            None => Vec::new(),
+            Some(Some(key)) => key,
+            Some(None) => bail!("invalid buildid {:?}", after),
+        };
+        let rows = sqlx::query(&format!(
+            "{SELECT_ENTRIES} where builds.buildid > $1 order by builds.buildid limit $2;"
+        ))
+        .bind(after)
+        .bind(limit as i64)
+        .fetch_all(&self.sqlite)
+        .await
+        .context("reading a page of entries from cache db")?;
+        rows.iter().map(entry_from_row).collect()
+    }
+
+    /// Gets the entries of these buildids which are known, in no particular order.
+    ///
+    /// Invalid buildids are ignored.
+    pub async fn get_entries(&self, buildids: &[String]) -> anyhow::Result<Vec<Entry>> {
+        let keys: Vec<Vec<u8>> = buildids
+            .iter()
+            .filter_map(|buildid| buildid_to_db(buildid))
+            .collect();
+        let mut result = Vec::new();
+        for chunk in keys.chunks(INSERT_CHUNK_SIZE) {
+            let mut query =
+                sqlx::QueryBuilder::new(format!("{SELECT_ENTRIES} where builds.buildid in ("));
+            let mut separated = query.separated(", ");
+            for key in chunk {
+                separated.push_bind(key.as_slice());
+            }
+            query.push(");");
+            let rows = query
+                .build()
+                .fetch_all(&self.sqlite)
+                .await
+                .context("reading entries from cache db")?;
+            for row in rows.iter() {
+                result.push(entry_from_row(row)?);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Lists the entries whose executable or debuginfo is in this store path, ordered by
+    /// buildid.
+    pub async fn get_entries_of_store_path(&self, storepath: &str) -> anyhow::Result<Vec<Entry>> {
+        let name = match path_to_db(storepath) {
+            (Some(name), "") => name,
+            _ => bail!("{} is not a store path", storepath),
+        };
+        let rows = sqlx::query(&format!(
+            "{SELECT_ENTRIES} where e.path = $1 or d.path = $1 order by builds.buildid;"
+        ))
+        .bind(name)
+        .fetch_all(&self.sqlite)
+        .await
+        .with_context(|| format!("reading entries of {} from cache db", storepath))?;
+        rows.iter().map(entry_from_row).collect()
+    }
+
+    /// Lists the entries whose executable is named `name`, like `libfoo.so.1`, and the CRC-32 of
+    /// whose debuginfo was recorded as `crc` with [Cache::set_debuglink_crc].
+    pub async fn get_entries_by_debuglink(
+        &self,
+        name: &str,
+        crc: u32,
+    ) -> anyhow::Result<Vec<Entry>> {
+        let rows = sqlx::query(&format!(
+            "{SELECT_ENTRIES} join debuglinks on debuglinks.buildid = builds.buildid
+            where debuglinks.crc = $2
+                and (builds.executable = $1
+                    or substr(builds.executable, -length($1) - 1) = '/' || $1);"
+        ))
+        .bind(name)
+        .bind(crc as i64)
+        .fetch_all(&self.sqlite)
+        .await
+        .with_context(|| format!("reading entries of debuglink {} from cache db", name))?;
+        rows.iter().map(entry_from_row).collect()
+    }
+
+    /// Lists at most `limit` entries whose executable is named `name`, like `libfoo.so.1`, which
+    /// have debuginfo whose CRC-32 was not recorded with [Cache::set_debuglink_crc] yet.
+    pub async fn get_debuglink_candidates(
+        &self,
+        name: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Entry>> {
+        let rows = sqlx::query(&format!(
+            "{SELECT_ENTRIES} where (builds.executable = $1
+                    or substr(builds.executable, -length($1) - 1) = '/' || $1)
+                and builds.debuginfo is not null
+                and builds.buildid not in (select buildid from debuglinks)
+            limit $2;"
+        ))
+        .bind(name)
+        .bind(limit as i64)
+        .fetch_all(&self.sqlite)
+        .await
+        .with_context(|| {
+            format!(
+                "reading entries of executables named {} from cache db",
+                name
+            )
+        })?;
+        rows.iter().map(entry_from_row).collect()
+    }
+
+    /// Returns the CRC-32 of the debuginfo of this buildid, if it was recorded with
+    /// [Cache::set_debuglink_crc]
+    pub async fn get_debuglink_crc(&self, buildid: &str) -> anyhow::Result<Option<u32>> {
+        let key = match buildid_to_db(buildid) {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        let row = sqlx::query("select crc from debuglinks where buildid = $1;")
+            .bind(key)
+            .fetch_optional(&self.sqlite)
             .await
-            .context("reading next registered id in cache db")?;
-        Ok(row
-            .try_get("next")
-            .context("parsing next registered id from cache db")?)
+            .context("reading debuglink crc from cache db")?;
+        match row {
+            None => Ok(None),
+            Some(row) => {
+                let crc: i64 = row.try_get("crc").context("parsing debuglink crc")?;
+                Ok(Some(crc as u32))
+            }
+        }
     }
+
+    /// Records the CRC-32 of the debuginfo of this buildid
+    pub async fn set_debuglink_crc(&self, buildid: &str, crc: u32) -> anyhow::Result<()> {
+        let key = match buildid_to_db(buildid) {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        sqlx::query(
+            "insert into debuglinks (buildid, crc) values ($1, $2)
+            on conflict (buildid) do update set crc = $2;",
+        )
+        .bind(key)
+        .bind(crc as i64)
+        .execute(&self.sqlite)
+        .await
+        .context("recording debuglink crc in cache db")?;
+        Ok(())
+    }
+
+    /// Lists store paths containing executables for which no debuginfo is known, most recently
+    /// served first.
+    ///
+    /// These were typically indexed before their debug output was available, and indexing them
+    /// again may find it. Store paths recorded with [Cache::record_backfill_attempt] since
+    /// `attempted_before` are skipped.
+    pub async fn get_storepaths_missing_debuginfo(
+        &self,
+        limit: usize,
+        attempted_before: SystemTime,
+    ) -> anyhow::Result<Vec<String>> {
+        let rows = sqlx::query(
+            "select storepaths.path as path, max(builds.last_access) as last_access
+            from builds join storepaths on storepaths.id = builds.executable_storepath
+            left join backfilled on backfilled.path = storepaths.path
+            where builds.debuginfo is null
+            and (backfilled.attempted_at is null or backfilled.attempted_at < $2)
+            group by storepaths.path
+            order by last_access desc
+            limit $1;",
+        )
+        .bind(limit as i64)
+        .bind(unix_time(attempted_before))
+        .fetch_all(&self.sqlite)
+        .await
+        .context("reading entries without debuginfo from cache db")?;
+        rows.iter()
+            .map(|row| {
+                let path: String = row.try_get("path")?;
+                Ok(path_from_db(Some(path), String::new()))
+            })
+            .collect()
+    }
+
+    /// Lists debug outputs containing debuginfo for which no executable is known, most recently
+    /// served first.
+    ///
+    /// These were typically indexed alone, for example when substituted on demand, and indexing
+    /// them again with the other outputs of their deriver may find the executables. Store paths
+    /// recorded with [Cache::record_backfill_attempt] since `attempted_before` are skipped.
+    pub async fn get_storepaths_missing_executable(
+        &self,
+        limit: usize,
+        attempted_before: SystemTime,
+    ) -> anyhow::Result<Vec<String>> {
+        let rows = sqlx::query(
+            "select storepaths.path as path, max(builds.last_access) as last_access
+            from builds join storepaths on storepaths.id = builds.debuginfo_storepath
+            left join backfilled on backfilled.path = storepaths.path
+            where builds.executable is null
+            and (backfilled.attempted_at is null or backfilled.attempted_at < $2)
+            group by storepaths.path
+            order by last_access desc
+            limit $1;",
+        )
+        .bind(limit as i64)
+        .bind(unix_time(attempted_before))
+        .fetch_all(&self.sqlite)
+        .await
+        .context("reading entries without executable from cache db")?;
+        rows.iter()
+            .map(|row| {
+                let path: String = row.try_get("path")?;
+                Ok(path_from_db(Some(path), String::new()))
+            })
+            .collect()
+    }
+
+    /// Records that this store path, as returned by [Cache::get_storepaths_missing_debuginfo]
+    /// or [Cache::get_storepaths_missing_executable], was just reindexed to backfill its entries
+    pub async fn record_backfill_attempt(&self, storepath: &str) -> anyhow::Result<()> {
+        retry_busy("recording backfill attempt", || {
+            self.record_backfill_attempt_once(storepath)
+        })
+        .await
+    }
+
+    /// Attempts [Cache::record_backfill_attempt] once
+    async fn record_backfill_attempt_once(&self, storepath: &str) -> anyhow::Result<()> {
+        let path = match path_to_db(storepath) {
+            (Some(path), "") => path,
+            _ => bail!("{} is not a store path", storepath),
+        };
+        sqlx::query(
+            "insert into backfilled (path, attempted_at) values ($1, $2)
+            on conflict (path) do update set attempted_at = excluded.attempted_at;",
+        )
+        .bind(path)
+        .bind(unix_time_now())
+        .execute(&self.sqlite)
+        .await
+        .context("recording backfill attempt in cache db")?;
+        Ok(())
+    }
+
+    /// Records that these store paths, listed with `nix path-info --all`, were indexed
+    pub async fn register_listed(&self, paths: &[PathBuf]) -> anyhow::Result<()> {
+        retry_busy("recording listed store paths", || {
+            self.register_listed_once(paths)
+        })
+        .await
+    }
+
+    /// Attempts [Cache::register_listed] once
+    async fn register_listed_once(&self, paths: &[PathBuf]) -> anyhow::Result<()> {
+        let mut transaction = self.sqlite.begin().await.context("transaction sqlite")?;
+        for chunk in paths.chunks(INSERT_CHUNK_SIZE) {
+            let mut query = sqlx::QueryBuilder::new("insert or ignore into listed (path) ");
+            query.push_values(chunk, |mut row, path| {
+                row.push_bind(path.to_string_lossy());
+            });
+            query
+                .build()
+                .execute(&mut *transaction)
+                .await
+                .context("recording listed store paths")?;
+        }
+        transaction
+            .commit()
+            .await
+            .context("committing listed store paths")?;
+        Ok(())
+    }
+
+    /// Records that indexing this store path of the store of this cache failed with this error
+    pub async fn record_failed(&self, path: &Path, error: &str) -> anyhow::Result<()> {
+        retry_busy("recording failed store path", || {
+            self.record_failed_once(path, error)
+        })
+        .await
+    }
+
+    /// Attempts [Cache::record_failed] once
+    async fn record_failed_once(&self, path: &Path, error: &str) -> anyhow::Result<()> {
+        sqlx::query(
+            "insert into failed (store, path, error, failed_at) values ($1, $2, $3, $4)
+            on conflict (store, path) do update set error = excluded.error,
+            failed_at = excluded.failed_at;",
+        )
+        .bind(&*self.store)
+        .bind(path.to_string_lossy())
+        .bind(error)
+        .bind(unix_time_now())
+        .execute(&self.sqlite)
+        .await
+        .context("recording failed store path in cache db")?;
+        Ok(())
+    }
+
+    /// Returns the store paths recorded by [Cache::record_failed], with their error
+    pub async fn get_failed_paths(&self) -> anyhow::Result<Vec<(PathBuf, String)>> {
+        let rows = sqlx::query("select path, error from failed where store = $1 order by path;")
+            .bind(&*self.store)
+            .fetch_all(&self.sqlite)
+            .await
+            .context("reading failed store paths from cache db")?;
+        rows.iter()
+            .map(|row| {
+                let path: String = row
+                    .try_get("path")
+                    .context("parsing failed store path from cache db")?;
+                let error: String = row
+                    .try_get("error")
+                    .context("parsing error of failed store path from cache db")?;
+                Ok((PathBuf::from(path), error))
+            })
+            .collect()
+    }
+
+    /// Forgets that indexing these store paths failed, unless it failed again at `since` or
+    /// later
+    pub async fn forget_failed(&self, paths: &[PathBuf], since: SystemTime) -> anyhow::Result<()> {
+        let since = since
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+        retry_busy("forgetting failed store paths", || {
+            self.forget_failed_once(paths, since)
+        })
+        .await
+    }
+
+    /// Attempts [Cache::forget_failed] once
+    async fn forget_failed_once(&self, paths: &[PathBuf], since: i64) -> anyhow::Result<()> {
+        let mut transaction = self.sqlite.begin().await.context("transaction sqlite")?;
+        for path in paths {
+            sqlx::query("delete from failed where store = $1 and path = $2 and failed_at < $3;")
+                .bind(&*self.store)
+                .bind(path.to_string_lossy())
+                .bind(since)
+                .execute(&mut *transaction)
+                .await
+                .context("forgetting failed store path in cache db")?;
+        }
+        transaction
+            .commit()
+            .await
+            .context("committing forgotten failed store paths")?;
+        Ok(())
+    }
+
+    /// Returns the store paths recorded by [Cache::register_listed]
+    pub async fn get_listed_paths(&self) -> anyhow::Result<HashSet<PathBuf>> {
+        let rows = sqlx::query("select path from listed;")
+            .fetch_all(&self.sqlite)
+            .await
+            .context("reading listed store paths from cache db")?;
+        rows.iter()
+            .map(|row| {
+                let path: String = row
+                    .try_get("path")
+                    .context("parsing listed store path from cache db")?;
+                Ok(PathBuf::from(path))
+            })
+            .collect()
+    }
+
+    /// Records that a file for this buildid was just served.
+    ///
+    /// This is used by [Cache::prune] to keep the most useful entries, and counted for
+    /// [Cache::get_top_hits].
+    pub async fn touch(&self, buildid: &str) -> anyhow::Result<()> {
+        let key = match buildid_to_db(buildid) {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        retry_busy("recording access", || self.touch_once(&key)).await
+    }
+
+    /// Attempts [Cache::touch] once
+    async fn touch_once(&self, key: &[u8]) -> anyhow::Result<()> {
+        let now = unix_time_now();
+        let mut transaction = self.sqlite.begin().await.context("transaction sqlite")?;
+        sqlx::query("update builds set last_access = $1 where buildid = $2;")
+            .bind(now)
+            .bind(key)
+            .execute(&mut *transaction)
+            .await
+            .context("updating last access time in cache db")?;
+        sqlx::query(
+            "insert into hits (buildid, count, first_served, last_served)
+            values ($1, 1, $2, $2)
+            on conflict (buildid) do update set count = count + 1, last_served = $2;",
+        )
+        .bind(key)
+        .bind(now)
+        .execute(&mut *transaction)
+        .await
+        .context("recording hit in cache db")?;
+        transaction
+            .commit()
+            .await
+            .context("committing access in cache db")?;
+        Ok(())
+    }
+
+    /// Lists the buildids whose files were served the most, most served first.
+    pub async fn get_top_hits(&self, limit: usize) -> anyhow::Result<Vec<Hit>> {
+        let rows = sqlx::query(
+            "select hits.buildid, hits.count, hits.first_served, hits.last_served,
+                e.path as e_storepath, builds.executable,
+                d.path as d_storepath, builds.debuginfo
+            from hits
+            left join builds on builds.buildid = hits.buildid
+            left join storepaths e on e.id = builds.executable_storepath
+            left join storepaths d on d.id = builds.debuginfo_storepath
+            order by hits.count desc, hits.last_served desc
+            limit $1;",
+        )
+        .bind(limit as i64)
+        .fetch_all(&self.sqlite)
+        .await
+        .context("reading hits from cache db")?;
+        rows.iter()
+            .map(|row| {
+                let buildid: Vec<u8> = row.try_get("buildid")?;
+                let count: i64 = row.try_get("count")?;
+                let path = |storepath: &str, rest: &str| -> anyhow::Result<Option<String>> {
+                    let storepath: Option<String> = row.try_get(storepath)?;
+                    let rest: Option<String> = row.try_get(rest)?;
+                    Ok(rest.map(|rest| path_from_db(storepath, rest)))
+                };
+                let executable = path("e_storepath", "executable")?;
+                Ok(Hit {
+                    buildid: base16::encode_lower(&buildid),
+                    package: executable
+                        .as_deref()
+                        .and_then(|path| package_from_store_path(Path::new(path))),
+                    executable,
+                    debuginfo: path("d_storepath", "debuginfo")?,
+                    count: count as u64,
+                    first_served: row.try_get("first_served")?,
+                    last_served: row.try_get("last_served")?,
+                })
+            })
+            .collect()
+    }
+
+    /// Records that the debuginfo of this buildid was requested but not found.
+    pub async fn record_miss(&self, buildid: &str) -> anyhow::Result<()> {
+        let key = match buildid_to_db(buildid) {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        let now = unix_time_now();
+        sqlx::query(
+            "insert into misses (buildid, count, first_requested, last_requested)
+            values ($1, 1, $2, $2)
+            on conflict (buildid) do update set count = count + 1, last_requested = $2;",
+        )
+        .bind(key)
+        .bind(now)
+        .execute(&self.sqlite)
+        .await
+        .context("recording miss in cache db")?;
+        Ok(())
+    }
+
+    /// Forgets that the debuginfo of this buildid was not found, because it now is.
+    pub async fn forget_miss(&self, buildid: &str) -> anyhow::Result<()> {
+        let key = match buildid_to_db(buildid) {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        sqlx::query("delete from misses where buildid = $1;")
+            .bind(key)
+            .execute(&self.sqlite)
+            .await
+            .context("removing miss from cache db")?;
+        Ok(())
+    }
+
+    /// Lists the buildids whose debuginfo was requested but never found, most requested first.
+    pub async fn get_misses(&self, limit: usize) -> anyhow::Result<Vec<Miss>> {
+        let rows = sqlx::query(
+            "select misses.buildid, misses.count, misses.first_requested, misses.last_requested,
+                e.path as e_storepath, builds.executable
+            from misses
+            left join builds on builds.buildid = misses.buildid
+            left join storepaths e on e.id = builds.executable_storepath
+            order by misses.count desc, misses.last_requested desc
+            limit $1;",
+        )
+        .bind(limit as i64)
+        .fetch_all(&self.sqlite)
+        .await
+        .context("reading misses from cache db")?;
+        rows.iter()
+            .map(|row| {
+                let buildid: Vec<u8> = row.try_get("buildid")?;
+                let storepath: Option<String> = row.try_get("e_storepath")?;
+                let executable: Option<String> = row.try_get("executable")?;
+                let count: i64 = row.try_get("count")?;
+                let executable = executable.map(|rest| path_from_db(storepath, rest));
+                Ok(Miss {
+                    buildid: base16::encode_lower(&buildid),
+                    package: executable
+                        .as_deref()
+                        .and_then(|path| package_from_store_path(Path::new(path))),
+                    executable,
+                    count: count as u64,
+                    first_requested: row.try_get("first_requested")?,
+                    last_requested: row.try_get("last_requested")?,
+                })
+            })
+            .collect()
+    }
+
+    /// Counts the buildids of the cache.
+    pub async fn coverage(&self) -> anyhow::Result<Coverage> {
+        let row = sqlx::query(
+            "select count(*) as buildids, count(debuginfo) as with_debuginfo,
+                count(source) as with_source
+            from builds;",
+        )
+        .fetch_one(&self.sqlite)
+        .await
+        .context("counting entries in cache db")?;
+        let get = |column: &str| -> anyhow::Result<u64> {
+            let n: i64 = row.try_get(column)?;
+            Ok(n as u64)
+        };
+        Ok(Coverage {
+            buildids: get("buildids")?,
+            with_debuginfo: get("with_debuginfo")?,
+            with_source: get("with_source")?,
+        })
+    }
+
+    /// Returns the buildid of the executable at this path, if it was indexed.
+    ///
+    /// This scans the whole table, prefer reading the buildid of the file when it exists.
+    pub async fn get_buildid_of_executable(&self, path: &str) -> anyhow::Result<Option<String>> {
+        let (storepath, rest) = path_to_db(path);
+        let row = sqlx::query(
+            "select builds.buildid from builds
+            left join storepaths e on e.id = builds.executable_storepath
+            where builds.executable = $1 and e.path is $2
+            limit 1;",
+        )
+        .bind(rest)
+        .bind(storepath)
+        .fetch_optional(&self.sqlite)
+        .await
+        .with_context(|| format!("looking up buildid of {} in cache db", path))?;
+        match row {
+            None => Ok(None),
+            Some(row) => {
+                let buildid: Vec<u8> = row.try_get("buildid")?;
+                Ok(Some(base16::encode_lower(&buildid)))
+            }
+        }
+    }
+
+    /// Returns the size of the cache db in bytes, without its write-ahead log.
+    pub async fn db_size(&self) -> anyhow::Result<u64> {
+        let row = sqlx::query(
+            "select page_count * page_size as size from pragma_page_count(), pragma_page_size();",
+        )
+        .fetch_one(&self.sqlite)
+        .await
+        .context("reading size of cache db")?;
+        let size: i64 = row.try_get("size")?;
+        Ok(size as u64)
+    }
+
+    /// Returns how many buildids had their debuginfo requested but never found.
+    pub async fn count_misses(&self) -> anyhow::Result<u64> {
+        let row = sqlx::query("select count(*) as n from misses;")
+            .fetch_one(&self.sqlite)
+            .await
+            .context("counting misses in cache db")?;
+        let n: i64 = row.try_get("n")?;
+        Ok(n as u64)
+    }
+
+    /// Removes entries according to this policy.
+    ///
+    /// Returns the number of removed entries.
+    pub async fn prune(&self, policy: &PrunePolicy) -> anyhow::Result<u64> {
+        retry_busy("pruning", || self.prune_once(policy)).await
+    }
+
+    /// Attempts [Cache::prune] once
+    async fn prune_once(&self, policy: &PrunePolicy) -> anyhow::Result<u64> {
+        let mut removed = 0;
+        let mut transaction = self.sqlite.begin().await.context("transaction sqlite")?;
+        if let Some(max_age) = policy.max_age {
+            let limit = unix_time_now().saturating_sub(max_age.as_secs() as i64);
+            removed += sqlx::query("delete from builds where last_access < $1;")
+                .bind(limit)
+                .execute(&mut *transaction)
+                .await
+                .context("removing old entries from cache db")?
+                .rows_affected();
+            sqlx::query("delete from misses where last_requested < $1;")
+                .bind(limit)
+                .execute(&mut *transaction)
+                .await
+                .context("removing old misses from cache db")?;
+            sqlx::query("delete from hits where last_served < $1;")
+                .bind(limit)
+                .execute(&mut *transaction)
+                .await
+                .context("removing old hits from cache db")?;
+        }
+        if let Some(max_entries) = policy.max_entries {
+            removed += sqlx::query(
+                "delete from builds where rowid in
+                    (select rowid from builds order by last_access desc limit -1 offset $1);",
+            )
+            .bind(max_entries as i64)
+            .execute(&mut *transaction)
+            .await
+            .context("removing least recently used entries from cache db")?
+            .rows_affected();
+        }
+        sqlx::query(
+            "delete from storepaths where id not in (
+                select executable_storepath from builds where executable_storepath is not null
+                union select debuginfo_storepath from builds where debuginfo_storepath is not null
+                union select source_storepath from builds where source_storepath is not null
+            );",
+        )
+        .execute(&mut *transaction)
+        .await
+        .context("removing unused store paths from cache db")?;
+        sqlx::query("delete from debuglinks where buildid not in (select buildid from builds);")
+            .execute(&mut *transaction)
+            .await
+            .context("removing unused debuglink crcs from cache db")?;
+        sqlx::query("update gc set timestamp = $1;")
+            .bind(unix_time_now())
+            .execute(&mut *transaction)
+            .await
+            .context("recording pruning time in cache db")?;
+        transaction
+            .commit()
+            .await
+            .context("committing cache pruning")?;
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.hits.lock().unwrap().clear();
+        Ok(removed)
+    }
+
+    /// Lists the store paths which registered files are in.
+    pub async fn get_registered_storepaths(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let rows = sqlx::query("select path from storepaths order by path;")
+            .fetch_all(&self.sqlite)
+            .await
+            .context("reading registered store paths from cache db")?;
+        rows.iter()
+            .map(|row| {
+                let path: String = row
+                    .try_get("path")
+                    .context("parsing registered store path from cache db")?;
+                Ok(PathBuf::from(path_from_db(Some(path), String::new())))
+            })
+            .collect()
+    }
+
+    /// Forgets the files registered in these store paths, for example because they were
+    /// garbage collected, and the entries which have neither executable nor debuginfo left.
+    ///
+    /// Returns the number of removed entries.
+    pub async fn forget_storepaths(&self, storepaths: &[PathBuf]) -> anyhow::Result<u64> {
+        retry_busy("forgetting store paths", || {
+            self.forget_storepaths_once(storepaths)
+        })
+        .await
+    }
+
+    /// Attempts [Cache::forget_storepaths] once
+    async fn forget_storepaths_once(&self, storepaths: &[PathBuf]) -> anyhow::Result<u64> {
+        let mut transaction = self.sqlite.begin().await.context("transaction sqlite")?;
+        for storepath in storepaths {
+            let storepath = storepath.to_string_lossy();
+            let key = match path_to_db(&storepath) {
+                (Some(key), "") => key,
+                _ => continue,
+            };
+            let id: Option<i64> = sqlx::query("select id from storepaths where path = $1;")
+                .bind(key)
+                .fetch_optional(&mut *transaction)
+                .await
+                .context("reading store path id from cache db")?
+                .map(|row| row.try_get("id"))
+                .transpose()
+                .context("parsing store path id from cache db")?;
+            let id = match id {
+                Some(id) => id,
+                None => continue,
+            };
+            for kind in ["executable", "debuginfo"] {
+                sqlx::query(&format!(
+                    "update builds set {kind} = null, {kind}_storepath = null,
+                        {kind}_size = null, {kind}_mtime = null
+                    where {kind}_storepath = $1;"
+                ))
+                .bind(id)
+                .execute(&mut *transaction)
+                .await
+                .with_context(|| format!("forgetting {} in cache db", kind))?;
+            }
+            sqlx::query(
+                "update builds set source = null, source_storepath = null
+                where source_storepath = $1;",
+            )
+            .bind(id)
+            .execute(&mut *transaction)
+            .await
+            .context("forgetting source in cache db")?;
+            sqlx::query("delete from storepaths where id = $1;")
+                .bind(id)
+                .execute(&mut *transaction)
+                .await
+                .context("forgetting store path in cache db")?;
+        }
+        let removed =
+            sqlx::query("delete from builds where executable is null and debuginfo is null;")
+                .execute(&mut *transaction)
+                .await
+                .context("removing empty entries from cache db")?
+                .rows_affected();
+        sqlx::query("delete from debuglinks where buildid not in (select buildid from builds);")
+            .execute(&mut *transaction)
+            .await
+            .context("removing unused debuglink crcs from cache db")?;
+        transaction
+            .commit()
+            .await
+            .context("committing forgotten store paths")?;
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.hits.lock().unwrap().clear();
+        Ok(removed)
+    }
+
+    /// Returns when [Cache::prune] was last called.
+    pub async fn last_pruned(&self) -> anyhow::Result<SystemTime> {
+        let row = sqlx::query("select timestamp from gc")
+            .fetch_one(&self.sqlite)
+            .await
+            .context("reading last pruning time in cache db")?;
+        let timestamp: i64 = row
+            .try_get("timestamp")
+            .context("parsing last pruning time from cache db")?;
+        Ok(UNIX_EPOCH + Duration::from_secs(timestamp.max(0) as u64))
+    }
+
+    /// Store the next store path id to read from the nix db
+    pub async fn set_next_id(&self, id: Id) -> anyhow::Result<()> {
+        retry_busy("advancing next id", || self.set_next_id_once(id)).await
+    }
+
+    /// Attempts [Cache::set_next_id] once
+    async fn set_next_id_once(&self, id: Id) -> anyhow::Result<()> {
+        let mut transaction = self.sqlite.begin().await.context("transaction sqlite")?;
+        sqlx::query(
+            "insert into id values ($1, $2)
+            on conflict(store) do update set next = max(next, excluded.next);",
+        )
+        .bind(&*self.store)
+        .bind(id)
+        .execute(&mut *transaction)
+        .await
+        .context("advancing next registered id in cache db")?;
+        sqlx::query("delete from indexed where store = $1 and id < $2;")
+            .bind(&*self.store)
+            .bind(id)
+            .execute(&mut *transaction)
+            .await
+            .context("forgetting indexed store paths before next id in cache db")?;
+        transaction.commit().await.context("committing next id")?;
+        Ok(())
+    }
+
+    /// Forgets which store paths of the store of this cache were indexed, so that the whole
+    /// store is indexed again from scratch. Registered entries are kept, and updated when their
+    /// store paths are indexed again.
+    pub async fn reset_indexation(&self) -> anyhow::Result<()> {
+        retry_busy("resetting indexation", || self.reset_indexation_once()).await
+    }
+
+    /// Attempts [Cache::reset_indexation] once
+    async fn reset_indexation_once(&self) -> anyhow::Result<()> {
+        let mut transaction = self.sqlite.begin().await.context("transaction sqlite")?;
+        sqlx::query("delete from id where store = $1;")
+            .bind(&*self.store)
+            .execute(&mut *transaction)
+            .await
+            .context("resetting next registered id in cache db")?;
+        sqlx::query("delete from indexed where store = $1;")
+            .bind(&*self.store)
+            .execute(&mut *transaction)
+            .await
+            .context("forgetting indexed store paths in cache db")?;
+        // store paths are only listed with `nix path-info --all` in the system store
+        if self.store.is_empty() {
+            sqlx::query("delete from listed;")
+                .execute(&mut *transaction)
+                .await
+                .context("forgetting listed store paths in cache db")?;
+        }
+        transaction
+            .commit()
+            .await
+            .context("committing indexation reset")?;
+        Ok(())
+    }
+
+    /// Get the ids of the store paths between `start` (included) and `end` (excluded) which
+    /// were recorded as indexed by [Cache::register_indexed].
+    pub async fn get_indexed_ids(&self, start: Id, end: Id) -> anyhow::Result<HashSet<Id>> {
+        let rows = sqlx::query("select id from indexed where store = $1 and id >= $2 and id < $3;")
+            .bind(&*self.store)
+            .bind(start)
+            .bind(end)
+            .fetch_all(&self.sqlite)
+            .await
+            .context("reading indexed store paths from cache db")?;
+        rows.iter()
+            .map(|row| {
+                row.try_get("id")
+                    .context("parsing indexed id from cache db")
+            })
+            .collect()
+    }
+
+    /// get the next store path id to read from the nix db
+    pub async fn get_next_id(&self) -> anyhow::Result<Id> {
+        let row = sqlx::query("select next from id where store = $1")
+            .bind(&*self.store)
+            .fetch_optional(&self.sqlite)
+            .await
+            .context("reading next registered id in cache db")?;
+        match row {
+            None => Ok(0),
+            Some(row) => row
+                .try_get("next")
+                .context("parsing next registered id from cache db"),
+        }
+    }
+
+    /// Get the store paths whose closures were recorded as indexed for this index root by
+    /// [Cache::set_root_targets]
+    pub async fn get_root_targets(&self, root: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        let row = sqlx::query("select targets from roots where store = $1 and root = $2;")
+            .bind(&*self.store)
+            .bind(root.to_string_lossy())
+            .fetch_optional(&self.sqlite)
+            .await
+            .context("reading index root progress from cache db")?;
+        let targets: String = match row {
+            None => return Ok(Vec::new()),
+            Some(row) => row
+                .try_get("targets")
+                .context("parsing index root progress from cache db")?,
+        };
+        Ok(targets.lines().map(PathBuf::from).collect())
+    }
+
+    /// Records that the closures of these store paths, the targets of this index root, were
+    /// completely indexed
+    pub async fn set_root_targets(&self, root: &Path, targets: &[PathBuf]) -> anyhow::Result<()> {
+        retry_busy("recording index root progress", || {
+            self.set_root_targets_once(root, targets)
+        })
+        .await
+    }
+
+    /// Attempts [Cache::set_root_targets] once
+    async fn set_root_targets_once(&self, root: &Path, targets: &[PathBuf]) -> anyhow::Result<()> {
+        let targets = targets
+            .iter()
+            .map(|target| target.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("\n");
+        sqlx::query(
+            "insert into roots (store, root, targets) values ($1, $2, $3)
+            on conflict (store, root) do update set targets = excluded.targets;",
+        )
+        .bind(&*self.store)
+        .bind(root.to_string_lossy())
+        .bind(targets)
+        .execute(&self.sqlite)
+        .await
+        .context("recording index root progress in cache db")?;
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_register_merges_entries() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let buildid = "483bd7f7229bdb06462222e1e353e4f37e15c293";
+    assert_eq!(cache.get_executable(buildid).await.unwrap(), None);
+    let metadata = FileMetadata { size: 42, mtime: 1 };
+    cache
+        .register(&[Entry {
+            buildid: buildid.to_owned(),
+            executable: Some("/nix/store/aaa-foo/bin/foo".to_owned()),
+            executable_metadata: Some(metadata),
+            debuginfo: None,
+            debuginfo_metadata: None,
+            arch: Some("x86_64".to_owned()),
+            source: None,
+        }])
+        .await
+        .unwrap();
+    cache
+        .register(&[Entry {
+            buildid: buildid.to_owned(),
+            executable: None,
+            executable_metadata: None,
+            debuginfo: Some("/nix/store/bbb-foo-debug/lib/debug/foo.debug".to_owned()),
+            debuginfo_metadata: None,
+            arch: None,
+            source: None,
+        }])
+        .await
+        .unwrap();
+    assert_eq!(
+        cache.get_executable(buildid).await.unwrap().as_deref(),
+        Some("/nix/store/aaa-foo/bin/foo")
+    );
+    assert_eq!(
+        cache.get_debuginfo(buildid).await.unwrap().as_deref(),
+        Some("/nix/store/bbb-foo-debug/lib/debug/foo.debug")
+    );
+    assert_eq!(
+        cache.get_executable_metadata(buildid).await.unwrap(),
+        Some(metadata)
+    );
+    assert_eq!(cache.get_debuginfo_metadata(buildid).await.unwrap(), None);
+    assert_eq!(
+        cache.get_arch(buildid).await.unwrap().as_deref(),
+        Some("x86_64")
+    );
+}
+
+#[tokio::test]
+async fn test_get_entries() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let entry = Entry {
+        buildid: "483bd7f7229bdb06462222e1e353e4f37e15c293".to_owned(),
+        executable: Some("/nix/store/aaa-foo/bin/foo".to_owned()),
+        executable_metadata: Some(FileMetadata { size: 42, mtime: 1 }),
+        debuginfo: None,
+        debuginfo_metadata: None,
+        arch: None,
+        source: None,
+    };
+    cache.register(std::slice::from_ref(&entry)).await.unwrap();
+    let entries = cache
+        .get_entries(&[
+            entry.buildid.clone(),
+            "0123".to_owned(),
+            "not hex".to_owned(),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].buildid, entry.buildid);
+    assert_eq!(entries[0].executable, entry.executable);
+    assert_eq!(entries[0].executable_metadata, entry.executable_metadata);
+}
+
+#[tokio::test]
+async fn test_get_entries_after() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let entries: Vec<Entry> = ["cc", "aa", "bb"]
+        .iter()
+        .map(|buildid| Entry {
+            buildid: buildid.to_string(),
+            executable: Some(format!("/nix/store/{buildid}-foo/bin/foo")),
+            executable_metadata: None,
+            debuginfo: None,
+            debuginfo_metadata: None,
+            arch: None,
+            source: None,
+        })
+        .collect();
+    cache.register(&entries).await.unwrap();
+    let page = |after: Option<&'static str>, limit| {
+        let cache = cache.clone();
+        async move {
+            cache
+                .get_entries_after(after, limit)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.buildid)
+                .collect::<Vec<_>>()
+        }
+    };
+    assert_eq!(page(None, 2).await, vec!["aa", "bb"]);
+    assert_eq!(page(Some("bb"), 2).await, vec!["cc"]);
+    assert!(page(Some("cc"), 2).await.is_empty());
+    assert!(cache.get_entries_after(Some("xyz"), 2).await.is_err());
+    assert!(cache.db_size().await.unwrap() > 0);
+}
+
+#[tokio::test]
+async fn test_debuglinks() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let entry = |buildid: &str, executable: &str| Entry {
+        buildid: buildid.to_owned(),
+        executable: Some(executable.to_owned()),
+        executable_metadata: None,
+        debuginfo: Some(format!(
+            "/nix/store/ddd-foo-debug/lib/debug/{}.debug",
+            buildid
+        )),
+        debuginfo_metadata: None,
+        arch: None,
+        source: None,
+    };
+    cache
+        .register(&[
+            entry("aa", "/nix/store/aaa-foo/lib/libfoo.so.1"),
+            entry("bb", "/nix/store/bbb-foo/lib/libfoo.so.1"),
+            entry("cc", "/nix/store/aaa-foo/lib/xlibfoo.so.1"),
+        ])
+        .await
+        .unwrap();
+    let buildids = |entries: Vec<Entry>| {
+        let mut buildids: Vec<String> = entries.into_iter().map(|entry| entry.buildid).collect();
+        buildids.sort();
+        buildids
+    };
+    let candidates = cache.get_debuglink_candidates("libfoo.so.1", 10);
+    assert_eq!(buildids(candidates.await.unwrap()), vec!["aa", "bb"]);
+    assert_eq!(cache.get_debuglink_crc("aa").await.unwrap(), None);
+    cache.set_debuglink_crc("aa", 0xcbf4_3926).await.unwrap();
+    assert_eq!(
+        cache.get_debuglink_crc("aa").await.unwrap(),
+        Some(0xcbf4_3926)
+    );
+    let candidates = cache.get_debuglink_candidates("libfoo.so.1", 10);
+    assert_eq!(buildids(candidates.await.unwrap()), vec!["bb"]);
+    let found = cache.get_entries_by_debuglink("libfoo.so.1", 0xcbf4_3926);
+    assert_eq!(buildids(found.await.unwrap()), vec!["aa"]);
+    let found = cache.get_entries_by_debuglink("libfoo.so.1", 0x1234);
+    assert!(found.await.unwrap().is_empty());
+    let found = cache.get_entries_by_debuglink("xlibfoo.so.1", 0xcbf4_3926);
+    assert!(found.await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_forget_storepaths() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let entry = |buildid: &str, executable: &str, debuginfo: &str| Entry {
+        buildid: buildid.to_owned(),
+        executable: Some(executable.to_owned()),
+        executable_metadata: None,
+        debuginfo: Some(debuginfo.to_owned()),
+        debuginfo_metadata: None,
+        arch: None,
+        source: None,
+    };
+    cache
+        .register(&[
+            entry(
+                "aa",
+                "/nix/store/aaa-foo/bin/foo",
+                "/nix/store/aaa-foo-debug/lib/debug/.build-id/aa.debug",
+            ),
+            entry(
+                "bb",
+                "/nix/store/bbb-bar/bin/bar",
+                "/nix/store/bbb-bar-debug/lib/debug/.build-id/bb.debug",
+            ),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(
+        cache.get_registered_storepaths().await.unwrap(),
+        vec![
+            PathBuf::from("/nix/store/aaa-foo"),
+            PathBuf::from("/nix/store/aaa-foo-debug"),
+            PathBuf::from("/nix/store/bbb-bar"),
+            PathBuf::from("/nix/store/bbb-bar-debug"),
+        ]
+    );
+    assert!(cache.get_debuginfo("bb").await.unwrap().is_some());
+    let removed = cache
+        .forget_storepaths(&[
+            PathBuf::from("/nix/store/aaa-foo"),
+            PathBuf::from("/nix/store/aaa-foo-debug"),
+            PathBuf::from("/nix/store/bbb-bar-debug"),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(removed, 1);
+    assert_eq!(cache.get_executable("aa").await.unwrap(), None);
+    assert_eq!(cache.get_debuginfo("bb").await.unwrap(), None);
+    assert_eq!(
+        cache.get_executable("bb").await.unwrap().as_deref(),
+        Some("/nix/store/bbb-bar/bin/bar")
+    );
+    assert_eq!(
+        cache.get_registered_storepaths().await.unwrap(),
+        vec![PathBuf::from("/nix/store/bbb-bar")]
+    );
+}
+
+#[tokio::test]
+async fn test_unresolved() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let buildid = "483bd7f7229bdb06462222e1e353e4f37e15c293";
+    assert!(!cache.is_unresolved(buildid, "debuginfo"));
+    cache.record_unresolved(buildid, "debuginfo");
+    assert!(cache.is_unresolved(buildid, "debuginfo"));
+    assert!(!cache.is_unresolved(buildid, "executable"));
+    // anything registered may be what was missing
+    cache
+        .register(&[Entry {
+            buildid: "aa".to_owned(),
+            executable: Some("/nix/store/aaa-foo/bin/foo".to_owned()),
+            executable_metadata: None,
+            debuginfo: None,
+            debuginfo_metadata: None,
+            arch: None,
+            source: None,
+        }])
+        .await
+        .unwrap();
+    assert!(!cache.is_unresolved(buildid, "debuginfo"));
+}
+
+#[tokio::test]
+async fn test_wait_for_buildid() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let buildid = "483bd7f7229bdb06462222e1e353e4f37e15c293";
+    assert!(!cache
+        .wait_for_buildid(buildid, Duration::from_millis(10))
+        .await
+        .unwrap());
+    let waiter = {
+        let cache = cache.clone();
+        tokio::spawn(async move {
+            cache
+                .wait_for_buildid(buildid, Duration::from_secs(10))
+                .await
+                .unwrap()
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    cache
+        .register(&[Entry {
+            buildid: buildid.to_owned(),
+            executable: Some("/nix/store/aaa-foo/bin/foo".to_owned()),
+            executable_metadata: None,
+            debuginfo: None,
+            debuginfo_metadata: None,
+            arch: None,
+            source: None,
+        }])
+        .await
+        .unwrap();
+    assert!(waiter.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_get_all_entries() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let metadata = FileMetadata { size: 42, mtime: 1 };
+    cache
+        .register(&[Entry {
+            buildid: "483bd7f7229bdb06462222e1e353e4f37e15c293".to_owned(),
+            executable: Some("/nix/store/aaa-foo/bin/foo".to_owned()),
+            executable_metadata: Some(metadata),
+            debuginfo: None,
+            debuginfo_metadata: None,
+            arch: None,
+            source: Some("/nix/store/ccc-foo.tar.gz".to_owned()),
+        }])
+        .await
+        .unwrap();
+    let entries = cache.get_all_entries().await.unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(
+        entries[0].buildid,
+        "483bd7f7229bdb06462222e1e353e4f37e15c293"
+    );
+    assert_eq!(
+        entries[0].executable.as_deref(),
+        Some("/nix/store/aaa-foo/bin/foo")
+    );
+    assert_eq!(entries[0].executable_metadata, Some(metadata));
+    assert_eq!(entries[0].debuginfo, None);
+    assert_eq!(
+        entries[0].source.as_deref(),
+        Some("/nix/store/ccc-foo.tar.gz")
+    );
+}
+
+#[tokio::test]
+async fn test_write_canonical() {
+    let entry = |buildid: &str, executable: &str| Entry {
+        buildid: buildid.to_owned(),
+        executable: Some(executable.to_owned()),
+        executable_metadata: Some(FileMetadata { size: 42, mtime: 1 }),
+        debuginfo: None,
+        debuginfo_metadata: None,
+        arch: Some("x86_64".to_owned()),
+        source: None,
+    };
+    let entries = [
+        entry("aabb", "/nix/store/aaa-foo/bin/foo"),
+        entry("ccdd", "/nix/store/bbb-bar/bin/bar"),
+        entry("eeff", "/nix/store/aaa-foo/bin/baz"),
+    ];
+    let dir = tempfile::tempdir().unwrap();
+    let mut written = Vec::new();
+    for (i, order) in [[0, 1, 2], [2, 1, 0]].into_iter().enumerate() {
+        let cache = Cache::open_in_memory().await.unwrap();
+        for j in order {
+            cache.register(&entries[j..j + 1]).await.unwrap();
+        }
+        cache.touch("ccdd").await.unwrap();
+        // not relocatable
+        cache
+            .register(&[entry("0011", "/usr/bin/foo")])
+            .await
+            .unwrap();
+        let output = dir.path().join(format!("{}.sqlite3", i));
+        assert_eq!(cache.write_canonical(&output).await.unwrap(), 3);
+        written.push(std::fs::read(&output).unwrap());
+    }
+    assert!(written[0] == written[1]);
+    let cache = Cache::open_path(&dir.path().join("0.sqlite3"))
+        .await
+        .unwrap();
+    let read = cache.get_all_entries().await.unwrap();
+    assert_eq!(
+        read.iter().map(|e| e.buildid.as_str()).collect::<Vec<_>>(),
+        vec!["aabb", "ccdd", "eeff"]
+    );
+    assert_eq!(read[1].executable, entries[1].executable);
+}
+
+#[tokio::test]
+async fn test_get_entries_of_store_path() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let entry = |buildid: &str, executable: Option<&str>, debuginfo: Option<&str>| Entry {
+        buildid: buildid.to_owned(),
+        executable: executable.map(str::to_owned),
+        executable_metadata: None,
+        debuginfo: debuginfo.map(str::to_owned),
+        debuginfo_metadata: None,
+        arch: None,
+        source: None,
+    };
+    cache
+        .register(&[
+            entry("aa", Some("/nix/store/aaa-foo/bin/foo"), None),
+            entry("bb", Some("/nix/store/aaa-foo/lib/libfoo.so"), None),
+            entry(
+                "cc",
+                None,
+                Some("/nix/store/bbb-foo-debug/lib/debug/cc.debug"),
+            ),
+            entry("dd", Some("/nix/store/ccc-bar/bin/bar"), None),
+        ])
+        .await
+        .unwrap();
+    let buildids = |entries: Vec<Entry>| -> Vec<String> {
+        entries.into_iter().map(|entry| entry.buildid).collect()
+    };
+    assert_eq!(
+        buildids(
+            cache
+                .get_entries_of_store_path("/nix/store/aaa-foo")
+                .await
+                .unwrap()
+        ),
+        vec!["aa", "bb"]
+    );
+    assert_eq!(
+        buildids(
+            cache
+                .get_entries_of_store_path("/nix/store/bbb-foo-debug")
+                .await
+                .unwrap()
+        ),
+        vec!["cc"]
+    );
+    assert!(cache
+        .get_entries_of_store_path("/nix/store/aaa-foo/bin/foo")
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_get_buildid_of_executable() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let buildid = "483bd7f7229bdb06462222e1e353e4f37e15c293";
+    cache
+        .register(&[Entry {
+            buildid: buildid.to_owned(),
+            executable: Some("/nix/store/aaa-foo/bin/foo".to_owned()),
+            executable_metadata: None,
+            debuginfo: None,
+            debuginfo_metadata: None,
+            arch: None,
+            source: None,
+        }])
+        .await
+        .unwrap();
+    assert_eq!(
+        cache
+            .get_buildid_of_executable("/nix/store/aaa-foo/bin/foo")
+            .await
+            .unwrap()
+            .as_deref(),
+        Some(buildid)
+    );
+    assert_eq!(
+        cache
+            .get_buildid_of_executable("/nix/store/bbb-foo/bin/foo")
+            .await
+            .unwrap(),
+        None
+    );
+}
+
+#[tokio::test]
+async fn test_misses() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let buildid = "483bd7f7229bdb06462222e1e353e4f37e15c293";
+    cache
+        .register(&[Entry {
+            buildid: buildid.to_owned(),
+            executable: Some("/nix/store/aaa-foo/bin/foo".to_owned()),
+            executable_metadata: None,
+            debuginfo: None,
+            debuginfo_metadata: None,
+            arch: None,
+            source: None,
+        }])
+        .await
+        .unwrap();
+    cache.record_miss(buildid).await.unwrap();
+    cache.record_miss(buildid).await.unwrap();
+    cache.record_miss("aabb").await.unwrap();
+    let misses = cache.get_misses(10).await.unwrap();
+    assert_eq!(misses.len(), 2);
+    assert_eq!(misses[0].buildid, buildid);
+    assert_eq!(misses[0].count, 2);
+    assert_eq!(
+        misses[0].executable.as_deref(),
+        Some("/nix/store/aaa-foo/bin/foo")
+    );
+    assert_eq!(misses[1].buildid, "aabb");
+    assert_eq!(misses[1].executable, None);
+    cache.forget_miss(buildid).await.unwrap();
+    assert_eq!(cache.count_misses().await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn test_hits() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let buildid = "483bd7f7229bdb06462222e1e353e4f37e15c293";
+    cache
+        .register(&[Entry {
+            buildid: buildid.to_owned(),
+            executable: Some("/nix/store/aaa-foo-1.0/bin/foo".to_owned()),
+            executable_metadata: None,
+            debuginfo: Some("/nix/store/bbb-foo-1.0-debug/lib/debug/foo".to_owned()),
+            debuginfo_metadata: None,
+            arch: None,
+            source: None,
+        }])
+        .await
+        .unwrap();
+    cache.touch("aabb").await.unwrap();
+    cache.touch(buildid).await.unwrap();
+    cache.touch(buildid).await.unwrap();
+    let hits = cache.get_top_hits(10).await.unwrap();
+    assert_eq!(hits.len(), 2);
+    assert_eq!(hits[0].buildid, buildid);
+    assert_eq!(hits[0].count, 2);
+    assert_eq!(
+        hits[0].debuginfo.as_deref(),
+        Some("/nix/store/bbb-foo-1.0-debug/lib/debug/foo")
+    );
+    assert_eq!(hits[0].package.as_ref().unwrap().pname, "foo");
+    assert_eq!(hits[1].buildid, "aabb");
+    assert_eq!(hits[1].executable, None);
+    assert_eq!(cache.get_top_hits(1).await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_coverage() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let entries: Vec<Entry> = ["aa", "bb"]
+        .iter()
+        .map(|buildid| Entry {
+            buildid: buildid.to_string(),
+            executable: Some(format!("/nix/store/{buildid}-foo/bin/foo")),
+            executable_metadata: None,
+            debuginfo: (*buildid == "aa").then(|| format!("/nix/store/{buildid}-foo-debug/foo")),
+            debuginfo_metadata: None,
+            arch: None,
+            source: None,
+        })
+        .collect();
+    cache.register(&entries).await.unwrap();
+    assert_eq!(
+        cache.coverage().await.unwrap(),
+        Coverage {
+            buildids: 2,
+            with_debuginfo: 1,
+            with_source: 0
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_prune_max_entries() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let entries: Vec<Entry> = ["aa", "bb", "cc"]
+        .iter()
+        .map(|buildid| Entry {
+            buildid: buildid.to_string(),
+            executable: Some(format!("/nix/store/{buildid}-foo/bin/foo")),
+            executable_metadata: None,
+            debuginfo: None,
+            debuginfo_metadata: None,
+            arch: None,
+            source: None,
+        })
+        .collect();
+    cache.register(&entries).await.unwrap();
+    sqlx::query("update builds set last_access = 0;")
+        .execute(&cache.sqlite)
+        .await
+        .unwrap();
+    cache.touch("bb").await.unwrap();
+    let policy = PrunePolicy {
+        max_age: None,
+        max_entries: Some(1),
+    };
+    assert_eq!(cache.prune(&policy).await.unwrap(), 2);
+    assert_eq!(cache.get_executable("aa").await.unwrap(), None);
+    assert!(cache.get_executable("bb").await.unwrap().is_some());
+    assert!(cache.last_pruned().await.unwrap() > UNIX_EPOCH);
+}
+
+#[tokio::test]
+async fn test_indexed_ids() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    cache.register_indexed(&[], &[3, 5, 8]).await.unwrap();
+    assert_eq!(
+        cache.get_indexed_ids(0, 8).await.unwrap(),
+        HashSet::from([3, 5])
+    );
+    cache.set_next_id(5).await.unwrap();
+    assert_eq!(cache.get_next_id().await.unwrap(), 5);
+    assert_eq!(
+        cache.get_indexed_ids(0, 10).await.unwrap(),
+        HashSet::from([5, 8])
+    );
+}
+
+#[tokio::test]
+async fn test_reset_indexation() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let extra = cache.for_store(Path::new("/home/alice/.local/share/nix/root"));
+    cache.register_indexed(&[], &[12]).await.unwrap();
+    cache.set_next_id(10).await.unwrap();
+    extra.set_next_id(4).await.unwrap();
+    cache
+        .register_listed(&[PathBuf::from("/nix/store/aaa-foo")])
+        .await
+        .unwrap();
+    cache.reset_indexation().await.unwrap();
+    assert_eq!(cache.get_next_id().await.unwrap(), 0);
+    assert!(cache.get_indexed_ids(0, 20).await.unwrap().is_empty());
+    assert!(cache.get_listed_paths().await.unwrap().is_empty());
+    assert_eq!(extra.get_next_id().await.unwrap(), 4);
+}
+
+#[tokio::test]
+async fn test_root_targets() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let extra = cache.for_store(Path::new("/home/alice/.local/share/nix/root"));
+    let root = Path::new("/nix/var/nix/gcroots");
+    assert!(cache.get_root_targets(root).await.unwrap().is_empty());
+    let targets = vec![
+        PathBuf::from("/nix/store/aaa-foo"),
+        PathBuf::from("/nix/store/bbb-bar"),
+    ];
+    cache.set_root_targets(root, &targets).await.unwrap();
+    assert_eq!(cache.get_root_targets(root).await.unwrap(), targets);
+    assert!(extra.get_root_targets(root).await.unwrap().is_empty());
+    cache.set_root_targets(root, &targets[1..]).await.unwrap();
+    assert_eq!(cache.get_root_targets(root).await.unwrap(), &targets[1..]);
+}
+
+#[tokio::test]
+async fn test_indexed_ids_per_store() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let extra = cache.for_store(Path::new("/home/alice/.local/share/nix/root"));
+    assert_eq!(extra.get_next_id().await.unwrap(), 0);
+    extra.register_indexed(&[], &[3]).await.unwrap();
+    extra.set_next_id(2).await.unwrap();
+    cache.set_next_id(7).await.unwrap();
+    assert_eq!(extra.get_next_id().await.unwrap(), 2);
+    assert_eq!(cache.get_next_id().await.unwrap(), 7);
+    assert_eq!(
+        extra.get_indexed_ids(0, 10).await.unwrap(),
+        HashSet::from([3])
+    );
+    assert!(cache.get_indexed_ids(0, 10).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_storepaths_missing_debuginfo() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let entry = |buildid: &str, storepath: &str, debuginfo: Option<&str>| Entry {
+        buildid: buildid.to_owned(),
+        executable: Some(format!("/nix/store/{storepath}/bin/foo")),
+        executable_metadata: None,
+        debuginfo: debuginfo.map(|s| s.to_owned()),
+        debuginfo_metadata: None,
+        arch: None,
+        source: None,
+    };
+    cache
+        .register(&[
+            entry("aa", "aaa-foo", None),
+            entry("bb", "aaa-foo", None),
+            entry(
+                "cc",
+                "ccc-bar",
+                Some("/nix/store/ccc-bar-debug/lib/debug/foo.debug"),
+            ),
+        ])
+        .await
+        .unwrap();
+    let now = SystemTime::now();
+    assert_eq!(
+        cache
+            .get_storepaths_missing_debuginfo(10, now)
+            .await
+            .unwrap(),
+        vec!["/nix/store/aaa-foo".to_owned()]
+    );
+    cache
+        .record_backfill_attempt("/nix/store/aaa-foo")
+        .await
+        .unwrap();
+    assert!(cache
+        .get_storepaths_missing_debuginfo(10, now)
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        cache
+            .get_storepaths_missing_debuginfo(10, now + Duration::from_secs(2))
+            .await
+            .unwrap(),
+        vec!["/nix/store/aaa-foo".to_owned()]
+    );
+    assert!(cache
+        .record_backfill_attempt("/nix/store/aaa-foo/bin/foo")
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_storepaths_missing_executable() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let entry = |buildid: &str, storepath: &str| Entry {
+        buildid: buildid.to_owned(),
+        executable: None,
+        executable_metadata: None,
+        debuginfo: Some(format!("/nix/store/{storepath}/lib/debug/foo.debug")),
+        debuginfo_metadata: None,
+        arch: None,
+        source: None,
+    };
+    cache
+        .register(&[entry("aa", "aaa-foo-debug"), entry("bb", "bbb-bar-debug")])
+        .await
+        .unwrap();
+    // found later with its executable
+    cache
+        .register(&[Entry {
+            executable: Some("/nix/store/bbb-bar/bin/bar".to_owned()),
+            debuginfo: None,
+            ..entry("bb", "bbb-bar-debug")
+        }])
+        .await
+        .unwrap();
+    assert_eq!(
+        cache
+            .get_storepaths_missing_executable(10, SystemTime::now())
+            .await
+            .unwrap(),
+        vec!["/nix/store/aaa-foo-debug".to_owned()]
+    );
+    assert_eq!(
+        cache.get_debuginfo("bb").await.unwrap().as_deref(),
+        Some("/nix/store/bbb-bar-debug/lib/debug/foo.debug")
+    );
+}
+
+#[tokio::test]
+async fn test_listed_paths() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let paths = [
+        PathBuf::from("/nix/store/aaa-foo"),
+        PathBuf::from("/nix/store/bbb-bar"),
+    ];
+    cache.register_listed(&paths[..1]).await.unwrap();
+    cache.register_listed(&paths).await.unwrap();
+    assert_eq!(
+        cache.get_listed_paths().await.unwrap(),
+        HashSet::from(paths)
+    );
+}
+
+#[tokio::test]
+async fn test_failed_paths() {
+    let cache = Cache::open_in_memory().await.unwrap();
+    let extra = cache.for_store(Path::new("/home/alice/.local/share/nix/root"));
+    let foo = PathBuf::from("/nix/store/aaa-foo");
+    let bar = PathBuf::from("/nix/store/bbb-bar");
+    cache.record_failed(&foo, "timed out").await.unwrap();
+    cache.record_failed(&bar, "no deriver").await.unwrap();
+    cache.record_failed(&foo, "timed out again").await.unwrap();
+    assert_eq!(
+        cache.get_failed_paths().await.unwrap(),
+        vec![
+            (foo.clone(), "timed out again".to_owned()),
+            (bar.clone(), "no deriver".to_owned())
+        ]
+    );
+    assert!(extra.get_failed_paths().await.unwrap().is_empty());
+    // failures recorded after the reindexation started are kept
+    cache
+        .forget_failed(&[foo.clone(), bar.clone()], UNIX_EPOCH)
+        .await
+        .unwrap();
+    assert_eq!(cache.get_failed_paths().await.unwrap().len(), 2);
+    cache
+        .forget_failed(
+            std::slice::from_ref(&foo),
+            SystemTime::now() + Duration::from_secs(1),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        cache.get_failed_paths().await.unwrap(),
+        vec![(bar, "no deriver".to_owned())]
+    );
+}
+
+#[tokio::test]
+async fn test_shared_db() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("cache.sqlite3");
+    let (indexer, server) = tokio::join!(Cache::open_path(&path), Cache::open_path(&path));
+    let (indexer, server) = (indexer.unwrap(), server.unwrap());
+    let buildid = "483bd7f7229bdb06462222e1e353e4f37e15c293";
+    let entry = |i: usize| Entry {
+        buildid: format!("{:040x}", i),
+        executable: Some(format!("/nix/store/aaa-foo/bin/foo{}", i)),
+        executable_metadata: None,
+        debuginfo: None,
+        debuginfo_metadata: None,
+        arch: None,
+        source: None,
+    };
+    let batch = |range: std::ops::Range<usize>| range.map(entry).collect::<Vec<_>>();
+    let (first, second) = (batch(0..500), batch(500..1000));
+    let (a, b) = tokio::join!(indexer.register(&first), server.register(&second));
+    a.unwrap();
+    b.unwrap();
+    assert_eq!(indexer.get_all_entries().await.unwrap().len(), 1000);
+    assert_eq!(server.get_executable(buildid).await.unwrap(), None);
+    indexer
+        .register(&[Entry {
+            buildid: buildid.to_owned(),
+            ..entry(0)
+        }])
+        .await
+        .unwrap();
+    // the miss is remembered for a while
+    assert_eq!(server.get_executable(buildid).await.unwrap(), None);
+    server.misses.lock().unwrap().clear();
+    assert_eq!(
+        server.get_executable(buildid).await.unwrap().as_deref(),
+        Some("/nix/store/aaa-foo/bin/foo0")
+    );
+    indexer
+        .register(&[Entry {
+            buildid: buildid.to_owned(),
+            debuginfo: Some("/nix/store/bbb-foo-debug/lib/debug/foo.debug".to_owned()),
+            ..entry(0)
+        }])
+        .await
+        .unwrap();
+    // so is the hit
+    assert_eq!(server.get_debuginfo(buildid).await.unwrap(), None);
+    let expired = Instant::now().checked_sub(MISS_TTL).unwrap();
+    server.hits.lock().unwrap().get_mut(buildid).unwrap().0 = expired;
+    assert_eq!(
+        server.get_debuginfo(buildid).await.unwrap().as_deref(),
+        Some("/nix/store/bbb-foo-debug/lib/debug/foo.debug")
+    );
 }
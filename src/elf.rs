@@ -0,0 +1,636 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Manipulation of elf files, to serve smaller or more useful files than what is in the store
+
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::Context;
+use object::elf::{
+    FileHeader32, FileHeader64, SHT_NOBITS, SHT_NOTE, SHT_NULL, SHT_STRTAB, SHT_SYMTAB,
+};
+use object::read::elf::{FileHeader, SectionHeader};
+use object::{Endian, Endianness, FileKind};
+
+use crate::cachedir::{evict, touch, Limit};
+use crate::log::ResultExt;
+
+/// How many bytes of split debug info [only_keep_debug_cached] keeps
+const MAX_SPLIT_CACHE_SIZE: u64 = 2 << 30;
+
+/// How many bytes of `.dwp` packages [dwp_cached] keeps
+const MAX_DWP_CACHE_SIZE: u64 = 2 << 30;
+
+/// How many bytes of extracted sections [section_cached] keeps
+const MAX_SECTIONS_CACHE_SIZE: u64 = 1 << 30;
+
+/// How many bytes a string section read by [for_each_debug_string] may take once uncompressed
+const MAX_DEBUG_STRINGS_SIZE: u64 = 512 << 20;
+
+/// Whether `objcopy --only-keep-debug` would keep the content of this section
+fn is_debug_section(name: &[u8], sh_type: u32) -> bool {
+    name.starts_with(b".debug")
+        || name.starts_with(b".zdebug")
+        || name == b".gdb_index"
+        || name == b".gnu_debugaltlink"
+        // contains the buildid
+        || sh_type == SHT_NOTE
+        || sh_type == SHT_SYMTAB
+        || sh_type == SHT_STRTAB
+}
+
+/// Overwrites a field of a structure of an elf file with a new value
+///
+/// `width` is 2, 4 or 8 bytes.
+fn patch<E: Endian>(buf: &mut [u8], offset: usize, width: usize, value: u64, endian: E) {
+    match width {
+        2 => buf[offset..offset + 2].copy_from_slice(&endian.write_u16_bytes(value as u16)),
+        4 => buf[offset..offset + 4].copy_from_slice(&endian.write_u32_bytes(value as u32)),
+        8 => buf[offset..offset + 8].copy_from_slice(&endian.write_u64_bytes(value)),
+        _ => unreachable!("elf fields are 2, 4 or 8 bytes wide"),
+    }
+}
+
+/// Implementation of [only_keep_debug] for a specific elf class
+fn only_keep_debug_generic<Elf: FileHeader<Endian = Endianness>>(
+    data: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let header = Elf::parse(data).map_err(|e| anyhow::anyhow!("parsing elf header: {}", e))?;
+    let endian = header
+        .endian()
+        .map_err(|e| anyhow::anyhow!("parsing elf endianness: {}", e))?;
+    let sections = header
+        .sections(endian, data)
+        .map_err(|e| anyhow::anyhow!("parsing elf section headers: {}", e))?;
+    // offsets of fields which need to be updated, see elf(5)
+    let is_64 = header.is_type_64();
+    let word = if is_64 { 8 } else { 4 };
+    let (e_phoff, e_shoff, e_phnum) = if is_64 { (32, 40, 56) } else { (28, 32, 44) };
+    let (sh_type, sh_offset) = if is_64 { (4, 24) } else { (4, 16) };
+
+    let mut out = data[..size_of::<Elf>()].to_vec();
+    let mut section_headers = Vec::new();
+    for section in sections.iter() {
+        let mut raw = object::pod::bytes_of(section).to_vec();
+        let kind = section.sh_type(endian);
+        let name = sections.section_name(endian, section).unwrap_or_default();
+        if kind == SHT_NULL {
+            // keep as is
+        } else if kind != SHT_NOBITS && is_debug_section(name, kind) {
+            let align = section.sh_addralign(endian).into().max(1) as usize;
+            out.resize(out.len().next_multiple_of(align), 0);
+            let content = section
+                .data(endian, data)
+                .map_err(|e| anyhow::anyhow!("reading section content: {}", e))?;
+            patch(&mut raw, sh_offset, word, out.len() as u64, endian);
+            out.extend_from_slice(content);
+        } else {
+            patch(&mut raw, sh_type, 4, SHT_NOBITS as u64, endian);
+            patch(&mut raw, sh_offset, word, out.len() as u64, endian);
+        }
+        section_headers.extend_from_slice(&raw);
+    }
+    out.resize(out.len().next_multiple_of(word), 0);
+    let shoff = out.len() as u64;
+    out.extend_from_slice(&section_headers);
+    // program headers describe how to load the file, which makes no sense anymore
+    patch(&mut out, e_phoff, word, 0, endian);
+    patch(&mut out, e_phnum, 2, 0, endian);
+    patch(&mut out, e_shoff, word, shoff, endian);
+    Ok(out)
+}
+
+/// Removes everything but debug info from this elf file, like `objcopy --only-keep-debug`.
+///
+/// Section headers are kept, so that the debugger can relocate the debug info, but the content
+/// of non-debug sections is removed.
+pub fn only_keep_debug(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    match FileKind::parse(data) {
+        Ok(FileKind::Elf32) => only_keep_debug_generic::<FileHeader32<Endianness>>(data),
+        Ok(FileKind::Elf64) => only_keep_debug_generic::<FileHeader64<Endianness>>(data),
+        _ => anyhow::bail!("not an elf file"),
+    }
+}
+
+/// Creates (or reuses) a file in `cache_dir` containing only the debug info of `path`,
+/// which has this buildid.
+///
+/// The least recently used files are removed when they take more than
+/// [MAX_SPLIT_CACHE_SIZE].
+///
+/// Returns the path of the created file. Blocking.
+pub fn only_keep_debug_cached(
+    path: &Path,
+    buildid: &str,
+    cache_dir: &Path,
+) -> anyhow::Result<PathBuf> {
+    let target = cache_dir.join(format!("{}.debug", buildid));
+    if target.is_file() {
+        touch(&target).or_warn();
+        return Ok(target);
+    }
+    std::fs::create_dir_all(cache_dir)
+        .with_context(|| format!("creating {}", cache_dir.display()))?;
+    let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    let split = only_keep_debug(&data)
+        .with_context(|| format!("extracting debug info of {}", path.display()))?;
+    let mut tmp = tempfile::NamedTempFile::new_in(cache_dir).context("creating temp file")?;
+    std::io::Write::write_all(&mut tmp, &split).context("writing split debug info")?;
+    tmp.persist(&target)
+        .with_context(|| format!("renaming temp file to {}", target.display()))?;
+    evict(cache_dir, Limit::Bytes(MAX_SPLIT_CACHE_SIZE), Some(&target))
+        .context("evicting split debug info")
+        .or_warn();
+    Ok(target)
+}
+
+/// Whether this elf file has an index of its debug info that gdb can use to start faster
+fn has_gdb_index<'data, R: object::ReadRef<'data>>(data: R) -> anyhow::Result<bool> {
+    use object::Object;
+    let file = object::File::parse(data).context("parsing elf file")?;
+    Ok(file.section_by_name(".gdb_index").is_some()
+        || file.section_by_name(".debug_names").is_some())
+}
+
+/// Runs this command, failing if it does not succeed
+fn run(command: &mut Command) -> anyhow::Result<()> {
+    let output = command
+        .output()
+        .with_context(|| format!("running {:?}", command))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{:?} failed with {}: {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(())
+}
+
+/// Creates (or reuses) a copy in `cache_dir` of the debug info file `path`, which has this
+/// buildid, with a `.gdb_index` section, like `gdb-add-index`. Requires `gdb` and `objcopy`.
+///
+/// gdb reads the whole debug info of a file without index on startup, which takes long for
+/// large C++ libraries.
+///
+/// Returns `path` if it already has an index, or the path of the created file. Blocking.
+pub fn with_gdb_index_cached(
+    path: &Path,
+    buildid: &str,
+    cache_dir: &Path,
+) -> anyhow::Result<PathBuf> {
+    let target = cache_dir.join(format!("{}.debug", buildid));
+    if target.is_file() {
+        return Ok(target);
+    }
+    // records that the debug info of this buildid already has an index
+    let marker = cache_dir.join(format!("{}.has-index", buildid));
+    if marker.is_file() {
+        return Ok(path.to_path_buf());
+    }
+    let file = std::fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    // only the section headers are read
+    let data = object::ReadCache::new(file);
+    let indexed =
+        has_gdb_index(&data).with_context(|| format!("reading sections of {}", path.display()))?;
+    drop(data);
+    std::fs::create_dir_all(cache_dir)
+        .with_context(|| format!("creating {}", cache_dir.display()))?;
+    if indexed {
+        std::fs::File::create(&marker).with_context(|| format!("creating {}", marker.display()))?;
+        return Ok(path.to_path_buf());
+    }
+    let tmp = tempfile::TempDir::new_in(cache_dir).context("creating temp dir")?;
+    let copy = tmp.path().join("debuginfo");
+    std::fs::copy(path, &copy).with_context(|| format!("copying {}", path.display()))?;
+    run(Command::new("gdb")
+        .arg("-batch")
+        .arg("-nx")
+        .arg("-iex")
+        .arg("set auto-load no")
+        .arg("-iex")
+        .arg("set debuginfod enabled off")
+        .arg("-ex")
+        .arg(format!("save gdb-index {}", tmp.path().display()))
+        .arg(&copy)
+        .stdin(Stdio::null()))
+    .context("generating gdb index")?;
+    let index = tmp.path().join("debuginfo.gdb-index");
+    if !index.is_file() {
+        // gdb does not create an index for files without debug info
+        anyhow::bail!("gdb did not create an index for {}", path.display());
+    }
+    let mut section = std::ffi::OsString::from(".gdb_index=");
+    section.push(&index);
+    let indexed = tmp.path().join("indexed");
+    run(Command::new("objcopy")
+        .arg("--add-section")
+        .arg(section)
+        .arg("--set-section-flags")
+        .arg(".gdb_index=readonly")
+        .arg(&copy)
+        .arg(&indexed)
+        .stdin(Stdio::null()))
+    .context("adding gdb index")?;
+    std::fs::rename(&indexed, &target)
+        .with_context(|| format!("renaming indexed file to {}", target.display()))?;
+    Ok(target)
+}
+
+/// Calls `f` on each string of the DWARF string sections of this elf file.
+///
+/// Only the headers and these sections are read, and each must be smaller than
+/// [MAX_DEBUG_STRINGS_SIZE] once uncompressed.
+fn for_each_debug_string<'data, R: object::ReadRef<'data>>(
+    data: R,
+    mut f: impl FnMut(&[u8]),
+) -> anyhow::Result<()> {
+    use object::{Object, ObjectSection};
+    let file = object::File::parse(data).context("parsing elf file")?;
+    for name in [".debug_str", ".debug_line_str"] {
+        let section = match file.section_by_name(name) {
+            Some(section) => section,
+            None => continue,
+        };
+        let size = section
+            .compressed_file_range()
+            .with_context(|| format!("reading {}", name))?
+            .uncompressed_size;
+        anyhow::ensure!(
+            size <= MAX_DEBUG_STRINGS_SIZE,
+            "{} is too large: {} bytes",
+            name,
+            size
+        );
+        let content = section
+            .uncompressed_data()
+            .with_context(|| format!("reading {}", name))?;
+        content.split(|&b| b == 0).for_each(&mut f);
+    }
+    Ok(())
+}
+
+/// Returns the file names of the split DWARF objects (`.dwo` files) this debug info refers to.
+///
+/// Skeleton units name their `.dwo` file in an attribute whose value is in the string sections,
+/// so strings ending in `.dwo` are good enough an approximation.
+fn referenced_dwo_names(data: &[u8]) -> anyhow::Result<Vec<String>> {
+    let mut names: Vec<String> = Vec::new();
+    for_each_debug_string(data, |string| {
+        if string.ends_with(b".dwo") {
+            let string = String::from_utf8_lossy(string);
+            let name = string.rsplit('/').next().unwrap_or_default();
+            if !names.iter().any(|n| n == name) {
+                names.push(name.to_owned());
+            }
+        }
+    })?;
+    Ok(names)
+}
+
+/// Extensions of the source files listed by [referenced_source_files]
+const SOURCE_EXTENSIONS: &[&str] = &[
+    "c", "h", "cc", "cpp", "cxx", "c++", "hh", "hpp", "hxx", "h++", "tcc", "inc", "def", "m", "mm",
+    "s", "S", "asm", "rs", "go", "zig", "d", "f", "f90", "f95", "f03", "y", "l",
+];
+
+/// Whether this string from the debug info looks like the name of a source file
+fn is_source_file_name(string: &str) -> bool {
+    let name = string.rsplit('/').next().unwrap_or_default();
+    match name.rsplit_once('.') {
+        Some((stem, extension)) => {
+            !stem.is_empty()
+                && !string.contains(char::is_whitespace)
+                && SOURCE_EXTENSIONS.contains(&extension)
+        }
+        None => false,
+    }
+}
+
+#[test]
+fn test_is_source_file_name() {
+    assert!(is_source_file_name("/build/source/src/main.c"));
+    assert!(is_source_file_name("foo.hpp"));
+    assert!(!is_source_file_name("/build/source/src/main.o"));
+    assert!(!is_source_file_name(".h"));
+    assert!(!is_source_file_name("unsigned int"));
+    assert!(!is_source_file_name("with space.c"));
+}
+
+/// Returns the names of the source files this debug info refers to, as recorded at build time,
+/// in the order they appear.
+///
+/// Like [referenced_dwo_names], this looks at strings in the string sections: the names of
+/// compilation units, and with DWARF 5 the files of line tables. Their directories may be
+/// recorded separately, so some names are relative.
+pub fn referenced_source_files<'data, R: object::ReadRef<'data>>(
+    data: R,
+) -> anyhow::Result<Vec<String>> {
+    let mut names = Vec::new();
+    let mut seen = std::collections::HashSet::new();
+    for_each_debug_string(data, |string| {
+        let string = String::from_utf8_lossy(string).into_owned();
+        if is_source_file_name(&string) && seen.insert(string.clone()) {
+            names.push(string);
+        }
+    })?;
+    Ok(names)
+}
+
+/// Creates (or reuses) a `.dwp` package in `cache_dir` of the split DWARF objects of the debug
+/// info file `path`, which has this buildid. Requires `dwp`.
+///
+/// The `.dwo` files are looked up in `search_dirs`, typically the outputs containing the
+/// debug info and the executable, because the directory recorded at compile time is gone.
+///
+/// The least recently used packages are removed when they take more than
+/// [MAX_DWP_CACHE_SIZE].
+///
+/// Returns `None` if `path` does not use split DWARF. Blocking.
+pub fn dwp_cached(
+    path: &Path,
+    search_dirs: &[&Path],
+    buildid: &str,
+    cache_dir: &Path,
+) -> anyhow::Result<Option<PathBuf>> {
+    let target = cache_dir.join(format!("{}.dwp", buildid));
+    if target.is_file() {
+        touch(&target).or_warn();
+        return Ok(Some(target));
+    }
+    let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    let names = referenced_dwo_names(&data)
+        .with_context(|| format!("looking for split dwarf in {}", path.display()))?;
+    drop(data);
+    if names.is_empty() {
+        return Ok(None);
+    }
+    let mut dwos = Vec::new();
+    for dir in search_dirs {
+        for entry in walkdir::WalkDir::new(dir).into_iter().flatten() {
+            let name = entry.file_name().to_string_lossy();
+            if entry.file_type().is_file() && names.iter().any(|n| *n == name) {
+                dwos.push(entry.into_path());
+            }
+        }
+    }
+    if dwos.is_empty() {
+        anyhow::bail!(
+            "none of the {} .dwo files of {} were found",
+            names.len(),
+            path.display()
+        );
+    }
+    if dwos.len() < names.len() {
+        tracing::info!(
+            "only {} of the {} .dwo files of {} were found",
+            dwos.len(),
+            names.len(),
+            path.display()
+        );
+    }
+    std::fs::create_dir_all(cache_dir)
+        .with_context(|| format!("creating {}", cache_dir.display()))?;
+    let tmp = tempfile::TempDir::new_in(cache_dir).context("creating temp dir")?;
+    let packaged = tmp.path().join("packaged.dwp");
+    run(Command::new("dwp")
+        .arg("-o")
+        .arg(&packaged)
+        .args(&dwos)
+        .stdin(Stdio::null()))
+    .context("packaging .dwo files")?;
+    std::fs::rename(&packaged, &target)
+        .with_context(|| format!("renaming package to {}", target.display()))?;
+    evict(cache_dir, Limit::Bytes(MAX_DWP_CACHE_SIZE), Some(&target))
+        .context("evicting .dwp packages")
+        .or_warn();
+    Ok(Some(target))
+}
+
+/// Returns the content of the section with this name in this elf file, uncompressed, or `None`
+/// if the file has no such section or does not contain its content (`SHT_NOBITS`, like the
+/// debug sections of a stripped executable or the code of a debuginfo file).
+fn section_data<'data, R: object::ReadRef<'data>>(
+    data: R,
+    name: &str,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    use object::{Object, ObjectSection};
+    let file = object::File::parse(data).context("parsing elf file")?;
+    let section = match file.section_by_name(name) {
+        Some(section) if section.file_range().is_some() => section,
+        _ => return Ok(None),
+    };
+    let content = section
+        .uncompressed_data()
+        .with_context(|| format!("reading {}", name))?;
+    Ok(Some(content.into_owned()))
+}
+
+/// Whether this is a section name that can be used as a file name
+pub fn is_valid_section_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '+'))
+}
+
+/// Creates (or reuses) a file in `cache_dir` containing the content of the section with this
+/// name (see [is_valid_section_name]), uncompressed, from the first of these elf files of this
+/// buildid which has it.
+///
+/// The least recently used sections are removed when they take more than
+/// [MAX_SECTIONS_CACHE_SIZE].
+///
+/// Returns `None` if none of them has it. Blocking.
+pub fn section_cached(
+    files: &[PathBuf],
+    name: &str,
+    buildid: &str,
+    cache_dir: &Path,
+) -> anyhow::Result<Option<PathBuf>> {
+    anyhow::ensure!(
+        is_valid_section_name(name),
+        "invalid section name {:?}",
+        name
+    );
+    let dir = cache_dir.join(buildid);
+    let target = dir.join(name);
+    if target.is_file() {
+        touch(&dir).or_warn();
+        return Ok(Some(target));
+    }
+    for path in files {
+        let file =
+            std::fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+        // only the headers and the section are read
+        let data = object::ReadCache::new(file);
+        let content = match section_data(&data, name)
+            .with_context(|| format!("extracting {} from {}", name, path.display()))?
+        {
+            Some(content) => content,
+            None => continue,
+        };
+        std::fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+        let mut tmp = tempfile::NamedTempFile::new_in(&dir).context("creating temp file")?;
+        std::io::Write::write_all(&mut tmp, &content).context("writing section")?;
+        tmp.persist(&target)
+            .with_context(|| format!("renaming temp file to {}", target.display()))?;
+        evict(cache_dir, Limit::Bytes(MAX_SECTIONS_CACHE_SIZE), Some(&dir))
+            .context("evicting extracted sections")
+            .or_warn();
+        return Ok(Some(target));
+    }
+    Ok(None)
+}
+
+#[test]
+fn test_section_cached() {
+    use object::{Object, ObjectSection};
+    // tests are built with debug info
+    let exe_path = std::env::current_exe().unwrap();
+    let exe = std::fs::read(&exe_path).unwrap();
+    let original = object::File::parse(exe.as_slice()).unwrap();
+    let debug_info = original
+        .section_by_name(".debug_info")
+        .unwrap()
+        .uncompressed_data()
+        .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let split = dir.path().join("split.debug");
+    std::fs::write(&split, only_keep_debug(&exe).unwrap()).unwrap();
+    let cache = dir.path().join("sections");
+    // .text has no content in the split debuginfo
+    let files = [split, exe_path];
+    let text = section_cached(&files, ".text", "aabb", &cache)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        std::fs::read(text).unwrap(),
+        original.section_by_name(".text").unwrap().data().unwrap()
+    );
+    let section = section_cached(&files, ".debug_info", "aabb", &cache)
+        .unwrap()
+        .unwrap();
+    assert_eq!(std::fs::read(section).unwrap(), debug_info.as_ref());
+    assert_eq!(
+        section_cached(&files, ".gdb_index", "aabb", &cache).unwrap(),
+        None
+    );
+    assert!(section_cached(&files, "..", "aabb", &cache).is_err());
+}
+
+#[test]
+fn test_only_keep_debug() {
+    use object::{Object, ObjectSection};
+    // tests are built with debug info
+    let exe = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+    let split = only_keep_debug(&exe).unwrap();
+    assert!(split.len() < exe.len());
+    let original = object::File::parse(exe.as_slice()).unwrap();
+    let split = object::File::parse(split.as_slice()).unwrap();
+    let debug_info = split.section_by_name(".debug_info").unwrap();
+    assert_eq!(
+        debug_info.data().unwrap(),
+        original
+            .section_by_name(".debug_info")
+            .unwrap()
+            .data()
+            .unwrap()
+    );
+    let text = split.section_by_name(".text").unwrap();
+    assert_eq!(
+        text.address(),
+        original.section_by_name(".text").unwrap().address()
+    );
+    assert_eq!(text.kind(), object::SectionKind::UninitializedData);
+}
+
+#[test]
+fn test_has_gdb_index() {
+    let exe = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+    // rustc does not generate an index by default
+    assert!(!has_gdb_index(exe.as_slice()).unwrap());
+    assert!(has_gdb_index(&b"not elf"[..]).is_err());
+}
+
+#[test]
+fn test_no_split_dwarf() {
+    // tests are not built with split dwarf
+    let exe = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+    assert!(referenced_dwo_names(&exe).unwrap().is_empty());
+}
+
+#[test]
+fn test_referenced_source_files() {
+    let path = std::env::current_exe().unwrap();
+    let exe = std::fs::read(&path).unwrap();
+    let names = referenced_source_files(exe.as_slice()).unwrap();
+    // reading only the needed sections gives the same result
+    let data = object::ReadCache::new(std::fs::File::open(&path).unwrap());
+    assert_eq!(referenced_source_files(&data).unwrap(), names);
+}
+
+/// Lookup table of [crc32], for the reversed IEEE polynomial
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Continues the CRC-32 `crc` of some data with `data`. Start with 0.
+///
+/// This is the checksum of the debug file recorded in `.gnu_debuglink` sections.
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc = CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[test]
+fn test_crc32() {
+    assert_eq!(crc32(0, b""), 0);
+    assert_eq!(crc32(0, b"123456789"), 0xcbf4_3926);
+    assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xcbf4_3926);
+}
+
+/// Returns the CRC-32 of the content of this file, as in `.gnu_debuglink` sections.
+///
+/// Blocking.
+pub fn file_crc32(path: &Path) -> anyhow::Result<u32> {
+    use std::io::Read;
+    let mut file =
+        std::fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let mut buffer = vec![0; 64 * 1024];
+    let mut crc = 0;
+    loop {
+        let n = file
+            .read(&mut buffer)
+            .with_context(|| format!("reading {}", path.display()))?;
+        if n == 0 {
+            return Ok(crc);
+        }
+        crc = crc32(crc, &buffer[..n]);
+    }
+}
@@ -0,0 +1,451 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Interoperability with the sqlite index of elfutils' `debuginfod`.
+//!
+//! elfutils' `debuginfod` stores which file provides the debuginfo or executable of each
+//! buildid in the `buildids10_*` tables of its database. Writing our entries there lets a site
+//! already running it serve nix binaries as well. Conversely, reading its database lets us serve
+//! the non-nix binaries it indexed, for example those of a distro mirror.
+//!
+//! elfutils forgets files whose mtime changed or which disappeared, so only files present in
+//! the store are exported. Sources are not exported: elfutils records source files one by one,
+//! whereas we only know the source store path of each buildid.
+
+use std::collections::HashMap;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use anyhow::Context;
+use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::{ConnectOptions, Connection, Row, SqliteConnection, SqlitePool};
+
+use crate::db::Cache;
+
+/// The tables of elfutils' `debuginfod` we write to, as created by `debuginfod` 0.189.
+const ELFUTILS_SCHEMA: &str = "
+create table if not exists buildids10_fileparts (
+    id integer primary key not null,
+    name text unique not null
+    );
+create table if not exists buildids10_files (
+    id integer primary key not null,
+    dirname integer not null,
+    basename integer not null,
+    unique (dirname, basename),
+    foreign key (dirname) references buildids10_fileparts(id) on delete cascade,
+    foreign key (basename) references buildids10_fileparts(id) on delete cascade
+    );
+create table if not exists buildids10_buildids (
+    id integer primary key not null,
+    hex text unique not null
+    );
+create table if not exists buildids10_file_mtime_scanned (
+    mtime integer not null,
+    file integer not null,
+    size integer not null,
+    sourcetype text(1) not null check (sourcetype IN ('F', 'R')),
+    foreign key (file) references buildids10_files(id) on update cascade on delete cascade,
+    primary key (file, mtime, sourcetype)
+    ) without rowid;
+create table if not exists buildids10_f_de (
+    buildid integer not null,
+    debuginfo_p integer not null,
+    executable_p integer not null,
+    file integer not null,
+    mtime integer not null,
+    foreign key (file) references buildids10_files(id) on update cascade on delete cascade,
+    foreign key (buildid) references buildids10_buildids(id) on update cascade on delete cascade,
+    primary key (buildid, file, mtime)
+    ) without rowid;
+create table if not exists buildids10_f_s (
+    buildid integer not null,
+    artifactsrc integer not null,
+    file integer not null,
+    mtime integer not null,
+    foreign key (file) references buildids10_files(id) on update cascade on delete cascade,
+    foreign key (artifactsrc) references buildids10_files(id) on update cascade on delete cascade,
+    foreign key (buildid) references buildids10_buildids(id) on update cascade on delete cascade,
+    primary key (buildid, artifactsrc, file, mtime)
+    ) without rowid;
+";
+
+/// Returns the id of this row of a table with an `id` and a unique column, inserting it if
+/// needed.
+async fn intern(
+    db: &mut SqliteConnection,
+    table: &str,
+    column: &str,
+    value: &str,
+) -> anyhow::Result<i64> {
+    sqlx::query(&format!(
+        "insert or ignore into {table} ({column}) values ($1);"
+    ))
+    .bind(value)
+    .execute(&mut *db)
+    .await
+    .with_context(|| format!("inserting into {}", table))?;
+    let row = sqlx::query(&format!("select id from {table} where {column} = $1;"))
+        .bind(value)
+        .fetch_one(&mut *db)
+        .await
+        .with_context(|| format!("reading id from {}", table))?;
+    Ok(row.try_get("id")?)
+}
+
+/// Returns the id of this file in the elfutils db, inserting it if needed
+async fn file_id(db: &mut SqliteConnection, path: &str) -> anyhow::Result<i64> {
+    let (dirname, basename) = path
+        .rsplit_once('/')
+        .ok_or_else(|| anyhow::anyhow!("{} is not an absolute path", path))?;
+    let dirname = intern(db, "buildids10_fileparts", "name", dirname).await?;
+    let basename = intern(db, "buildids10_fileparts", "name", basename).await?;
+    sqlx::query("insert or ignore into buildids10_files (dirname, basename) values ($1, $2);")
+        .bind(dirname)
+        .bind(basename)
+        .execute(&mut *db)
+        .await
+        .context("inserting into buildids10_files")?;
+    let row = sqlx::query("select id from buildids10_files where dirname = $1 and basename = $2;")
+        .bind(dirname)
+        .bind(basename)
+        .fetch_one(&mut *db)
+        .await
+        .context("reading id from buildids10_files")?;
+    Ok(row.try_get("id")?)
+}
+
+/// Records in the elfutils db that this file provides the debuginfo and/or executable of this
+/// buildid.
+///
+/// Returns false if the file does not exist.
+async fn export_file(
+    db: &mut SqliteConnection,
+    buildid: &str,
+    path: &str,
+    debuginfo: bool,
+    executable: bool,
+) -> anyhow::Result<bool> {
+    let metadata = match tokio::fs::metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e).with_context(|| format!("stat({})", path)),
+    };
+    let file = file_id(db, path).await?;
+    let buildid = intern(db, "buildids10_buildids", "hex", buildid).await?;
+    sqlx::query(
+        "insert or ignore into buildids10_file_mtime_scanned (mtime, file, size, sourcetype)
+        values ($1, $2, $3, 'F');",
+    )
+    .bind(metadata.mtime())
+    .bind(file)
+    .bind(metadata.size() as i64)
+    .execute(&mut *db)
+    .await
+    .context("inserting into buildids10_file_mtime_scanned")?;
+    sqlx::query(
+        "insert or replace into buildids10_f_de (buildid, debuginfo_p, executable_p, file, mtime)
+        values ($1, $2, $3, $4, $5);",
+    )
+    .bind(buildid)
+    .bind(debuginfo)
+    .bind(executable)
+    .bind(file)
+    .bind(metadata.mtime())
+    .execute(&mut *db)
+    .await
+    .context("inserting into buildids10_f_de")?;
+    Ok(true)
+}
+
+/// Writes the debuginfo and executables of this cache to the elfutils `debuginfod` db at this
+/// path, creating it if needed.
+///
+/// Returns how many files were exported.
+pub async fn export_to(cache: &Cache, output: &Path) -> anyhow::Result<usize> {
+    let mut db = SqliteConnectOptions::new()
+        .filename(output)
+        .create_if_missing(true)
+        .connect()
+        .await
+        .with_context(|| format!("opening {}", output.display()))?;
+    sqlx::query(ELFUTILS_SCHEMA)
+        .execute(&mut db)
+        .await
+        .context("creating elfutils tables")?;
+    let entries = cache.get_all_entries().await?;
+    let mut exported = 0;
+    let mut transaction = db.begin().await.context("starting transaction")?;
+    for entry in &entries {
+        let mut files: HashMap<&str, (bool, bool)> = HashMap::new();
+        if let Some(debuginfo) = &entry.debuginfo {
+            files.entry(debuginfo).or_default().0 = true;
+        }
+        if let Some(executable) = &entry.executable {
+            files.entry(executable).or_default().1 = true;
+        }
+        for (path, (debuginfo, executable)) in files {
+            if export_file(
+                &mut transaction,
+                &entry.buildid,
+                path,
+                debuginfo,
+                executable,
+            )
+            .await
+            .with_context(|| format!("exporting {}", path))?
+            {
+                exported += 1;
+            }
+        }
+    }
+    transaction.commit().await.context("committing export")?;
+    db.close().await.context("closing elfutils db")?;
+    Ok(exported)
+}
+
+/// Exports the cache to the elfutils `debuginfod` db at this path.
+pub async fn run_export(output: &Path) -> anyhow::Result<ExitCode> {
+    let cache = Cache::open().await.context("opening global cache")?;
+    let exported = export_to(&cache, output).await?;
+    tracing::info!("exported {} files to {}", exported, output.display());
+    Ok(ExitCode::SUCCESS)
+}
+
+/// A read only elfutils `debuginfod` db, used to serve the files it indexed.
+pub struct ElfutilsDb {
+    /// where the db is, for messages
+    path: PathBuf,
+    /// connection to the db
+    pool: SqlitePool,
+}
+
+/// Whether a file provides the debuginfo or the executable of a buildid, as recorded in
+/// `buildids10_f_de`
+#[derive(Debug, Clone, Copy)]
+enum Role {
+    Debuginfo,
+    Executable,
+}
+
+impl ElfutilsDb {
+    /// Opens the elfutils `debuginfod` db at this path, read only
+    pub async fn open(path: &Path) -> anyhow::Result<ElfutilsDb> {
+        let options = SqliteConnectOptions::new().filename(path).read_only(true);
+        let pool = SqlitePool::connect_with(options)
+            .await
+            .with_context(|| format!("opening {}", path.display()))?;
+        Ok(ElfutilsDb {
+            path: path.to_path_buf(),
+            pool,
+        })
+    }
+
+    /// Returns the files registered for this buildid in this role, most recent first
+    async fn get_files(&self, buildid: &str, role: Role) -> anyhow::Result<Vec<PathBuf>> {
+        let column = match role {
+            Role::Debuginfo => "debuginfo_p",
+            Role::Executable => "executable_p",
+        };
+        let rows = sqlx::query(&format!(
+            "select d.name || '/' || n.name as name
+            from buildids10_f_de f_de
+            join buildids10_buildids b on b.id = f_de.buildid
+            join buildids10_files f on f.id = f_de.file
+            join buildids10_fileparts d on d.id = f.dirname
+            join buildids10_fileparts n on n.id = f.basename
+            where b.hex = $1 and f_de.{column} = 1
+            order by f_de.mtime desc;"
+        ))
+        .bind(buildid)
+        .fetch_all(&self.pool)
+        .await
+        .with_context(|| format!("reading {}", self.path.display()))?;
+        rows.iter()
+            .map(|row| Ok(PathBuf::from(row.try_get::<String, _>("name")?)))
+            .collect()
+    }
+
+    /// Returns the on disk file for the source file `request` of this buildid, most recent
+    /// first.
+    ///
+    /// `request` is the path as compiled, relative to `/`.
+    async fn get_sources(&self, buildid: &str, request: &str) -> anyhow::Result<Vec<PathBuf>> {
+        let rows = sqlx::query(
+            "select d.name || '/' || n.name as name
+            from buildids10_f_s f_s
+            join buildids10_buildids b on b.id = f_s.buildid
+            join buildids10_files a on a.id = f_s.artifactsrc
+            join buildids10_fileparts ad on ad.id = a.dirname
+            join buildids10_fileparts an on an.id = a.basename
+            join buildids10_files f on f.id = f_s.file
+            join buildids10_fileparts d on d.id = f.dirname
+            join buildids10_fileparts n on n.id = f.basename
+            where b.hex = $1 and ad.name || '/' || an.name = $2
+            order by f_s.mtime desc;",
+        )
+        .bind(buildid)
+        .bind(format!("/{}", request.trim_start_matches('/')))
+        .fetch_all(&self.pool)
+        .await
+        .with_context(|| format!("reading {}", self.path.display()))?;
+        rows.iter()
+            .map(|row| Ok(PathBuf::from(row.try_get::<String, _>("name")?)))
+            .collect()
+    }
+}
+
+/// Returns the first of these files which still exists
+fn first_existing(files: Vec<PathBuf>) -> Option<PathBuf> {
+    files.into_iter().find(|file| file.is_file())
+}
+
+/// Looks up a file for this buildid in these elfutils dbs, in order.
+async fn find(dbs: &[ElfutilsDb], buildid: &str, role: Role) -> anyhow::Result<Option<PathBuf>> {
+    for db in dbs {
+        if let Some(file) = first_existing(db.get_files(buildid, role).await?) {
+            tracing::debug!("found {:?} of {} in {}", role, buildid, db.path.display());
+            return Ok(Some(file));
+        }
+    }
+    Ok(None)
+}
+
+/// Looks up the debuginfo of this buildid in these elfutils dbs
+pub async fn find_debuginfo(dbs: &[ElfutilsDb], buildid: &str) -> anyhow::Result<Option<PathBuf>> {
+    find(dbs, buildid, Role::Debuginfo).await
+}
+
+/// Looks up the executable of this buildid in these elfutils dbs
+pub async fn find_executable(dbs: &[ElfutilsDb], buildid: &str) -> anyhow::Result<Option<PathBuf>> {
+    find(dbs, buildid, Role::Executable).await
+}
+
+/// Looks up the source file `request` of this buildid in these elfutils dbs
+pub async fn find_source(
+    dbs: &[ElfutilsDb],
+    buildid: &str,
+    request: &str,
+) -> anyhow::Result<Option<PathBuf>> {
+    for db in dbs {
+        if let Some(file) = first_existing(db.get_sources(buildid, request).await?) {
+            return Ok(Some(file));
+        }
+    }
+    Ok(None)
+}
+
+#[tokio::test]
+async fn test_export() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let exe = dir.path().join("foo");
+    std::fs::write(&exe, b"not really elf").unwrap();
+    let cache = Cache::open_in_memory().await.unwrap();
+    cache
+        .register(&[
+            crate::db::Entry {
+                buildid: "aabb".to_owned(),
+                executable: Some(exe.display().to_string()),
+                executable_metadata: None,
+                debuginfo: Some(exe.display().to_string()),
+                debuginfo_metadata: None,
+                arch: None,
+                source: None,
+            },
+            crate::db::Entry {
+                buildid: "ccdd".to_owned(),
+                executable: Some("/nix/store/aaa-gone/bin/gone".to_owned()),
+                executable_metadata: None,
+                debuginfo: None,
+                debuginfo_metadata: None,
+                arch: None,
+                source: None,
+            },
+        ])
+        .await
+        .unwrap();
+    let output = dir.path().join("debuginfod.sqlite");
+    assert_eq!(export_to(&cache, &output).await.unwrap(), 1);
+    // exporting again does not duplicate anything
+    assert_eq!(export_to(&cache, &output).await.unwrap(), 1);
+    let mut db = SqliteConnectOptions::new()
+        .filename(&output)
+        .connect()
+        .await
+        .unwrap();
+    let rows = sqlx::query(
+        "select b.hex, d.name || '/' || n.name as name, f_de.debuginfo_p, f_de.executable_p
+        from buildids10_f_de f_de
+        join buildids10_buildids b on b.id = f_de.buildid
+        join buildids10_files f on f.id = f_de.file
+        join buildids10_fileparts d on d.id = f.dirname
+        join buildids10_fileparts n on n.id = f.basename;",
+    )
+    .fetch_all(&mut db)
+    .await
+    .unwrap();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].get::<String, _>("hex"), "aabb");
+    assert_eq!(rows[0].get::<String, _>("name"), exe.display().to_string());
+    assert!(rows[0].get::<bool, _>("debuginfo_p"));
+    assert!(rows[0].get::<bool, _>("executable_p"));
+}
+
+#[tokio::test]
+async fn test_import() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let exe = dir.path().join("foo");
+    std::fs::write(&exe, b"not really elf").unwrap();
+    let source = dir.path().join("foo.c");
+    std::fs::write(&source, b"int main() {}").unwrap();
+    let cache = Cache::open_in_memory().await.unwrap();
+    cache
+        .register(&[crate::db::Entry {
+            buildid: "aabb".to_owned(),
+            executable: Some(exe.display().to_string()),
+            executable_metadata: None,
+            debuginfo: None,
+            debuginfo_metadata: None,
+            arch: None,
+            source: None,
+        }])
+        .await
+        .unwrap();
+    let output = dir.path().join("debuginfod.sqlite");
+    export_to(&cache, &output).await.unwrap();
+    {
+        let mut db = SqliteConnectOptions::new()
+            .filename(&output)
+            .connect()
+            .await
+            .unwrap();
+        let buildid = intern(&mut db, "buildids10_buildids", "hex", "aabb")
+            .await
+            .unwrap();
+        let artifactsrc = file_id(&mut db, "/build/foo.c").await.unwrap();
+        let file = file_id(&mut db, &source.display().to_string())
+            .await
+            .unwrap();
+        sqlx::query("insert into buildids10_f_s values ($1, $2, $3, 0);")
+            .bind(buildid)
+            .bind(artifactsrc)
+            .bind(file)
+            .execute(&mut db)
+            .await
+            .unwrap();
+    }
+    let dbs = vec![ElfutilsDb::open(&output).await.unwrap()];
+    assert_eq!(find_executable(&dbs, "aabb").await.unwrap(), Some(exe));
+    assert_eq!(find_debuginfo(&dbs, "aabb").await.unwrap(), None);
+    assert_eq!(find_executable(&dbs, "ccdd").await.unwrap(), None);
+    assert_eq!(
+        find_source(&dbs, "aabb", "build/foo.c").await.unwrap(),
+        Some(source)
+    );
+    assert_eq!(
+        find_source(&dbs, "aabb", "build/bar.c").await.unwrap(),
+        None
+    );
+}
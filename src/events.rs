@@ -0,0 +1,124 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Structured events about what the server is doing, streamed on `GET /events`.
+//!
+//! External tools can follow indexation and requests in real time instead of polling
+//! `/admin/stats`. Events are only built when someone listens, and a listener which does not
+//! keep up misses events rather than slowing down the server.
+
+use futures_util::Stream;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// How many events are buffered for a slow listener before it misses some
+const EVENT_BUFFER: usize = 1024;
+
+/// Something that happened in the server
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A store path was indexed
+    PathIndexed {
+        /// the store path
+        storepath: String,
+    },
+    /// A buildid was registered in the cache
+    EntryRegistered {
+        /// the buildid
+        buildid: String,
+        /// whether its executable is known
+        executable: bool,
+        /// whether its debuginfo is known
+        debuginfo: bool,
+        /// whether its source is known
+        source: bool,
+    },
+    /// A file was served
+    Served {
+        /// the buildid the file was requested for
+        buildid: String,
+        /// `executable`, `debuginfo` or `source`
+        kind: &'static str,
+        /// the file
+        path: String,
+    },
+    /// The debuginfo of a buildid was requested but not found
+    Miss {
+        /// the buildid
+        buildid: String,
+    },
+    /// The listener was too slow, and missed this many events
+    Lagged {
+        /// how many events were missed
+        missed: u64,
+    },
+}
+
+impl Event {
+    /// The name of the type of this event, as in its json
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::PathIndexed { .. } => "path_indexed",
+            Event::EntryRegistered { .. } => "entry_registered",
+            Event::Served { .. } => "served",
+            Event::Miss { .. } => "miss",
+            Event::Lagged { .. } => "lagged",
+        }
+    }
+}
+
+/// Where events are sent
+static EVENTS: Lazy<broadcast::Sender<Event>> = Lazy::new(|| broadcast::channel(EVENT_BUFFER).0);
+
+/// Sends the event built by `event` to listeners, if there are any
+pub fn emit(event: impl FnOnce() -> Event) {
+    if EVENTS.receiver_count() > 0 {
+        // fails if all listeners left in the meantime
+        let _ = EVENTS.send(event());
+    }
+}
+
+/// Returns the events emitted from now on, with [Event::Lagged] in place of the events missed
+/// because they were not consumed fast enough
+pub fn subscribe() -> impl Stream<Item = Event> {
+    follow(EVENTS.subscribe())
+}
+
+/// Does the work of [subscribe]
+fn follow(receiver: broadcast::Receiver<Event>) -> impl Stream<Item = Event> {
+    futures_util::stream::unfold(receiver, |mut receiver| async move {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(missed)) => Event::Lagged { missed },
+            Err(broadcast::error::RecvError::Closed) => return None,
+        };
+        Some((event, receiver))
+    })
+}
+
+#[tokio::test]
+async fn test_follow() {
+    use futures_util::StreamExt;
+    let miss = |buildid: &str| Event::Miss {
+        buildid: buildid.to_owned(),
+    };
+    let (sender, receiver) = broadcast::channel(EVENT_BUFFER);
+    let events = follow(receiver);
+    futures_util::pin_mut!(events);
+    sender.send(miss("aa")).unwrap();
+    assert_eq!(events.next().await, Some(miss("aa")));
+    for _ in 0..EVENT_BUFFER + 3 {
+        sender.send(miss("bb")).unwrap();
+    }
+    assert_eq!(events.next().await, Some(Event::Lagged { missed: 3 }));
+    assert_eq!(events.next().await, Some(miss("bb")));
+    drop(sender);
+    assert_eq!(events.skip(EVENT_BUFFER - 1).next().await, None);
+    assert_eq!(
+        serde_json::to_value(miss("cc")).unwrap(),
+        serde_json::json!({"type": "miss", "buildid": "cc"})
+    );
+}
@@ -0,0 +1,528 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Forwarding requests for buildids nix does not know to upstream debuginfod servers.
+//!
+//! Pointing gdb at this server alone then still resolves binaries which were not built by nix,
+//! for example with `https://debuginfod.elfutils.org/` as upstream. Files downloaded from an
+//! upstream are kept on disk for a while, and so are the misses of each upstream, so that gdb
+//! retrying an unknown buildid does not query every upstream again each time. The sources of a
+//! buildid are only forwarded if its debuginfo or executable came from an upstream.
+//!
+//! Downloads run in the background, to a temporary file which is only moved in place once
+//! complete. Clients can read it while it is written, see [Upstreams::downloading].
+//!
+//! Reference: <https://www.mankier.com/8/debuginfod#Webapi>
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Context;
+use futures_util::{Stream, StreamExt};
+use hashlink::LruCache;
+use reqwest::{StatusCode, Url};
+use sha2::Digest;
+use tempfile::TempPath;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{watch, Notify};
+
+use crate::server::redact_url;
+
+/// How many misses are remembered for each upstream
+const MAX_MISSES: usize = 10000;
+
+/// Size of the chunks read from a file being downloaded
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A file of a buildid, as named in the debuginfod api
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind<'a> {
+    /// `/buildid/<buildid>/debuginfo`
+    Debuginfo,
+    /// `/buildid/<buildid>/executable`
+    Executable,
+    /// `/buildid/<buildid>/source/<path>`
+    Source(&'a str),
+}
+
+impl Kind<'_> {
+    /// The path components of the url of this file after `/buildid/<buildid>/`
+    fn segments(&self) -> Vec<&str> {
+        match self {
+            Kind::Debuginfo => vec!["debuginfo"],
+            Kind::Executable => vec!["executable"],
+            Kind::Source(path) => std::iter::once("source")
+                .chain(path.split('/').filter(|segment| !segment.is_empty()))
+                .collect(),
+        }
+    }
+
+    /// Where this file of a buildid is kept, relative to the directory of the buildid
+    fn cache_path(&self) -> PathBuf {
+        match self {
+            Kind::Debuginfo => "debuginfo".into(),
+            Kind::Executable => "executable".into(),
+            // requested paths can be anything, like `../../etc/passwd`
+            Kind::Source(path) => Path::new("source")
+                .join(base16::encode_lower(&sha2::Sha256::digest(path.as_bytes()))),
+        }
+    }
+}
+
+/// The url of this file of this buildid on the upstream server at `base`
+pub fn file_url(base: &Url, buildid: &str, kind: Kind) -> anyhow::Result<Url> {
+    let mut url = base.clone();
+    url.path_segments_mut()
+        .map_err(|()| anyhow::anyhow!("{} cannot be a base url", base))?
+        .pop_if_empty()
+        .push("buildid")
+        .push(buildid)
+        .extend(kind.segments());
+    Ok(url)
+}
+
+#[test]
+fn test_file_url() {
+    let base = Url::parse("https://debuginfod.example.org/").unwrap();
+    assert_eq!(
+        file_url(&base, "abcd", Kind::Debuginfo).unwrap().as_str(),
+        "https://debuginfod.example.org/buildid/abcd/debuginfo"
+    );
+    let base = Url::parse("http://example.org:8002/debuginfod").unwrap();
+    assert_eq!(
+        file_url(&base, "abcd", Kind::Source("/build/src dir/main.c"))
+            .unwrap()
+            .as_str(),
+        "http://example.org:8002/debuginfod/buildid/abcd/source/build/src%20dir/main.c"
+    );
+    assert_ne!(
+        Kind::Source("a.c").cache_path(),
+        Kind::Source("b.c").cache_path()
+    );
+}
+
+/// An upstream debuginfod server
+struct Upstream {
+    /// the root of its api
+    url: Url,
+    /// when the files it did not have were requested, by url
+    misses: Mutex<LruCache<Url, Instant>>,
+}
+
+impl Upstream {
+    /// Whether this upstream did not have the file at this url less than `ttl` ago
+    fn missed(&self, url: &Url, ttl: Duration) -> bool {
+        let mut misses = self.misses.lock().unwrap();
+        match misses.get(url) {
+            Some(when) if when.elapsed() < ttl => true,
+            Some(_) => {
+                misses.remove(url);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Remembers that this upstream does not have the file at this url
+    fn record_miss(&self, url: Url) {
+        self.misses.lock().unwrap().insert(url, Instant::now());
+    }
+}
+
+/// How far a [Download] went
+#[derive(Debug, Clone, Default)]
+struct Progress {
+    /// bytes written to the temporary file
+    written: u64,
+    /// set once the file was moved in place, or the download failed
+    done: Option<Result<(), String>>,
+}
+
+/// A file being downloaded from an upstream
+pub struct Download {
+    /// the temporary file it is written to
+    tmp: PathBuf,
+    /// where it is moved once complete
+    target: PathBuf,
+    /// the size announced by the upstream
+    size: Option<u64>,
+    progress: watch::Receiver<Progress>,
+}
+
+impl Download {
+    /// Where the file will be once complete
+    pub fn target(&self) -> &Path {
+        &self.target
+    }
+
+    /// The size of the file, if the upstream announced it
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// Waits until the file is complete and in place
+    pub async fn finished(&self) -> anyhow::Result<()> {
+        let mut progress = self.progress.clone();
+        loop {
+            let done = progress.borrow_and_update().done.clone();
+            if let Some(done) = done {
+                return done.map_err(anyhow::Error::msg);
+            }
+            if progress.changed().await.is_err() {
+                anyhow::bail!("downloading {} was interrupted", self.target.display());
+            }
+        }
+    }
+
+    /// Opens the file, wherever it currently is
+    async fn open(&self) -> std::io::Result<tokio::fs::File> {
+        match tokio::fs::File::open(&self.tmp).await {
+            // it was complete and moved in place in the meantime
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.finished()
+                    .await
+                    .map_err(|e| std::io::Error::other(format!("{:#}", e)))?;
+                tokio::fs::File::open(&self.target).await
+            }
+            file => file,
+        }
+    }
+
+    /// The content of the file, as it is downloaded. Fails if the download fails.
+    pub fn stream(self: Arc<Self>) -> impl Stream<Item = std::io::Result<Vec<u8>>> {
+        let progress = self.progress.clone();
+        futures_util::stream::try_unfold(
+            (self, progress, None),
+            |(download, mut progress, file)| async move {
+                let mut file = match file {
+                    Some(file) => file,
+                    None => download.open().await?,
+                };
+                loop {
+                    // what is written after this is notified, even if we miss it when reading
+                    drop(progress.borrow_and_update());
+                    let mut chunk = vec![0; CHUNK_SIZE];
+                    let read = file.read(&mut chunk).await?;
+                    if read > 0 {
+                        chunk.truncate(read);
+                        return Ok(Some((chunk, (download, progress, Some(file)))));
+                    }
+                    let done = progress.borrow().done.clone();
+                    match done {
+                        Some(Ok(())) => return Ok(None),
+                        Some(Err(e)) => return Err(std::io::Error::other(e)),
+                        None => {
+                            if progress.changed().await.is_err() {
+                                return Err(std::io::Error::other("download was interrupted"));
+                            }
+                        }
+                    }
+                }
+            },
+        )
+    }
+}
+
+/// The upstream debuginfod servers requests are forwarded to
+pub struct Upstreams {
+    client: reqwest::Client,
+    /// in order of preference
+    upstreams: Vec<Upstream>,
+    /// where downloaded files are kept
+    cache_dir: PathBuf,
+    /// for how long downloaded files are used before being downloaded again
+    ttl: Duration,
+    /// for how long an upstream is not asked again for a file it did not have
+    miss_ttl: Duration,
+    /// the downloads in progress, by target
+    downloads: Arc<Mutex<HashMap<PathBuf, Arc<Download>>>>,
+    /// notified when a download starts
+    started: Notify,
+}
+
+impl Upstreams {
+    /// Forwards requests to these servers, in order, keeping downloaded files in `cache_dir`
+    pub fn new(urls: Vec<Url>, cache_dir: PathBuf, ttl: Duration, miss_ttl: Duration) -> Self {
+        Upstreams {
+            client: crate::tls::client(),
+            upstreams: urls
+                .into_iter()
+                .map(|url| Upstream {
+                    url,
+                    misses: Mutex::new(LruCache::new(MAX_MISSES)),
+                })
+                .collect(),
+            cache_dir,
+            ttl,
+            miss_ttl,
+            downloads: Default::default(),
+            started: Notify::new(),
+        }
+    }
+
+    /// Whether files of this buildid were downloaded from an upstream
+    pub fn knows(&self, buildid: &str) -> bool {
+        self.cache_dir.join(buildid).exists()
+    }
+
+    /// Where this file of this buildid is kept
+    fn target(&self, buildid: &str, kind: Kind) -> PathBuf {
+        self.cache_dir.join(buildid).join(kind.cache_path())
+    }
+
+    /// The download of this file in progress, if any
+    fn in_progress(&self, target: &Path) -> Option<Arc<Download>> {
+        self.downloads.lock().unwrap().get(target).cloned()
+    }
+
+    /// Waits until this file of this buildid is being downloaded from an upstream, so that it
+    /// can be served before the download is complete.
+    pub async fn downloading(&self, buildid: &str, kind: Kind<'_>) -> Arc<Download> {
+        let target = self.target(buildid, kind);
+        loop {
+            let started = self.started.notified();
+            tokio::pin!(started);
+            // downloads starting from now on wake us up
+            started.as_mut().enable();
+            if let Some(download) = self.in_progress(&target) {
+                return download;
+            }
+            started.await;
+        }
+    }
+
+    /// Returns this file of this buildid, downloading it from the first upstream which has it
+    /// if it was not downloaded less than `ttl` ago.
+    ///
+    /// If no upstream can be reached, a copy older than `ttl` is still used.
+    pub async fn fetch(&self, buildid: &str, kind: Kind<'_>) -> anyhow::Result<Option<PathBuf>> {
+        let target = self.target(buildid, kind);
+        if let Some(download) = self.in_progress(&target) {
+            if download.finished().await.is_ok() {
+                return Ok(Some(target));
+            }
+        }
+        let age = tokio::fs::metadata(&target)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .map(|modified| {
+                SystemTime::now()
+                    .duration_since(modified)
+                    .unwrap_or_default()
+            });
+        match age {
+            Some(age) if age < self.ttl => return Ok(Some(target)),
+            _ => (),
+        }
+        let mut error = None;
+        for upstream in &self.upstreams {
+            let url = file_url(&upstream.url, buildid, kind)?;
+            if upstream.missed(&url, self.miss_ttl) {
+                tracing::debug!("{} was missing recently", redact_url(url.as_str()));
+                continue;
+            }
+            let downloaded = match self.download(&url, &target).await {
+                Ok(Some(download)) => download.finished().await.map(|()| true),
+                Ok(None) => Ok(false),
+                Err(e) => Err(e),
+            };
+            match downloaded {
+                Ok(true) => {
+                    tracing::info!("using {} for {}", redact_url(url.as_str()), buildid);
+                    return Ok(Some(target));
+                }
+                Ok(false) => upstream.record_miss(url),
+                Err(e) => {
+                    tracing::info!("{:#}", e);
+                    error = Some(e);
+                }
+            }
+        }
+        match error {
+            Some(_) if age.is_some() => {
+                tracing::info!("upstreams failed, serving stale {}", target.display());
+                Ok(Some(target))
+            }
+            Some(e) => Err(e),
+            None => Ok(None),
+        }
+    }
+
+    /// Starts downloading `url` to `target` in the background. Returns None if the server does
+    /// not have this file.
+    async fn download(&self, url: &Url, target: &Path) -> anyhow::Result<Option<Arc<Download>>> {
+        let shown = redact_url(url.as_str());
+        let response = match request(&self.client, url, &shown).await? {
+            Some(response) => response,
+            None => return Ok(None),
+        };
+        let tmp = temp_file(target).await?;
+        let (sender, progress) = watch::channel(Progress::default());
+        let download = Arc::new(Download {
+            tmp: tmp.to_path_buf(),
+            target: target.to_owned(),
+            size: response.content_length(),
+            progress,
+        });
+        self.downloads
+            .lock()
+            .unwrap()
+            .insert(target.to_owned(), download.clone());
+        self.started.notify_waiters();
+        let downloads = self.downloads.clone();
+        let registered = download.clone();
+        // not cancelled when the client which caused it goes away
+        tokio::spawn(async move {
+            let result = save(response, &shown, tmp, &registered.target, |written| {
+                sender.send_modify(|progress| progress.written = written)
+            })
+            .await;
+            sender.send_modify(|progress| {
+                progress.done = Some(result.map_err(|e| format!("{:#}", e)))
+            });
+            let mut downloads = downloads.lock().unwrap();
+            if downloads
+                .get(&registered.target)
+                .is_some_and(|current| Arc::ptr_eq(current, &registered))
+            {
+                downloads.remove(&registered.target);
+            }
+        });
+        Ok(Some(download))
+    }
+}
+
+/// Requests `url`. Returns None if the server does not have this file.
+///
+/// `shown` is the url as it can be logged.
+async fn request(
+    client: &reqwest::Client,
+    url: &Url,
+    shown: &str,
+) -> anyhow::Result<Option<reqwest::Response>> {
+    tracing::debug!("getting {}", shown);
+    let response = client
+        .get(url.clone())
+        .send()
+        .await
+        .map_err(reqwest::Error::without_url)
+        .with_context(|| format!("fetching {}", shown))?;
+    match response.status() {
+        StatusCode::OK => Ok(Some(response)),
+        StatusCode::NOT_FOUND => Ok(None),
+        status => anyhow::bail!("{} returned status {}", shown, status),
+    }
+}
+
+/// Creates a temporary file next to `target`, to be moved there once complete
+async fn temp_file(target: &Path) -> anyhow::Result<TempPath> {
+    let dir = target.parent().context("download target has no parent")?;
+    tokio::fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("creating {}", dir.display()))?;
+    Ok(tempfile::Builder::new()
+        .prefix(".download")
+        .tempfile_in(dir)
+        .context("creating temporary file")?
+        .into_temp_path())
+}
+
+/// Writes the body of `response` to `tmp`, calling `progress` with how many bytes were written
+/// after each chunk, then moves it to `target`.
+async fn save(
+    response: reqwest::Response,
+    shown: &str,
+    tmp: TempPath,
+    target: &Path,
+    mut progress: impl FnMut(u64),
+) -> anyhow::Result<()> {
+    let mut fd = tokio::fs::File::create(&tmp).await.context("temp file")?;
+    let mut written = 0;
+    let mut body = response.bytes_stream();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk
+            .map_err(reqwest::Error::without_url)
+            .with_context(|| format!("downloading {}", shown))?;
+        // readers of the temporary file must see what was reported
+        fd.write_all(&chunk).await.context("writing to tmp file")?;
+        fd.flush().await.context("writing to disk")?;
+        written += chunk.len() as u64;
+        progress(written);
+    }
+    tmp.persist(target).context("renaming temp file")?;
+    Ok(())
+}
+
+/// Downloads `url` to `target`, replacing it. Returns false if the server does not have this
+/// file.
+pub async fn download(client: &reqwest::Client, url: &Url, target: &Path) -> anyhow::Result<bool> {
+    // urls can contain credentials, which must not end up in logs nor in error responses
+    let shown = redact_url(url.as_str());
+    let response = match request(client, url, &shown).await? {
+        Some(response) => response,
+        None => return Ok(false),
+    };
+    let tmp = temp_file(target).await?;
+    save(response, &shown, tmp, target, |_| ()).await?;
+    Ok(true)
+}
+
+#[test]
+fn test_misses() {
+    let upstreams = Upstreams::new(
+        vec![Url::parse("https://debuginfod.example.org/").unwrap()],
+        PathBuf::from("/nonexistent"),
+        Duration::from_secs(60),
+        Duration::from_secs(60),
+    );
+    let upstream = &upstreams.upstreams[0];
+    let url = file_url(&upstream.url, "abcd", Kind::Debuginfo).unwrap();
+    assert!(!upstream.missed(&url, upstreams.miss_ttl));
+    upstream.record_miss(url.clone());
+    assert!(upstream.missed(&url, upstreams.miss_ttl));
+    assert!(!upstream.missed(&url, Duration::ZERO));
+    // expired misses are forgotten
+    assert!(!upstream.missed(&url, upstreams.miss_ttl));
+    assert!(!upstreams.knows("abcd"));
+}
+
+#[tokio::test]
+async fn test_stream_download() {
+    let dir = tempfile::tempdir().unwrap();
+    let target = dir.path().join("debuginfo");
+    let tmp = temp_file(&target).await.unwrap();
+    let (sender, progress) = watch::channel(Progress::default());
+    let download = Arc::new(Download {
+        tmp: tmp.to_path_buf(),
+        target: target.clone(),
+        size: None,
+        progress,
+    });
+    let mut stream = Box::pin(download.clone().stream());
+    std::fs::write(&tmp, b"abc").unwrap();
+    sender.send_modify(|progress| progress.written = 3);
+    assert_eq!(stream.next().await.unwrap().unwrap(), b"abc");
+    // the end of the file is not the end of the download
+    let writer = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&tmp)
+            .and_then(|mut file| std::io::Write::write_all(&mut file, b"def"))
+            .unwrap();
+        sender.send_modify(|progress| progress.written = 6);
+        tmp.persist(&target).unwrap();
+        sender.send_modify(|progress| progress.done = Some(Ok(())));
+    });
+    assert_eq!(stream.next().await.unwrap().unwrap(), b"def");
+    assert!(stream.next().await.is_none());
+    writer.await.unwrap();
+    download.finished().await.unwrap();
+    // once in place, it is read from there
+    let rest: Vec<_> = download.stream().map(Result::unwrap).collect().await;
+    assert_eq!(rest.concat(), b"abcdef");
+}
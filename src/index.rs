@@ -4,24 +4,58 @@
 
 //! Utilities to scan new store paths for buildids as they appear and populate the cache with them
 
+use crate::backend::is_guix;
 use crate::db::{Cache, Entry, Id};
+use crate::events::{self, Event};
+use crate::inotify::DirWatcher;
 use crate::log::ResultExt;
-use crate::store::{get_store_path, index_store_path};
+use crate::nixdb::{is_unreadable, NixDb};
+use crate::store::{
+    get_closure, get_store_path, index_store_path, is_read_only_store, list_store_paths, realise,
+};
+use crate::telemetry::{IndexerSnapshot, INDEXER};
+use crate::throttle;
 use anyhow::Context;
-use futures_util::{future::join_all, stream::FuturesOrdered, FutureExt, StreamExt};
-use sqlx::sqlite::SqliteConnectOptions;
-use sqlx::{ConnectOptions, Connection, Row};
+use futures_util::{
+    future::{join_all, BoxFuture},
+    stream::FuturesOrdered,
+    FutureExt, StreamExt,
+};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
 use tokio::sync::{mpsc::Sender, Semaphore};
+use tokio::sync::{Mutex, RwLock};
 use tokio::task::JoinHandle;
 
 /// enqueue indexing of this many store paths at the same time
 const BATCH_SIZE: usize = 100;
 /// index at most thie many store paths at the same time
 const N_WORKERS: usize = 8;
+/// write found entries to the cache in transactions of this many entries
+const REGISTRATION_BATCH_SIZE: usize = 500;
+/// write found entries to the cache at least this often, even if there are fewer than
+/// [REGISTRATION_BATCH_SIZE]
+const REGISTRATION_INTERVAL: Duration = Duration::from_secs(1);
+/// default for [StoreWatcher::with_max_queued_paths]
+pub const DEFAULT_MAX_QUEUED_PATHS: usize = 10 * BATCH_SIZE;
+/// if writing entries to the cache fails, keep at most this many in memory to retry later
+const MAX_BUFFERED_ENTRIES: usize = 20 * REGISTRATION_BATCH_SIZE;
+
+/// How new store paths are found, see [StoreWatcher::with_store_access]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum StoreAccess {
+    /// Read a private copy of the nix db, or list store paths through the daemon if the nix
+    /// db cannot be read
+    #[default]
+    Local,
+    /// Always list store paths with `nix path-info --all`, which goes through the nix daemon
+    Daemon,
+}
 
 #[derive(Clone)]
 /// A helper to examine all new store paths in parallel.
@@ -35,8 +69,47 @@ pub struct StoreWatcher {
     semaphore: Arc<Semaphore>,
     /// Locked when self.index_new_paths is running.
     working: Arc<Mutex<()>>,
+    /// where new store paths are listed
+    nixdb: NixDb,
+    /// the root of the chroot store indexed, if it is not the system store
+    store_root: Option<PathBuf>,
+    /// set when the nix db turned out not to be readable, in which case store paths are listed
+    /// with `nix path-info --all` instead
+    listing: Arc<AtomicBool>,
+    /// whether reading the nix db works
+    health: Arc<std::sync::Mutex<IndexerHealth>>,
+    /// how many store paths may be read from the nix db but not indexed yet, which bounds the
+    /// memory used by indexation on stores with millions of paths
+    max_queued_paths: usize,
+    /// whether new store paths are looked for in the nix db
+    read_nix_db: bool,
+    /// read-locked during the first pass over profiles and index roots, which the indexation
+    /// of the rest of the store waits for
+    priority: Arc<RwLock<()>>,
 }
 
+/// Whether a [StoreWatcher] can read the nix db
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct IndexerHealth {
+    /// how many attempts at reading the nix db failed in a row
+    pub consecutive_failures: u32,
+    /// the error of the last attempt, if it failed
+    pub last_error: Option<String>,
+}
+
+impl IndexerHealth {
+    /// Whether the last attempt at reading the nix db failed
+    pub fn is_degraded(&self) -> bool {
+        self.consecutive_failures > 0
+    }
+}
+
+/// How many failures in a row of reading the nix db are logged as errors
+const FAILURES_BEFORE_ALERT: u32 = 5;
+
+/// Maximum delay between attempts at reading the nix db when it fails
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
 impl StoreWatcher {
     /// Creates a [`StoreWatcher`] that populates the specified cache.
     ///
@@ -46,7 +119,115 @@ impl StoreWatcher {
             cache,
             semaphore: Arc::new(Semaphore::new(N_WORKERS)),
             working: Arc::new(Mutex::new(())),
+            nixdb: NixDb::default(),
+            store_root: None,
+            // there is no nix db to read
+            listing: Arc::new(AtomicBool::new(is_read_only_store())),
+            health: Arc::default(),
+            max_queued_paths: DEFAULT_MAX_QUEUED_PATHS,
+            read_nix_db: true,
+            priority: Arc::default(),
+        }
+    }
+
+    /// Indexes the chroot store at this root instead of the system store, reading its own nix
+    /// db and recording its own indexation progress.
+    pub fn for_store_root(mut self, root: &Path) -> Self {
+        self.cache = self.cache.for_store(root);
+        self.nixdb = NixDb::of_store_root(root);
+        self.store_root = Some(root.to_path_buf());
+        self
+    }
+
+    /// Never looks for new store paths in the nix db, so that only store paths indexed
+    /// explicitly are known.
+    pub fn without_nix_db(mut self) -> Self {
+        self.read_nix_db = false;
+        self
+    }
+
+    /// Finds new store paths this way. With [StoreAccess::Daemon], the nix db is never read,
+    /// which suits containers and stores whose nix db is elsewhere or only readable by the
+    /// daemon; but all store paths are listed on each check, which is slower on large stores.
+    pub fn with_store_access(mut self, access: StoreAccess) -> Self {
+        if access == StoreAccess::Daemon {
+            self.listing = Arc::new(AtomicBool::new(true));
+        }
+        self
+    }
+
+    /// Indexes at most this many store paths at the same time, including those waiting for a
+    /// worker. At least one batch is always allowed.
+    pub fn with_max_queued_paths(mut self, max_queued_paths: usize) -> Self {
+        self.max_queued_paths = max_queued_paths;
+        self
+    }
+
+    /// Returns counters describing the throughput of indexation, and how far behind the nix
+    /// db it is
+    pub async fn telemetry(&self) -> IndexerSnapshot {
+        let mut snapshot = INDEXER.snapshot();
+        snapshot.paused = throttle::paused();
+        if !self.read_nix_db || self.listing.load(Ordering::SeqCst) {
+            return snapshot;
+        }
+        let backlog = match self.cache.get_next_id().await {
+            Ok(next_id) => self.nixdb.get_backlog(next_id).await,
+            Err(e) => Err(e),
+        };
+        match backlog {
+            Ok(backlog) => {
+                snapshot.backlog = Some(backlog.count);
+                snapshot.backlog_age_secs = backlog.oldest.map(|registered| {
+                    let now = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|d| d.as_secs() as i64)
+                        .unwrap_or(0);
+                    now.saturating_sub(registered).max(0) as u64
+                });
+                snapshot.watermark = backlog.watermark;
+            }
+            Err(e) => tracing::debug!("cannot compute indexation backlog: {:#}", e),
+        }
+        snapshot
+    }
+
+    /// Whether new store paths are being indexed
+    pub fn is_indexing(&self) -> bool {
+        self.working.try_lock().is_err()
+    }
+
+    /// Returns whether reading the nix db currently works
+    pub fn health(&self) -> IndexerHealth {
+        self.health.lock().unwrap().clone()
+    }
+
+    /// Records the outcome of an attempt at reading the nix db, and passes it through
+    fn record_health<T>(&self, result: anyhow::Result<T>) -> anyhow::Result<T> {
+        let mut health = self.health.lock().unwrap();
+        match &result {
+            Ok(_) => {
+                if health.is_degraded() {
+                    tracing::info!(
+                        "reading the nix db works again after {} failures",
+                        health.consecutive_failures
+                    );
+                }
+                *health = IndexerHealth::default();
+            }
+            Err(e) => {
+                health.consecutive_failures += 1;
+                health.last_error = Some(format!("{:#}", e));
+                if health.consecutive_failures == FAILURES_BEFORE_ALERT {
+                    tracing::error!(
+                        "reading the nix db failed {} times in a row, new store paths are not indexed: {:#}",
+                        health.consecutive_failures,
+                        e
+                    );
+                }
+            }
         }
+        result
     }
 
     /// Index new store paths if there are new store paths.
@@ -54,15 +235,35 @@ impl StoreWatcher {
     /// If there are none, returns Ok(None).
     /// If there are some, starts a future to index them, and returns a JoinHandle to
     /// optionnally wait for completion of the indexation.
+    ///
+    /// If the nix db cannot be read by this user, for example when it is only readable by root
+    /// and nix is used through the daemon, store paths are listed with `nix path-info --all`
+    /// instead, from then on.
     pub async fn maybe_index_new_paths(&self) -> anyhow::Result<Option<JoinHandle<()>>> {
+        if !self.read_nix_db {
+            return Ok(None);
+        }
+        if self.listing.load(Ordering::SeqCst) {
+            return self.maybe_index_listed_paths().await;
+        }
         let start = self
             .cache
             .get_next_id()
             .await
             .context("reading cache next id")?;
-        let (paths, end) = get_new_store_path_batch(start)
-            .await
-            .context("looking for new paths registered in the nix store")?;
+        let batch = self.nixdb.get_new_store_path_batch(start, BATCH_SIZE).await;
+        if let Err(e) = &batch {
+            if is_unreadable(e) {
+                tracing::warn!(
+                    "cannot read the nix db: {:#}, listing store paths with `nix path-info --all` instead",
+                    e
+                );
+                self.listing.store(true, Ordering::SeqCst);
+                return self.maybe_index_listed_paths().await;
+            }
+        }
+        let (paths, end) =
+            self.record_health(batch.context("looking for new paths registered in the nix store"))?;
         if paths.is_empty() {
             Ok(None)
         } else {
@@ -92,7 +293,66 @@ impl StoreWatcher {
         }
     }
 
-    /// Indexes a single store path, and sends found buildids to this sender
+    /// Like [StoreWatcher::maybe_index_new_paths], but for stores whose nix db cannot be read:
+    /// lists all store paths with `nix path-info --all`, which goes through the nix daemon, and
+    /// starts indexing those which were not indexed this way yet.
+    async fn maybe_index_listed_paths(&self) -> anyhow::Result<Option<JoinHandle<()>>> {
+        let root = self.store_root.clone();
+        let paths = self.record_health(
+            tokio::task::spawn_blocking(move || list_store_paths(root.as_deref()))
+                .await
+                .context("listing store paths")
+                .and_then(|result| result),
+        )?;
+        let listed = self
+            .cache
+            .get_listed_paths()
+            .await
+            .context("reading listed store paths")?;
+        if paths.iter().all(|path| listed.contains(path)) {
+            return Ok(None);
+        }
+        let cloned_self = self.clone();
+        Ok(Some(tokio::spawn(async move {
+            let guard = cloned_self.working.lock().await;
+            // another indexation may have completed while we waited for the lock
+            let listed = match cloned_self.cache.get_listed_paths().await {
+                Ok(listed) => listed,
+                Err(e) => {
+                    tracing::warn!(
+                        "reading listed store paths: {:#}, dropping indexation request",
+                        e
+                    );
+                    return;
+                }
+            };
+            let paths: Vec<PathBuf> = paths
+                .into_iter()
+                .filter(|path| !listed.contains(path))
+                .collect();
+            if !paths.is_empty() {
+                tracing::info!("Starting indexation of {} listed store paths", paths.len());
+                for chunk in paths.chunks(BATCH_SIZE) {
+                    match cloned_self.index_and_register(chunk, true).await {
+                        Ok(()) => cloned_self
+                            .cache
+                            .register_listed(chunk)
+                            .await
+                            .context("recording listed store paths")
+                            .or_warn(),
+                        Err(e) => tracing::warn!("cannot index listed store paths: {:#}", e),
+                    }
+                }
+                tracing::info!("Done indexing listed store paths");
+            }
+            drop(guard);
+        })))
+    }
+
+    /// Indexes a single store path, and sends found buildids to this sender.
+    ///
+    /// If this fails, the store path is recorded as failed in the cache, see
+    /// [StoreWatcher::reindex_failed].
     async fn index_store_path(&self, path: PathBuf, sendto: Sender<Entry>) {
         let path2 = path.clone();
         let permit = self
@@ -101,19 +361,90 @@ impl StoreWatcher {
             .acquire_owned()
             .await
             .expect("closed semaphore");
-        tokio::task::spawn_blocking(move || {
-            index_store_path(path.as_path(), sendto, true);
+        let indexed = tokio::task::spawn_blocking(move || {
+            // the nix db watcher indexes the other outputs anyway
+            let indexed = index_store_path(path.as_path(), sendto, true, false);
             drop(permit);
+            indexed
         })
         .await
-        .with_context(|| format!("examining {} failed", path2.as_path().display()))
-        .or_warn();
+        .map_err(anyhow::Error::from)
+        .and_then(|indexed| indexed)
+        .with_context(|| format!("examining {} failed", path2.as_path().display()));
+        if let Err(e) = indexed {
+            // already logged when it happened
+            tracing::debug!("{:#}", e);
+            self.cache
+                .record_failed(&path2, &format!("{:#}", e))
+                .await
+                .or_warn();
+        }
+        events::emit(|| Event::PathIndexed {
+            storepath: path2.to_string_lossy().into_owned(),
+        });
+    }
+
+    /// Starts indexing this batch of store paths, as returned by
+    /// [NixDb::get_new_store_path_batch].
+    ///
+    /// Paths already indexed before a restart are skipped. Found entries are sent to
+    /// `entries_tx`, and the id of each path is sent to `done_tx` once all its entries were sent.
+    ///
+    /// Returns a future that completes with `end` when the whole batch is indexed, and how many
+    /// paths will be indexed.
+    async fn start_batch(
+        &self,
+        paths: Vec<(Id, PathBuf)>,
+        end: Id,
+        entries_tx: &Sender<Entry>,
+        done_tx: &Sender<Id>,
+    ) -> (BoxFuture<'_, Id>, usize) {
+        let start = paths.first().map(|(id, _)| *id).unwrap_or(end);
+        let indexed = match self.cache.get_indexed_ids(start, end).await {
+            Ok(indexed) => indexed,
+            Err(e) => {
+                tracing::warn!("cannot read indexation progress from sqlite db: {:#}", e);
+                HashSet::new()
+            }
+        };
+        if !indexed.is_empty() {
+            tracing::debug!(
+                skipped = indexed.len(),
+                start = start,
+                end = end,
+                "resuming interrupted batch"
+            );
+        }
+        let paths: Vec<_> = paths
+            .into_iter()
+            .filter(|(id, _)| !indexed.contains(id))
+            .collect();
+        let queued = paths.len();
+        INDEXER.queued(queued);
+        let batch: Vec<_> = paths
+            .into_iter()
+            .map(|(id, path)| {
+                let done_tx = done_tx.clone();
+                let entries_tx = entries_tx.clone();
+                throttle::wait()
+                    .then(move |()| self.index_store_path(path, entries_tx))
+                    .then(move |()| async move {
+                        INDEXER.path_indexed();
+                        done_tx
+                            .send(id)
+                            .await
+                            .context("sending indexed id failed")
+                            .or_warn();
+                    })
+            })
+            .collect();
+        (join_all(batch).map(move |_| end).boxed(), queued)
     }
 
     /// Indexes all new store paths in the store by batches.
     ///
-    /// Arguments are the first batch, as returned by [get_new_store_path_batch]
-    async fn index_new_paths(&self, paths: Vec<PathBuf>, id: Id) {
+    /// Arguments are the first batch, as returned by [NixDb::get_new_store_path_batch]
+    async fn index_new_paths(&self, paths: Vec<(Id, PathBuf)>, id: Id) {
         if paths.is_empty() {
             return;
         };
@@ -129,26 +460,43 @@ impl StoreWatcher {
             return;
         }
         tracing::debug!(size = paths.len(), end = id, start = start, "First batch");
-        let (entries_tx, mut entries_rx) = tokio::sync::mpsc::channel(3 * BATCH_SIZE);
-        let batch: Vec<_> = paths
-            .into_iter()
-            .map(|path| self.index_store_path(path, entries_tx.clone()))
-            .collect();
-        let batch_handle = join_all(batch).map(move |_| id).boxed();
+        let (entries_tx, mut entries_rx) = tokio::sync::mpsc::channel(REGISTRATION_BATCH_SIZE);
+        let (done_tx, mut done_rx) = tokio::sync::mpsc::channel(BATCH_SIZE);
+        let (batch_handle, mut queued) = self.start_batch(paths, id, &entries_tx, &done_tx).await;
         let mut max_id = id;
         let mut unfinished_batches = FuturesOrdered::new();
         unfinished_batches.push_back(batch_handle);
-        let mut entry_buffer = Vec::with_capacity(BATCH_SIZE);
+        let mut entry_buffer = Vec::with_capacity(REGISTRATION_BATCH_SIZE);
+        // ids of store paths whose entries are all in entry_buffer or registered
+        let mut done_buffer = Vec::with_capacity(BATCH_SIZE);
         let mut get_new_batches = true;
+        // set when entries were dropped: the next id must then not be advanced past their store
+        // paths, which are indexed again after a restart
+        let mut dropped_entries = false;
+        // so that entries of store paths which take long to index are not kept unregistered
+        let mut flush = tokio::time::interval(REGISTRATION_INTERVAL);
+        flush.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
         loop {
             tokio::select! {
+                _ = flush.tick(), if !entry_buffer.is_empty() || !done_buffer.is_empty() => {
+                    match self.cache.register_indexed(&entry_buffer, &done_buffer).await {
+                        Ok(()) => {
+                            entry_buffer.clear();
+                            done_buffer.clear();
+                        },
+                        Err(e) => tracing::warn!("cannot write entries to sqlite db: {:#}", e),
+                    }
+                }
                 entry = entries_rx.recv() => {
                     match entry {
                         Some(entry) => {
                             entry_buffer.push(entry);
-                            if entry_buffer.len() >= BATCH_SIZE {
-                                match self.cache.register(&entry_buffer).await {
-                                    Ok(()) => entry_buffer.clear(),
+                            if entry_buffer.len() >= REGISTRATION_BATCH_SIZE {
+                                match self.cache.register_indexed(&entry_buffer, &done_buffer).await {
+                                    Ok(()) => {
+                                        entry_buffer.clear();
+                                        done_buffer.clear();
+                                    },
                                     Err(e) => tracing::warn!("cannot write entries to sqlite db: {:#}", e),
                                 }
                             }
@@ -156,13 +504,38 @@ impl StoreWatcher {
                         None => tracing::warn!("entries_rx closed"),
                     }
                 }
+                done = done_rx.recv() => {
+                    match done {
+                        Some(done) => {
+                            // all entries of this path were sent before it was done
+                            while let Ok(entry) = entries_rx.try_recv() {
+                                entry_buffer.push(entry);
+                            }
+                            done_buffer.push(done);
+                            queued = queued.saturating_sub(1);
+                        },
+                        None => tracing::warn!("done_rx closed"),
+                    }
+                }
                 id = unfinished_batches.next() => {
+                    while let Ok(done) = done_rx.try_recv() {
+                        done_buffer.push(done);
+                        queued = queued.saturating_sub(1);
+                    }
+                    while let Ok(entry) = entries_rx.try_recv() {
+                        entry_buffer.push(entry);
+                    }
                     match id {
                         Some(id) => {
-                            match self.cache.register(&entry_buffer).await {
+                            match self.cache.register_indexed(&entry_buffer, &done_buffer).await {
                                 Ok(()) => {
                                     entry_buffer.clear();
-                                    self.cache.set_next_id(id).await.context("writing next id").or_warn();
+                                    done_buffer.clear();
+                                    // store paths of completed batches are recorded as indexed
+                                    // anyway, so they are skipped after a restart
+                                    if !dropped_entries {
+                                        self.cache.set_next_id(id).await.context("writing next id").or_warn();
+                                    }
                                     tracing::debug!("batch {} complete", id);
                                 },
                                 Err(e) => tracing::warn!("cannot write entries to sqlite db: {:#}", e),
@@ -170,38 +543,55 @@ impl StoreWatcher {
                         },
                         None => {
                             // there are no more running batches
-                            self.cache.register(&entry_buffer).await.context("registering entries").or_warn();
+                            self.cache.register_indexed(&entry_buffer, &done_buffer).await.context("registering entries").or_warn();
                             entry_buffer.clear();
+                            done_buffer.clear();
                             tracing::info!("Done indexing new store paths");
                             return;
                         },
                     }
                 }
             }
-            if get_new_batches && self.semaphore.available_permits() > 0 {
+            if entry_buffer.len() > MAX_BUFFERED_ENTRIES {
+                // their store paths are not recorded as indexed, so they will be indexed again
+                // after a restart
+                tracing::warn!(
+                    "cannot write entries to sqlite db, dropping {} of them",
+                    entry_buffer.len()
+                );
+                entry_buffer.clear();
+                done_buffer.clear();
+                dropped_entries = true;
+            }
+            if get_new_batches
+                && self.semaphore.available_permits() > 0
+                && queued + BATCH_SIZE <= self.max_queued_paths.max(BATCH_SIZE)
+            {
                 tracing::debug!("considering starting a new batch of store paths to index");
-                let (paths, id) = match get_new_store_path_batch(max_id).await {
+                let (paths, id) = match self.record_health(
+                    self.nixdb
+                        .get_new_store_path_batch(max_id, BATCH_SIZE)
+                        .await,
+                ) {
                     Ok(x) => x,
                     Err(e) => {
                         tracing::warn!("cannot read nix store db: {:#}", e);
                         continue;
                     }
                 };
-                let batch: Vec<_> = paths
-                    .into_iter()
-                    .map(|path| self.index_store_path(path, entries_tx.clone()))
-                    .collect();
-                if batch.is_empty() {
+                if paths.is_empty() {
                     tracing::debug!("batch is empty");
                     get_new_batches = false;
                 } else {
                     tracing::debug!(
-                        size = batch.len(),
+                        size = paths.len(),
                         start = max_id,
                         end = id,
                         "Indexing new batch of paths"
                     );
-                    let batch_handle = join_all(batch).map(move |_| id).boxed();
+                    let (batch_handle, started) =
+                        self.start_batch(paths, id, &entries_tx, &done_tx).await;
+                    queued += started;
                     max_id = id;
                     unfinished_batches.push_back(batch_handle);
                 }
@@ -209,90 +599,711 @@ impl StoreWatcher {
         }
     }
 
-    /// starts a task that periodically indexes new store paths in the store.
+    /// starts a task that indexes new store paths in the store as soon as the nix db changes,
+    /// or every `interval` if it cannot be watched with inotify.
+    ///
+    /// The first pass of [StoreWatcher::watch_profiles] and [StoreWatcher::index_roots], if
+    /// started before, completes first.
     ///
     /// Returns immediately.
-    pub fn watch_store(&self) {
+    pub fn watch_store(&self, interval: Duration) {
         let self_clone = self.clone();
         tokio::spawn(async move {
+            let watcher = match self_clone.nixdb.watch() {
+                Ok(watcher) => Some(watcher),
+                Err(e) => {
+                    tracing::info!(
+                        "cannot watch the nix db ({:#}), looking for new store paths every {:?}",
+                        e,
+                        interval
+                    );
+                    None
+                }
+            };
+            // profiles and index roots first
+            drop(self_clone.priority.write().await);
             loop {
+                let refreshed = Instant::now();
                 match self_clone.maybe_index_new_paths().await {
-                    Ok(None) => tokio::time::sleep(Duration::from_secs(60)).await,
+                    Ok(None) => {
+                        self_clone
+                            .wait_for_nix_db(watcher.as_ref(), interval, refreshed)
+                            .await
+                    }
                     Ok(Some(handle)) => {
                         handle.await.context("waiting for indexation").or_warn();
-                        tokio::time::sleep(Duration::from_secs(60)).await;
+                        self_clone
+                            .wait_for_nix_db(watcher.as_ref(), interval, refreshed)
+                            .await;
                     }
                     Err(e) => {
-                        tracing::warn!("while watching store for new paths: {:#}", e);
-                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        let failures = self_clone.health().consecutive_failures;
+                        let backoff = backoff(failures);
+                        tracing::warn!(
+                            "while watching store for new paths: {:#}, retrying in {:?}",
+                            e,
+                            backoff
+                        );
+                        tokio::time::sleep(backoff).await;
                     }
                 }
             }
         });
     }
-}
 
-/// Reads the nix db to find new store paths.
-///
-/// New store paths are paths of id greater or equal to `from_id`.
-///
-/// Returns the id you should call this function with for the "next" paths.
-async fn get_new_store_path_batch(from_id: Id) -> anyhow::Result<(Vec<PathBuf>, Id)> {
-    // note: this is a hack. One cannot open a sqlite db read only with WAL if the underlying
-    // file is not writable. So we promise sqlite that the db will not be modified with
-    // immutable=1, but it's false.
-    let mut db = SqliteConnectOptions::new()
-        .filename("/nix/var/nix/db/db.sqlite")
-        .immutable(true)
-        .read_only(true)
-        .connect()
-        .await
-        .context("opening nix db")?;
-    let rows =
-        sqlx::query("select path, id from ValidPaths where id >= $1 order by id asc limit $2")
-            .bind(from_id)
-            .bind(BATCH_SIZE as u32)
-            .fetch_all(&mut db)
-            .await
-            .context("reading nix db")?;
-    let mut paths = Vec::new();
-    let mut max_id = 0;
-    for row in rows {
-        let path: &str = row.try_get("path").context("parsing path in nix db")?;
-        let path = match get_store_path(Path::new(path)) {
-            Some(path) => path,
-            None => anyhow::bail!(
-                "read corrupted stuff from nix db: {}, concurrent write?",
-                path
+    /// Waits until the nix db changes according to `watcher`, or for `interval` at most.
+    ///
+    /// Changes less than [MIN_NIX_DB_REFRESH_INTERVAL] after `refreshed`, when the nix db was
+    /// last read, are coalesced with the ones that follow until then.
+    async fn wait_for_nix_db(
+        &self,
+        watcher: Option<&DirWatcher>,
+        interval: Duration,
+        refreshed: Instant,
+    ) {
+        let watcher = match watcher {
+            Some(watcher) => watcher,
+            None => return tokio::time::sleep(interval).await,
+        };
+        let mut timeout = std::pin::pin!(tokio::time::sleep(interval));
+        loop {
+            tokio::select! {
+                _ = &mut timeout => return,
+                changed = watcher.changed() => match changed {
+                    Ok(names) => {
+                        let db_changed = names
+                            .iter()
+                            .any(|name| name.is_empty() || self.nixdb.is_db_file(name));
+                        if db_changed {
+                            // nix writes a new store path in several steps
+                            let settled = Instant::now() + NIX_DB_SETTLE_TIME;
+                            let allowed = refreshed + MIN_NIX_DB_REFRESH_INTERVAL;
+                            tokio::time::sleep_until(settled.max(allowed).into()).await;
+                            // drop the events queued meanwhile, this refresh covers them
+                            while let Ok(Ok(_)) =
+                                tokio::time::timeout(Duration::ZERO, watcher.changed()).await
+                            {
+                            }
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        tracing::warn!("watching the nix db: {:#}", e);
+                        return timeout.await;
+                    }
+                },
+            }
+        }
+    }
+
+    /// Indexes the closures of all profiles, and the closures of their new generations as
+    /// they appear, including new NixOS systems as they are switched to.
+    ///
+    /// Profiles contain the binaries users actually run, so it is worth indexing them before
+    /// the rest of the store.
+    ///
+    /// Returns immediately.
+    pub fn watch_profiles(&self) {
+        let self_clone = self.clone();
+        let mut first_pass = self.priority.clone().try_read_owned().ok();
+        tokio::spawn(async move {
+            // profile link -> store path it pointed to last time we looked
+            let mut seen: HashMap<PathBuf, PathBuf> = HashMap::new();
+            // store paths already indexed by this task
+            let mut indexed: HashSet<PathBuf> = HashSet::new();
+            loop {
+                self_clone
+                    .index_new_link_targets(profile_links(), &mut seen, &mut indexed, true)
+                    .await;
+                first_pass.take();
+                tokio::time::sleep(PROFILE_POLL_INTERVAL).await;
+            }
+        });
+    }
+
+    /// Indexes the closures of the store paths designated by these roots, in this order, and
+    /// again when they change. A root is either a store path or a symlink to one, a directory
+    /// of such symlinks like `/nix/var/nix/gcroots`, or a file listing store paths one per line
+    /// like the output of `nix-store -qR`.
+    ///
+    /// Which targets of each root were indexed is recorded in the cache, so that after a
+    /// restart only what changed meanwhile is indexed again.
+    ///
+    /// Returns immediately.
+    pub fn index_roots(&self, roots: Vec<PathBuf>) {
+        let self_clone = self.clone();
+        let mut first_pass = self.priority.clone().try_read_owned().ok();
+        tokio::spawn(async move {
+            let mut indexed: HashSet<PathBuf> = HashSet::new();
+            loop {
+                for root in &roots {
+                    self_clone
+                        .index_root(root, &mut indexed)
+                        .await
+                        .with_context(|| format!("indexing index root {}", root.display()))
+                        .or_warn();
+                }
+                first_pass.take();
+                tokio::time::sleep(PROFILE_POLL_INTERVAL).await;
+            }
+        });
+    }
+
+    /// Indexes the closures of the targets of this root which were not recorded as indexed
+    /// yet, like [StoreWatcher::index_closure]
+    async fn index_root(&self, root: &Path, indexed: &mut HashSet<PathBuf>) -> anyhow::Result<()> {
+        let root_clone = root.to_path_buf();
+        let targets = tokio::task::spawn_blocking(move || root_targets(&root_clone)).await??;
+        let recorded = self.cache.get_root_targets(root).await?;
+        if recorded == targets {
+            return Ok(());
+        }
+        // recorded targets which are still targets need not be indexed again
+        let mut done: Vec<PathBuf> = recorded
+            .into_iter()
+            .filter(|target| targets.binary_search(target).is_ok())
+            .collect();
+        tracing::info!(
+            "indexing {} new targets of index root {}",
+            targets.len() - done.len(),
+            root.display()
+        );
+        for target in &targets {
+            if done.contains(target) {
+                continue;
+            }
+            self.index_closure(target, indexed, true)
+                .await
+                .with_context(|| format!("indexing closure of {}", target.display()))?;
+            done.push(target.clone());
+            done.sort();
+            self.cache.set_root_targets(root, &done).await?;
+        }
+        // targets which disappeared
+        self.cache.set_root_targets(root, &targets).await
+    }
+
+    /// Indexes the closures of the `result` symlinks created by `nix build` and `nix-build` in
+    /// these directories as they appear or change, so that what was just built can be debugged
+    /// right away.
+    ///
+    /// Unlike other background indexation, this is never paused by [throttle].
+    ///
+    /// Returns immediately.
+    pub fn watch_projects(&self, dirs: Vec<PathBuf>) {
+        let self_clone = self.clone();
+        tokio::spawn(async move {
+            let mut seen: HashMap<PathBuf, PathBuf> = HashMap::new();
+            let mut indexed: HashSet<PathBuf> = HashSet::new();
+            loop {
+                let links = dirs.iter().flat_map(|dir| result_links(dir)).collect();
+                self_clone
+                    .index_new_link_targets(links, &mut seen, &mut indexed, false)
+                    .await;
+                tokio::time::sleep(PROJECT_POLL_INTERVAL).await;
+            }
+        });
+    }
+
+    /// Indexes the closures of the targets of these symlinks, unless they still point where
+    /// they pointed to according to `seen`, which is then updated.
+    ///
+    /// Store paths in `indexed` are skipped, and newly indexed ones added to it.
+    async fn index_new_link_targets(
+        &self,
+        links: Vec<PathBuf>,
+        seen: &mut HashMap<PathBuf, PathBuf>,
+        indexed: &mut HashSet<PathBuf>,
+        throttled: bool,
+    ) {
+        for link in links {
+            let target = match std::fs::canonicalize(&link) {
+                Ok(target) => target,
+                Err(_) => continue,
+            };
+            if seen.get(&link) == Some(&target) {
+                continue;
+            }
+            tracing::info!(
+                "indexing closure of {} -> {}",
+                link.display(),
+                target.display()
+            );
+            self.index_closure(&target, indexed, throttled)
+                .await
+                .with_context(|| format!("indexing closure of {}", link.display()))
+                .or_warn();
+            seen.insert(link, target);
+        }
+    }
+
+    /// Indexes the closures of `roots`, like `/run/current-system/sw` for the `systemPackages`
+    /// of NixOS, and substitutes the debug outputs of what they contain, so that crashes of
+    /// these programs can later be debugged without network access.
+    ///
+    /// Returns immediately.
+    pub fn warm_up(&self, roots: Vec<PathBuf>) {
+        let self_clone = self.clone();
+        tokio::spawn(async move {
+            for root in roots {
+                self_clone
+                    .warm_up_one(&root)
+                    .await
+                    .with_context(|| format!("warming up debuginfo of {}", root.display()))
+                    .or_warn();
+            }
+        });
+    }
+
+    /// Indexes again the store paths whose indexation failed, as recorded in the cache, for
+    /// example because nix could not be queried for their deriver at the time.
+    ///
+    /// Store paths which are indexed successfully are forgotten as failed; the others are
+    /// recorded again with their new error.
+    ///
+    /// Returns immediately.
+    pub fn reindex_failed(&self) {
+        let self_clone = self.clone();
+        tokio::spawn(async move {
+            self_clone
+                .reindex_failed_paths()
+                .await
+                .context("indexing failed store paths again")
+                .or_warn();
+        });
+    }
+
+    /// Does the work of [StoreWatcher::reindex_failed], and returns when done
+    pub async fn reindex_failed_paths(&self) -> anyhow::Result<()> {
+        let failed = self.cache.get_failed_paths().await?;
+        if failed.is_empty() {
+            return Ok(());
+        }
+        tracing::info!("indexing {} failed store paths again", failed.len());
+        let (present, gone): (Vec<PathBuf>, Vec<PathBuf>) = failed
+            .into_iter()
+            .map(|(path, _)| path)
+            .partition(|path| path.exists());
+        // garbage collected since
+        self.cache
+            .forget_failed(&gone, std::time::SystemTime::now())
+            .await?;
+        for chunk in present.chunks(BATCH_SIZE) {
+            let started = std::time::SystemTime::now();
+            self.index_and_register(chunk, true).await?;
+            self.cache.forget_failed(chunk, started).await?;
+        }
+        let failed = self.cache.get_failed_paths().await?.len();
+        tracing::info!(
+            "done indexing failed store paths again, {} still fail",
+            failed
+        );
+        Ok(())
+    }
+
+    /// Tells how many store paths failed to be indexed, if any, and how to index them again
+    pub async fn report_failed(&self) {
+        match self.cache.get_failed_paths().await {
+            Ok(failed) if !failed.is_empty() => tracing::info!(
+                "indexing {} store paths failed, for example {}: {}. Restart with \
+                --reindex-failed to index them again.",
+                failed.len(),
+                failed[0].0.display(),
+                failed[0].1
             ),
+            Ok(_) => (),
+            Err(e) => tracing::warn!("reading failed store paths: {:#}", e),
+        }
+    }
+
+    /// Does the work of [StoreWatcher::warm_up] for one root
+    async fn warm_up_one(&self, root: &Path) -> anyhow::Result<()> {
+        let entries = self.closure_entries(root, true, true).await?;
+        let mut debug_outputs = BTreeSet::new();
+        for entry in entries {
+            if let Some(output) = entry
+                .debuginfo
+                .as_deref()
+                .and_then(|debuginfo| get_store_path(Path::new(debuginfo)))
+            {
+                debug_outputs.insert(output.to_owned());
+            }
+        }
+        let mut failed = 0;
+        for output in &debug_outputs {
+            if let Err(e) = realise(output).await {
+                tracing::info!("cannot substitute {}: {:#}", output.display(), e);
+                failed += 1;
+            }
+        }
+        tracing::info!(
+            "warm-up of {}: {} of {} debug outputs present",
+            root.display(),
+            debug_outputs.len() - failed,
+            debug_outputs.len()
+        );
+        Ok(())
+    }
+
+    /// Returns the entries registered for the store paths in the closure of `root`, which may be
+    /// a symlink to a store path.
+    ///
+    /// If `index`, the closure is indexed first, waiting for [throttle] if `throttled`.
+    pub async fn closure_entries(
+        &self,
+        root: &Path,
+        index: bool,
+        throttled: bool,
+    ) -> anyhow::Result<Vec<Entry>> {
+        let target = tokio::fs::canonicalize(root)
+            .await
+            .with_context(|| format!("resolving {}", root.display()))?;
+        let closure = tokio::task::spawn_blocking(move || get_closure(&target)).await??;
+        if index {
+            self.index_and_register(&closure, throttled).await?;
+        }
+        let mut entries = Vec::new();
+        for path in &closure {
+            entries.extend(
+                self.cache
+                    .get_entries_of_store_path(&path.to_string_lossy())
+                    .await?,
+            );
+        }
+        Ok(entries)
+    }
+
+    /// Indexes right away the `limit` most recently registered store paths, if they were not
+    /// indexed yet.
+    ///
+    /// This is meant for buildids which are not found in the cache: they are likely to come
+    /// from something that was just built, and that automatic indexation has not reached yet.
+    ///
+    /// Does nothing when store paths are listed with `nix path-info --all`, which does not tell
+    /// which were registered recently.
+    pub async fn index_latest_paths(&self, limit: usize) -> anyhow::Result<()> {
+        if !self.read_nix_db || self.listing.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let next = self
+            .cache
+            .get_next_id()
+            .await
+            .context("reading cache next id")?;
+        let paths = self
+            .nixdb
+            .get_latest_store_paths(next, limit)
+            .await
+            .context("looking for latest paths registered in the nix store")?;
+        let end = match paths.first() {
+            None => return Ok(()),
+            Some((id, _)) => id + 1,
+        };
+        let indexed = self
+            .cache
+            .get_indexed_ids(next, end)
+            .await
+            .context("reading indexation progress")?;
+        let paths: Vec<(Id, PathBuf)> = paths
+            .into_iter()
+            .filter(|(id, _)| !indexed.contains(id))
+            .collect();
+        if paths.is_empty() {
+            return Ok(());
+        }
+        tracing::debug!(size = paths.len(), "indexing latest store paths on demand");
+        let (entries_tx, mut entries_rx) = tokio::sync::mpsc::channel(REGISTRATION_BATCH_SIZE);
+        let indexing = join_all(
+            paths
+                .iter()
+                .map(|(_, path)| self.index_store_path(path.clone(), entries_tx.clone())),
+        );
+        drop(entries_tx);
+        let collect = async {
+            let mut entries = Vec::new();
+            while let Some(entry) = entries_rx.recv().await {
+                entries.push(entry);
+            }
+            entries
+        };
+        let (_, entries) = tokio::join!(indexing, collect);
+        // recording the ids prevents automatic indexation from doing the work again
+        let ids: Vec<Id> = paths.iter().map(|(id, _)| *id).collect();
+        self.cache
+            .register_indexed(&entries, &ids)
+            .await
+            .context("registering entries")
+    }
+
+    /// Indexes all store paths in the closure of this one, except those in `indexed`, waiting
+    /// for [throttle] if `throttled`.
+    ///
+    /// Newly indexed store paths are added to `indexed`.
+    async fn index_closure(
+        &self,
+        storepath: &Path,
+        indexed: &mut HashSet<PathBuf>,
+        throttled: bool,
+    ) -> anyhow::Result<()> {
+        let storepath = storepath.to_path_buf();
+        let closure = tokio::task::spawn_blocking(move || get_closure(&storepath)).await??;
+        let closure: Vec<PathBuf> = closure
+            .into_iter()
+            .filter(|path| !indexed.contains(path))
+            .collect();
+        self.index_and_register(&closure, throttled).await?;
+        indexed.extend(closure);
+        Ok(())
+    }
+
+    /// Indexes these store paths, waiting for [throttle] before each one if `throttled`, and
+    /// registers the entries found in the cache
+    async fn index_and_register(&self, paths: &[PathBuf], throttled: bool) -> anyhow::Result<()> {
+        let (entries_tx, mut entries_rx) = tokio::sync::mpsc::channel(REGISTRATION_BATCH_SIZE);
+        let indexing = join_all(paths.iter().map(|path| {
+            let entries_tx = entries_tx.clone();
+            async move {
+                if throttled {
+                    throttle::wait().await;
+                }
+                self.index_store_path(path.clone(), entries_tx).await
+            }
+        }));
+        drop(entries_tx);
+        let registration = async {
+            let mut entry_buffer = Vec::with_capacity(REGISTRATION_BATCH_SIZE);
+            while let Some(entry) = entries_rx.recv().await {
+                entry_buffer.push(entry);
+                if entry_buffer.len() >= REGISTRATION_BATCH_SIZE {
+                    self.cache
+                        .register(&entry_buffer)
+                        .await
+                        .context("registering entries")?;
+                    entry_buffer.clear();
+                }
+            }
+            self.cache
+                .register(&entry_buffer)
+                .await
+                .context("registering entries")
+        };
+        let (_, registered) = tokio::join!(indexing, registration);
+        registered
+    }
+}
+
+/// How long to wait before reading the nix db again after this many failures in a row
+fn backoff(failures: u32) -> Duration {
+    Duration::from_secs(1)
+        .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .min(MAX_BACKOFF)
+}
+
+#[test]
+fn test_backoff() {
+    assert_eq!(backoff(1), Duration::from_secs(1));
+    assert_eq!(backoff(2), Duration::from_secs(2));
+    assert_eq!(backoff(4), Duration::from_secs(8));
+    assert_eq!(backoff(100), MAX_BACKOFF);
+}
+
+/// How long to wait after the nix db changed before reading it, so that a registration in
+/// progress is complete and several registrations in a row are indexed together
+const NIX_DB_SETTLE_TIME: Duration = Duration::from_millis(200);
+
+/// Minimum time between two reads of the nix db when it changes, because each read takes a
+/// snapshot of the whole db
+const MIN_NIX_DB_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often [StoreWatcher::watch_profiles] checks for new profile generations
+const PROFILE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often [StoreWatcher::watch_projects] checks for new `result` symlinks
+const PROJECT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How deep symlinks to store paths are looked for in directories given as index roots:
+/// `/nix/var/nix/gcroots/auto/<hash>` is at depth 2, `per-user/<user>/<link>` at depth 3
+const INDEX_ROOT_MAX_DEPTH: usize = 3;
+
+/// Lists the store paths designated by this index root, see [StoreWatcher::index_roots],
+/// sorted and without duplicates
+fn root_targets(root: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let resolved =
+        std::fs::canonicalize(root).with_context(|| format!("resolving {}", root.display()))?;
+    let mut targets = Vec::new();
+    if let Some(storepath) = get_store_path(&resolved) {
+        targets.push(storepath.to_path_buf());
+    } else if resolved.is_dir() {
+        let links = walkdir::WalkDir::new(&resolved)
+            .max_depth(INDEX_ROOT_MAX_DEPTH)
+            .into_iter()
+            .flatten()
+            .filter(|entry| entry.path_is_symlink());
+        for link in links {
+            // dangling gc roots are common
+            if let Ok(target) = std::fs::canonicalize(link.path()) {
+                if let Some(storepath) = get_store_path(&target) {
+                    targets.push(storepath.to_path_buf());
+                }
+            }
+        }
+    } else {
+        let contents = std::fs::read_to_string(&resolved)
+            .with_context(|| format!("reading {}", resolved.display()))?;
+        for line in contents.lines() {
+            if let Some(storepath) = get_store_path(Path::new(line.trim())) {
+                targets.push(storepath.to_path_buf());
+            }
+        }
+    }
+    targets.sort();
+    targets.dedup();
+    Ok(targets)
+}
+
+#[test]
+fn test_root_targets() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    let list = dir.join("closure");
+    std::fs::write(
+        &list,
+        "/nix/store/bbb-bar\n/nix/store/aaa-foo/bin/foo\nnot a store path\n\n/nix/store/bbb-bar\n",
+    )
+    .unwrap();
+    assert_eq!(
+        root_targets(&list).unwrap(),
+        vec![
+            PathBuf::from("/nix/store/aaa-foo"),
+            PathBuf::from("/nix/store/bbb-bar")
+        ]
+    );
+    let gcroots = dir.join("gcroots");
+    std::fs::create_dir_all(gcroots.join("auto")).unwrap();
+    std::os::unix::fs::symlink(&list, gcroots.join("auto/link")).unwrap();
+    std::os::unix::fs::symlink("/nix/store/missing", gcroots.join("dangling")).unwrap();
+    assert!(root_targets(&gcroots).unwrap().is_empty());
+    assert!(root_targets(&dir.join("missing")).is_err());
+}
+
+/// Lists the symlinks to build results in this directory: `result`, and `result-<output>` or
+/// `result-<n>` when there are several.
+fn result_links(dir: &Path) -> Vec<PathBuf> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut links: Vec<PathBuf> = entries
+        .flatten()
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            (name == "result" || name.starts_with("result-"))
+                && entry.file_type().is_ok_and(|t| t.is_symlink())
+        })
+        .map(|entry| entry.path())
+        .collect();
+    links.sort();
+    links
+}
+
+#[test]
+fn test_result_links() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir = dir.path();
+    assert!(result_links(dir).is_empty());
+    std::os::unix::fs::symlink("/nix/store/aaa-foo", dir.join("result")).unwrap();
+    std::os::unix::fs::symlink("/nix/store/aaa-foo-dev", dir.join("result-dev")).unwrap();
+    std::os::unix::fs::symlink("/nix/store/aaa-foo", dir.join("results")).unwrap();
+    std::fs::write(dir.join("result-notes"), "not a link").unwrap();
+    assert_eq!(
+        result_links(dir),
+        vec![dir.join("result"), dir.join("result-dev")]
+    );
+    assert!(result_links(&dir.join("missing")).is_empty());
+}
+
+/// Lists the symlinks to profiles whose closure is likely to be debugged: the running NixOS
+/// system, user profiles, per-user NixOS profiles and home-manager generations.
+fn profile_links() -> Vec<PathBuf> {
+    if is_guix() {
+        return guix_profile_links();
+    }
+    let mut links = vec![
+        // changes on nixos-rebuild switch
+        PathBuf::from("/run/current-system"),
+        // changes on nixos-rebuild boot
+        PathBuf::from("/nix/var/nix/profiles/system"),
+        PathBuf::from("/nix/var/nix/profiles/default"),
+    ];
+    if let Some(home) = std::env::var_os("HOME") {
+        let home = PathBuf::from(home);
+        links.push(home.join(".nix-profile"));
+        links.push(home.join(".local/state/nix/profiles/profile"));
+        links.push(home.join(".local/state/nix/profiles/home-manager"));
+    }
+    for dir in ["/etc/profiles/per-user", "/nix/var/nix/profiles/per-user"] {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
         };
-        paths.push(PathBuf::from(path));
-        let id: Id = row.try_get("id").context("parsing id in nix db")?;
-        max_id = id.max(max_id);
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.starts_with("/etc") {
+                // /etc/profiles/per-user/$user is the profile itself
+                links.push(path);
+            } else {
+                // /nix/var/nix/profiles/per-user/$user/ contains profile and home-manager
+                links.push(path.join("profile"));
+                links.push(path.join("home-manager"));
+            }
+        }
     }
-    // As we lie about the database being immutable let's not keep the connection open
-    db.close().await.context("closing nix db").or_warn();
-    if (max_id == 0) ^ paths.is_empty() {
-        anyhow::bail!("read paths with id == 0...");
+    links
+}
+
+/// Like [profile_links], for Guix: the running Guix System, user profiles and the current
+/// guix of each user.
+fn guix_profile_links() -> Vec<PathBuf> {
+    let mut links = vec![
+        PathBuf::from("/run/current-system"),
+        PathBuf::from("/var/guix/profiles/system"),
+    ];
+    if let Some(home) = std::env::var_os("HOME") {
+        let home = PathBuf::from(home);
+        links.push(home.join(".guix-profile"));
+        links.push(home.join(".guix-home"));
+        links.push(home.join(".config/guix/current"));
     }
-    Ok((paths, max_id + 1))
+    if let Ok(entries) = std::fs::read_dir("/var/guix/profiles/per-user") {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            links.push(path.join("guix-profile"));
+            links.push(path.join("guix-home"));
+            links.push(path.join("current-guix"));
+        }
+    }
+    links
 }
 
 /// Index this path, but harder than automatic indexation
 ///
-/// Specifically, this is allowed to download the .drv file from a cache.
+/// Specifically, this is allowed to download the .drv file from a cache. The other outputs of
+/// its deriver which are present are indexed as well, so that an executable is not registered
+/// without its debuginfo.
 pub async fn index_single_store_path_to_cache(
     cache: &Cache,
     path: &Path,
     online: bool,
 ) -> anyhow::Result<()> {
-    let (tx, mut rx) = tokio::sync::mpsc::channel(BATCH_SIZE);
+    let (tx, mut rx) = tokio::sync::mpsc::channel(REGISTRATION_BATCH_SIZE);
     let path = path.to_path_buf();
-    let handle = tokio::task::spawn_blocking(move || index_store_path(&path, tx, online));
+    let path_clone = path.clone();
+    let handle = tokio::task::spawn_blocking(move || index_store_path(&path, tx, online, true));
     let mut batch = Vec::new();
     while let Some(entry) = rx.recv().await {
         batch.push(entry);
-        if batch.len() > BATCH_SIZE {
+        if batch.len() >= REGISTRATION_BATCH_SIZE {
             cache
                 .register(&batch)
                 .await
@@ -304,6 +1315,45 @@ pub async fn index_single_store_path_to_cache(
         .register(&batch)
         .await
         .context("registering new entries")?;
-    handle.await?;
+    // entries were registered anyway
+    if let Err(e) = handle.await? {
+        tracing::warn!("{:#}", e);
+        cache
+            .record_failed(&path_clone, &format!("{:#}", e))
+            .await
+            .or_warn();
+    }
     Ok(())
 }
+
+/// Indexes the closures of these store paths (or symlinks to store paths) into a new cache db
+/// at `output`, written with [Cache::write_canonical], so that a pre-built index can be
+/// shipped with a system image and used with `--seed-db`.
+///
+/// Store paths are indexed one at a time in a fixed order, and `.drv` files are not
+/// downloaded, so that the result only depends on the content of the store.
+pub async fn run_build_index(roots: &[PathBuf], output: &Path) -> anyhow::Result<ExitCode> {
+    let cache = Cache::open_in_memory()
+        .await
+        .context("opening in memory cache")?;
+    let mut closure = BTreeSet::new();
+    for root in roots {
+        let target = tokio::fs::canonicalize(root)
+            .await
+            .with_context(|| format!("resolving {}", root.display()))?;
+        closure.extend(tokio::task::spawn_blocking(move || get_closure(&target)).await??);
+    }
+    for path in &closure {
+        index_single_store_path_to_cache(&cache, path, false)
+            .await
+            .with_context(|| format!("indexing {}", path.display()))?;
+    }
+    let written = cache.write_canonical(output).await?;
+    tracing::info!(
+        "wrote {} buildids from {} store paths to {}",
+        written,
+        closure.len(),
+        output.display()
+    );
+    Ok(ExitCode::SUCCESS)
+}
@@ -0,0 +1,227 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Bookkeeping of the lookups the server is currently doing.
+//!
+//! Identical concurrent lookups are coalesced, and what each lookup is doing can be listed
+//! to understand what a seemingly hung server is up to.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use futures_util::future::{BoxFuture, Shared};
+use futures_util::FutureExt;
+use serde::Serialize;
+
+/// What a lookup is currently doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    /// Looking up the cache, or indexing to populate it
+    Cache,
+    /// Downloading the file from a binary cache
+    Realise,
+    /// The file was found, and is being sent to clients
+    Serving,
+}
+
+/// Progress of a lookup, shared by all the requests waiting for it
+struct Progress {
+    /// when the lookup started
+    started: Instant,
+    /// what it is doing now
+    stage: Mutex<Stage>,
+    /// how many requests are waiting for it or serving its result
+    waiters: Mutex<usize>,
+}
+
+tokio::task_local! {
+    /// Progress of the lookup being polled, if it is run by [InFlight]
+    static PROGRESS: Arc<Progress>;
+}
+
+/// Records what the current lookup is doing.
+///
+/// Does nothing if not called from a lookup run by [InFlight].
+pub fn set_stage(stage: Stage) {
+    let _ = PROGRESS.try_with(|progress| *progress.stage.lock().unwrap() = stage);
+}
+
+/// A lookup being processed
+struct Pending<T> {
+    future: Shared<BoxFuture<'static, T>>,
+    progress: Arc<Progress>,
+}
+
+/// Description of an in-flight lookup, as listed by [InFlight::list]
+#[derive(Debug, Clone, Serialize)]
+pub struct LookupStatus {
+    /// what is being looked up, for example `debuginfo`
+    pub kind: &'static str,
+    /// the buildid, and the requested path for sources
+    pub key: String,
+    /// what the lookup is doing
+    pub stage: Stage,
+    /// how long ago the lookup started, in milliseconds
+    pub elapsed_ms: u64,
+    /// how many requests are waiting for this lookup
+    pub waiters: usize,
+}
+
+/// Lookups currently being processed, so that identical concurrent requests (for example from
+/// gdb and a symbolizer at the same time) are answered by doing the work only once.
+pub struct InFlight<T> {
+    /// what is looked up, for [LookupStatus::kind]
+    kind: &'static str,
+    requests: Arc<Mutex<HashMap<String, Pending<T>>>>,
+}
+
+impl<T> Clone for InFlight<T> {
+    fn clone(&self) -> Self {
+        InFlight {
+            kind: self.kind,
+            requests: self.requests.clone(),
+        }
+    }
+}
+
+/// Keeps a lookup listed as [Stage::Serving] until dropped
+pub struct Serving<T> {
+    in_flight: InFlight<T>,
+    key: String,
+    progress: Arc<Progress>,
+}
+
+impl<T> Drop for Serving<T> {
+    fn drop(&mut self) {
+        let mut requests = self.in_flight.requests.lock().unwrap();
+        let mut waiters = self.progress.waiters.lock().unwrap();
+        *waiters -= 1;
+        if *waiters == 0
+            && requests
+                .get(&self.key)
+                .is_some_and(|current| Arc::ptr_eq(&current.progress, &self.progress))
+        {
+            requests.remove(&self.key);
+        }
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> InFlight<T> {
+    /// Creates an empty set of lookups of this kind
+    pub fn new(kind: &'static str) -> Self {
+        InFlight {
+            kind,
+            requests: Arc::default(),
+        }
+    }
+
+    /// Returns the output of `work`, unless a lookup with the same key is already being
+    /// processed, in which case its output is returned instead and `work` is not run.
+    ///
+    /// The lookup is listed as [Stage::Serving] until the returned guard is dropped.
+    pub async fn coalesce(
+        self,
+        key: String,
+        work: impl Future<Output = T> + Send + 'static,
+    ) -> (T, Serving<T>) {
+        let (future, progress) = {
+            let mut requests = self.requests.lock().unwrap();
+            if requests.contains_key(&key) {
+                tracing::debug!("{} lookup of {} already in flight", self.kind, key);
+            }
+            let pending = requests.entry(key.clone()).or_insert_with(|| {
+                let progress = Arc::new(Progress {
+                    started: Instant::now(),
+                    stage: Mutex::new(Stage::Cache),
+                    waiters: Mutex::new(0),
+                });
+                Pending {
+                    future: PROGRESS.scope(progress.clone(), work).boxed().shared(),
+                    progress,
+                }
+            });
+            *pending.progress.waiters.lock().unwrap() += 1;
+            (pending.future.clone(), pending.progress.clone())
+        };
+        // if we are cancelled while waiting, still unregister
+        let guard = Serving {
+            in_flight: self,
+            key,
+            progress,
+        };
+        let result = future.await;
+        *guard.progress.stage.lock().unwrap() = Stage::Serving;
+        (result, guard)
+    }
+
+    /// Lists the lookups currently being processed
+    pub fn list(&self) -> Vec<LookupStatus> {
+        let requests = self.requests.lock().unwrap();
+        requests
+            .iter()
+            .map(|(key, pending)| LookupStatus {
+                kind: self.kind,
+                key: key.clone(),
+                stage: *pending.progress.stage.lock().unwrap(),
+                elapsed_ms: pending.progress.started.elapsed().as_millis() as u64,
+                waiters: *pending.progress.waiters.lock().unwrap(),
+            })
+            .collect()
+    }
+}
+
+#[tokio::test]
+async fn test_in_flight_coalesces_requests() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    let in_flight: InFlight<usize> = InFlight::new("test");
+    let runs = Arc::new(AtomicUsize::new(0));
+    let work = |runs: Arc<AtomicUsize>| async move {
+        set_stage(Stage::Realise);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        runs.fetch_add(1, Ordering::SeqCst) + 1
+    };
+    let ((a, guard_a), (b, guard_b)) = tokio::join!(
+        in_flight
+            .clone()
+            .coalesce("a".to_owned(), work(runs.clone())),
+        in_flight
+            .clone()
+            .coalesce("a".to_owned(), work(runs.clone())),
+    );
+    assert_eq!((a, b), (1, 1));
+    let list = in_flight.list();
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0].stage, Stage::Serving);
+    assert_eq!(list[0].waiters, 2);
+    drop(guard_a);
+    drop(guard_b);
+    assert!(in_flight.list().is_empty());
+    // once done, the work is done again
+    let (c, _) = in_flight
+        .clone()
+        .coalesce("a".to_owned(), work(runs.clone()))
+        .await;
+    assert_eq!(c, 2);
+}
+
+#[tokio::test]
+async fn test_in_flight_stage() {
+    let in_flight: InFlight<()> = InFlight::new("test");
+    let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+    let lookup = tokio::spawn(in_flight.clone().coalesce("a".to_owned(), async move {
+        set_stage(Stage::Realise);
+        rx.await.unwrap();
+    }));
+    while in_flight.list().first().map(|status| status.stage) != Some(Stage::Realise) {
+        tokio::task::yield_now().await;
+    }
+    assert_eq!(in_flight.list()[0].kind, "test");
+    tx.send(()).unwrap();
+    lookup.await.unwrap();
+    assert!(in_flight.list().is_empty());
+}
@@ -0,0 +1,135 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Waiting for files of a directory to change with inotify, so that new store paths are
+//! indexed as soon as nix registers them in its db, instead of at the next poll.
+
+use std::ffi::{CString, OsString};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+
+use anyhow::Context;
+use tokio::io::unix::AsyncFd;
+
+/// Size of the fixed part of a `struct inotify_event`, before the name
+const EVENT_HEADER_SIZE: usize = std::mem::size_of::<libc::inotify_event>();
+
+/// An inotify instance watching the files of one directory
+pub struct DirWatcher {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl DirWatcher {
+    /// Watches files being created, modified, or moved into this directory
+    pub fn new(dir: &Path) -> anyhow::Result<DirWatcher> {
+        // SAFETY: inotify_init1 has no preconditions
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error()).context("creating inotify instance");
+        }
+        // SAFETY: fd was just created and is owned by nothing else
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let c_dir = CString::new(dir.as_os_str().as_bytes())
+            .with_context(|| format!("{} contains a nul byte", dir.display()))?;
+        let mask = libc::IN_CREATE | libc::IN_MODIFY | libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO;
+        // SAFETY: fd is an inotify instance, and c_dir a valid C string
+        if unsafe { libc::inotify_add_watch(fd.as_raw_fd(), c_dir.as_ptr(), mask) } < 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("watching {} with inotify", dir.display()));
+        }
+        let fd = AsyncFd::new(fd).context("registering inotify instance")?;
+        Ok(DirWatcher { fd })
+    }
+
+    /// Waits until files of the directory change, and returns their names.
+    ///
+    /// An empty name means that events were lost, so anything may have changed.
+    pub async fn changed(&self) -> anyhow::Result<Vec<OsString>> {
+        // enough for several events, even with names of NAME_MAX bytes
+        let mut buffer = vec![0u8; 16 * (EVENT_HEADER_SIZE + 256)];
+        loop {
+            let mut guard = self
+                .fd
+                .readable()
+                .await
+                .context("waiting for inotify events")?;
+            let read = guard.try_io(|fd| {
+                // SAFETY: buffer is valid for writes of its length
+                let n =
+                    unsafe { libc::read(fd.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len()) };
+                if n < 0 {
+                    Err(std::io::Error::last_os_error())
+                } else {
+                    Ok(n as usize)
+                }
+            });
+            match read {
+                Ok(Ok(n)) => return Ok(parse_events(&buffer[..n])),
+                Ok(Err(e)) => return Err(e).context("reading inotify events"),
+                // spurious wakeup
+                Err(_) => continue,
+            }
+        }
+    }
+}
+
+/// Returns the names in these `struct inotify_event`, as read from an inotify instance
+fn parse_events(mut buffer: &[u8]) -> Vec<OsString> {
+    let mut names = Vec::new();
+    while buffer.len() >= EVENT_HEADER_SIZE {
+        let field = |offset: usize| {
+            u32::from_ne_bytes(buffer[offset..offset + 4].try_into().expect("4 bytes"))
+        };
+        let (mask, len) = (field(4), field(12) as usize);
+        let end = (EVENT_HEADER_SIZE + len).min(buffer.len());
+        let name = &buffer[EVENT_HEADER_SIZE..end];
+        // the name is padded with nul bytes
+        let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+        if mask & libc::IN_Q_OVERFLOW != 0 || !name.is_empty() {
+            names.push(OsString::from_vec(name.to_vec()));
+        }
+        buffer = &buffer[end..];
+    }
+    names
+}
+
+#[test]
+fn test_parse_events() {
+    let event = |mask: u32, name: &str| {
+        let mut event = Vec::new();
+        let padded = if name.is_empty() { 0 } else { 16 };
+        event.extend_from_slice(&1i32.to_ne_bytes());
+        event.extend_from_slice(&mask.to_ne_bytes());
+        event.extend_from_slice(&0u32.to_ne_bytes());
+        event.extend_from_slice(&(padded as u32).to_ne_bytes());
+        let mut name = name.as_bytes().to_vec();
+        name.resize(padded, 0);
+        event.extend_from_slice(&name);
+        event
+    };
+    let mut buffer = event(libc::IN_MODIFY, "db.sqlite-wal");
+    buffer.extend(event(libc::IN_CLOSE_WRITE, "db.sqlite"));
+    buffer.extend(event(libc::IN_Q_OVERFLOW, ""));
+    assert_eq!(
+        parse_events(&buffer),
+        vec![
+            OsString::from("db.sqlite-wal"),
+            OsString::from("db.sqlite"),
+            OsString::new()
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_dir_watcher() {
+    let dir = tempfile::tempdir().unwrap();
+    let watcher = DirWatcher::new(dir.path()).unwrap();
+    std::fs::write(dir.path().join("db.sqlite"), "x").unwrap();
+    let names = tokio::time::timeout(std::time::Duration::from_secs(10), watcher.changed())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(names.contains(&OsString::from("db.sqlite")));
+}
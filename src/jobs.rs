@@ -0,0 +1,144 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Bookkeeping of the prefetch jobs started with `POST /prefetch`.
+//!
+//! A job fetches the debuginfo and sources of many buildids in the background, which can take
+//! a long time; clients poll its progress by id. Only the most recent jobs are remembered.
+
+use std::sync::{Arc, Mutex};
+
+use hashlink::LruCache;
+use serde::Serialize;
+
+/// How many jobs are remembered; older ones cannot be polled anymore
+const MAX_JOBS: usize = 64;
+
+/// How many errors are reported for each job; later ones are only counted
+const MAX_JOB_ERRORS: usize = 20;
+
+/// Progress of a prefetch job, as returned by `GET /prefetch/<id>`
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Progress {
+    /// the id of the job
+    pub id: u64,
+    /// how many buildids are to be prefetched, once known
+    pub buildids: usize,
+    /// how many of them were processed
+    pub done: usize,
+    /// how many debuginfo files are now present
+    pub debuginfo: usize,
+    /// how many sources are now present
+    pub sources: usize,
+    /// how many buildids could not be fetched completely
+    pub failed: usize,
+    /// the first errors encountered
+    pub errors: Vec<String>,
+    /// whether the job is over
+    pub finished: bool,
+}
+
+/// A running job, which records its progress, and is finished when this is dropped
+pub struct Job(Arc<Mutex<Progress>>);
+
+impl Job {
+    /// The id to poll the progress of this job with
+    pub fn id(&self) -> u64 {
+        self.0.lock().unwrap().id
+    }
+
+    /// Records the progress of the job
+    pub fn update(&self, update: impl FnOnce(&mut Progress)) {
+        update(&mut self.0.lock().unwrap())
+    }
+
+    /// Records an error, counting the buildid as failed if it was about one
+    pub fn error(&self, buildid: Option<&str>, error: &anyhow::Error) {
+        self.update(|progress| {
+            if buildid.is_some() {
+                progress.failed += 1;
+            }
+            if progress.errors.len() < MAX_JOB_ERRORS {
+                progress.errors.push(match buildid {
+                    Some(buildid) => format!("{}: {:#}", buildid, error),
+                    None => format!("{:#}", error),
+                });
+            }
+        })
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        self.update(|progress| progress.finished = true)
+    }
+}
+
+/// The most recent jobs
+#[derive(Clone)]
+pub struct Jobs {
+    inner: Arc<Mutex<JobsInner>>,
+}
+
+struct JobsInner {
+    /// the id of the next job
+    next_id: u64,
+    /// the progress of the most recent jobs, by id
+    jobs: LruCache<u64, Arc<Mutex<Progress>>>,
+}
+
+impl Default for Jobs {
+    fn default() -> Self {
+        Jobs {
+            inner: Arc::new(Mutex::new(JobsInner {
+                next_id: 1,
+                jobs: LruCache::new(MAX_JOBS),
+            })),
+        }
+    }
+}
+
+impl Jobs {
+    /// Registers a new job
+    pub fn start(&self) -> Job {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        let progress = Arc::new(Mutex::new(Progress {
+            id,
+            ..Default::default()
+        }));
+        inner.jobs.insert(id, progress.clone());
+        Job(progress)
+    }
+
+    /// Returns the progress of this job, if it is remembered
+    pub fn get(&self, id: u64) -> Option<Progress> {
+        let progress = self.inner.lock().unwrap().jobs.get(&id).cloned()?;
+        let progress = progress.lock().unwrap().clone();
+        Some(progress)
+    }
+}
+
+#[test]
+fn test_jobs() {
+    let jobs = Jobs::default();
+    let job = jobs.start();
+    let id = job.id();
+    job.update(|progress| progress.buildids = 2);
+    job.update(|progress| progress.done += 1);
+    job.error(Some("abcd"), &anyhow::anyhow!("no debuginfo"));
+    let progress = jobs.get(id).unwrap();
+    assert!(!progress.finished);
+    assert_eq!(progress.done, 1);
+    assert_eq!(progress.failed, 1);
+    assert_eq!(progress.errors, vec!["abcd: no debuginfo".to_owned()]);
+    drop(job);
+    assert!(jobs.get(id).unwrap().finished);
+    assert_eq!(jobs.get(id + 1), None);
+    for _ in 0..MAX_JOBS {
+        jobs.start();
+    }
+    assert_eq!(jobs.get(id), None);
+}
@@ -0,0 +1,206 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Queries to the nix store through the C API of libstore, with the `libstore` cargo feature.
+//!
+//! This avoids spawning a `nix-store` process for each query, and reports the errors of nix
+//! itself instead of what could be parsed from the output of `nix-store`. The C API can neither
+//! substitute arbitrary store paths nor query derivers and closures, so these still go through
+//! the nix commands.
+
+use std::ffi::{c_int, c_uint, CStr, CString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use anyhow::Context as _;
+use once_cell::sync::OnceCell;
+
+/// Declarations of `nix_api_util.h` and `nix_api_store.h`
+mod ffi {
+    use std::ffi::{c_char, c_int, c_uint};
+
+    #[repr(C)]
+    #[allow(non_camel_case_types)]
+    pub struct nix_c_context {
+        _private: [u8; 0],
+    }
+
+    #[repr(C)]
+    pub struct Store {
+        _private: [u8; 0],
+    }
+
+    #[repr(C)]
+    pub struct StorePath {
+        _private: [u8; 0],
+    }
+
+    /// `NIX_OK`
+    pub const NIX_OK: c_int = 0;
+
+    #[link(name = "nixutilc")]
+    extern "C" {
+        pub fn nix_c_context_create() -> *mut nix_c_context;
+        pub fn nix_c_context_free(context: *mut nix_c_context);
+        pub fn nix_err_code(read_context: *const nix_c_context) -> c_int;
+        pub fn nix_err_msg(
+            context: *mut nix_c_context,
+            read_context: *const nix_c_context,
+            n: *mut c_uint,
+        ) -> *const c_char;
+    }
+
+    #[link(name = "nixstorec")]
+    extern "C" {
+        pub fn nix_libstore_init(context: *mut nix_c_context) -> c_int;
+        pub fn nix_store_open(
+            context: *mut nix_c_context,
+            uri: *const c_char,
+            params: *mut *mut *const c_char,
+        ) -> *mut Store;
+        pub fn nix_store_free(store: *mut Store);
+        pub fn nix_store_parse_path(
+            context: *mut nix_c_context,
+            store: *mut Store,
+            path: *const c_char,
+        ) -> *mut StorePath;
+        pub fn nix_store_path_free(path: *mut StorePath);
+        pub fn nix_store_is_valid_path(
+            context: *mut nix_c_context,
+            store: *mut Store,
+            path: *mut StorePath,
+        ) -> bool;
+    }
+}
+
+/// An error context of the C API, where the last error of a call is stored
+struct Context(*mut ffi::nix_c_context);
+
+impl Context {
+    fn new() -> anyhow::Result<Context> {
+        // SAFETY: no preconditions
+        let context = unsafe { ffi::nix_c_context_create() };
+        anyhow::ensure!(!context.is_null(), "cannot allocate nix error context");
+        Ok(Context(context))
+    }
+
+    /// The message of the last error stored in this context
+    fn message(&self) -> String {
+        let mut n: c_uint = 0;
+        // SAFETY: the message is owned by self.0, and copied before it can be freed
+        unsafe {
+            let message = ffi::nix_err_msg(std::ptr::null_mut(), self.0, &mut n);
+            if message.is_null() {
+                "unknown error".to_owned()
+            } else {
+                CStr::from_ptr(message).to_string_lossy().into_owned()
+            }
+        }
+    }
+
+    /// Turns the return code of a call into a result
+    fn check(&self, code: c_int) -> anyhow::Result<()> {
+        if code == ffi::NIX_OK {
+            Ok(())
+        } else {
+            anyhow::bail!("{}", self.message())
+        }
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        // SAFETY: allocated by nix_c_context_create
+        unsafe { ffi::nix_c_context_free(self.0) }
+    }
+}
+
+/// A parsed store path, freed on drop
+struct StorePath(*mut ffi::StorePath);
+
+impl Drop for StorePath {
+    fn drop(&mut self) {
+        // SAFETY: allocated by nix_store_parse_path
+        unsafe { ffi::nix_store_path_free(self.0) }
+    }
+}
+
+/// An open nix store
+pub struct Store(*mut ffi::Store);
+
+// SAFETY: stores of libstore can be used from several threads at the same time
+unsafe impl Send for Store {}
+unsafe impl Sync for Store {}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        // SAFETY: allocated by nix_store_open
+        unsafe { ffi::nix_store_free(self.0) }
+    }
+}
+
+/// The store opened by [store]
+static STORE: OnceCell<Option<Store>> = OnceCell::new();
+
+/// Returns the store of the nix configuration, opening it on first call.
+///
+/// Returns `None` if it cannot be opened, in which case the nix commands should be used.
+pub fn store() -> Option<&'static Store> {
+    STORE
+        .get_or_init(|| match Store::open() {
+            Ok(store) => Some(store),
+            Err(e) => {
+                tracing::warn!(
+                    "cannot open the nix store with libstore, using nix commands: {e:#}"
+                );
+                None
+            }
+        })
+        .as_ref()
+}
+
+impl Store {
+    /// Opens the store of the nix configuration, like `nix-store` does
+    fn open() -> anyhow::Result<Store> {
+        let context = Context::new()?;
+        // SAFETY: context is valid
+        context
+            .check(unsafe { ffi::nix_libstore_init(context.0) })
+            .context("initializing libstore")?;
+        // an empty uri means the default store
+        let uri = CString::default();
+        // SAFETY: context and uri are valid, and params may be null
+        let store = unsafe { ffi::nix_store_open(context.0, uri.as_ptr(), std::ptr::null_mut()) };
+        if store.is_null() {
+            anyhow::bail!("opening store: {}", context.message());
+        }
+        Ok(Store(store))
+    }
+
+    /// Parses a store path, not a file inside it
+    fn parse_path(&self, context: &Context, path: &Path) -> anyhow::Result<StorePath> {
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .with_context(|| format!("{} contains a nul byte", path.display()))?;
+        // SAFETY: context, store and path are valid
+        let parsed = unsafe { ffi::nix_store_parse_path(context.0, self.0, c_path.as_ptr()) };
+        if parsed.is_null() {
+            anyhow::bail!("parsing {}: {}", path.display(), context.message());
+        }
+        Ok(StorePath(parsed))
+    }
+
+    /// Whether this store path is registered in the store, and thus completely present
+    pub fn is_valid_path(&self, path: &Path) -> anyhow::Result<bool> {
+        let context = Context::new()?;
+        let parsed = self.parse_path(&context, path)?;
+        // SAFETY: context, store and path are valid
+        let valid = unsafe { ffi::nix_store_is_valid_path(context.0, self.0, parsed.0) };
+        // false is also returned on errors
+        // SAFETY: context is valid
+        context
+            .check(unsafe { ffi::nix_err_code(context.0) })
+            .with_context(|| format!("checking validity of {}", path.display()))?;
+        Ok(valid)
+    }
+}
@@ -22,3 +22,78 @@ impl<T: Display> ResultExt for Result<(), T> {
         }
     }
 }
+
+/// How many messages of each kind a [LogSampler] logs before only counting them
+const SAMPLED_MESSAGES: usize = 3;
+
+/// Logs the first few messages of each kind, and how many more there were when dropped.
+///
+/// Walking a store path can produce the same message for thousands of files, for example
+/// when they are unreadable, which would flood the journal.
+#[derive(Debug)]
+pub struct LogSampler {
+    /// what the messages are about, like the store path being walked, for the summary
+    context: String,
+    /// how many messages were seen, by kind
+    counts: std::collections::BTreeMap<&'static str, usize>,
+}
+
+impl LogSampler {
+    /// Creates a sampler for messages about `context`
+    pub fn new(context: impl Display) -> Self {
+        LogSampler {
+            context: context.to_string(),
+            counts: Default::default(),
+        }
+    }
+
+    /// Counts a message of this kind, like `files whose buildid cannot be read`, and returns
+    /// whether it should be logged
+    fn record(&mut self, kind: &'static str) -> bool {
+        let count = self.counts.entry(kind).or_default();
+        *count += 1;
+        *count <= SAMPLED_MESSAGES
+    }
+
+    /// Calls [tracing::info!] with this message, unless enough messages of this kind were
+    /// logged already
+    pub fn info(&mut self, kind: &'static str, message: impl FnOnce() -> String) {
+        if self.record(kind) {
+            tracing::info!("{}", message());
+        }
+    }
+
+    /// Like [LogSampler::info], with [tracing::warn!]
+    pub fn warn(&mut self, kind: &'static str, message: impl FnOnce() -> String) {
+        if self.record(kind) {
+            tracing::warn!("{}", message());
+        }
+    }
+}
+
+impl Drop for LogSampler {
+    fn drop(&mut self) {
+        for (kind, &count) in self.counts.iter() {
+            if count > SAMPLED_MESSAGES {
+                tracing::info!(
+                    "{} {} in {} ({} not logged)",
+                    count,
+                    kind,
+                    self.context,
+                    count - SAMPLED_MESSAGES
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_log_sampler() {
+    let mut sampler = LogSampler::new("/nix/store/aaa-foo");
+    for _ in 0..SAMPLED_MESSAGES {
+        assert!(sampler.record("unreadable files"));
+    }
+    assert!(!sampler.record("unreadable files"));
+    assert!(sampler.record("other files"));
+    assert_eq!(sampler.counts["unreadable files"], SAMPLED_MESSAGES + 1);
+}
@@ -13,9 +13,9 @@
 //!
 //! Finally the [server] module provides server that serves the populated [db::Cache].
 
-use std::{net::SocketAddr, process::ExitCode};
+use std::{ffi::OsString, net::SocketAddr, path::PathBuf, process::ExitCode, time::Duration};
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 use tikv_jemallocator::Jemalloc;
 
@@ -23,37 +23,496 @@ use tikv_jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
+pub mod activation;
+pub mod backend;
+pub mod cachedir;
+pub mod clientcache;
 pub mod config;
+pub mod confine;
+pub mod dashboard;
 pub mod db;
+pub mod elf;
+pub mod elfutils;
+pub mod events;
+pub mod federation;
 pub mod index;
+pub mod inflight;
+pub mod inotify;
+pub mod jobs;
+#[cfg(feature = "libstore")]
+pub mod libstore;
 pub mod log;
+pub mod mirror;
+pub mod nar;
+pub mod nixdb;
+pub mod realiselog;
+pub mod rpc;
+pub mod rr;
 pub mod server;
+pub mod shell;
+pub mod sourcecache;
 pub mod store;
 pub mod substituter;
+pub mod swh;
+pub mod telemetry;
+pub mod throttle;
+pub mod tls;
 
 /// A debuginfod implementation that fetches debuginfo and sources from nix binary caches
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Options {
-    /// Address for the server
+    /// Address for the server, like `127.0.0.1:1949` or `[::1]:1949`. Can be specified several
+    /// times to listen on several addresses. `[::]:1949` listens on all IPv6 addresses, and on
+    /// all IPv4 addresses too unless the `net.ipv6.bindv6only` sysctl is set.
     #[arg(short, long, default_value = "127.0.0.1:1949")]
-    listen_address: SocketAddr,
+    listen_address: Vec<SocketAddr>,
+    /// Also listen on a unix domain socket at this path, for example for a reverse proxy on the
+    /// same machine. A stale socket file left at this path is replaced. Can be specified
+    /// several times.
+    #[arg(long, value_name = "PATH")]
+    listen_socket: Vec<PathBuf>,
+    /// Also listen on this address, requiring credentials (the admin token or basic auth) for
+    /// all requests but health checks, like `--auth-scope all`. Can be specified several times.
+    #[arg(long, value_name = "ADDRESS")]
+    private_listen_address: Vec<SocketAddr>,
+    /// Which requests to `--listen-address` require credentials (the admin token or basic
+    /// auth): `changes` for only the requests changing the configuration, and `admin` for all of
+    /// `/admin/`, both of which require the admin token anyway, and `all` for all requests but
+    /// health checks
+    #[arg(long, value_enum, value_name = "SCOPE", default_value_t = server::AuthScope::Changes)]
+    auth_scope: server::AuthScope,
+    /// Accept HTTP basic authentication with the `user:password` pairs of this file, one per
+    /// line, where credentials are required
+    #[arg(long, value_name = "FILE")]
+    basic_auth_file: Option<PathBuf>,
+    /// Serve HTTPS on TCP listeners with this PEM certificate chain, instead of plain HTTP
+    #[arg(long, value_name = "FILE")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of `--tls-cert`, if it is not in the same file
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
     /// Only index the store and quit without serving
     #[arg(short, long)]
     index_only: bool,
+    /// Forget cache entries which were not served for this many days
+    #[arg(long, value_name = "DAYS")]
+    expire_after: Option<u64>,
+    /// Keep at most this many entries in the cache, forgetting the least recently served ones
+    #[arg(long, value_name = "N")]
+    max_entries: Option<u64>,
+    /// When a binary was not stripped, serve a file containing only its debug info as debuginfo
+    /// instead of the whole binary. Split files are kept in the cache directory, up to 2GiB.
+    #[arg(long)]
+    split_unstripped: bool,
+    /// Add a `.gdb_index` section to served debuginfo which lack one, so that gdb loads it
+    /// faster. Requires `gdb` and `objcopy`; indexed files are kept in the cache directory.
+    #[arg(long)]
+    gdb_index: bool,
+    /// Serve missing debuginfo and executables by extracting only the requested file from the
+    /// nar of their store path in a binary cache, into the cache directory, instead of
+    /// realising the whole store path. Falls back to realising when no binary cache has it.
+    #[arg(long)]
+    fetch_nar_members: bool,
+    /// Answer 404 right away during this many seconds to requests for a debuginfo or executable
+    /// which could not be found, instead of looking for it again, unless new store paths are
+    /// indexed in the meantime
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    negative_cache_ttl: u64,
+    /// Store the cache db at this path instead of in the cache directory. Several processes
+    /// can use the same cache db, for example an indexer started with `--index-only` as root
+    /// and servers started with `--no-index` as unprivileged users.
+    #[arg(long, value_name = "PATH")]
+    cache_db: Option<PathBuf>,
+    /// When the cache db does not exist yet, start from a copy of this one, written by the
+    /// `build-index` subcommand, for example when it is shipped in a system image
+    #[arg(long, value_name = "PATH")]
+    seed_db: Option<PathBuf>,
+    /// Store downloaded, split and extracted files in this directory. Defaults to
+    /// `$CACHE_DIRECTORY/nixseparatedebuginfod` when started by systemd with `CacheDirectory=`,
+    /// and to the cache directory of the user otherwise.
+    #[arg(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+    /// Store the cache db in this directory, unless `--cache-db` is given. Defaults to
+    /// `$STATE_DIRECTORY` when started by systemd with `StateDirectory=`, and to the cache
+    /// directory otherwise.
+    #[arg(long, value_name = "DIR")]
+    state_dir: Option<PathBuf>,
+    /// Write temporary files, including those of subprocesses, in this directory. Defaults to
+    /// `$RUNTIME_DIRECTORY` when started by systemd with `RuntimeDirectory=`, and to `$TMPDIR`
+    /// otherwise.
+    #[arg(long, value_name = "DIR")]
+    runtime_dir: Option<PathBuf>,
+    /// Never index the store, and only serve what another process using the same cache db
+    /// indexed
+    #[arg(long)]
+    no_index: bool,
+    /// Hardened profile for servers exposed beyond a trusted network: implies `--no-index`,
+    /// never indexes store paths on demand, and disables the admin api, webhooks and
+    /// prefetching, so that requests can only read what was already indexed (missing store
+    /// paths are still realised).
+    #[arg(long)]
+    hardened: bool,
+    /// Look for new store paths to index every this many seconds. With 0, new store paths are
+    /// only indexed when a request is received. When the nix db can be watched with inotify,
+    /// new store paths are indexed as soon as they are registered anyway.
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    poll_interval: u64,
+    /// Forget the files of store paths which were garbage collected and cannot be substituted
+    /// again every this many seconds. With 0, only the `prune` subcommand does.
+    #[arg(long, value_name = "SECONDS", default_value_t = 3600)]
+    sweep_interval: u64,
+    /// Index at most this many store paths at the same time. Lower values bound the memory
+    /// used to index large stores, at the expense of indexing speed.
+    #[arg(long, value_name = "N", default_value_t = index::DEFAULT_MAX_QUEUED_PATHS)]
+    max_queued_paths: usize,
+    /// Pause background indexation while the load average over the last minute exceeds this,
+    /// like the number of cores. Indexation for requests is never paused.
+    #[arg(long, value_name = "LOAD")]
+    max_load: Option<f64>,
+    /// Pause background indexation while tasks are stalled waiting for memory more than this
+    /// percentage of the time, according to `/proc/pressure/memory`
+    #[arg(long, value_name = "PERCENT")]
+    max_memory_pressure: Option<f64>,
+    /// Pause background indexation while running on battery
+    #[arg(long)]
+    pause_on_battery: bool,
+    /// Copy missing debug outputs and sources from this binary cache with `nix copy --from`
+    /// before trying the substituters of the nix configuration. The cache must be signed by a
+    /// key in `trusted-public-keys`.
+    #[arg(long, value_name = "URL")]
+    realise_from: Option<String>,
+    /// Kill nix (or guix) subprocesses which run for longer than this, like a substitution
+    /// stuck on an unresponsive binary cache. Clients are answered that the file is temporarily
+    /// unavailable.
+    #[arg(long, value_name = "SECONDS", default_value_t = 600)]
+    subprocess_timeout: u64,
+    /// How many store paths may be substituted at the same time; other requests wait for
+    /// their turn
+    #[arg(long, value_name = "N", default_value_t = 4)]
+    max_realisations: usize,
+    /// When the source of a derivation can be neither substituted nor refetched, look for it
+    /// in the Software Heritage archive
+    #[arg(long)]
+    software_heritage: bool,
+    /// Count the requests of each client, and report them in `/admin/stats`
+    #[arg(long)]
+    client_stats: bool,
+    /// With `--client-stats`, identify clients by the value of this header (for example
+    /// `X-Forwarded-For` behind a reverse proxy) instead of their address
+    #[arg(long, value_name = "HEADER")]
+    client_header: Option<http::HeaderName>,
+    /// Send outbound HTTP(S) requests, to binary caches and Software Heritage, through this
+    /// proxy, like `http://proxy.example.com:3128`. By default, the `http_proxy`, `https_proxy`
+    /// and `no_proxy` environment variables are honored.
+    #[arg(long, value_name = "URL")]
+    proxy: Option<reqwest::Url>,
+    /// Comma separated list of hosts to contact without the proxy, like `no_proxy`
+    #[arg(long, value_name = "HOSTS")]
+    no_proxy: Option<String>,
+    /// Trust the certificate authorities of this PEM bundle, in addition to the system ones, for
+    /// outbound HTTPS requests, for example behind a proxy intercepting TLS. Defaults to
+    /// `$NIX_SSL_CERT_FILE`.
+    #[arg(long, value_name = "FILE")]
+    ca_file: Option<PathBuf>,
+    /// Present this PEM client certificate to binary caches requiring one
+    #[arg(long, value_name = "FILE")]
+    tls_client_cert: Option<PathBuf>,
+    /// The PKCS#8 PEM private key of `--tls-client-cert`, if it is not in the same file
+    #[arg(long, value_name = "FILE", requires = "tls_client_cert")]
+    tls_client_key: Option<PathBuf>,
+    /// How many times a request to a binary cache failing with a network error or a 5xx status
+    /// is retried
+    #[arg(long, value_name = "N", default_value_t = 2)]
+    fetch_retries: u32,
+    /// Delay before retrying a request to a binary cache, doubled for each following retry
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 200)]
+    fetch_backoff: u64,
+    /// When a binary cache did not answer a debuginfo lookup after this delay, also query the
+    /// next one, and use the first answer. 0 queries all of them at once.
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 500)]
+    hedge_delay: u64,
+    /// Stop contacting a binary cache for a while after this many failed fetches in a row. 0
+    /// disables this.
+    #[arg(long, value_name = "N", default_value_t = 5)]
+    breaker_threshold: u32,
+    /// How long a binary cache which keeps failing is not contacted
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    breaker_cooldown: u64,
+    /// When indexing, skip files smaller than this many bytes
+    #[arg(long, value_name = "BYTES", default_value_t = 0)]
+    index_min_size: u64,
+    /// When indexing, skip files with this extension, like `png` or `html`. Can be specified
+    /// several times.
+    #[arg(long, value_name = "EXTENSION")]
+    index_skip_extension: Vec<String>,
+    /// When indexing, only look for executables in this top-level directory of store paths,
+    /// like `bin` or `lib`. Can be specified several times. Debug outputs are always indexed.
+    #[arg(long, value_name = "DIR")]
+    index_only_dir: Vec<String>,
+    /// When indexing, what to do with symlinks pointing into the store: ignore them, follow
+    /// them as if the files were in the indexed store path (for symlinks to other outputs of
+    /// the same derivation), or register the store paths they point to as well.
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = store::SymlinkPolicy::Ignore)]
+    index_symlinks: store::SymlinkPolicy,
+    /// Also consider outputs with this name, like `debugsym`, as separate debug outputs, in
+    /// addition to `debug`. Can be specified several times.
+    #[arg(long, value_name = "NAME")]
+    debug_output_name: Vec<String>,
+    /// Look up requested source files starting with `FROM` as if they started with `TO`, to
+    /// undo a `-fdebug-prefix-map=TO=FROM` at build time, like `/usr/src/foo=/build/foo-1.2`.
+    /// Can be specified several times.
+    #[arg(long, value_name = "FROM=TO")]
+    source_prefix_map: Vec<String>,
+    /// Index and serve the store of this package manager: nix, or GNU Guix (`/gnu/store`)
+    #[arg(long, value_enum, value_name = "BACKEND", default_value_t = backend::Backend::Nix)]
+    backend: backend::Backend,
+    /// How to find new store paths: `local` reads a copy of the nix db, and falls back to
+    /// `daemon` if it cannot be read; `daemon` lists store paths with `nix path-info --all`
+    /// through the nix daemon, for containers or when the nix db is not readable
+    #[arg(long, value_enum, value_name = "ACCESS", default_value_t = index::StoreAccess::Local)]
+    store: index::StoreAccess,
+    /// Also index and serve the chroot store at this root, whose store paths are in
+    /// `ROOT/nix/store`, like `~/.local/share/nix/root` for rootless nix. Can be specified
+    /// several times.
+    #[arg(long, value_name = "ROOT")]
+    extra_store: Vec<PathBuf>,
+    /// Also serve the files indexed in this sqlite db of elfutils' debuginfod, for example for
+    /// binaries not built by nix. Can be specified several times.
+    #[arg(long, value_name = "PATH")]
+    elfutils_db: Vec<PathBuf>,
+    /// Forward requests for buildids nix does not know to these debuginfod servers, like
+    /// `https://debuginfod.elfutils.org/`, and serve what they return. Takes a space separated
+    /// list like `DEBUGINFOD_URLS`, and can be specified several times. This server itself must
+    /// not be listed.
+    #[arg(long, value_name = "URLS")]
+    upstream: Vec<String>,
+    /// Download files from upstream servers again once they were kept this many seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = 7 * 24 * 60 * 60)]
+    upstream_cache_ttl: u64,
+    /// Do not ask an upstream server again for a file it did not have during this many seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = 600)]
+    upstream_miss_ttl: u64,
+    /// Only serve files of this architecture, like `x86_64` or `aarch64`, by default. Clients
+    /// can request another one with `?arch=ARCH`. Files whose architecture is unknown are
+    /// always served.
+    #[arg(long, value_name = "ARCH")]
+    arch: Option<String>,
+    /// Run nix (or guix) subprocesses as this user instead of the user of the server, like
+    /// `nobody` or `990:985` (uid:gid). Requires the server to run as root; realising then
+    /// goes through the daemon, so this user must be allowed to use nix.
+    #[arg(long, value_name = "USER")]
+    subprocess_user: Option<String>,
+    /// After indexing the closure of this store path or profile, substitute the debug outputs
+    /// of the binaries it contains, so that they can be debugged without network access, like
+    /// `/run/current-system/sw` for the `systemPackages` of NixOS. Can be specified several
+    /// times.
+    #[arg(long, value_name = "PATH")]
+    warm_up: Vec<PathBuf>,
+    /// Index the closures of the `result` symlinks created by `nix build` and `nix-build` in
+    /// this directory, like a project checkout, within seconds of their creation, so that what
+    /// was just built can be debugged right away. Can be specified several times.
+    #[arg(long, value_name = "DIR")]
+    watch_project: Vec<PathBuf>,
+    /// Index the closures of the store paths designated by this root before the rest of the
+    /// store, and again when it changes: a store path or a symlink to one, a directory of such
+    /// symlinks like `/nix/var/nix/gcroots`, or a file listing store paths one per line like
+    /// the output of `nix-store -qR`. Can be specified several times, in order of priority.
+    #[arg(long, value_name = "PATH")]
+    index_root: Vec<PathBuf>,
+    /// Index again the store paths whose indexation failed, for example because nix could not
+    /// tell their deriver at the time, so that their debuginfo and source may be found
+    #[arg(long)]
+    reindex_failed: bool,
+    /// Enable `/admin/`, like adding and removing binary caches at runtime through
+    /// `/admin/substituters`, to requests carrying the content of this file as
+    /// `Authorization: Bearer <token>`
+    #[arg(long, value_name = "PATH")]
+    admin_token_file: Option<PathBuf>,
+    /// When started by systemd socket activation, exit after this many seconds without
+    /// requests nor indexing. systemd starts the server again on the next connection.
+    #[arg(long, value_name = "SECONDS")]
+    idle_timeout: Option<u64>,
+    /// Do something else than serving
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Alternative modes of operation
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Index the store and serve the debuginfod protocol; this is what happens without a
+    /// subcommand
+    Serve,
+    /// Index these store paths, or files in them, and quit. Without store paths, index all the
+    /// store paths not indexed yet and quit, like `--index-only`.
+    Index {
+        /// The store paths, or symlinks to them like `./result`
+        paths: Vec<PathBuf>,
+        /// Index the whole store again, for example after upgrading to a version which indexes
+        /// more files. Registered entries are kept meanwhile.
+        #[arg(long, conflicts_with = "paths")]
+        from_scratch: bool,
+    },
+    /// Print the executable, debuginfo and source known for this buildid, without fetching
+    /// anything unlike `resolve`. The exit code is 1 if the buildid is unknown.
+    Query {
+        /// The buildid, as printed by `file` or `readelf -n`
+        buildid: String,
+    },
+    /// Download the debuginfo and executables of the binaries used in an rr trace and quit, so
+    /// that `rr replay` does not wait for them
+    PrefetchRr {
+        /// The directory of the trace, as created by `rr record`
+        trace_dir: PathBuf,
+        /// Also write them to the cache of the debuginfod client of elfutils
+        /// (`~/.cache/debuginfod_client`), where gdb finds them even when this server is not
+        /// running
+        #[arg(long)]
+        client_cache: bool,
+    },
+    /// Register the debug outputs of a channel and quit, without downloading them, so that
+    /// this server can serve binaries no local machine has built
+    Mirror {
+        /// A channel url, for example <https://channels.nixos.org/nixos-unstable>, or the url
+        /// or path of its `store-paths.xz`
+        store_paths: String,
+        /// The binary cache to read the listings of debug outputs from
+        #[arg(long, default_value = "https://cache.nixos.org")]
+        substituter: String,
+    },
+    /// Write the debuginfo and executables known to this server to the sqlite db of elfutils'
+    /// debuginfod and quit, so that it can serve them too
+    ExportElfutils {
+        /// The db of elfutils' debuginfod, as passed to its `-d` option (`~/.debuginfod.sqlite`
+        /// by default). It is created if it does not exist.
+        output: PathBuf,
+    },
+    /// Index the closures of store paths into a new cache db and quit. The db only depends on
+    /// what was indexed, not on the machine nor the order of indexation, so that it can be built
+    /// reproducibly, shipped in a system image and used with `--seed-db`.
+    BuildIndex {
+        /// Where to write the db. It is replaced if it exists.
+        #[arg(long, value_name = "PATH")]
+        output: PathBuf,
+        /// The store paths, or symlinks to them like `./result`
+        #[arg(required = true)]
+        roots: Vec<PathBuf>,
+    },
+    /// Print the buildids whose debuginfo was requested but never found, with how many times
+    /// and their package and executable when known, so that their packages can be built with
+    /// `separateDebugInfo`
+    Misses {
+        /// Print at most this many buildids
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+    },
+    /// Forget the files of store paths which were garbage collected and cannot be substituted
+    /// again, prune the cache according to `--expire-after` and `--max-entries`, and quit
+    Prune {
+        /// Also forget store paths which could be substituted again, like the debug outputs
+        /// registered by `mirror`, without querying binary caches
+        #[arg(long)]
+        local_only: bool,
+    },
+    /// Print the buildids whose files were served the most, with how many files and their
+    /// package and debuginfo when known, to choose which debug outputs to keep as gc roots
+    Hits {
+        /// Print at most this many buildids
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+    },
+    /// Print the buildids registered from a store path, and the files with a buildid in it which
+    /// are not indexed (in which case the exit code is 1), to check that a freshly built
+    /// package was indexed
+    Buildids {
+        /// The store path, or a symlink to it like `./result`
+        storepath: PathBuf,
+    },
+    /// Print the buildid of a binary and where its executable, debuginfo and source are,
+    /// fetching them like the server would, to check the setup or in scripts. The exit code is
+    /// 1 if its debuginfo cannot be found.
+    Resolve {
+        /// The binary, like `/run/current-system/sw/bin/ls`
+        binary: PathBuf,
+        /// Also write its executable and debuginfo to the cache of the debuginfod client of
+        /// elfutils (`~/.cache/debuginfod_client`), where gdb finds them even when this server
+        /// is not running
+        #[arg(long)]
+        client_cache: bool,
+    },
+    /// Index only these store paths without reading the nix db, serve them on a random port of
+    /// localhost and print the corresponding `DEBUGINFOD_URLS` line, for CI jobs. Exits when
+    /// the parent process exits.
+    Ephemeral {
+        /// The store paths to serve, or files in them
+        #[arg(required = true)]
+        storepaths: Vec<PathBuf>,
+    },
+    /// Fetch a file of this buildid from the servers of `DEBUGINFOD_URLS` into the cache of the
+    /// debuginfod client of elfutils (`~/.cache/debuginfod_client`) if it is not there yet,
+    /// print where it is and quit, like `debuginfod-find`. The exit code is 1 if no server has
+    /// it. Nix is not needed.
+    Fetch {
+        /// The buildid, as printed by `file` or `readelf -n`
+        buildid: String,
+        /// Which file of the buildid to fetch
+        #[arg(value_enum, default_value_t = clientcache::FetchKind::Debuginfo)]
+        kind: clientcache::FetchKind,
+        /// For `source`, the path of the source file as recorded in the debuginfo
+        #[arg(required_if_eq("kind", "source"))]
+        path: Option<String>,
+    },
+    /// Run a command (by default, `$SHELL`) with `DEBUGINFOD_URLS` set to use this server,
+    /// starting it on `--listen-address` for the duration of the command if it is not running
+    Shell {
+        /// The command and its arguments
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<OsString>,
+    },
+}
+
+impl Options {
+    /// Whether this process never indexes the store by itself
+    fn no_index(&self) -> bool {
+        self.no_index || self.hardened
+    }
+
+    /// How often the store should be polled for new store paths, if at all
+    fn poll_interval(&self) -> Option<Duration> {
+        match self.poll_interval {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        }
+    }
+
+    /// How often garbage collected store paths should be forgotten, if at all
+    fn sweep_interval(&self) -> Option<Duration> {
+        match self.sweep_interval {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        }
+    }
+
+    /// How the cache should be pruned according to these options
+    fn prune_policy(&self) -> db::PrunePolicy {
+        db::PrunePolicy {
+            max_age: self
+                .expire_after
+                .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+            max_entries: self.max_entries,
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<ExitCode> {
-    match (
+    if let (None, Some(dir)) = (
         std::env::var_os("XDG_CACHE_HOME"),
         std::env::var_os("CACHE_DIRECTORY"),
     ) {
-        (None, Some(dir)) => {
-            // this env var is set by systemd
-            std::env::set_var("XDG_CACHE_HOME", dir);
-        }
-        _ => (),
+        // this env var is set by systemd
+        std::env::set_var("XDG_CACHE_HOME", dir);
     }
     if std::env::var_os("RUST_LOG").is_none() {
         std::env::set_var(
@@ -61,8 +520,105 @@ async fn main() -> anyhow::Result<ExitCode> {
             "nixseparatedebuginfo=info,tower_http=debug,sqlx=warn,warn",
         )
     }
-    let args = Options::parse();
+    let mut args = Options::parse();
+    // also applies to nix, when it downloads without the daemon
+    if let Some(proxy) = &args.proxy {
+        std::env::set_var("http_proxy", proxy.as_str());
+        std::env::set_var("https_proxy", proxy.as_str());
+    }
+    if let Some(no_proxy) = &args.no_proxy {
+        std::env::set_var("no_proxy", no_proxy);
+    }
+    match &args.ca_file {
+        Some(ca_file) => std::env::set_var("NIX_SSL_CERT_FILE", ca_file),
+        None => args.ca_file = std::env::var_os("NIX_SSL_CERT_FILE").map(PathBuf::from),
+    }
     tracing_subscriber::fmt::init();
+    tls::set_tls_config(&tls::TlsConfig {
+        ca_file: args.ca_file.clone(),
+        client_cert: args.tls_client_cert.clone(),
+        client_key: args.tls_client_key.clone(),
+    })?;
+    if let Some(url) = &args.realise_from {
+        store::set_realise_from(url.clone());
+    }
+    store::set_subprocess_timeout(Duration::from_secs(args.subprocess_timeout));
+    store::set_max_realisations(args.max_realisations);
+    // these env vars are set by systemd
+    if args.state_dir.is_none() {
+        args.state_dir = std::env::var_os("STATE_DIRECTORY").map(PathBuf::from);
+    }
+    if args.runtime_dir.is_none() {
+        args.runtime_dir = std::env::var_os("RUNTIME_DIRECTORY").map(PathBuf::from);
+    }
+    if let Some(dir) = &args.cache_dir {
+        db::set_cache_directory(dir.clone());
+    }
+    if let Some(dir) = &args.state_dir {
+        db::set_state_directory(dir.clone());
+    }
+    if let Some(dir) = &args.runtime_dir {
+        std::env::set_var("TMPDIR", dir);
+    }
+    if let Some(path) = &args.cache_db {
+        db::set_cache_db(path.clone());
+    }
+    if let Some(path) = &args.seed_db {
+        db::set_seed_db(path.clone());
+    }
+    db::set_negative_cache_ttl(Duration::from_secs(args.negative_cache_ttl));
+    substituter::set_retry_policy(substituter::RetryPolicy {
+        retries: args.fetch_retries,
+        backoff: Duration::from_millis(args.fetch_backoff),
+        failure_threshold: args.breaker_threshold,
+        cooldown: Duration::from_secs(args.breaker_cooldown),
+    });
+    store::set_debug_output_names(args.debug_output_name.clone());
+    backend::set_backend(args.backend);
+    if args.backend == backend::Backend::Nix && !store::has_system_store() {
+        for root in store::user_store_roots(|name| std::env::var_os(name)) {
+            if !args.extra_store.contains(&root) {
+                tracing::info!(
+                    "there is no {}, using the rootless nix store in {}",
+                    store::NIX_STORE,
+                    root.display()
+                );
+                args.extra_store.push(root);
+            }
+        }
+    }
+    store::set_extra_stores(args.extra_store.clone());
+    if let Some(user) = &args.subprocess_user {
+        store::set_subprocess_user(user)?;
+    }
+    store::set_source_prefix_map(&args.source_prefix_map)?;
+    throttle::set_thresholds(throttle::Thresholds {
+        max_load: args.max_load,
+        max_memory_pressure: args.max_memory_pressure,
+        pause_on_battery: args.pause_on_battery,
+    });
+    store::set_walk_filter(store::WalkFilter {
+        min_size: args.index_min_size,
+        skip_extensions: args.index_skip_extension.clone(),
+        only_dirs: args.index_only_dir.clone(),
+        symlinks: args.index_symlinks,
+    });
+
+    // a client, which does not need nix
+    if let Some(Command::Fetch {
+        buildid,
+        kind,
+        path,
+    }) = &args.command
+    {
+        let kind = match (kind, path) {
+            (clientcache::FetchKind::Debuginfo, _) => federation::Kind::Debuginfo,
+            (clientcache::FetchKind::Executable, _) => federation::Kind::Executable,
+            (clientcache::FetchKind::Source, Some(path)) => federation::Kind::Source(path),
+            (clientcache::FetchKind::Source, None) => anyhow::bail!("source requires a path"),
+        };
+        return clientcache::run_fetch(buildid, kind).await;
+    }
 
     // check that nix-store is present
     match store::detect_nix() {
@@ -70,6 +626,60 @@ async fn main() -> anyhow::Result<ExitCode> {
             tracing::error!("nix is not available: {:#}", e);
             return Ok(ExitCode::FAILURE);
         }
-        Ok(()) => server::run_server(args).await,
+        Ok(()) => match &args.command {
+            None | Some(Command::Serve) => server::run_server(args).await,
+            Some(Command::Index {
+                paths,
+                from_scratch,
+            }) => {
+                let (paths, from_scratch) = (paths.clone(), *from_scratch);
+                server::run_index(args, &paths, from_scratch).await
+            }
+            Some(Command::Query { buildid }) => server::print_query(buildid).await,
+            Some(Command::PrefetchRr {
+                trace_dir,
+                client_cache,
+            }) => {
+                let buildids = rr::buildids_in_trace(trace_dir)?;
+                tracing::info!("prefetching {} buildids", buildids.len());
+                let client_cache = client_cache
+                    .then(clientcache::client_cache_dir)
+                    .transpose()?;
+                server::prefetch(args, buildids, client_cache).await
+            }
+            Some(Command::Mirror {
+                store_paths,
+                substituter,
+            }) => mirror::run_mirror(store_paths, substituter).await,
+            Some(Command::ExportElfutils { output }) => elfutils::run_export(output).await,
+            Some(Command::BuildIndex { output, roots }) => {
+                index::run_build_index(roots, output).await
+            }
+            Some(Command::Misses { limit }) => server::print_misses(*limit).await,
+            Some(Command::Hits { limit }) => server::print_hits(*limit).await,
+            Some(Command::Prune { local_only }) => server::run_prune(&args, *local_only).await,
+            Some(Command::Buildids { storepath }) => {
+                server::print_store_path_buildids(storepath).await
+            }
+            Some(Command::Resolve {
+                binary,
+                client_cache,
+            }) => {
+                let binary = binary.clone();
+                let client_cache = client_cache
+                    .then(clientcache::client_cache_dir)
+                    .transpose()?;
+                server::print_resolution(args, &binary, client_cache).await
+            }
+            Some(Command::Ephemeral { storepaths }) => {
+                let storepaths = storepaths.clone();
+                server::run_ephemeral(args, &storepaths).await
+            }
+            Some(Command::Shell { command }) => {
+                // clap ensures there is at least the default address
+                shell::run_shell(args.listen_address[0], command).await
+            }
+            Some(Command::Fetch { .. }) => unreachable!("handled before detecting nix"),
+        },
     }
 }
@@ -0,0 +1,247 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Populating the cache from a channel and a binary cache, without realising anything.
+//!
+//! A channel (or a Hydra job building one) publishes the list of its store paths in
+//! `store-paths.xz`. Among them, debug outputs contain `lib/debug/.build-id/xx/yyyy.debug`
+//! files, whose name is the buildid. Binary caches publish a listing of the files of each
+//! store path as `<hash>.ls`, so the buildids of all debug outputs can be registered without
+//! downloading them. The files are only realised when a client requests them.
+
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use anyhow::Context;
+use futures_util::StreamExt;
+use serde::Deserialize;
+
+use crate::db::{Cache, Entry};
+use crate::store::{is_debug_output, is_valid_buildid, NIX_STORE};
+use crate::substituter::{FileSubstituter, HttpSubstituter, Substituter};
+
+/// How many listings are downloaded at the same time
+const CONCURRENT_DOWNLOADS: usize = 16;
+
+/// Register entries in transactions of that many debug outputs
+const REGISTRATION_BATCH_SIZE: usize = 100;
+
+/// Magic bytes of xz compressed files
+const XZ_MAGIC: &[u8] = b"\xfd7zXZ\x00";
+
+/// A node of a nar listing, as found in `.ls` files of binary caches
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Listing {
+    Regular {},
+    Symlink {},
+    Directory {
+        #[serde(default)]
+        entries: std::collections::BTreeMap<String, Listing>,
+    },
+}
+
+/// The content of a `.ls` file
+#[derive(Deserialize, Debug)]
+struct NarListing {
+    root: Listing,
+}
+
+/// Returns the buildids of the debug files in this listing of a debug output, and the path of
+/// the corresponding file relative to the store path.
+fn buildids_in_listing(listing: &NarListing) -> Vec<(String, String)> {
+    let mut node = &listing.root;
+    for component in ["lib", "debug", ".build-id"] {
+        node = match node {
+            Listing::Directory { entries } => match entries.get(component) {
+                Some(child) => child,
+                None => return vec![],
+            },
+            _ => return vec![],
+        };
+    }
+    let mut result = Vec::new();
+    if let Listing::Directory { entries } = node {
+        for (mid, child) in entries {
+            if let Listing::Directory { entries } = child {
+                for name in entries.keys() {
+                    let stem = match name.strip_suffix(".debug") {
+                        Some(stem) => stem,
+                        None => continue,
+                    };
+                    let buildid = format!("{}{}", mid, stem);
+                    if mid.len() == 2 && is_valid_buildid(&buildid) {
+                        result.push((buildid, format!("lib/debug/.build-id/{}/{}", mid, name)));
+                    }
+                }
+            }
+        }
+    }
+    result
+}
+
+#[test]
+fn test_buildids_in_listing() {
+    let listing: NarListing = serde_json::from_str(
+        r#"{"version":1,"root":{"type":"directory","entries":{"lib":{"type":"directory","entries":{
+        "debug":{"type":"directory","entries":{".build-id":{"type":"directory","entries":{
+        "48":{"type":"directory","entries":{
+            "3bd7f7229bdb06462222e1e353e4f37e15c293.debug":{"type":"regular","size":42,"narOffset":400},
+            "README":{"type":"regular","size":1,"narOffset":500}
+        }}}}}}}}}}}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        buildids_in_listing(&listing),
+        vec![(
+            "483bd7f7229bdb06462222e1e353e4f37e15c293".to_owned(),
+            "lib/debug/.build-id/48/3bd7f7229bdb06462222e1e353e4f37e15c293.debug".to_owned()
+        )]
+    );
+}
+
+/// Uncompresses this data if it is compressed in a format supported by libarchive.
+fn maybe_uncompress(data: Vec<u8>, compressed: bool) -> anyhow::Result<Vec<u8>> {
+    if !compressed {
+        return Ok(data);
+    }
+    let mut result = Vec::new();
+    compress_tools::uncompress_data(data.as_slice(), &mut result).context("uncompressing")?;
+    Ok(result)
+}
+
+/// Reads the list of store paths of a channel.
+///
+/// `source` is either a local file or an url. If it does not end in `store-paths` or
+/// `store-paths.xz`, `/store-paths.xz` is appended.
+async fn get_store_paths(source: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let source = if source.ends_with("store-paths") || source.ends_with("store-paths.xz") {
+        source.to_owned()
+    } else {
+        format!("{}/store-paths.xz", source.trim_end_matches('/'))
+    };
+    let data = if source.starts_with("http://") || source.starts_with("https://") {
+        let response = reqwest::get(&source)
+            .await
+            .with_context(|| format!("fetching {}", source))?
+            .error_for_status()
+            .with_context(|| format!("fetching {}", source))?;
+        response
+            .bytes()
+            .await
+            .with_context(|| format!("downloading {}", source))?
+            .to_vec()
+    } else {
+        tokio::fs::read(&source)
+            .await
+            .with_context(|| format!("reading {}", source))?
+    };
+    let compressed = data.starts_with(XZ_MAGIC);
+    let data = tokio::task::spawn_blocking(move || maybe_uncompress(data, compressed))
+        .await?
+        .with_context(|| format!("uncompressing {}", source))?;
+    let text = String::from_utf8(data).context("store path list is not utf8")?;
+    Ok(text
+        .lines()
+        .filter(|line| line.starts_with(NIX_STORE))
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Finds the buildids provided by this debug output, by downloading its listing from this
+/// substituter.
+async fn index_debug_output(
+    substituter: &dyn Substituter,
+    storepath: &Path,
+) -> anyhow::Result<Vec<Entry>> {
+    let name = storepath
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow::anyhow!("invalid store path {}", storepath.display()))?;
+    let hash = match name.split_once('-') {
+        Some((hash, _)) => hash,
+        None => anyhow::bail!("invalid store path {}", storepath.display()),
+    };
+    let file = match substituter
+        .fetch(Path::new(&format!("{}.ls", hash)))
+        .await
+        .with_context(|| format!("fetching listing of {}", storepath.display()))?
+    {
+        Some(file) => file,
+        None => anyhow::bail!("{} has no listing in {}", name, substituter.url()),
+    };
+    let data = tokio::fs::read(&file)
+        .await
+        .with_context(|| format!("reading {}", file.display()))?;
+    let compressed = !data.starts_with(b"{");
+    let data = tokio::task::spawn_blocking(move || maybe_uncompress(data, compressed))
+        .await?
+        .with_context(|| format!("uncompressing listing of {}", storepath.display()))?;
+    let listing: NarListing = serde_json::from_slice(&data)
+        .with_context(|| format!("parsing listing of {}", storepath.display()))?;
+    Ok(buildids_in_listing(&listing)
+        .into_iter()
+        .map(|(buildid, relative)| Entry {
+            buildid,
+            executable: None,
+            executable_metadata: None,
+            debuginfo: Some(format!("{}/{}", storepath.display(), relative)),
+            debuginfo_metadata: None,
+            arch: None,
+            source: None,
+        })
+        .collect())
+}
+
+/// Registers the buildids of all debug outputs of this channel, as listed by this binary cache.
+pub async fn run_mirror(store_paths: &str, substituter_url: &str) -> anyhow::Result<ExitCode> {
+    let substituter: Box<dyn Substituter> = match FileSubstituter::from_url(substituter_url).await?
+    {
+        Some(s) => Box::new(s),
+        None => match HttpSubstituter::from_url(substituter_url).await? {
+            Some(s) => Box::new(s),
+            None => anyhow::bail!("unsupported substituter url {}", substituter_url),
+        },
+    };
+    let cache = Cache::open().await.context("opening global cache")?;
+    let debug_outputs: Vec<PathBuf> = get_store_paths(store_paths)
+        .await
+        .context("listing store paths to mirror")?
+        .into_iter()
+        .filter(|path| is_debug_output(path))
+        .collect();
+    tracing::info!("mirroring {} debug outputs", debug_outputs.len());
+    let substituter = substituter.as_ref();
+    let mut results =
+        futures_util::stream::iter(debug_outputs.iter())
+            .map(|storepath| async move {
+                (storepath, index_debug_output(substituter, storepath).await)
+            })
+            .buffer_unordered(CONCURRENT_DOWNLOADS)
+            .chunks(REGISTRATION_BATCH_SIZE);
+    let mut registered = 0;
+    let mut failed = 0;
+    while let Some(chunk) = results.next().await {
+        let mut entries = Vec::new();
+        for (storepath, result) in chunk {
+            match result {
+                Ok(found) => entries.extend(found),
+                Err(e) => {
+                    failed += 1;
+                    tracing::warn!("cannot mirror {}: {:#}", storepath.display(), e);
+                }
+            }
+        }
+        cache
+            .register(&entries)
+            .await
+            .context("registering mirrored entries")?;
+        registered += entries.len();
+        tracing::info!("registered {} buildids", registered);
+    }
+    if failed > 0 {
+        tracing::warn!("{} debug outputs could not be mirrored", failed);
+    }
+    Ok(ExitCode::SUCCESS)
+}
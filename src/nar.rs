@@ -0,0 +1,856 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Fetching single files of store paths from binary caches, without realising them.
+//!
+//! A debug output can weigh hundreds of megabytes, but serving a buildid only needs one file of
+//! it. Instead of realising the store path, its narinfo is fetched from a binary cache, then the
+//! nar it points to, which is decompressed and read until the requested file. This file is kept
+//! in the cache directory, and the least recently used files are removed when they take more
+//! than [MAX_CACHE_SIZE].
+//!
+//! As nix does when substituting, narinfos must be signed by one of the `trusted-public-keys`
+//! (see [set_trusted_public_keys]), and the file is only kept if the whole nar has the hash the
+//! narinfo announces.
+
+use std::collections::HashMap;
+use std::io::{BufReader, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+use base64::Engine;
+use once_cell::sync::OnceCell;
+use sha2::Digest;
+
+use crate::cachedir::{evict, touch, Limit};
+use crate::log::ResultExt;
+use crate::store::{get_store_path, split_store_root, TemporaryFailure};
+use crate::substituter::Substituter;
+
+/// How many bytes of extracted files are kept in the cache directory
+const MAX_CACHE_SIZE: u64 = 2 << 30;
+
+/// The longest string of a nar other than file contents: tags, file names and symlink targets
+const MAX_STRING_LEN: u64 = 4096;
+
+/// How deep directories of a nar may be nested
+const MAX_DEPTH: usize = 256;
+
+/// Digits of the base32 encoding of hashes by nix, which omits `e`, `o`, `u` and `t`
+const NIX_BASE32_CHARS: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+/// Length of a sha256 hash in nix's base32
+const NIX_BASE32_LEN: usize = 52;
+
+/// Encodes a sha256 hash in nix's base32, which is not RFC 4648 base32
+fn to_nix_base32(hash: &[u8; 32]) -> String {
+    (0..NIX_BASE32_LEN)
+        .rev()
+        .map(|n| {
+            let (i, j) = (n * 5 / 8, n * 5 % 8);
+            let high = match hash.get(i + 1) {
+                Some(&next) => (next as u16) << (8 - j),
+                None => 0,
+            };
+            let digit = ((hash[i] >> j) as u16 | high) & 0x1f;
+            NIX_BASE32_CHARS[digit as usize] as char
+        })
+        .collect()
+}
+
+/// Decodes a sha256 hash encoded by [to_nix_base32]
+fn from_nix_base32(text: &str) -> Option<[u8; 32]> {
+    if text.len() != NIX_BASE32_LEN {
+        return None;
+    }
+    let mut hash = [0u8; 32];
+    for (n, c) in text.bytes().rev().enumerate() {
+        let digit = NIX_BASE32_CHARS.iter().position(|&d| d == c)? as u16;
+        let (i, j) = (n * 5 / 8, n * 5 % 8);
+        let shifted = digit << j;
+        hash[i] |= shifted as u8;
+        match hash.get_mut(i + 1) {
+            Some(next) => *next |= (shifted >> 8) as u8,
+            None if shifted >> 8 != 0 => return None,
+            None => (),
+        }
+    }
+    Some(hash)
+}
+
+#[test]
+fn test_nix_base32() {
+    let empty: [u8; 32] = sha2::Sha256::digest(b"").into();
+    let encoded = "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73";
+    assert_eq!(to_nix_base32(&empty), encoded);
+    assert_eq!(from_nix_base32(encoded), Some(empty));
+    let all: [u8; 32] = std::array::from_fn(|i| (i * 37) as u8);
+    assert_eq!(from_nix_base32(&to_nix_base32(&all)), Some(all));
+    assert_eq!(
+        from_nix_base32("0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c7e"),
+        None
+    );
+    assert_eq!(
+        from_nix_base32("zmdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"),
+        None
+    );
+    assert_eq!(from_nix_base32("0mdqa9"), None);
+}
+
+/// Parses a sha256 hash of a narinfo: `sha256:` followed by nix base32 or hexadecimal, or
+/// `sha256-` followed by base64
+fn parse_hash(text: &str) -> anyhow::Result<[u8; 32]> {
+    let hash = if let Some(digest) = text.strip_prefix("sha256:") {
+        if digest.len() == NIX_BASE32_LEN {
+            from_nix_base32(digest)
+        } else {
+            base16::decode(digest)
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+        }
+    } else if let Some(digest) = text.strip_prefix("sha256-") {
+        base64::engine::general_purpose::STANDARD
+            .decode(digest)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+    } else {
+        None
+    };
+    hash.ok_or_else(|| anyhow::anyhow!("unsupported hash {}", text))
+}
+
+/// What we need to know about a store path from its narinfo
+#[derive(Debug, PartialEq, Eq)]
+struct NarInfo {
+    /// the store path this narinfo describes
+    store_path: String,
+    /// where the nar is, relative to the root of the binary cache
+    url: String,
+    /// `none`, `xz`, `zstd`, `bzip2`...
+    compression: String,
+    /// sha256 of the compressed nar, if known. It is not signed.
+    file_hash: Option<[u8; 32]>,
+    /// sha256 of the uncompressed nar
+    nar_hash: [u8; 32],
+    /// size of the uncompressed nar
+    nar_size: u64,
+    /// names of the store paths this store path refers to, without the store directory
+    references: Vec<String>,
+    /// signatures, like `cache.nixos.org-1:base64`
+    signatures: Vec<String>,
+}
+
+impl NarInfo {
+    /// What the signatures of the narinfo sign, like `ValidPathInfo::fingerprint` in nix
+    fn fingerprint(&self) -> String {
+        let store_dir = match self.store_path.rsplit_once('/') {
+            Some((store_dir, _)) => store_dir,
+            None => "",
+        };
+        let references: Vec<String> = self
+            .references
+            .iter()
+            .map(|reference| format!("{}/{}", store_dir, reference))
+            .collect();
+        format!(
+            "1;{};sha256:{};{};{}",
+            self.store_path,
+            to_nix_base32(&self.nar_hash),
+            self.nar_size,
+            references.join(",")
+        )
+    }
+}
+
+/// Parses the `key: value` lines of a narinfo file
+fn parse_narinfo(text: &str) -> anyhow::Result<NarInfo> {
+    let mut store_path = None;
+    let mut url = None;
+    let mut compression = None;
+    let mut file_hash = None;
+    let mut nar_hash = None;
+    let mut nar_size = None;
+    let mut references = Vec::new();
+    let mut signatures = Vec::new();
+    for line in text.lines() {
+        match line.split_once(": ") {
+            Some(("StorePath", value)) => store_path = Some(value.to_owned()),
+            Some(("URL", value)) => url = Some(value.to_owned()),
+            Some(("Compression", value)) => compression = Some(value.to_owned()),
+            Some(("FileHash", value)) => file_hash = Some(parse_hash(value)?),
+            Some(("NarHash", value)) => nar_hash = Some(parse_hash(value)?),
+            Some(("NarSize", value)) => nar_size = Some(value.parse().context("parsing NarSize")?),
+            Some(("References", value)) => {
+                references = value.split_whitespace().map(str::to_owned).collect()
+            }
+            Some(("Sig", value)) => signatures.push(value.to_owned()),
+            _ => (),
+        }
+    }
+    let missing = |field: &str| anyhow::anyhow!("narinfo has no {}", field);
+    Ok(NarInfo {
+        store_path: store_path.ok_or_else(|| missing("StorePath"))?,
+        url: url.ok_or_else(|| missing("URL"))?,
+        // nix defaults to bzip2 for old narinfo files without this field
+        compression: compression.unwrap_or_else(|| "bzip2".to_owned()),
+        file_hash,
+        nar_hash: nar_hash.ok_or_else(|| missing("NarHash"))?,
+        nar_size: nar_size.ok_or_else(|| missing("NarSize"))?,
+        references,
+        signatures,
+    })
+}
+
+#[test]
+fn test_parse_narinfo() {
+    let text = "StorePath: /nix/store/gcf1kpwbmj0pbf8kvfk3gi5lkg6f4qaa-hello-2.12.1-debug\n\
+        URL: nar/1b2fs8d5v8lpbd8k5pvqzy5dkfpz1cjnvxpxhmcsd1lgsk5i6j9f.nar.xz\n\
+        Compression: xz\n\
+        FileHash: sha256:1b2fs8d5v8lpbd8k5pvqzy5dkfpz1cjnvxpxhmcsd1lgsk5i6j9f\n\
+        NarHash: sha256:0bl4xkfzn3gh4q2dzy5w6y5sfy4djzvzzyzzgawn16pisgmiv8jx\n\
+        NarSize: 106776\n\
+        References: 9vjnfn4x5w8fbs3z8rbqd7dvd8jfy0j1-glibc-2.38\n\
+        Sig: cache.nixos.org-1:abcd\n";
+    let narinfo = parse_narinfo(text).unwrap();
+    assert_eq!(
+        narinfo.url,
+        "nar/1b2fs8d5v8lpbd8k5pvqzy5dkfpz1cjnvxpxhmcsd1lgsk5i6j9f.nar.xz"
+    );
+    assert_eq!(narinfo.compression, "xz");
+    assert_eq!(narinfo.nar_size, 106776);
+    assert_eq!(
+        narinfo.file_hash,
+        from_nix_base32("1b2fs8d5v8lpbd8k5pvqzy5dkfpz1cjnvxpxhmcsd1lgsk5i6j9f")
+    );
+    assert_eq!(
+        narinfo.signatures,
+        vec!["cache.nixos.org-1:abcd".to_owned()]
+    );
+    assert_eq!(
+        narinfo.fingerprint(),
+        "1;/nix/store/gcf1kpwbmj0pbf8kvfk3gi5lkg6f4qaa-hello-2.12.1-debug;\
+        sha256:0bl4xkfzn3gh4q2dzy5w6y5sfy4djzvzzyzzgawn16pisgmiv8jx;106776;\
+        /nix/store/9vjnfn4x5w8fbs3z8rbqd7dvd8jfy0j1-glibc-2.38"
+    );
+    let minimal = "StorePath: /nix/store/aaa-foo\nURL: nar/foo.nar\nNarSize: 0\n\
+        NarHash: sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=\n";
+    let narinfo = parse_narinfo(minimal).unwrap();
+    assert_eq!(narinfo.compression, "bzip2");
+    assert_eq!(
+        narinfo.nar_hash,
+        <[u8; 32]>::from(sha2::Sha256::digest(b""))
+    );
+    assert!(parse_narinfo("Compression: xz\n").is_err());
+    assert!(parse_narinfo(&minimal.replace("NarSize: 0\n", "")).is_err());
+}
+
+/// Public keys of `trusted-public-keys` by name, or `None` if signatures are not required.
+///
+/// Set by [set_trusted_public_keys].
+static TRUSTED_PUBLIC_KEYS: OnceCell<Option<HashMap<String, Vec<u8>>>> = OnceCell::new();
+
+/// Parses a space separated list of public keys like `cache.nixos.org-1:base64`
+fn parse_public_keys(keys: &str) -> HashMap<String, Vec<u8>> {
+    let mut result = HashMap::new();
+    for key in keys.split_whitespace() {
+        let decoded = key.split_once(':').and_then(|(name, key)| {
+            let key = base64::engine::general_purpose::STANDARD.decode(key).ok()?;
+            Some((name.to_owned(), key))
+        });
+        match decoded {
+            Some((name, key)) => {
+                result.insert(name, key);
+            }
+            None => tracing::warn!("ignoring invalid trusted public key {}", key),
+        }
+    }
+    result
+}
+
+/// Only uses nars whose narinfo is signed by one of these keys, a space separated list like
+/// `trusted-public-keys` in `nix.conf`. Like nix with `require-sigs = false`, unsigned narinfos
+/// are accepted if `require_sigs` is false, but nars must still have the hash they announce.
+///
+/// Until this is called, no nar is used.
+pub fn set_trusted_public_keys(keys: &str, require_sigs: bool) {
+    let keys = require_sigs.then(|| parse_public_keys(keys));
+    if TRUSTED_PUBLIC_KEYS.set(keys).is_err() {
+        tracing::warn!("trusted public keys were already set");
+    }
+}
+
+/// Checks an ed25519 signature, in the format used by nix
+fn verify_ed25519(key: &[u8], message: &[u8], signature: &[u8]) -> anyhow::Result<bool> {
+    anyhow::ensure!(key.len() == 32, "invalid ed25519 public key");
+    let key = ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, key);
+    Ok(key.verify(message, signature).is_ok())
+}
+
+/// Fails unless one of the signatures of this narinfo is valid and by one of these keys
+fn verify_signatures(narinfo: &NarInfo, keys: &HashMap<String, Vec<u8>>) -> anyhow::Result<()> {
+    let fingerprint = narinfo.fingerprint();
+    for signature in &narinfo.signatures {
+        let (name, signature) = match signature.split_once(':') {
+            Some(split) => split,
+            None => continue,
+        };
+        let key = match keys.get(name) {
+            Some(key) => key,
+            None => continue,
+        };
+        let valid = base64::engine::general_purpose::STANDARD
+            .decode(signature)
+            .context("decoding signature")
+            .and_then(|signature| verify_ed25519(key, fingerprint.as_bytes(), &signature));
+        match valid {
+            Ok(true) => return Ok(()),
+            Ok(false) => tracing::warn!(
+                "invalid signature by {} of the narinfo of {}",
+                name,
+                narinfo.store_path
+            ),
+            Err(e) => tracing::warn!(
+                "signature by {} of the narinfo of {}: {:#}",
+                name,
+                narinfo.store_path,
+                e
+            ),
+        }
+    }
+    anyhow::bail!(
+        "the narinfo of {} has no valid signature by a trusted key",
+        narinfo.store_path
+    )
+}
+
+/// Fails unless this narinfo is signed as [set_trusted_public_keys] requires
+fn check_signature(narinfo: &NarInfo) -> anyhow::Result<()> {
+    match TRUSTED_PUBLIC_KEYS.get() {
+        None => anyhow::bail!("trusted public keys are unknown"),
+        Some(None) => Ok(()),
+        Some(Some(keys)) => verify_signatures(narinfo, keys),
+    }
+}
+
+#[cfg(test)]
+fn sign_narinfo(narinfo: &mut NarInfo, name: &str, seed: u8) -> String {
+    use ring::signature::KeyPair;
+    let key = ring::signature::Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap();
+    let signature = key.sign(narinfo.fingerprint().as_bytes());
+    let engine = base64::engine::general_purpose::STANDARD;
+    narinfo
+        .signatures
+        .push(format!("{}:{}", name, engine.encode(signature)));
+    format!("{}:{}", name, engine.encode(key.public_key()))
+}
+
+#[test]
+fn test_verify_signatures() {
+    let mut narinfo = parse_narinfo(
+        "StorePath: /nix/store/aaa-foo\nURL: nar/foo.nar\nNarSize: 0\n\
+        NarHash: sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73\n",
+    )
+    .unwrap();
+    assert!(verify_signatures(&narinfo, &HashMap::new()).is_err());
+    let trusted = sign_narinfo(&mut narinfo, "trusted-1", 1);
+    let keys = parse_public_keys(&format!("invalid {}", trusted));
+    assert_eq!(keys.len(), 1);
+    verify_signatures(&narinfo, &keys).unwrap();
+    // signed by another key with the same name
+    let mut forged = parse_narinfo(
+        "StorePath: /nix/store/aaa-foo\nURL: nar/foo.nar\nNarSize: 0\n\
+        NarHash: sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73\n",
+    )
+    .unwrap();
+    sign_narinfo(&mut forged, "trusted-1", 2);
+    assert!(verify_signatures(&forged, &keys).is_err());
+    // the signature does not cover another size
+    narinfo.nar_size = 1;
+    assert!(verify_signatures(&narinfo, &keys).is_err());
+}
+
+/// Reads an integer of a nar
+fn read_u64(nar: &mut impl Read) -> anyhow::Result<u64> {
+    let mut buffer = [0u8; 8];
+    nar.read_exact(&mut buffer).context("truncated nar")?;
+    Ok(u64::from_le_bytes(buffer))
+}
+
+/// Skips the padding after `len` bytes of data in a nar
+fn skip_padding(nar: &mut impl Read, len: u64) -> anyhow::Result<()> {
+    let mut padding = [0u8; 8];
+    let padding = &mut padding[..((8 - len % 8) % 8) as usize];
+    nar.read_exact(padding).context("truncated nar")?;
+    anyhow::ensure!(padding.iter().all(|&b| b == 0), "invalid nar padding");
+    Ok(())
+}
+
+/// Reads a string of a nar, other than file contents
+fn read_string(nar: &mut impl Read) -> anyhow::Result<Vec<u8>> {
+    let len = read_u64(nar)?;
+    anyhow::ensure!(len <= MAX_STRING_LEN, "nar string of {} bytes", len);
+    let mut result = vec![0u8; len as usize];
+    nar.read_exact(&mut result).context("truncated nar")?;
+    skip_padding(nar, len)?;
+    Ok(result)
+}
+
+/// Reads a string of a nar and fails if it is not `expected`
+fn expect(nar: &mut impl Read, expected: &str) -> anyhow::Result<()> {
+    let found = read_string(nar)?;
+    anyhow::ensure!(
+        found == expected.as_bytes(),
+        "expected {:?} in nar, found {:?}",
+        expected,
+        String::from_utf8_lossy(&found)
+    );
+    Ok(())
+}
+
+/// Reads the node of a nar at `path`, and copies the contents of the regular file `member` to
+/// `out` if it is in this node.
+///
+/// Returns whether `member` was found, and then stops reading right after it.
+fn read_node(
+    nar: &mut impl Read,
+    path: &mut PathBuf,
+    member: &Path,
+    out: &mut impl Write,
+    depth: usize,
+) -> anyhow::Result<bool> {
+    anyhow::ensure!(depth <= MAX_DEPTH, "nar nested too deep");
+    expect(nar, "(")?;
+    expect(nar, "type")?;
+    match read_string(nar)?.as_slice() {
+        b"regular" => {
+            let mut tag = read_string(nar)?;
+            if tag == b"executable" {
+                expect(nar, "")?;
+                tag = read_string(nar)?;
+            }
+            anyhow::ensure!(tag == b"contents", "expected contents in nar");
+            let len = read_u64(nar)?;
+            let mut contents = nar.by_ref().take(len);
+            if path.as_path() == member {
+                let copied = std::io::copy(&mut contents, out).context("extracting from nar")?;
+                anyhow::ensure!(copied == len, "truncated nar");
+                return Ok(true);
+            }
+            let skipped =
+                std::io::copy(&mut contents, &mut std::io::sink()).context("reading nar")?;
+            anyhow::ensure!(skipped == len, "truncated nar");
+            skip_padding(nar, len)?;
+        }
+        b"symlink" => {
+            expect(nar, "target")?;
+            read_string(nar)?;
+        }
+        b"directory" => loop {
+            match read_string(nar)?.as_slice() {
+                b")" => return Ok(false),
+                b"entry" => (),
+                other => anyhow::bail!(
+                    "unexpected {:?} in nar directory",
+                    String::from_utf8_lossy(other)
+                ),
+            }
+            expect(nar, "(")?;
+            expect(nar, "name")?;
+            let name = read_string(nar)?;
+            let name = Path::new(std::ffi::OsStr::from_bytes(&name));
+            anyhow::ensure!(
+                matches!(
+                    name.components().collect::<Vec<_>>()[..],
+                    [Component::Normal(_)]
+                ),
+                "invalid file name {} in nar",
+                name.display()
+            );
+            expect(nar, "node")?;
+            path.push(name);
+            let found = read_node(nar, path, member, out, depth + 1)?;
+            path.pop();
+            if found {
+                return Ok(true);
+            }
+            expect(nar, ")")?;
+        },
+        other => anyhow::bail!(
+            "unknown node type {:?} in nar",
+            String::from_utf8_lossy(other)
+        ),
+    }
+    expect(nar, ")")?;
+    Ok(false)
+}
+
+/// Copies the regular file at relative path `member` in this nar to `out`.
+///
+/// Returns false if the nar has no such file. Stops reading the nar right after the file.
+pub fn extract_member(
+    nar: &mut impl Read,
+    member: &Path,
+    out: &mut impl Write,
+) -> anyhow::Result<bool> {
+    expect(nar, "nix-archive-1")?;
+    read_node(nar, &mut PathBuf::new(), member, out, 0)
+}
+
+#[cfg(test)]
+fn nar_string(nar: &mut Vec<u8>, s: &[u8]) {
+    nar.extend_from_slice(&(s.len() as u64).to_le_bytes());
+    nar.extend_from_slice(s);
+    nar.resize(nar.len() + (8 - s.len() % 8) % 8, 0);
+}
+
+#[cfg(test)]
+fn test_nar() -> Vec<u8> {
+    let mut nar = Vec::new();
+    for s in [
+        "nix-archive-1",
+        "(",
+        "type",
+        "directory",
+        "entry",
+        "(",
+        "name",
+        "lib",
+        "node",
+        "(",
+        "type",
+        "directory",
+        "entry",
+        "(",
+        "name",
+        "a.debug",
+        "node",
+        "(",
+        "type",
+        "regular",
+        "contents",
+        "first",
+        ")",
+        ")",
+        "entry",
+        "(",
+        "name",
+        "b.debug",
+        "node",
+        "(",
+        "type",
+        "regular",
+        "executable",
+        "",
+        "contents",
+        "second file",
+        ")",
+        ")",
+        "entry",
+        "(",
+        "name",
+        "link",
+        "node",
+        "(",
+        "type",
+        "symlink",
+        "target",
+        "a.debug",
+        ")",
+        ")",
+        ")",
+        ")",
+        ")",
+    ] {
+        nar_string(&mut nar, s.as_bytes());
+    }
+    nar
+}
+
+#[test]
+fn test_extract_member() {
+    let nar = test_nar();
+    let extract = |member: &str| {
+        let mut out = Vec::new();
+        let found = extract_member(&mut nar.as_slice(), Path::new(member), &mut out).unwrap();
+        found.then_some(out)
+    };
+    assert_eq!(extract("lib/a.debug"), Some(b"first".to_vec()));
+    assert_eq!(extract("lib/b.debug"), Some(b"second file".to_vec()));
+    assert_eq!(extract("lib/c.debug"), None);
+    assert_eq!(extract("lib/link"), None);
+    assert_eq!(extract("lib"), None);
+    assert!(extract_member(
+        &mut &nar[..nar.len() / 2],
+        Path::new("lib/c.debug"),
+        &mut Vec::new()
+    )
+    .is_err());
+}
+
+/// Hashes what is read through it
+struct HashingReader<R> {
+    inner: R,
+    hasher: sha2::Sha256,
+    /// how many bytes were read
+    len: u64,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            hasher: sha2::Sha256::new(),
+            len: 0,
+        }
+    }
+
+    /// Reads the rest of the input, and checks that the whole input has this size and sha256.
+    fn check(mut self, hash: &[u8; 32], size: u64) -> anyhow::Result<()> {
+        std::io::copy(&mut self, &mut std::io::sink()).context("reading the end of the nar")?;
+        anyhow::ensure!(
+            self.len == size,
+            "nar has {} bytes instead of {}",
+            self.len,
+            size
+        );
+        anyhow::ensure!(
+            self.hasher.finalize().as_slice() == hash,
+            "nar does not have the hash announced by its narinfo"
+        );
+        Ok(())
+    }
+}
+
+/// The sha256 of this file
+fn file_sha256(path: &Path) -> anyhow::Result<[u8; 32]> {
+    let mut file =
+        std::fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let mut hasher = sha2::Sha256::new();
+    std::io::copy(&mut file, &mut hasher).with_context(|| format!("reading {}", path.display()))?;
+    Ok(hasher.finalize().into())
+}
+
+/// Extracts `member` from this nar file, decompressing it on the fly, into `out`.
+///
+/// Fails if the nar does not have the hashes announced by `narinfo`, in which case `out` must
+/// be discarded.
+///
+/// Blocking.
+fn extract_compressed_member(
+    compressed: &Path,
+    narinfo: &NarInfo,
+    member: &Path,
+    out: &mut impl Write,
+) -> anyhow::Result<bool> {
+    if let Some(file_hash) = &narinfo.file_hash {
+        anyhow::ensure!(
+            &file_sha256(compressed)? == file_hash,
+            "compressed nar does not have the hash announced by its narinfo"
+        );
+    }
+    let file = std::fs::File::open(compressed)
+        .with_context(|| format!("opening nar {}", compressed.display()))?;
+    if narinfo.compression == "none" {
+        let mut nar = HashingReader::new(BufReader::new(file));
+        let found = extract_member(&mut nar, member, out)?;
+        nar.check(&narinfo.nar_hash, narinfo.nar_size)?;
+        return Ok(found);
+    }
+    // decompress in another thread into a socket, so that the nar is never written to disk
+    let (mut writer, reader) = UnixStream::pair().context("creating socket pair")?;
+    let decompress = std::thread::spawn(move || {
+        let res = compress_tools::uncompress_data(file, &mut writer);
+        drop(writer);
+        res
+    });
+    let mut nar = HashingReader::new(BufReader::new(&reader));
+    let found = extract_member(&mut nar, member, out).and_then(|found| {
+        nar.check(&narinfo.nar_hash, narinfo.nar_size)
+            .map(|()| found)
+    });
+    // on error, the decompressing thread fails to write once the socket is closed
+    drop(reader);
+    let decompressed = decompress.join().expect("decompression thread panicked");
+    match found {
+        Ok(found) => Ok(found),
+        Err(e) => match decompressed {
+            Err(decompression) => Err(decompression)
+                .with_context(|| format!("decompressing {} nar", narinfo.compression)),
+            Ok(_) => Err(e),
+        },
+    }
+}
+
+/// The name in the cache directory of the file `member` of the store path with this hash
+fn cache_name(hash: &str, member: &Path) -> String {
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(member.as_os_str().as_bytes());
+    let digest = hasher.finalize();
+    let name = member
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    format!("{}-{}-{}", hash, base16::encode_lower(&digest[..8]), name)
+}
+
+/// Fetches this file from this binary cache. Failures are [TemporaryFailure]s.
+async fn fetch(substituter: &dyn Substituter, path: &Path) -> anyhow::Result<Option<PathBuf>> {
+    substituter
+        .fetch(path)
+        .await
+        .map_err(|e| TemporaryFailure(format!("{:#}", e)).into())
+}
+
+/// Fetches the narinfo of the store path with this hash from this binary cache, then its nar,
+/// and extracts `member` from it to `target`.
+///
+/// The narinfo must be signed as [set_trusted_public_keys] requires, and the nar must have the
+/// hashes of the narinfo.
+///
+/// Returns false if the binary cache does not have the store path, or if it has no such file.
+async fn fetch_member_from(
+    substituter: &dyn Substituter,
+    hash: &str,
+    member: &Path,
+    target: &Path,
+) -> anyhow::Result<bool> {
+    let narinfo_path = PathBuf::from(format!("{}.narinfo", hash));
+    let narinfo = match fetch(substituter, &narinfo_path).await? {
+        None => return Ok(false),
+        Some(file) => file,
+    };
+    let text = tokio::fs::read_to_string(&narinfo)
+        .await
+        .with_context(|| format!("reading {}", narinfo.display()));
+    substituter.discard(&narinfo_path);
+    let narinfo = parse_narinfo(&text?)
+        .and_then(|narinfo| {
+            let name = Path::new(&narinfo.store_path)
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy();
+            anyhow::ensure!(
+                name.starts_with(&format!("{}-", hash)),
+                "narinfo is for {}",
+                narinfo.store_path
+            );
+            check_signature(&narinfo)?;
+            Ok(narinfo)
+        })
+        .with_context(|| {
+            format!(
+                "checking {} from {}",
+                narinfo_path.display(),
+                substituter.url()
+            )
+        })?;
+    let nar_path = PathBuf::from(&narinfo.url);
+    anyhow::ensure!(
+        nar_path.is_relative(),
+        "nar url {} in {} is not relative",
+        narinfo.url,
+        substituter.url()
+    );
+    let nar = match fetch(substituter, &nar_path).await? {
+        None => anyhow::bail!("{} lacks nar {}", substituter.url(), narinfo.url),
+        Some(file) => file,
+    };
+    let member = member.to_owned();
+    let target = target.to_owned();
+    let found = tokio::task::spawn_blocking(move || {
+        let dir = target.parent().unwrap_or(Path::new("."));
+        let mut tmp = tempfile::Builder::new()
+            .prefix(".extracting")
+            .tempfile_in(dir)
+            .context("creating temporary file")?;
+        let found = extract_compressed_member(&nar, &narinfo, &member, tmp.as_file_mut())?;
+        if found {
+            tmp.persist(&target)
+                .with_context(|| format!("renaming to {}", target.display()))?;
+        }
+        anyhow::Ok(found)
+    })
+    .await?;
+    substituter.discard(&nar_path);
+    found.with_context(|| {
+        format!(
+            "extracting from nar {} of {}",
+            nar_path.display(),
+            substituter.url()
+        )
+    })
+}
+
+/// Fetches the file at `path`, in a store path which need not be present, from the nar of this
+/// store path in the first of these binary caches which has it, and returns where it was
+/// extracted in the cache directory.
+///
+/// Returns `None` if no binary cache has it, and the last error if one failed. If it could not
+/// be reached, the error is a [TemporaryFailure].
+pub async fn fetch_member(
+    substituters: &[Arc<dyn Substituter>],
+    path: &Path,
+) -> anyhow::Result<Option<PathBuf>> {
+    let (_, logical) = split_store_root(path);
+    let storepath = match get_store_path(&logical) {
+        None => anyhow::bail!("{} is not in the store", path.display()),
+        Some(storepath) => storepath,
+    };
+    let member = logical.strip_prefix(storepath)?;
+    let hash = storepath
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.split('-').next())
+        .ok_or_else(|| anyhow::anyhow!("invalid store path {}", storepath.display()))?;
+    let cache_dir = crate::db::cache_directory()?.join("nar-members");
+    let target = cache_dir.join(cache_name(hash, member));
+    if target.is_file() {
+        touch(&target).or_warn();
+        return Ok(Some(target));
+    }
+    tokio::fs::create_dir_all(&cache_dir)
+        .await
+        .with_context(|| format!("creating {}", cache_dir.display()))?;
+    let mut failure = None;
+    for substituter in crate::substituter::by_health(substituters) {
+        match fetch_member_from(substituter.as_ref(), hash, member, &target).await {
+            Ok(true) => {
+                tracing::info!(
+                    "extracted {} from the nar of {} in {}",
+                    member.display(),
+                    storepath.display(),
+                    substituter.url()
+                );
+                let keep = target.clone();
+                tokio::task::spawn_blocking(move || {
+                    evict(&cache_dir, Limit::Bytes(MAX_CACHE_SIZE), Some(&keep))
+                })
+                .await?
+                .or_warn();
+                return Ok(Some(target));
+            }
+            Ok(false) => (),
+            Err(e) => {
+                tracing::debug!("{:#}", e);
+                failure = Some(e);
+            }
+        }
+    }
+    match failure {
+        None => Ok(None),
+        Some(e) => Err(e),
+    }
+}
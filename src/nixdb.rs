@@ -0,0 +1,687 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Read access to the nix db `/nix/var/nix/db/db.sqlite`, to list store paths. The db of Guix,
+//! `/var/guix/db/db.sqlite`, has the same layout.
+//!
+//! One cannot open a sqlite db read only with WAL if the underlying file is not writable, and
+//! we are not allowed to write the nix db. Opening it with `immutable=1` is a lie, and reading
+//! it while nix writes to it can return garbage. Instead, we query a private copy of the db,
+//! which is refreshed when the nix db changes. The copy is made with the online backup API of
+//! sqlite, which reads a consistent state of the db even while nix writes to it.
+//!
+//! The layout of the db differs slightly between versions of nix and its forks like Lix, so
+//! the columns we rely on are checked on each snapshot, see [Schema].
+
+use std::ffi::{CStr, CString, OsStr};
+use std::os::raw::c_int;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::Context;
+use libsqlite3_sys as ffi;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection, SqliteRow};
+use sqlx::{ConnectOptions, Connection, Row};
+use tempfile::TempDir;
+use tokio::sync::Mutex;
+
+use crate::db::Id;
+use crate::inotify::DirWatcher;
+use crate::log::ResultExt;
+use crate::store::{get_store_path, physical};
+
+/// The sqlite db of nix
+const NIX_DB: &str = "/nix/var/nix/db/db.sqlite";
+
+/// How long the backup of the nix db waits for nix to release its locks
+const SNAPSHOT_BUSY_TIMEOUT_MS: c_int = 5000;
+
+/// Size and mtime of the nix db and its WAL, to detect changes
+type Stamp = Vec<Option<(u64, SystemTime)>>;
+
+/// A private copy of the nix db
+struct Snapshot {
+    /// the directory containing the copy, in the cache directory rather than in the
+    /// temporary directory, which is often in memory
+    dir: TempDir,
+    /// the state of the nix db when it was copied
+    stamp: Stamp,
+    /// the layout of the copy, once detected
+    schema: Option<Schema>,
+}
+
+/// What differs between the layouts of the nix db we support.
+///
+/// All versions of nix and Lix have `ValidPaths` with `id` and `path`, which is all we need to
+/// index. Other columns and tables are optional.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Schema {
+    /// whether `ValidPaths` has a `registrationTime` column
+    registration_time: bool,
+    /// whether the db has the tables of content-addressed derivations (`Realisations`)
+    realisations: bool,
+}
+
+/// Reads the layout of this nix db, failing if it lacks what we need.
+async fn detect_schema(db: &mut SqliteConnection) -> anyhow::Result<Schema> {
+    let names = |rows: Vec<SqliteRow>| -> anyhow::Result<Vec<String>> {
+        rows.iter()
+            .map(|row| row.try_get("name").context("parsing name in nix db"))
+            .collect()
+    };
+    let columns = names(
+        sqlx::query("select name from pragma_table_info('ValidPaths')")
+            .fetch_all(&mut *db)
+            .await
+            .context("listing columns of ValidPaths")?,
+    )?;
+    anyhow::ensure!(
+        !columns.is_empty(),
+        "nix db has no ValidPaths table, its layout is not supported"
+    );
+    for required in ["id", "path"] {
+        anyhow::ensure!(
+            columns.iter().any(|column| column == required),
+            "nix db has no column {} in ValidPaths, its layout is not supported",
+            required
+        );
+    }
+    let tables = names(
+        sqlx::query("select name from sqlite_master where type = 'table'")
+            .fetch_all(&mut *db)
+            .await
+            .context("listing tables of nix db")?,
+    )?;
+    Ok(Schema {
+        registration_time: columns.iter().any(|column| column == "registrationTime"),
+        realisations: tables.iter().any(|table| table == "Realisations"),
+    })
+}
+
+impl Snapshot {
+    /// Path of the copy of the db
+    fn db(&self) -> PathBuf {
+        self.dir.path().join("db.sqlite")
+    }
+}
+
+/// The files making up a sqlite db in WAL mode
+fn db_files(db: &Path) -> [PathBuf; 2] {
+    let mut wal = db.as_os_str().to_owned();
+    wal.push("-wal");
+    [db.to_path_buf(), wal.into()]
+}
+
+/// Returns the size and mtime of the files of this db
+async fn stamp(db: &Path) -> Stamp {
+    let mut result = Vec::new();
+    for file in db_files(db) {
+        let metadata = tokio::fs::metadata(&file).await.ok();
+        result.push(metadata.and_then(|m| Some((m.len(), m.modified().ok()?))));
+    }
+    result
+}
+
+/// A connection opened with the C API of sqlite, closed on drop
+struct RawDb(*mut ffi::sqlite3);
+
+impl RawDb {
+    /// Opens the db at this path with these `SQLITE_OPEN_*` flags
+    fn open(path: &Path, flags: c_int) -> anyhow::Result<RawDb> {
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .with_context(|| format!("{} contains a nul byte", path.display()))?;
+        let mut db = std::ptr::null_mut();
+        // SAFETY: c_path is a valid C string, and db a valid place for the handle
+        let code =
+            unsafe { ffi::sqlite3_open_v2(c_path.as_ptr(), &mut db, flags, std::ptr::null()) };
+        // a handle is allocated even when opening fails
+        let db = RawDb(db);
+        db.check(code)
+            .with_context(|| format!("opening {}", path.display()))?;
+        Ok(db)
+    }
+
+    /// Turns the return code of a call on this connection into a result
+    fn check(&self, code: c_int) -> anyhow::Result<()> {
+        if code == ffi::SQLITE_OK || code == ffi::SQLITE_DONE {
+            return Ok(());
+        }
+        if self.0.is_null() {
+            anyhow::bail!("{}", error_string(code));
+        }
+        // SAFETY: the message is owned by self.0, and copied before the next call
+        let message = unsafe { CStr::from_ptr(ffi::sqlite3_errmsg(self.0)) };
+        anyhow::bail!("sqlite error {}: {}", code, message.to_string_lossy())
+    }
+}
+
+impl Drop for RawDb {
+    fn drop(&mut self) {
+        // SAFETY: opened by sqlite3_open_v2, and the backup using it is finished
+        unsafe { ffi::sqlite3_close(self.0) };
+    }
+}
+
+/// The generic message of this sqlite result code
+fn error_string(code: c_int) -> String {
+    // SAFETY: sqlite3_errstr returns a static string
+    let message = unsafe { CStr::from_ptr(ffi::sqlite3_errstr(code)) };
+    format!("sqlite error {}: {}", code, message.to_string_lossy())
+}
+
+/// Copies the db at `db` to `copy` with the online backup API of sqlite
+fn backup(db: &Path, copy: &Path) -> anyhow::Result<()> {
+    let source = RawDb::open(db, ffi::SQLITE_OPEN_READONLY)?;
+    let dest = RawDb::open(copy, ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE)?;
+    // SAFETY: source.0 is an open connection
+    source.check(unsafe { ffi::sqlite3_busy_timeout(source.0, SNAPSHOT_BUSY_TIMEOUT_MS) })?;
+    let main = CStr::from_bytes_with_nul(b"main\0").expect("nul terminated");
+    // SAFETY: both connections are open, and outlive the backup which is finished below
+    unsafe {
+        let backup = ffi::sqlite3_backup_init(dest.0, main.as_ptr(), source.0, main.as_ptr());
+        if backup.is_null() {
+            // the reason is recorded on the destination
+            return dest.check(ffi::SQLITE_ERROR).context("starting backup");
+        }
+        // all pages in one step, so that the copy is of a single state of the db
+        let step = ffi::sqlite3_backup_step(backup, -1);
+        // records the errors of the backup on the destination
+        let finish = ffi::sqlite3_backup_finish(backup);
+        dest.check(finish)?;
+        anyhow::ensure!(
+            step == ffi::SQLITE_DONE,
+            "backup did not complete: {}",
+            error_string(step)
+        );
+        Ok(())
+    }
+}
+
+/// Copies this db to a new temporary directory in the cache directory.
+///
+/// When there is no WAL, the nix db may not be readable as a WAL db by a user who cannot
+/// create the WAL next to it. Nix is not writing to it then, so the db file is copied instead,
+/// and the copy is discarded if the db changed meanwhile.
+async fn take_snapshot(db: &Path) -> anyhow::Result<Snapshot> {
+    // fails with an io error when the nix db is not readable, see [is_unreadable]
+    std::fs::File::open(db).with_context(|| format!("opening {}", db.display()))?;
+    let snapshots = crate::db::cache_directory()?.join("nixdb");
+    tokio::fs::create_dir_all(&snapshots)
+        .await
+        .with_context(|| format!("creating {}", snapshots.display()))?;
+    let dir = tempfile::Builder::new()
+        .prefix("snapshot")
+        .tempdir_in(&snapshots)
+        .context("creating directory for nix db snapshot")?;
+    let before = stamp(db).await;
+    let db_clone = db.to_path_buf();
+    let copy = dir.path().join("db.sqlite");
+    let backed_up = tokio::task::spawn_blocking(move || backup(&db_clone, &copy))
+        .await
+        .context("joining backup task")?;
+    let [_, wal] = db_files(db);
+    match backed_up {
+        Ok(()) => (),
+        Err(e) if !wal.exists() => {
+            tracing::debug!("{:#}, copying {} instead", e, db.display());
+            let copy = dir.path().join("db.sqlite");
+            // remove what the failed backup left behind
+            tokio::fs::remove_file(&copy).await.ok();
+            tokio::fs::copy(db, &copy)
+                .await
+                .with_context(|| format!("copying {}", db.display()))?;
+            anyhow::ensure!(
+                stamp(db).await == before,
+                "{} changed while copying it",
+                db.display()
+            );
+        }
+        Err(e) => return Err(e).with_context(|| format!("backing up {}", db.display())),
+    }
+    Ok(Snapshot {
+        dir,
+        // changes during the backup may be missing from the copy, so they must be read again
+        stamp: before,
+        schema: None,
+    })
+}
+
+/// Whether this error of [NixDb] means that the nix db cannot be read by this user at all, for
+/// example because it is only readable by root, as opposed to a transient failure.
+pub fn is_unreadable(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        matches!(
+            cause
+                .downcast_ref::<std::io::Error>()
+                .map(std::io::Error::kind),
+            Some(std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::NotFound)
+        )
+    })
+}
+
+/// The store paths of the nix db which are not indexed yet, see [NixDb::get_backlog]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backlog {
+    /// how many there are
+    pub count: u64,
+    /// when the first of them was registered, in seconds since the epoch, if the nix db
+    /// records it
+    pub oldest: Option<i64>,
+    /// when the last indexed store path was registered, likewise
+    pub watermark: Option<i64>,
+}
+
+/// Reads store paths from the nix db.
+///
+/// Cloning this structure returns a structure sharing the same snapshot.
+#[derive(Clone)]
+pub struct NixDb {
+    /// the db we read
+    path: PathBuf,
+    /// the root of the chroot store this db belongs to, if it is not the system store
+    store_root: Option<PathBuf>,
+    /// the latest copy of the db, if any
+    snapshot: Arc<Mutex<Option<Snapshot>>>,
+}
+
+impl Default for NixDb {
+    fn default() -> Self {
+        NixDb::new(Path::new(crate::backend::backend().db()))
+    }
+}
+
+impl NixDb {
+    /// Reads store paths from a nix db at this path
+    pub fn new(path: &Path) -> Self {
+        NixDb {
+            path: path.to_path_buf(),
+            store_root: None,
+            snapshot: Arc::default(),
+        }
+    }
+
+    /// Reads store paths from the nix db of the chroot store at this root.
+    ///
+    /// The returned paths are where they can be read, under the root, see
+    /// [crate::store::set_extra_stores].
+    pub fn of_store_root(root: &Path) -> Self {
+        NixDb {
+            store_root: Some(root.to_path_buf()),
+            ..NixDb::new(&root.join(NIX_DB.trim_start_matches('/')))
+        }
+    }
+
+    /// Starts watching the directory of the nix db for changes, see [NixDb::is_db_file]
+    pub fn watch(&self) -> anyhow::Result<DirWatcher> {
+        let dir = self
+            .path
+            .parent()
+            .context("the nix db has no parent directory")?;
+        DirWatcher::new(dir)
+    }
+
+    /// Whether a file of the directory of the nix db with this name is part of the db, rather
+    /// than its lock or shared memory, which change when the db is only read
+    pub fn is_db_file(&self, name: &OsStr) -> bool {
+        db_files(&self.path)
+            .iter()
+            .any(|file| file.file_name() == Some(name))
+    }
+
+    /// Runs the query built for the layout of an up to date snapshot of the nix db, with this
+    /// first parameter and optionally this second parameter.
+    async fn query(
+        &self,
+        query: impl FnOnce(Schema) -> String,
+        first: Id,
+        second: Option<u32>,
+    ) -> anyhow::Result<Vec<SqliteRow>> {
+        let mut snapshot = self.snapshot.lock().await;
+        let current = stamp(&self.path).await;
+        if snapshot.as_ref().map(|s| &s.stamp) != Some(&current) {
+            *snapshot = Some(
+                take_snapshot(&self.path)
+                    .await
+                    .with_context(|| format!("taking snapshot of {}", self.path.display()))?,
+            );
+        }
+        let snapshot = snapshot.as_mut().expect("snapshot was just taken");
+        let mut db = SqliteConnectOptions::new()
+            .filename(snapshot.db())
+            .connect()
+            .await
+            .context("opening nix db snapshot")?;
+        let schema = match snapshot.schema {
+            Some(schema) => schema,
+            None => {
+                let schema = detect_schema(&mut db)
+                    .await
+                    .with_context(|| format!("reading layout of {}", self.path.display()))?;
+                tracing::debug!("layout of {}: {:?}", self.path.display(), schema);
+                if schema.realisations {
+                    // their outputs are registered in ValidPaths like other store paths
+                    tracing::debug!("nix db supports content-addressed derivations");
+                }
+                snapshot.schema = Some(schema);
+                schema
+            }
+        };
+        let query = query(schema);
+        let mut query = sqlx::query(&query).bind(first);
+        if let Some(second) = second {
+            query = query.bind(second);
+        }
+        let rows = query
+            .fetch_all(&mut db)
+            .await
+            .context("reading nix db snapshot")?;
+        db.close()
+            .await
+            .context("closing nix db snapshot")
+            .or_warn();
+        Ok(rows)
+    }
+
+    /// Runs this query on an up to date snapshot of the nix db, and parses the resulting
+    /// store paths.
+    ///
+    /// The query selects `path` and `id` from `ValidPaths`, and has two parameters: the
+    /// minimal id and the maximal number of rows.
+    async fn query_paths(
+        &self,
+        query: &str,
+        from_id: Id,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(Id, PathBuf)>> {
+        let rows = self
+            .query(|_| query.to_owned(), from_id, Some(limit as u32))
+            .await?;
+        let mut paths = Vec::new();
+        for row in rows {
+            let path: &str = row.try_get("path").context("parsing path in nix db")?;
+            let path = match get_store_path(Path::new(path)) {
+                Some(path) => path,
+                None => anyhow::bail!("invalid store path in nix db: {}", path),
+            };
+            let id: Id = row.try_get("id").context("parsing id in nix db")?;
+            paths.push((id, physical(self.store_root.as_deref(), path)));
+        }
+        Ok(paths)
+    }
+
+    /// Reads the nix db to find new store paths.
+    ///
+    /// New store paths are paths of id greater or equal to `from_id`. At most `limit` paths are
+    /// returned. Ids are increasing in the order paths are registered, unlike registration times
+    /// which can be equal for many paths or go backwards when the clock jumps, so the next id is
+    /// what the cache persists to resume indexing.
+    ///
+    /// Returns the paths with their ids, and the id you should call this function with for the
+    /// "next" paths.
+    pub async fn get_new_store_path_batch(
+        &self,
+        from_id: Id,
+        limit: usize,
+    ) -> anyhow::Result<(Vec<(Id, PathBuf)>, Id)> {
+        let paths = self
+            .query_paths(
+                "select path, id from ValidPaths where id >= $1 order by id asc limit $2",
+                from_id,
+                limit,
+            )
+            .await?;
+        let max_id = paths.iter().map(|(id, _)| *id).max().unwrap_or(0);
+        if (max_id == 0) ^ paths.is_empty() {
+            anyhow::bail!("read paths with id == 0...");
+        }
+        Ok((paths, max_id + 1))
+    }
+
+    /// Returns how many store paths have an id greater or equal to `from_id`, and when the
+    /// paths around this id were registered.
+    pub async fn get_backlog(&self, from_id: Id) -> anyhow::Result<Backlog> {
+        let rows = self
+            .query(
+                |schema| {
+                    let (oldest, watermark) = if schema.registration_time {
+                        (
+                            "(select registrationTime from ValidPaths where id >= $1
+                                order by id asc limit 1)",
+                            "(select registrationTime from ValidPaths where id < $1
+                                order by id desc limit 1)",
+                        )
+                    } else {
+                        ("null", "null")
+                    };
+                    format!(
+                        "select count(*) as n, {} as oldest, {} as watermark from ValidPaths
+                            where id >= $1",
+                        oldest, watermark
+                    )
+                },
+                from_id,
+                None,
+            )
+            .await?;
+        let row = rows
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("counting store paths returned nothing"))?;
+        let count: i64 = row.try_get("n").context("parsing count in nix db")?;
+        let oldest: Option<i64> = row
+            .try_get("oldest")
+            .context("parsing registration time in nix db")?;
+        let watermark: Option<i64> = row
+            .try_get("watermark")
+            .context("parsing registration time in nix db")?;
+        Ok(Backlog {
+            count: count as u64,
+            oldest,
+            watermark,
+        })
+    }
+
+    /// Returns the `limit` most recently registered store paths, among those of id greater or
+    /// equal to `from_id`, most recent first.
+    pub async fn get_latest_store_paths(
+        &self,
+        from_id: Id,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(Id, PathBuf)>> {
+        self.query_paths(
+            "select path, id from ValidPaths where id >= $1 order by id desc limit $2",
+            from_id,
+            limit,
+        )
+        .await
+    }
+}
+
+#[tokio::test]
+async fn test_snapshot_follows_changes() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("db.sqlite");
+    let url = format!("sqlite:{}?mode=rwc", path.display());
+    let pool = sqlx::SqlitePool::connect(&url).await.unwrap();
+    sqlx::query("pragma journal_mode = wal;")
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("create table ValidPaths (id integer primary key, path text not null);")
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("insert into ValidPaths values (1, '/nix/store/aaa-foo');")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let nixdb = NixDb::new(&path);
+    let (paths, next) = nixdb.get_new_store_path_batch(0, 10).await.unwrap();
+    assert_eq!(paths, vec![(1, PathBuf::from("/nix/store/aaa-foo"))]);
+    assert_eq!(next, 2);
+    sqlx::query("insert into ValidPaths values (2, '/nix/store/bbb-bar/bin');")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let (paths, next) = nixdb.get_new_store_path_batch(next, 10).await.unwrap();
+    assert_eq!(paths, vec![(2, PathBuf::from("/nix/store/bbb-bar"))]);
+    assert_eq!(next, 3);
+}
+
+#[tokio::test]
+async fn test_batches_follow_ids() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("db.sqlite");
+    let url = format!("sqlite:{}?mode=rwc", path.display());
+    let pool = sqlx::SqlitePool::connect(&url).await.unwrap();
+    // registration times are irrelevant, even when they are equal or go backwards
+    sqlx::query(
+        "create table ValidPaths (id integer primary key, path text not null,
+            registrationTime integer not null);",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    for (id, time) in [(1, 10), (2, 10), (3, 10), (5, 3), (6, 10)] {
+        sqlx::query("insert into ValidPaths values ($1, $2, $3);")
+            .bind(id)
+            .bind(format!("/nix/store/{id}-foo"))
+            .bind(time)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    let nixdb = NixDb::new(&path);
+    let mut next = 0;
+    let mut all = Vec::new();
+    loop {
+        let (paths, new_next) = nixdb.get_new_store_path_batch(next, 2).await.unwrap();
+        if paths.is_empty() {
+            break;
+        }
+        assert!(paths.len() <= 2);
+        all.extend(paths.into_iter().map(|(_, path)| path));
+        next = new_next;
+    }
+    let expected: Vec<PathBuf> = [1, 2, 3, 5, 6]
+        .iter()
+        .map(|id| PathBuf::from(format!("/nix/store/{id}-foo")))
+        .collect();
+    assert_eq!(all, expected);
+    assert_eq!(next, 7);
+}
+
+#[tokio::test]
+async fn test_latest_store_paths() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("db.sqlite");
+    let url = format!("sqlite:{}?mode=rwc", path.display());
+    let pool = sqlx::SqlitePool::connect(&url).await.unwrap();
+    sqlx::query("create table ValidPaths (id integer primary key, path text not null);")
+        .execute(&pool)
+        .await
+        .unwrap();
+    for id in 1..=5 {
+        sqlx::query("insert into ValidPaths values ($1, $2);")
+            .bind(id)
+            .bind(format!("/nix/store/{id}-foo"))
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    let nixdb = NixDb::new(&path);
+    let ids: Vec<Id> = nixdb
+        .get_latest_store_paths(0, 2)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect();
+    assert_eq!(ids, vec![5, 4]);
+    assert!(nixdb.get_latest_store_paths(6, 2).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_backlog() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("db.sqlite");
+    let url = format!("sqlite:{}?mode=rwc", path.display());
+    let pool = sqlx::SqlitePool::connect(&url).await.unwrap();
+    sqlx::query(
+        "create table ValidPaths (id integer primary key, path text not null,
+            registrationTime integer not null);",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    for id in 1..=5 {
+        sqlx::query("insert into ValidPaths values ($1, $2, $3);")
+            .bind(id)
+            .bind(format!("/nix/store/{id}-foo"))
+            .bind(id * 100)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    let nixdb = NixDb::new(&path);
+    assert_eq!(
+        nixdb.get_backlog(3).await.unwrap(),
+        Backlog {
+            count: 3,
+            oldest: Some(300),
+            watermark: Some(200)
+        }
+    );
+    assert_eq!(
+        nixdb.get_backlog(6).await.unwrap(),
+        Backlog {
+            count: 0,
+            oldest: None,
+            watermark: Some(500)
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_schema_differences() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("db.sqlite");
+    let url = format!("sqlite:{}?mode=rwc", path.display());
+    let pool = sqlx::SqlitePool::connect(&url).await.unwrap();
+    sqlx::query("create table Paths (id integer primary key, path text not null);")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let nixdb = NixDb::new(&path);
+    let error = nixdb.get_new_store_path_batch(0, 10).await.unwrap_err();
+    assert!(format!("{:#}", error).contains("layout is not supported"));
+    // a db without registration times can still be indexed
+    sqlx::query("create table ValidPaths (id integer primary key, path text not null);")
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("insert into ValidPaths values (1, '/nix/store/aaa-foo');")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let (paths, _) = nixdb.get_new_store_path_batch(0, 10).await.unwrap();
+    assert_eq!(paths, vec![(1, PathBuf::from("/nix/store/aaa-foo"))]);
+    assert_eq!(
+        nixdb.get_backlog(0).await.unwrap(),
+        Backlog {
+            count: 1,
+            oldest: None,
+            watermark: None
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_missing_db_is_unreadable() {
+    let dir = TempDir::new().unwrap();
+    let nixdb = NixDb::new(&dir.path().join("db.sqlite"));
+    let error = nixdb.get_new_store_path_batch(0, 10).await.unwrap_err();
+    assert!(is_unreadable(&error));
+}
@@ -0,0 +1,233 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Recording what the commands realising store paths print.
+//!
+//! When fetching a file hangs or fails, `/buildid/<buildid>/log` shows the output of nix for
+//! the store paths of this buildid instead of a bare 404. Only the end of the output of the
+//! most recently realised store paths is kept, in memory.
+
+use std::path::{Path, PathBuf};
+use std::process::{Output, Stdio};
+use std::sync::{Arc, Mutex};
+
+use futures_util::Stream;
+use hashlink::LruCache;
+use once_cell::sync::Lazy;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::watch;
+
+/// For how many store paths the output of realisation is kept
+const MAX_LOGS: usize = 256;
+
+/// How many bytes of output are kept for each store path; older output is dropped
+const MAX_LOG_SIZE: usize = 64 * 1024;
+
+/// What was printed while realising a store path
+#[derive(Debug, Default, Clone)]
+pub struct Log {
+    /// the end of the output
+    text: String,
+    /// how many bytes were written since the log was created, including dropped ones
+    written: usize,
+    /// how many realisations of this store path are running
+    running: usize,
+    /// why the last realisation failed, if it did
+    failure: Option<String>,
+}
+
+impl Log {
+    /// Appends a line, dropping the beginning of the output if it is too long
+    fn push(&mut self, line: &str) {
+        self.text.push_str(line);
+        self.text.push('\n');
+        self.written += line.len() + 1;
+        if self.text.len() > MAX_LOG_SIZE {
+            let mut start = self.text.len() - MAX_LOG_SIZE;
+            while !self.text.is_char_boundary(start) {
+                start += 1;
+            }
+            self.text.drain(..start);
+        }
+    }
+
+    /// Returns what was written after the first `seen` bytes, or all that is left if some of
+    /// it was dropped
+    fn since(&self, seen: usize) -> &str {
+        let dropped = self.written - self.text.len();
+        let mut start = seen.saturating_sub(dropped).min(self.text.len());
+        while !self.text.is_char_boundary(start) {
+            start += 1;
+        }
+        &self.text[start..]
+    }
+}
+
+#[test]
+fn test_log() {
+    let mut log = Log::default();
+    log.push("copying path");
+    assert_eq!(log.since(0), "copying path\n");
+    let seen = log.written;
+    log.push("error: é");
+    assert_eq!(log.since(seen), "error: é\n");
+    let long = "x".repeat(MAX_LOG_SIZE);
+    log.push(&long);
+    assert_eq!(log.text.len(), MAX_LOG_SIZE);
+    assert!(log.since(0).ends_with("xxx\n"));
+    assert_eq!(log.since(log.written), "");
+}
+
+/// The logs of the most recently realised store paths
+static LOGS: Lazy<Mutex<LruCache<PathBuf, Arc<watch::Sender<Log>>>>> =
+    Lazy::new(|| Mutex::new(LruCache::new(MAX_LOGS)));
+
+/// Records the output of a realisation of a store path, which is considered finished when this
+/// is dropped
+pub struct LogWriter(Arc<watch::Sender<Log>>);
+
+impl LogWriter {
+    /// Appends a line to the log
+    pub fn line(&self, line: &str) {
+        self.0.send_modify(|log| log.push(line));
+    }
+
+    /// Records that the realisation failed for this reason, which is appended to the log and
+    /// returned by [failure]
+    pub fn fail(&self, reason: &str) {
+        self.0.send_modify(|log| {
+            log.push(reason);
+            log.failure = Some(reason.to_owned());
+        });
+    }
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        self.0.send_modify(|log| log.running -= 1);
+    }
+}
+
+/// Starts recording a realisation of this store path, after the output of the previous ones
+pub fn start(storepath: &Path) -> LogWriter {
+    let mut logs = LOGS.lock().unwrap();
+    let sender = match logs.get(storepath) {
+        Some(sender) => sender.clone(),
+        None => {
+            let (sender, _) = watch::channel(Log::default());
+            let sender = Arc::new(sender);
+            logs.insert(storepath.to_path_buf(), sender.clone());
+            sender
+        }
+    };
+    drop(logs);
+    sender.send_modify(|log| {
+        log.running += 1;
+        log.failure = None;
+    });
+    LogWriter(sender)
+}
+
+/// Returns the log of the realisations of this store path, if any was recorded
+pub fn subscribe(storepath: &Path) -> Option<watch::Receiver<Log>> {
+    LOGS.lock()
+        .unwrap()
+        .get(storepath)
+        .map(|sender| sender.subscribe())
+}
+
+/// Returns why the last realisation of this store path failed, if it did and its log is still
+/// kept
+pub fn failure(storepath: &Path) -> Option<String> {
+    LOGS.lock()
+        .unwrap()
+        .get(storepath)
+        .and_then(|sender| sender.borrow().failure.clone())
+}
+
+/// Runs this command like [Command::output], recording what it prints on stderr in `log` as
+/// it runs.
+pub async fn output(command: &mut Command, log: &LogWriter) -> std::io::Result<Output> {
+    log.line(&format!("$ {:?}", command.as_std()));
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    let mut child = command.spawn()?;
+    let mut stdout = child.stdout.take().expect("piped stdout");
+    let stderr = child.stderr.take().expect("piped stderr");
+    let read_stdout = async {
+        let mut buffer = Vec::new();
+        stdout.read_to_end(&mut buffer).await.map(|_| buffer)
+    };
+    let read_stderr = async {
+        let mut lines = BufReader::new(stderr).split(b'\n');
+        let mut buffer = Vec::new();
+        while let Some(line) = lines.next_segment().await? {
+            log.line(&String::from_utf8_lossy(&line));
+            buffer.extend_from_slice(&line);
+            buffer.push(b'\n');
+        }
+        Ok(buffer)
+    };
+    let (stdout, stderr) = tokio::try_join!(read_stdout, read_stderr)?;
+    let status = child.wait().await?;
+    log.line(&format!("{}", status));
+    Ok(Output {
+        status,
+        stdout,
+        stderr,
+    })
+}
+
+/// Returns the content of this log, and then what is written to it as it is, until no
+/// realisation runs anymore.
+pub fn follow(receiver: watch::Receiver<Log>) -> impl Stream<Item = String> {
+    futures_util::stream::unfold(Some((receiver, 0)), |state| async move {
+        let (mut receiver, seen) = state?;
+        loop {
+            let (chunk, written, running) = {
+                let log = receiver.borrow_and_update();
+                (log.since(seen).to_owned(), log.written, log.running > 0)
+            };
+            if !running {
+                return Some((chunk, None));
+            }
+            if !chunk.is_empty() {
+                return Some((chunk, Some((receiver, written))));
+            }
+            if receiver.changed().await.is_err() {
+                return None;
+            }
+        }
+    })
+}
+
+#[tokio::test]
+async fn test_output() {
+    use futures_util::StreamExt;
+    let storepath = Path::new("/nix/store/00000000000000000000000000000000-test-output");
+    let log = start(storepath);
+    let receiver = subscribe(storepath).unwrap();
+    let following = tokio::spawn(follow(receiver).collect::<Vec<String>>());
+    let output = output(
+        Command::new("sh").arg("-c").arg("echo out; echo err >&2"),
+        &log,
+    )
+    .await
+    .unwrap();
+    assert_eq!(output.stdout, b"out\n");
+    assert_eq!(output.stderr, b"err\n");
+    assert_eq!(failure(storepath), None);
+    log.fail("error: no substituter can build it");
+    drop(log);
+    assert_eq!(
+        failure(storepath).as_deref(),
+        Some("error: no substituter can build it")
+    );
+    drop(start(storepath));
+    assert_eq!(failure(storepath), None);
+    let followed = following.await.unwrap().concat();
+    assert!(followed.starts_with("$ "));
+    assert!(followed.contains("\nerr\n"));
+    assert!(!followed.contains("\nout\n"));
+}
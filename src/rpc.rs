@@ -0,0 +1,255 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! The envelope of the JSON-RPC 2.0 api served on `POST /rpc`.
+//!
+//! Services like crash ingestion pipelines or symbol servers can call the lookup operations
+//! with structured requests and errors, instead of interpreting the status codes of the
+//! debuginfod http api. The methods themselves are implemented by the server.
+//!
+//! Reference: <https://www.jsonrpc.org/specification>
+
+use std::future::Future;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// How many requests a batch may contain
+const MAX_BATCH_SIZE: usize = 100;
+
+/// The body is not valid json
+const PARSE_ERROR: i64 = -32700;
+/// The json is not a JSON-RPC request
+const INVALID_REQUEST: i64 = -32600;
+/// The method does not exist
+const METHOD_NOT_FOUND: i64 = -32601;
+/// The parameters do not match the method
+const INVALID_PARAMS: i64 = -32602;
+/// The method failed
+const INTERNAL_ERROR: i64 = -32603;
+/// The method exists but the configuration of the server disables it
+const DISABLED: i64 = -32000;
+
+/// A call to a method
+#[derive(Debug, Deserialize)]
+struct Request {
+    /// must be `2.0`
+    jsonrpc: String,
+    /// the name of the method
+    method: String,
+    /// the parameters of the method, by name or position
+    #[serde(default)]
+    params: Value,
+    /// identifies the response to this request; notifications have none and get no response
+    id: Option<Value>,
+}
+
+/// The outcome of a call to a method
+#[derive(Debug, Serialize)]
+struct Response {
+    /// always `2.0`
+    jsonrpc: &'static str,
+    /// what the method returned, if it succeeded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    /// why the method failed, if it did
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<Error>,
+    /// the id of the request
+    id: Value,
+}
+
+/// Why a call failed
+#[derive(Debug, Serialize)]
+pub struct Error {
+    /// a standard error code
+    code: i64,
+    /// a description of the error
+    message: String,
+}
+
+impl Error {
+    /// The parameters do not match what the method expects
+    pub fn invalid_params(message: impl std::fmt::Display) -> Self {
+        Error {
+            code: INVALID_PARAMS,
+            message: message.to_string(),
+        }
+    }
+
+    /// No method has this name
+    pub fn method_not_found(method: &str) -> Self {
+        Error {
+            code: METHOD_NOT_FOUND,
+            message: format!("no method named {:?}", method),
+        }
+    }
+
+    /// The configuration of the server disables this method
+    pub fn disabled(method: &str) -> Self {
+        Error {
+            code: DISABLED,
+            message: format!("method {:?} is disabled on this server", method),
+        }
+    }
+
+    /// The method failed
+    pub fn internal(error: anyhow::Error) -> Self {
+        Error {
+            code: INTERNAL_ERROR,
+            message: format!("{:#}", error),
+        }
+    }
+}
+
+/// Deserializes the parameters of a method
+pub fn params<T: DeserializeOwned>(params: Value) -> Result<T, Error> {
+    serde_json::from_value(params).map_err(Error::invalid_params)
+}
+
+/// Converts the outcome of a method to what [answer] expects
+pub fn result<T: Serialize>(value: anyhow::Result<T>) -> Result<Value, Error> {
+    value
+        .and_then(|value| Ok(serde_json::to_value(value)?))
+        .map_err(Error::internal)
+}
+
+/// Answers one request of a batch, or a single request
+async fn answer_one<F, Fut>(request: Value, call: &F) -> Option<Response>
+where
+    F: Fn(String, Value) -> Fut,
+    Fut: Future<Output = Result<Value, Error>>,
+{
+    let request: Request = match serde_json::from_value(request) {
+        Ok(request) => request,
+        Err(e) => {
+            return Some(Response {
+                jsonrpc: "2.0",
+                result: None,
+                error: Some(Error {
+                    code: INVALID_REQUEST,
+                    message: e.to_string(),
+                }),
+                id: Value::Null,
+            })
+        }
+    };
+    let outcome = if request.jsonrpc == "2.0" {
+        call(request.method, request.params).await
+    } else {
+        Err(Error {
+            code: INVALID_REQUEST,
+            message: format!("unsupported JSON-RPC version {:?}", request.jsonrpc),
+        })
+    };
+    let id = request.id?;
+    let (result, error) = match outcome {
+        Ok(result) => (Some(result), None),
+        Err(error) => (None, Some(error)),
+    };
+    Some(Response {
+        jsonrpc: "2.0",
+        result,
+        error,
+        id,
+    })
+}
+
+/// Answers the JSON-RPC request (or batch of requests) in `body`, calling `call` with the
+/// name and parameters of each method called.
+///
+/// Returns the json of the response, or `None` if only notifications were sent.
+pub async fn answer<F, Fut>(body: &[u8], call: F) -> Option<Value>
+where
+    F: Fn(String, Value) -> Fut,
+    Fut: Future<Output = Result<Value, Error>>,
+{
+    let request: Value = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) => {
+            let response = Response {
+                jsonrpc: "2.0",
+                result: None,
+                error: Some(Error {
+                    code: PARSE_ERROR,
+                    message: e.to_string(),
+                }),
+                id: Value::Null,
+            };
+            return serde_json::to_value(response).ok();
+        }
+    };
+    match request {
+        Value::Array(requests) if requests.len() > MAX_BATCH_SIZE => {
+            let response = Response {
+                jsonrpc: "2.0",
+                result: None,
+                error: Some(Error {
+                    code: INVALID_REQUEST,
+                    message: format!("batches contain at most {} requests", MAX_BATCH_SIZE),
+                }),
+                id: Value::Null,
+            };
+            serde_json::to_value(response).ok()
+        }
+        Value::Array(requests) if !requests.is_empty() => {
+            let mut responses = Vec::new();
+            for request in requests {
+                responses.extend(answer_one(request, &call).await);
+            }
+            if responses.is_empty() {
+                None
+            } else {
+                serde_json::to_value(responses).ok()
+            }
+        }
+        request => serde_json::to_value(answer_one(request, &call).await?).ok(),
+    }
+}
+
+#[tokio::test]
+async fn test_answer() {
+    use serde_json::json;
+    let call = |method: String, params: Value| async move {
+        match method.as_str() {
+            "echo" => Ok(params),
+            _ => Err(Error::method_not_found(&method)),
+        }
+    };
+    let ask = |body: Value| async move { answer(body.to_string().as_bytes(), call).await };
+    assert_eq!(
+        ask(json!({"jsonrpc": "2.0", "method": "echo", "params": [1], "id": 3})).await,
+        Some(json!({"jsonrpc": "2.0", "result": [1], "id": 3}))
+    );
+    assert_eq!(
+        ask(json!({"jsonrpc": "2.0", "method": "nope", "id": "a"}))
+            .await
+            .unwrap()["error"]["code"],
+        json!(METHOD_NOT_FOUND)
+    );
+    // notification
+    assert_eq!(ask(json!({"jsonrpc": "2.0", "method": "echo"})).await, None);
+    assert_eq!(
+        ask(json!([
+            {"jsonrpc": "2.0", "method": "echo", "params": {"a": 1}, "id": 1},
+            {"jsonrpc": "2.0", "method": "echo"},
+            {"foo": "bar"},
+        ]))
+        .await,
+        Some(json!([
+            {"jsonrpc": "2.0", "result": {"a": 1}, "id": 1},
+            {"jsonrpc": "2.0", "error": {"code": INVALID_REQUEST, "message": "missing field `jsonrpc`"}, "id": null},
+        ]))
+    );
+    assert_eq!(
+        answer(b"{", call).await.unwrap()["error"]["code"],
+        json!(PARSE_ERROR)
+    );
+    let batch = vec![json!({"jsonrpc": "2.0", "method": "echo", "id": 1}); MAX_BATCH_SIZE + 1];
+    assert_eq!(
+        ask(Value::Array(batch)).await.unwrap()["error"]["code"],
+        json!(INVALID_REQUEST)
+    );
+}
@@ -0,0 +1,54 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Reading the buildids of the binaries an `rr` trace depends on.
+//!
+//! `rr record` hardlinks or copies the files mapped by the recorded processes into the trace
+//! directory (all of them after `rr pack`), so we find their buildids by looking at the elf
+//! files in the trace.
+
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::store::get_buildid;
+
+/// Returns the buildids of the elf files in this rr trace directory.
+pub fn buildids_in_trace(trace_dir: &Path) -> anyhow::Result<BTreeSet<String>> {
+    if !trace_dir.join("version").is_file() {
+        anyhow::bail!("{} is not an rr trace directory", trace_dir.display());
+    }
+    let mut buildids = BTreeSet::new();
+    for file in walkdir::WalkDir::new(trace_dir) {
+        let file = file.with_context(|| format!("listing rr trace {}", trace_dir.display()))?;
+        if !file.file_type().is_file() {
+            continue;
+        }
+        match get_buildid(file.path()) {
+            Err(e) => tracing::info!("cannot get buildid of {}: {:#}", file.path().display(), e),
+            Ok(Some(buildid)) => {
+                buildids.insert(buildid);
+            }
+            Ok(None) => (),
+        }
+    }
+    Ok(buildids)
+}
+
+#[test]
+fn test_buildids_in_trace() {
+    let dir = tempfile::TempDir::new().unwrap();
+    assert!(buildids_in_trace(dir.path()).is_err());
+    std::fs::write(dir.path().join("version"), "85\n").unwrap();
+    std::fs::write(dir.path().join("data"), "not elf").unwrap();
+    assert!(buildids_in_trace(dir.path()).unwrap().is_empty());
+    let exe = std::env::current_exe().unwrap();
+    std::fs::copy(&exe, dir.path().join("mmap_hardlink_3_test")).unwrap();
+    let buildids = buildids_in_trace(dir.path()).unwrap();
+    assert_eq!(
+        buildids.into_iter().collect::<Vec<_>>(),
+        get_buildid(&exe).unwrap().into_iter().collect::<Vec<_>>()
+    );
+}
@@ -2,11 +2,31 @@
 --
 -- SPDX-License-Identifier: GPL-3.0-only
 
+-- store paths, relative to the store directory
+create table if not exists storepaths (
+  id integer primary key,
+  path text unique not null
+  );
+
+-- buildid is the raw bytes of the buildid
+-- paths are split in a store path, and the path relative to this store path.
+-- paths outside the store have a null store path.
 create table if not exists builds (
-  buildid text unique not null,
+  buildid blob unique not null,
+  executable_storepath int references storepaths(id),
   executable text,
+  debuginfo_storepath int references storepaths(id),
   debuginfo text,
-  source text
+  source_storepath int references storepaths(id),
+  source text,
+  executable_size int,
+  executable_mtime int,
+  debuginfo_size int,
+  debuginfo_mtime int,
+  -- architecture of the executable, like x86_64, if known
+  arch text,
+  -- unix timestamp of the last time a file of this buildid was served
+  last_access int not null default 0
   );
 
 create index if not exists bybuildid on builds(buildid);
@@ -15,4 +35,78 @@ create table if not exists version (version int not null);
 
 create table if not exists gc (timestamp int not null);
 
-create table if not exists id (next int not null);
+-- next id to read in the nix db of each store: the empty string for the system store, or the
+-- root of an extra chroot store
+create table if not exists id (store text primary key, next int not null);
+
+-- ids of store paths in the nix db of this store which were completely indexed, although the
+-- next id to read was not yet advanced past them
+create table if not exists indexed (
+  store text not null,
+  id integer not null,
+  primary key (store, id)
+  );
+
+-- store paths whose closures were completely indexed as the targets of each root given with
+-- `--index-root`, newline separated and sorted, so that a root is only indexed again when it
+-- changes
+create table if not exists roots (
+  store text not null,
+  root text not null,
+  targets text not null,
+  primary key (store, root)
+  );
+
+-- store paths whose indexation failed, for example because their deriver could not be queried,
+-- so that their entries may lack debuginfo or source. They are indexed again with
+-- `--reindex-failed`.
+create table if not exists failed (
+  store text not null,
+  path text not null,
+  error text not null,
+  -- unix timestamp
+  failed_at int not null,
+  primary key (store, path)
+  );
+
+-- store paths indexed after listing them with `nix path-info --all`, when the nix db is not
+-- readable
+create table if not exists listed (path text primary key);
+
+-- store paths reindexed to backfill the debuginfo or executables their entries lack, relative to
+-- the store directory like in storepaths, so that each is only attempted again after a while,
+-- even across restarts
+create table if not exists backfilled (
+  path text primary key,
+  -- unix timestamp of the last attempt
+  attempted_at int not null
+  );
+
+-- CRC-32 of the debuginfo of buildids, as recorded in `.gnu_debuglink` sections. Computed
+-- when debuginfo is looked up by debuglink.
+create table if not exists debuglinks (
+  buildid blob primary key,
+  crc int not null
+  );
+
+-- how often files of each buildid were served, for admins to know which debug outputs are worth
+-- keeping around. Unlike last_access in builds, this survives the removal of the entry.
+create table if not exists hits (
+  buildid blob primary key,
+  -- number of files served
+  count int not null,
+  -- unix timestamps
+  first_served int not null,
+  last_served int not null
+  );
+
+-- buildids whose debuginfo was requested but could not be found, for admins to know which
+-- packages lack separateDebugInfo. Rows are removed when the debuginfo is found.
+create table if not exists misses (
+  buildid blob primary key,
+  -- number of requests
+  count int not null,
+  -- unix timestamps
+  first_requested int not null,
+  last_requested int not null
+  );
@@ -0,0 +1,181 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Running a command with a server available, for ad-hoc debugging sessions.
+//!
+//! If no server listens on the requested address, one is started in the background for the
+//! duration of the command. The command is then run with `DEBUGINFOD_URLS` pointing to the
+//! server, and `NIX_DEBUG_INFO_DIRS` pointing to the debug outputs installed in nix profiles,
+//! which the gdb of nixpkgs reads.
+
+use std::ffi::{OsStr, OsString};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::process::{ExitCode, Stdio};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+
+/// How long we wait for a server we started to listen
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Whether a server listens at this address
+async fn is_listening(address: SocketAddr) -> bool {
+    tokio::net::TcpStream::connect(address).await.is_ok()
+}
+
+/// Starts a server listening on this address in the background, and waits for it to listen.
+///
+/// The server is killed when the returned handle is dropped. Its logs are written to
+/// `shell.log` in the cache directory.
+async fn spawn_server(address: SocketAddr) -> anyhow::Result<tokio::process::Child> {
+    let exe = std::env::current_exe().context("finding our own executable")?;
+    let cache_dir = crate::db::cache_directory()?;
+    std::fs::create_dir_all(&cache_dir)
+        .with_context(|| format!("creating {}", cache_dir.display()))?;
+    let log_path = cache_dir.join("shell.log");
+    let log = std::fs::File::create(&log_path)
+        .with_context(|| format!("creating {}", log_path.display()))?;
+    let mut child = tokio::process::Command::new(exe)
+        .arg("--listen-address")
+        .arg(address.to_string())
+        .stdin(Stdio::null())
+        .stdout(log.try_clone().context("duplicating log file")?)
+        .stderr(log)
+        .kill_on_drop(true)
+        .spawn()
+        .context("starting server")?;
+    let start = Instant::now();
+    while !is_listening(connect_address(address)).await {
+        if let Some(status) = child.try_wait().context("waiting for server")? {
+            anyhow::bail!(
+                "server exited with {} before listening, see {}",
+                status,
+                log_path.display()
+            );
+        }
+        if start.elapsed() > STARTUP_TIMEOUT {
+            anyhow::bail!(
+                "server did not listen on {} after {:?}, see {}",
+                address,
+                STARTUP_TIMEOUT,
+                log_path.display()
+            );
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    tracing::info!(
+        "started server on {}, logs are in {}",
+        address,
+        log_path.display()
+    );
+    Ok(child)
+}
+
+/// Returns the address to connect to a server listening on `address`: the loopback address
+/// when it listens on all addresses.
+fn connect_address(mut address: SocketAddr) -> SocketAddr {
+    if address.ip().is_unspecified() {
+        let loopback: IpAddr = match address {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        };
+        address.set_ip(loopback);
+    }
+    address
+}
+
+#[test]
+fn test_connect_address() {
+    let parse = |s: &str| s.parse::<SocketAddr>().unwrap();
+    assert_eq!(
+        connect_address(parse("0.0.0.0:1949")),
+        parse("127.0.0.1:1949")
+    );
+    assert_eq!(connect_address(parse("[::]:1949")), parse("[::1]:1949"));
+    assert_eq!(
+        connect_address(parse("[fe80::1]:1949")),
+        parse("[fe80::1]:1949")
+    );
+    assert_eq!(connect_address(parse("10.0.0.1:80")), parse("10.0.0.1:80"));
+}
+
+/// Returns the value of `DEBUGINFOD_URLS` with this url added in first position, if absent.
+fn add_debuginfod_url(existing: Option<&OsStr>, url: &str) -> OsString {
+    let existing = existing.map(|s| s.to_string_lossy()).unwrap_or_default();
+    let mut urls = vec![url];
+    urls.extend(
+        existing
+            .split_ascii_whitespace()
+            .filter(|other| other.trim_end_matches('/') != url),
+    );
+    urls.join(" ").into()
+}
+
+#[test]
+fn test_add_debuginfod_url() {
+    assert_eq!(add_debuginfod_url(None, "http://a"), "http://a");
+    assert_eq!(
+        add_debuginfod_url(Some(OsStr::new("http://b  http://a/")), "http://a"),
+        "http://a http://b"
+    );
+}
+
+/// Returns a value for `NIX_DEBUG_INFO_DIRS` listing the debug outputs installed in the
+/// profiles of `NIX_PROFILES`, like `environment.enableDebugInfo` on NixOS.
+fn profile_debug_info_dirs(profiles: Option<&OsStr>) -> Option<OsString> {
+    let profiles = profiles?.to_string_lossy().into_owned();
+    let dirs: Vec<String> = profiles
+        .split_ascii_whitespace()
+        .map(|profile| PathBuf::from(profile).join("lib/debug"))
+        .filter(|dir| dir.is_dir())
+        .map(|dir| dir.display().to_string())
+        .collect();
+    if dirs.is_empty() {
+        None
+    } else {
+        Some(dirs.join(":").into())
+    }
+}
+
+/// Runs this command (or `$SHELL`) with a server listening on this address, starting it if
+/// necessary, and returns the exit code of the command.
+pub async fn run_shell(address: SocketAddr, command: &[OsString]) -> anyhow::Result<ExitCode> {
+    let _server = if is_listening(connect_address(address)).await {
+        tracing::info!("using the server already listening on {}", address);
+        None
+    } else {
+        Some(spawn_server(address).await?)
+    };
+    let (program, args) = match command.split_first() {
+        Some((program, args)) => (program.clone(), args),
+        None => (
+            std::env::var_os("SHELL").unwrap_or_else(|| "sh".into()),
+            &[][..],
+        ),
+    };
+    let mut cmd = tokio::process::Command::new(&program);
+    cmd.args(args);
+    cmd.env(
+        "DEBUGINFOD_URLS",
+        add_debuginfod_url(
+            std::env::var_os("DEBUGINFOD_URLS").as_deref(),
+            &format!("http://{}", connect_address(address)),
+        ),
+    );
+    if std::env::var_os("NIX_DEBUG_INFO_DIRS").is_none() {
+        if let Some(dirs) = profile_debug_info_dirs(std::env::var_os("NIX_PROFILES").as_deref()) {
+            cmd.env("NIX_DEBUG_INFO_DIRS", dirs);
+        }
+    }
+    let status = cmd
+        .status()
+        .await
+        .with_context(|| format!("running {}", program.to_string_lossy()))?;
+    Ok(match status.code() {
+        Some(code) => ExitCode::from(code as u8),
+        // killed by a signal
+        None => ExitCode::FAILURE,
+    })
+}
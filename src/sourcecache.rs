@@ -0,0 +1,181 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! On disk cache of extracted source archives.
+//!
+//! When stepping through a package whose source is a tarball, each source file request would
+//! otherwise decompress the archive again. Instead, the archive is extracted once in the cache
+//! directory, and the least recently used extracted trees are removed.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::cachedir::{evict, touch, Limit};
+use crate::log::ResultExt;
+
+/// How many extracted source trees are kept
+const MAX_EXTRACTED_SOURCES: usize = 16;
+
+/// Creates a temporary directory in `cache_dir` to extract an archive into
+fn extraction_dir(cache_dir: &Path) -> anyhow::Result<tempfile::TempDir> {
+    tempfile::Builder::new()
+        .prefix(".extracting")
+        .tempdir_in(cache_dir)
+        .context("creating temporary directory for extraction")
+}
+
+/// Extracts this archive in `dir` with libarchive
+fn extract_with_libarchive(archive: &Path, dir: &Path) -> anyhow::Result<()> {
+    let file = std::fs::File::open(archive)
+        .with_context(|| format!("opening source archive {}", archive.display()))?;
+    compress_tools::uncompress_archive(file, dir, compress_tools::Ownership::Ignore)
+        .with_context(|| format!("extracting source archive {}", archive.display()))
+}
+
+/// Extracts this tarball in `dir` with `tar`, which supports all the extensions found in real
+/// world tarballs: PAX extended headers, GNU long names, hard links and sparse members.
+fn extract_with_tar(archive: &Path, dir: &Path) -> anyhow::Result<()> {
+    let mut cmd = std::process::Command::new("tar");
+    cmd.arg("--extract")
+        .arg("--no-same-owner")
+        .arg("--no-same-permissions")
+        .arg("--file")
+        .arg(archive)
+        .arg("--directory")
+        .arg(dir)
+        .stdin(std::process::Stdio::null());
+    let out = cmd.output().with_context(|| format!("running {:?}", cmd))?;
+    if !out.status.success() {
+        anyhow::bail!("{:?} failed: {}", cmd, String::from_utf8_lossy(&out.stderr));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_extract_with_tar() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let tree = dir.path().join("tree");
+    let long = "a".repeat(120);
+    std::fs::create_dir_all(tree.join(&long)).unwrap();
+    std::fs::write(tree.join(&long).join("main.c"), "int main;").unwrap();
+    std::fs::hard_link(tree.join(&long).join("main.c"), tree.join("link.c")).unwrap();
+    let archive = dir.path().join("source.tar.gz");
+    let status = std::process::Command::new("tar")
+        .arg("--create")
+        .arg("--gzip")
+        .arg("--format=gnu")
+        .arg("--file")
+        .arg(&archive)
+        .arg("--directory")
+        .arg(&tree)
+        .arg(".")
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let extracted = dir.path().join("extracted");
+    std::fs::create_dir(&extracted).unwrap();
+    extract_with_tar(&archive, &extracted).unwrap();
+    for path in [long.clone() + "/main.c", "link.c".to_owned()] {
+        assert_eq!(std::fs::read(extracted.join(path)).unwrap(), b"int main;");
+    }
+    let cached = extracted_in(&archive, &dir.path().join("cache")).unwrap();
+    assert_eq!(std::fs::read(cached.join("link.c")).unwrap(), b"int main;");
+}
+
+/// Returns a directory containing the extracted content of this source archive, extracting it
+/// in `cache_dir` if necessary.
+///
+/// Archives libarchive fails to extract are extracted with `tar` instead.
+///
+/// Blocking.
+pub fn extracted_in(archive: &Path, cache_dir: &Path) -> anyhow::Result<PathBuf> {
+    let name = archive
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("source archive {} has no name", archive.display()))?;
+    let target = cache_dir.join(name);
+    if target.is_dir() {
+        touch(&target).or_warn();
+        return Ok(target);
+    }
+    std::fs::create_dir_all(cache_dir)
+        .with_context(|| format!("creating {}", cache_dir.display()))?;
+    let mut tmp = extraction_dir(cache_dir)?;
+    if let Err(e) = extract_with_libarchive(archive, tmp.path()) {
+        tracing::info!("{:#}, extracting it with tar instead", e);
+        // start again from an empty directory
+        tmp = extraction_dir(cache_dir)?;
+        extract_with_tar(archive, tmp.path())
+            .with_context(|| format!("extracting source archive {}", archive.display()))?;
+    }
+    let tmp = tmp.into_path();
+    if let Err(e) = std::fs::rename(&tmp, &target) {
+        std::fs::remove_dir_all(&tmp).or_warn();
+        // another request extracted it concurrently
+        if !target.is_dir() {
+            return Err(e).with_context(|| format!("renaming extracted {}", archive.display()));
+        }
+    }
+    evict(
+        cache_dir,
+        Limit::Entries(MAX_EXTRACTED_SOURCES),
+        Some(&target),
+    )
+    .context("evicting extracted sources")
+    .or_warn();
+    Ok(target)
+}
+
+/// Like [extracted_in], in the default cache directory.
+pub fn extracted(archive: &Path) -> anyhow::Result<PathBuf> {
+    extracted_in(archive, &crate::db::cache_directory()?.join("sources"))
+}
+
+/// Returns a command writing a gzipped tarball of this source tree or file to its stdout.
+///
+/// Requires `tar`.
+pub fn tar_command(source: &Path) -> anyhow::Result<tokio::process::Command> {
+    let (dir, member) = if source.is_dir() {
+        (source, std::ffi::OsStr::new("."))
+    } else {
+        match (source.parent(), source.file_name()) {
+            (Some(dir), Some(name)) => (dir, name),
+            _ => anyhow::bail!("cannot archive {}", source.display()),
+        }
+    };
+    let mut cmd = tokio::process::Command::new("tar");
+    cmd.arg("--create")
+        .arg("--gzip")
+        .arg("--directory")
+        .arg(dir)
+        .arg("--")
+        .arg(member)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped());
+    Ok(cmd)
+}
+
+#[test]
+fn test_tar_command() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let file = dir.path().join("foo.patch");
+    std::fs::write(&file, "patch").unwrap();
+    let args = |source: &Path| -> Vec<std::ffi::OsString> {
+        let cmd = tar_command(source).unwrap();
+        cmd.as_std().get_args().map(|arg| arg.to_owned()).collect()
+    };
+    let expected = |member: &str| -> Vec<std::ffi::OsString> {
+        vec![
+            "--create".into(),
+            "--gzip".into(),
+            "--directory".into(),
+            dir.path().into(),
+            "--".into(),
+            member.into(),
+        ]
+    };
+    assert_eq!(args(dir.path()), expected("."));
+    assert_eq!(args(&file), expected("foo.patch"));
+}
@@ -4,44 +4,1194 @@
 
 //! Lower level utilities to query the store.
 
-use crate::db::Entry;
-use crate::log::ResultExt;
+use crate::backend::{
+    backend, guix_closure, guix_derivers, guix_ensure_path, guix_list_store_paths,
+    guix_show_derivations, is_guix,
+};
+use crate::confine::confine;
+use crate::db::{Entry, FileMetadata};
+use crate::log::{LogSampler, ResultExt};
+use crate::realiselog;
+use crate::telemetry::INDEXER;
 use anyhow::Context;
-use object::read::Object;
+use object::read::{Object, ObjectSection};
+use once_cell::sync::OnceCell;
 use once_cell::unsync::Lazy;
+use serde::Serialize;
 use std::{
+    collections::{BTreeSet, HashSet},
     ffi::{OsStr, OsString},
-    os::unix::prelude::{OsStrExt, OsStringExt},
+    os::unix::prelude::{MetadataExt, OsStrExt, OsStringExt},
     path::{Path, PathBuf},
+    process::{Output, Stdio},
     sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
 };
-use tokio::sync::mpsc::Sender;
+use tokio::sync::{mpsc::Sender, Semaphore};
 
 /// Whether nix-store supports --query --valid-derivers (>= 2.18)
 ///
 /// Set by [detect_nix].
 static NIX_STORE_QUERY_VALID_DERIVERS_SUPPORTED: AtomicBool = AtomicBool::new(false);
 
-const NIX_STORE: &'static str = "/nix/store";
+/// Whether `nix-store` is missing, so that the `nix` command must be used instead
+///
+/// Set by [detect_nix].
+static NIX_STORE_MISSING: AtomicBool = AtomicBool::new(false);
+
+/// The directory of the nix store
+pub const NIX_STORE: &str = "/nix/store";
+
+/// The directory of the store of the [backend](crate::backend::set_backend): [NIX_STORE], or
+/// `/gnu/store` for Guix
+pub fn store_dir() -> &'static str {
+    backend().store_dir()
+}
+
+/// Whether the store is read-only and there is neither nix db nor nix daemon, like in
+/// container images made of store paths, so that the files in the store are served without
+/// ever realising anything.
+///
+/// Set by [detect_nix].
+static READ_ONLY_STORE: AtomicBool = AtomicBool::new(false);
+
+/// Whether the store is served without nix, see [detect_nix]
+pub fn is_read_only_store() -> bool {
+    READ_ONLY_STORE.load(Ordering::SeqCst)
+}
+
+/// Whether the mount containing `path` is read-only, according to this content of
+/// `/proc/self/mountinfo`
+fn is_read_only_mount(mountinfo: &str, path: &Path) -> bool {
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let mount_point = unescape_mount_point(fields.nth(4)?);
+            let options = fields.next()?;
+            Some((mount_point, options))
+        })
+        .filter(|(mount_point, _)| path.starts_with(mount_point))
+        // the last of the deepest mounts is the visible one
+        .max_by_key(|(mount_point, _)| mount_point.components().count())
+        .is_some_and(|(_, options)| options.split(',').any(|o| o == "ro"))
+}
+
+/// Decodes the octal escapes, like `\040` for spaces, of a mount point in
+/// `/proc/self/mountinfo`
+fn unescape_mount_point(escaped: &str) -> PathBuf {
+    let bytes = escaped.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes
+            .get(i + 1..i + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        match (bytes[i], octal) {
+            (b'\\', Some(byte)) => {
+                result.push(byte);
+                i += 4;
+            }
+            (byte, _) => {
+                result.push(byte);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(OsString::from_vec(result))
+}
+
+#[test]
+fn test_is_read_only_mount() {
+    let mountinfo = "\
+22 1 0:21 / / rw,relatime - overlay overlay rw
+23 22 0:22 / /nix/store ro,relatime - overlay overlay rw
+24 22 0:23 / /mnt/my\\040disk ro,relatime - ext4 /dev/sda1 rw
+25 23 0:24 / /nix/store rw,relatime - tmpfs tmpfs rw
+";
+    assert!(!is_read_only_mount(mountinfo, Path::new("/nix/var/nix/db")));
+    assert!(is_read_only_mount(
+        &mountinfo.lines().take(2).collect::<Vec<_>>().join("\n"),
+        Path::new("/nix/store/aaa-foo")
+    ));
+    // remounted read-write on top
+    assert!(!is_read_only_mount(
+        mountinfo,
+        Path::new("/nix/store/aaa-foo")
+    ));
+    assert!(is_read_only_mount(mountinfo, Path::new("/mnt/my disk/foo")));
+    assert!(!is_read_only_mount(mountinfo, Path::new("/mnt/my")));
+}
+
+/// Whether the store is on a read-only mount
+fn store_is_read_only() -> bool {
+    match std::fs::read_to_string("/proc/self/mountinfo") {
+        Ok(mountinfo) => is_read_only_mount(&mountinfo, Path::new(store_dir())),
+        Err(e) => {
+            tracing::debug!("cannot read mounts: {:#}", e);
+            false
+        }
+    }
+}
+
+/// Whether only the `nix` command is available, and not `nix-store`
+fn new_cli_only() -> bool {
+    NIX_STORE_MISSING.load(Ordering::SeqCst)
+}
+
+/// The user nix and guix subprocesses run as, if not the user of this process
+///
+/// Set by [set_subprocess_user].
+static SUBPROCESS_USER: OnceCell<SubprocessUser> = OnceCell::new();
+
+/// A user to run subprocesses as, see [set_subprocess_user]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubprocessUser {
+    /// its uid
+    pub uid: u32,
+    /// its primary group
+    pub gid: u32,
+    /// its home directory, if known
+    pub home: Option<PathBuf>,
+}
+
+/// Finds the user named by `spec` in this content of `/etc/passwd`.
+///
+/// `spec` is a user name, a uid, or `uid:gid`. When the primary group is not known, it is
+/// the same number as the uid.
+pub fn parse_subprocess_user(spec: &str, passwd: &str) -> anyhow::Result<SubprocessUser> {
+    let (user, gid) = match spec.split_once(':') {
+        Some((user, gid)) => (
+            user,
+            Some(
+                gid.parse::<u32>()
+                    .with_context(|| format!("invalid gid in {}", spec))?,
+            ),
+        ),
+        None => (spec, None),
+    };
+    let uid = user.parse::<u32>().ok();
+    let entry = passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() < 6 {
+            return None;
+        }
+        let entry_uid = fields[2].parse::<u32>().ok()?;
+        let entry_gid = fields[3].parse::<u32>().ok()?;
+        (fields[0] == user || uid == Some(entry_uid))
+            .then(|| (entry_uid, entry_gid, PathBuf::from(fields[5])))
+    });
+    match (entry, uid) {
+        (Some((uid, entry_gid, home)), _) => Ok(SubprocessUser {
+            uid,
+            gid: gid.unwrap_or(entry_gid),
+            home: Some(home),
+        }),
+        (None, Some(uid)) => Ok(SubprocessUser {
+            uid,
+            gid: gid.unwrap_or(uid),
+            home: None,
+        }),
+        (None, None) => anyhow::bail!("unknown user {}", user),
+    }
+}
+
+#[test]
+fn test_parse_subprocess_user() {
+    let passwd = "root:x:0:0:System administrator:/root:/bin/sh\n\
+        nixseparatedebuginfod:x:990:985::/var/empty:/run/current-system/sw/bin/nologin\n";
+    let user = |uid, gid, home: Option<&str>| SubprocessUser {
+        uid,
+        gid,
+        home: home.map(PathBuf::from),
+    };
+    assert_eq!(
+        parse_subprocess_user("nixseparatedebuginfod", passwd).unwrap(),
+        user(990, 985, Some("/var/empty"))
+    );
+    assert_eq!(
+        parse_subprocess_user("990", passwd).unwrap(),
+        user(990, 985, Some("/var/empty"))
+    );
+    assert_eq!(
+        parse_subprocess_user("990:100", passwd).unwrap(),
+        user(990, 100, Some("/var/empty"))
+    );
+    assert_eq!(
+        parse_subprocess_user("12345", passwd).unwrap(),
+        user(12345, 12345, None)
+    );
+    assert!(parse_subprocess_user("nobody", passwd).is_err());
+    assert!(parse_subprocess_user("990:users", passwd).is_err());
+}
+
+/// Makes nix and guix subprocesses run as this user (see [parse_subprocess_user]), to limit
+/// what a compromised or misbehaving subprocess can do when the server runs as root.
+///
+/// Realising then goes through the nix daemon, so this user must be allowed to use it.
+pub fn set_subprocess_user(spec: &str) -> anyhow::Result<()> {
+    let passwd = std::fs::read_to_string("/etc/passwd").unwrap_or_default();
+    let user = parse_subprocess_user(spec, &passwd)?;
+    tracing::info!("running subprocesses as uid {} gid {}", user.uid, user.gid);
+    SUBPROCESS_USER.set(user).ok();
+    Ok(())
+}
+
+/// A command running `program`, as the user set by [set_subprocess_user] if any.
+///
+/// All nix and guix subprocesses are created with this.
+pub fn store_command(program: &str) -> std::process::Command {
+    use std::os::unix::process::CommandExt;
+    let mut cmd = std::process::Command::new(program);
+    if let Some(user) = SUBPROCESS_USER.get() {
+        // supplementary groups are dropped too
+        cmd.uid(user.uid).gid(user.gid);
+        if let Some(home) = &user.home {
+            cmd.env("HOME", home);
+        }
+    }
+    cmd
+}
+
+/// Makes this command run without network, in a new network namespace whose only interface
+/// is a loopback which is down. Unix sockets, like the one of the nix daemon, still work.
+///
+/// For commands which only read the store, which have no reason to reach the network.
+/// Without the privilege to create a network namespace, a user namespace is created too,
+/// mapping only the user of the command to itself. When neither is possible (for example
+/// when unprivileged user namespaces are disabled), the command runs with the network.
+fn offline(mut cmd: std::process::Command) -> std::process::Command {
+    use std::os::unix::process::CommandExt;
+    let (uid, gid) = match SUBPROCESS_USER.get() {
+        Some(user) => (user.uid, user.gid),
+        // SAFETY: no preconditions
+        None => unsafe { (libc::getuid(), libc::getgid()) },
+    };
+    // allocated before forking
+    let uid_map = format!("{uid} {uid} 1");
+    let gid_map = format!("{gid} {gid} 1");
+    // SAFETY: the closure only makes system calls, which are async-signal-safe
+    unsafe {
+        cmd.pre_exec(move || {
+            if libc::unshare(libc::CLONE_NEWNET) == 0 {
+                return Ok(());
+            }
+            // after a change of uid, /proc/self is only writable by root otherwise
+            libc::prctl(libc::PR_SET_DUMPABLE, 1, 0, 0, 0);
+            if libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNET) == 0 {
+                write_proc_file(b"/proc/self/uid_map\0", uid_map.as_bytes());
+                write_proc_file(b"/proc/self/setgroups\0", b"deny");
+                write_proc_file(b"/proc/self/gid_map\0", gid_map.as_bytes());
+            }
+            Ok(())
+        });
+    }
+    cmd
+}
+
+/// Writes `content` to the file at this nul terminated path, ignoring failures.
+///
+/// Only makes system calls, so that it can run between fork and exec.
+fn write_proc_file(path: &[u8], content: &[u8]) {
+    // SAFETY: path is nul terminated, and content valid for reads of its length
+    unsafe {
+        let fd = libc::open(path.as_ptr().cast(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd >= 0 {
+            libc::write(fd, content.as_ptr().cast(), content.len());
+            libc::close(fd);
+        }
+    }
+}
+
+#[test]
+fn test_offline() {
+    let output = offline(std::process::Command::new("id"))
+        .arg("-u")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    // the user is the same in the user namespace, if one was created
+    // SAFETY: no preconditions
+    let uid = unsafe { libc::getuid() };
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        uid.to_string()
+    );
+}
+
+/// `nix` with the `nix-command` experimental feature enabled
+fn nix_command() -> std::process::Command {
+    let mut cmd = store_command("nix");
+    cmd.arg("--extra-experimental-features").arg("nix-command");
+    cmd
+}
+
+/// A command behaving as `nix-store`.
+///
+/// For the few operations which have no equivalent in the new CLI, like `--restore`. When
+/// `nix-store` is missing, this relies on `nix` behaving as `nix-store` when invoked under this
+/// name.
+pub fn nix_store_command() -> std::process::Command {
+    if new_cli_only() {
+        use std::os::unix::process::CommandExt;
+        let mut cmd = store_command("nix");
+        cmd.arg0("nix-store");
+        cmd
+    } else {
+        store_command("nix-store")
+    }
+}
+
+/// A binary cache that [realise] copies missing store paths from before falling back to the
+/// substituters of the nix configuration
+///
+/// Set by [set_realise_from].
+static REALISE_FROM: OnceCell<String> = OnceCell::new();
+
+/// Makes [realise] copy missing store paths from this binary cache with `nix copy --from`
+/// first.
+///
+/// Should be called on startup.
+pub fn set_realise_from(url: String) {
+    if REALISE_FROM.set(url).is_err() {
+        tracing::warn!("binary cache to realise store paths from was already set");
+    }
+}
+
+/// How long nix and guix subprocesses may run by default before being killed
+const DEFAULT_SUBPROCESS_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How many store paths may be realised at the same time by default
+const DEFAULT_MAX_REALISATIONS: usize = 4;
+
+/// How long nix and guix subprocesses may run, see [set_subprocess_timeout]
+static SUBPROCESS_TIMEOUT: OnceCell<Duration> = OnceCell::new();
+
+/// Permits to realise store paths, see [set_max_realisations]
+static REALISATION_SLOTS: OnceCell<Semaphore> = OnceCell::new();
+
+/// Kills nix and guix subprocesses which run for longer than this, instead of
+/// [DEFAULT_SUBPROCESS_TIMEOUT]. The timeout is a [TemporaryFailure].
+pub fn set_subprocess_timeout(timeout: Duration) {
+    SUBPROCESS_TIMEOUT
+        .set(timeout)
+        .expect("the subprocess timeout was already set");
+}
+
+/// How long nix and guix subprocesses may run
+fn subprocess_timeout() -> Duration {
+    SUBPROCESS_TIMEOUT
+        .get()
+        .copied()
+        .unwrap_or(DEFAULT_SUBPROCESS_TIMEOUT)
+}
+
+/// Lets [realise] run at most this many realisations at the same time instead of
+/// [DEFAULT_MAX_REALISATIONS]. Others wait for their turn.
+pub fn set_max_realisations(max: usize) {
+    if REALISATION_SLOTS.set(Semaphore::new(max.max(1))).is_err() {
+        tracing::warn!("the maximum number of realisations was already set");
+    }
+}
+
+/// Permits to realise store paths
+fn realisation_slots() -> &'static Semaphore {
+    REALISATION_SLOTS.get_or_init(|| Semaphore::new(DEFAULT_MAX_REALISATIONS))
+}
+
+/// Error of a subprocess killed after running for longer than [set_subprocess_timeout]
+fn timed_out(what: &str) -> anyhow::Error {
+    TemporaryFailure(format!(
+        "{} timed out after {}s",
+        what,
+        subprocess_timeout().as_secs()
+    ))
+    .into()
+}
+
+/// Runs this command like [std::process::Command::output], but kills it if it runs for longer
+/// than [set_subprocess_timeout].
+pub fn command_output(cmd: &mut std::process::Command) -> anyhow::Result<Output> {
+    use std::io::Read;
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("running {:?}", cmd))?;
+    // read both pipes while waiting, so that the child does not block on a full pipe
+    fn read_all<R: Read + Send + 'static>(
+        pipe: Option<R>,
+    ) -> std::thread::JoinHandle<std::io::Result<Vec<u8>>> {
+        std::thread::spawn(move || {
+            let mut buffer = Vec::new();
+            if let Some(mut pipe) = pipe {
+                pipe.read_to_end(&mut buffer)?;
+            }
+            Ok(buffer)
+        })
+    }
+    let stdout = read_all(child.stdout.take());
+    let stderr = read_all(child.stderr.take());
+    let deadline = Instant::now() + subprocess_timeout();
+    // most queries return within milliseconds
+    let mut poll = Duration::from_millis(1);
+    let status = loop {
+        match child
+            .try_wait()
+            .with_context(|| format!("waiting for {:?}", cmd))?
+        {
+            Some(status) => break status,
+            None if Instant::now() >= deadline => {
+                child.kill().ok();
+                child.wait().ok();
+                return Err(timed_out(&format!("{:?}", cmd)));
+            }
+            None => {
+                std::thread::sleep(poll);
+                poll = (poll * 2).min(Duration::from_millis(100));
+            }
+        }
+    };
+    let join = |reader: std::thread::JoinHandle<std::io::Result<Vec<u8>>>| match reader.join() {
+        Ok(read) => read.with_context(|| format!("reading output of {:?}", cmd)),
+        Err(_) => anyhow::bail!("reading output of {:?} panicked", cmd),
+    };
+    Ok(Output {
+        status,
+        stdout: join(stdout)?,
+        stderr: join(stderr)?,
+    })
+}
+
+#[test]
+fn test_command_output() {
+    let output = command_output(
+        std::process::Command::new("sh")
+            .arg("-c")
+            .arg("echo out; echo err >&2; exit 3"),
+    )
+    .unwrap();
+    assert_eq!(output.stdout, b"out\n");
+    assert_eq!(output.stderr, b"err\n");
+    assert_eq!(output.status.code(), Some(3));
+    assert!(command_output(&mut std::process::Command::new("/nonexistent")).is_err());
+}
+
+/// Runs this command like [tokio::process::Command::output], but kills it if it runs for
+/// longer than [set_subprocess_timeout], or if the returned future is dropped, for example
+/// because the client which requested a file disconnected.
+pub async fn command_output_async(command: &mut tokio::process::Command) -> anyhow::Result<Output> {
+    command.kill_on_drop(true);
+    match tokio::time::timeout(subprocess_timeout(), command.output()).await {
+        Ok(output) => output.with_context(|| format!("running {:?}", command)),
+        Err(_) => Err(timed_out(&format!("{:?}", command))),
+    }
+}
+
+/// Runs this realisation command like [realiselog::output], waiting for one of the
+/// [set_max_realisations] permits first, and with the same timeout as [command_output_async].
+async fn realisation_output(
+    command: &mut tokio::process::Command,
+    log: &realiselog::LogWriter,
+) -> anyhow::Result<Output> {
+    let _permit = realisation_slots()
+        .acquire()
+        .await
+        .context("waiting for a realisation slot")?;
+    command.kill_on_drop(true);
+    match tokio::time::timeout(subprocess_timeout(), realiselog::output(command, log)).await {
+        Ok(output) => output.with_context(|| format!("running {:?}", command)),
+        Err(_) => {
+            let e = timed_out(&format!("{:?}", command));
+            log.line(&format!("{:#}", e));
+            Err(e)
+        }
+    }
+}
+
+/// The last line nix printed as an error on this stderr, like
+/// `error: path '/nix/store/...' is required, but there is no substituter that can build it`
+fn last_error_line(stderr: &str) -> Option<&str> {
+    stderr
+        .lines()
+        .rev()
+        .map(str::trim)
+        .find(|line| line.starts_with("error:"))
+}
+
+#[test]
+fn test_last_error_line() {
+    assert_eq!(
+        last_error_line("copying path\nwarning: foo\nerror: no substituter\n  at foo\n"),
+        Some("error: no substituter")
+    );
+    assert_eq!(last_error_line("copying path\n"), None);
+}
+
+/// Roots of the chroot stores indexed in addition to the system store, like
+/// `~/.local/share/nix/root` for rootless nix. The store of root `ROOT` is in `ROOT/nix/store`,
+/// and nix knows its paths as if they were in `/nix/store`.
+///
+/// Set by [set_extra_stores].
+static EXTRA_STORES: OnceCell<Vec<PathBuf>> = OnceCell::new();
+
+/// Also recognizes the paths of the chroot stores with these roots as store paths, and
+/// queries and realises them with `--store ROOT`.
+///
+/// Should be called on startup.
+pub fn set_extra_stores(roots: Vec<PathBuf>) {
+    if EXTRA_STORES.set(roots).is_err() {
+        tracing::warn!("extra stores were already set");
+    }
+}
+
+/// The roots set by [set_extra_stores]
+pub fn extra_stores() -> &'static [PathBuf] {
+    EXTRA_STORES.get().map(Vec::as_slice).unwrap_or_default()
+}
+
+/// Whether the store directory of the system exists. Rootless nix installs, like nix-portable,
+/// have none, and only a chroot store under the home directory.
+pub fn has_system_store() -> bool {
+    Path::new(store_dir()).is_dir()
+}
+
+/// Lists the roots of the chroot stores of rootless nix installs of the current user, reading
+/// environment variables with `var`: `$NIX_USER_STORE`, nix-portable's (in `$NP_LOCATION` or
+/// the home directory) and the one nix itself uses when `/nix` cannot be created
+/// (`~/.local/share/nix/root`).
+///
+/// Only roots containing a `nix/store` directory are returned.
+pub fn user_store_roots(var: impl Fn(&str) -> Option<OsString>) -> Vec<PathBuf> {
+    let home = var("HOME").map(PathBuf::from);
+    let mut candidates = Vec::new();
+    candidates.extend(var("NIX_USER_STORE").map(PathBuf::from));
+    match var("NP_LOCATION") {
+        Some(location) => candidates.push(PathBuf::from(location).join(".nix-portable")),
+        None => candidates.extend(home.as_ref().map(|home| home.join(".nix-portable"))),
+    }
+    match var("XDG_DATA_HOME") {
+        Some(data) => candidates.push(PathBuf::from(data).join("nix/root")),
+        None => candidates.extend(home.as_ref().map(|home| home.join(".local/share/nix/root"))),
+    }
+    let mut roots: Vec<PathBuf> = Vec::new();
+    for candidate in candidates {
+        if candidate.join("nix/store").is_dir() && !roots.contains(&candidate) {
+            roots.push(candidate);
+        }
+    }
+    roots
+}
+
+#[test]
+fn test_user_store_roots() {
+    let home = tempfile::tempdir().unwrap();
+    let home = home.path().to_owned();
+    let env = |vars: Vec<(&'static str, OsString)>| {
+        move |name: &str| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.clone())
+        }
+    };
+    let with_home = || vec![("HOME", home.clone().into_os_string())];
+    assert!(user_store_roots(env(with_home())).is_empty());
+    std::fs::create_dir_all(home.join(".local/share/nix/root/nix/store")).unwrap();
+    std::fs::create_dir_all(home.join(".nix-portable/nix/store")).unwrap();
+    assert_eq!(
+        user_store_roots(env(with_home())),
+        vec![
+            home.join(".nix-portable"),
+            home.join(".local/share/nix/root")
+        ]
+    );
+    let mut vars = with_home();
+    vars.push((
+        "NIX_USER_STORE",
+        home.join(".nix-portable").into_os_string(),
+    ));
+    vars.push(("XDG_DATA_HOME", home.join("data").into_os_string()));
+    assert_eq!(
+        user_store_roots(env(vars)),
+        vec![home.join(".nix-portable")]
+    );
+}
+
+/// Whether this directory is the system store or the store of an
+/// [extra store](set_extra_stores)
+fn is_store_dir(dir: &Path) -> bool {
+    dir.as_os_str() == store_dir()
+        || extra_stores()
+            .iter()
+            .any(|root| dir.strip_prefix(root).ok() == Some(Path::new("nix/store")))
+}
+
+/// Splits a path of an [extra store](set_extra_stores) into the root of this store and the
+/// path nix knows it by, in `/nix/store`.
+///
+/// Other paths are returned unchanged, without root.
+pub fn split_store_root(path: &Path) -> (Option<&'static Path>, PathBuf) {
+    for root in extra_stores() {
+        if let Ok(rest) = path.strip_prefix(root) {
+            if rest.starts_with(NIX_STORE.trim_start_matches('/')) {
+                return (Some(root.as_path()), Path::new("/").join(rest));
+            }
+        }
+    }
+    (None, path.to_owned())
+}
+
+/// Where the path nix knows as `logical` in the store of this root can be read, see
+/// [split_store_root]
+pub fn physical(root: Option<&Path>, logical: &Path) -> PathBuf {
+    match (root, logical.strip_prefix("/")) {
+        (Some(root), Ok(relative)) => root.join(relative),
+        _ => logical.to_owned(),
+    }
+}
+
+#[test]
+fn test_split_store_root() {
+    let _ = EXTRA_STORES.set(vec![PathBuf::from("/home/alice/.local/share/nix/root")]);
+    let path = Path::new("/home/alice/.local/share/nix/root/nix/store/aaa-foo/bin/foo");
+    let (root, logical) = split_store_root(path);
+    assert_eq!(root, Some(Path::new("/home/alice/.local/share/nix/root")));
+    assert_eq!(logical, Path::new("/nix/store/aaa-foo/bin/foo"));
+    assert_eq!(physical(root, &logical), path);
+    assert_eq!(
+        get_store_path(path),
+        Some(Path::new(
+            "/home/alice/.local/share/nix/root/nix/store/aaa-foo"
+        ))
+    );
+    let system = Path::new("/nix/store/aaa-foo");
+    assert_eq!(split_store_root(system), (None, system.to_owned()));
+    assert_eq!(physical(None, system), system);
+}
+
+/// Arguments making a nix command operate on the store of this root instead of the system
+/// store
+fn store_args(root: Option<&Path>) -> Vec<&OsStr> {
+    match root {
+        Some(root) => vec!["--store".as_ref(), root.as_os_str()],
+        None => Vec::new(),
+    }
+}
+
+/// Names of outputs containing separate debug info, in addition to `debug`
+///
+/// Set by [set_debug_output_names].
+static DEBUG_OUTPUT_NAMES: OnceCell<Vec<String>> = OnceCell::new();
+
+/// Also considers outputs with these names, like `debugsym`, as debug outputs.
+///
+/// Should be called on startup.
+pub fn set_debug_output_names(names: Vec<String>) {
+    if DEBUG_OUTPUT_NAMES.set(names).is_err() {
+        tracing::warn!("names of debug outputs were already set");
+    }
+}
+
+/// Whether the name of this store path ends with `-<name>` for one of these output names
+fn has_output_name<'a>(storepath: &Path, mut names: impl Iterator<Item = &'a str>) -> bool {
+    let path = storepath.as_os_str().as_bytes();
+    names.any(|name| {
+        path.strip_suffix(name.as_bytes())
+            .is_some_and(|rest| rest.ends_with(b"-"))
+    })
+}
+
+#[test]
+fn test_has_output_name() {
+    let names = || ["debug", "debugsym"].into_iter();
+    assert!(has_output_name(
+        Path::new("/nix/store/aaa-foo-debug"),
+        names()
+    ));
+    assert!(has_output_name(
+        Path::new("/nix/store/aaa-foo-debugsym"),
+        names()
+    ));
+    assert!(!has_output_name(
+        Path::new("/nix/store/aaa-foo-nodebug"),
+        names()
+    ));
+    assert!(!has_output_name(Path::new("/nix/store/aaa-foo"), names()));
+}
+
+/// Whether this store path is a debug output, according to its name
+pub fn is_debug_output(storepath: &Path) -> bool {
+    let extra = DEBUG_OUTPUT_NAMES.get().into_iter().flatten();
+    has_output_name(
+        storepath,
+        std::iter::once("debug").chain(extra.map(String::as_str)),
+    )
+}
+
+/// Which files are examined when looking for executables in a store path
+///
+/// Skipping some files trades a little completeness for faster indexing of store paths with
+/// many files which are not executables, like documentation or icons.
+#[derive(Debug, Clone, Default)]
+pub struct WalkFilter {
+    /// files smaller than this many bytes are skipped
+    pub min_size: u64,
+    /// files with these extensions (without dot) are skipped
+    pub skip_extensions: Vec<String>,
+    /// if not empty, only files in these top-level directories of store paths are examined
+    pub only_dirs: Vec<String>,
+    /// what to do with symlinks
+    pub symlinks: SymlinkPolicy,
+}
+
+/// What [index_store_path] does with symlinks found in store paths
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SymlinkPolicy {
+    /// ignore symlinks
+    #[default]
+    Ignore,
+    /// walk symlinks pointing into the store as if the files were in the store path. The
+    /// files found this way are assumed to come from the same derivation.
+    Follow,
+    /// also index the store paths that symlinks point to
+    Register,
+}
+
+impl WalkFilter {
+    /// Whether to walk into this entry, found in `storepath`
+    fn descend(&self, storepath: &Path, entry: &walkdir::DirEntry) -> bool {
+        if self.symlinks == SymlinkPolicy::Follow
+            && entry.path_is_symlink()
+            && symlink_target_store_path(entry.path()).is_none()
+        {
+            return false;
+        }
+        if entry.depth() != 1 || self.only_dirs.is_empty() {
+            return true;
+        }
+        let name = entry.file_name().to_string_lossy();
+        let accepted = self.only_dirs.iter().any(|dir| *dir == name);
+        if !accepted {
+            tracing::trace!("not indexing {} in {}", name, storepath.display());
+        }
+        accepted
+    }
+
+    /// Whether to look for a buildid in this file
+    fn examine(&self, entry: &walkdir::DirEntry) -> bool {
+        let path = entry.path();
+        if let Some(extension) = path.extension() {
+            let extension = extension.to_string_lossy();
+            if self
+                .skip_extensions
+                .iter()
+                .any(|skipped| skipped.trim_start_matches('.') == extension)
+            {
+                return false;
+            }
+        }
+        if self.min_size > 0 {
+            match entry.metadata() {
+                Ok(metadata) if metadata.len() < self.min_size => return false,
+                _ => (),
+            }
+        }
+        true
+    }
+}
+
+#[test]
+fn test_walk_filter_follow_outside_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let outside = tempfile::tempdir().unwrap();
+    std::fs::write(outside.path().join("foo"), b"foo").unwrap();
+    std::os::unix::fs::symlink(outside.path(), dir.path().join("lib")).unwrap();
+    let filter = WalkFilter {
+        symlinks: SymlinkPolicy::Follow,
+        ..Default::default()
+    };
+    let examined = walkdir::WalkDir::new(dir.path())
+        .follow_links(true)
+        .into_iter()
+        .filter_entry(|entry| filter.descend(dir.path(), entry))
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .count();
+    assert_eq!(examined, 0);
+}
+
+/// The store path containing the target of this symlink, if it points into the store
+fn symlink_target_store_path(link: &Path) -> Option<PathBuf> {
+    let target = std::fs::canonicalize(link).ok()?;
+    get_store_path(&target).map(Path::to_owned)
+}
+
+/// The filter applied by [index_store_path]
+///
+/// Set by [set_walk_filter].
+static WALK_FILTER: OnceCell<WalkFilter> = OnceCell::new();
+
+/// Makes [index_store_path] skip files according to this filter.
+///
+/// Should be called on startup.
+pub fn set_walk_filter(filter: WalkFilter) {
+    if WALK_FILTER.set(filter).is_err() {
+        tracing::warn!("filter of indexed files was already set");
+    }
+}
+
+#[test]
+fn test_walk_filter() {
+    let dir = tempfile::tempdir().unwrap();
+    for (path, size) in [
+        ("bin/foo", 100),
+        ("bin/small", 1),
+        ("lib/libfoo.so", 100),
+        ("lib/python3/foo.py", 100),
+        ("share/doc/foo.html", 100),
+    ] {
+        let path = dir.path().join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, vec![0u8; size]).unwrap();
+    }
+    let filter = WalkFilter {
+        min_size: 10,
+        skip_extensions: vec![".py".to_owned(), "html".to_owned()],
+        only_dirs: vec!["bin".to_owned(), "lib".to_owned()],
+        symlinks: SymlinkPolicy::Ignore,
+    };
+    let mut examined: Vec<PathBuf> = walkdir::WalkDir::new(dir.path())
+        .into_iter()
+        .filter_entry(|entry| filter.descend(dir.path(), entry))
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file() && filter.examine(entry))
+        .map(|entry| entry.path().strip_prefix(dir.path()).unwrap().to_owned())
+        .collect();
+    examined.sort();
+    assert_eq!(
+        examined,
+        vec![PathBuf::from("bin/foo"), PathBuf::from("lib/libfoo.so")]
+    );
+}
+
+/// Error of [realise] when substitution failed for a reason that may go away, like the network
+/// or a binary cache being down
+#[derive(Debug)]
+pub struct TemporaryFailure(pub String);
+
+impl std::fmt::Display for TemporaryFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TemporaryFailure {}
+
+/// Whether this error, or one of its causes, is a [TemporaryFailure]
+pub fn is_temporary(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<TemporaryFailure>().is_some()
+}
+
+/// Whether the stderr of a failed `nix-store --realise` or `nix copy` shows that a binary
+/// cache could not be reached, rather than that the path is not in any binary cache
+fn is_download_failure(stderr: &str) -> bool {
+    stderr.contains("unable to download")
+        || stderr.contains("Couldn't resolve host name")
+        || stderr.contains("Couldn't connect to server")
+        || stderr.contains("Timeout was reached")
+}
+
+#[test]
+fn test_is_download_failure() {
+    assert!(is_download_failure("warning: error: unable to download 'https://cache.nixos.org/0123.narinfo': Couldn't resolve host name (6); retrying in 281 ms\n"));
+    assert!(!is_download_failure(
+        "error: path '/nix/store/0123-foo' is required, but there is no substituter that can build it\n"
+    ));
+}
+
+/// Whether this file is in the store.
+///
+/// With libstore, the store path containing it must also be valid: a store path being
+/// substituted may already exist partially.
+pub async fn is_present(path: &Path) -> bool {
+    #[cfg(feature = "libstore")]
+    if let (Some(store), Some(storepath), (None, _)) = (
+        crate::libstore::store(),
+        get_store_path(path),
+        split_store_root(path),
+    ) {
+        let storepath = storepath.to_owned();
+        match tokio::task::spawn_blocking(move || store.is_valid_path(&storepath)).await {
+            Ok(Ok(false)) => return false,
+            Ok(Ok(true)) => (),
+            Ok(Err(e)) => tracing::debug!("{:#}", e),
+            Err(e) => tracing::debug!("checking validity with libstore: {:#}", e),
+        }
+    }
+    tokio::fs::metadata(path).await.is_ok()
+}
+
+/// Space left free on the store filesystem in addition to what substituting a store path takes,
+/// for the database and temporary files of nix
+const DISK_SPACE_MARGIN: u64 = 64 * 1024 * 1024;
+
+/// Parses how many bytes substituting store paths would take once unpacked, from what
+/// `nix-store --realise --dry-run` prints, like
+/// `these 2 paths will be fetched (12.50 MiB download, 60.25 MiB unpacked):`
+fn parse_dry_run_unpacked_size(stderr: &str) -> Option<u64> {
+    let start = stderr.find("download, ")? + "download, ".len();
+    let rest = &stderr[start..];
+    let mib: f64 = rest[..rest.find(" MiB unpacked")?].parse().ok()?;
+    Some((mib * 1024.0 * 1024.0) as u64)
+}
+
+#[test]
+fn test_parse_dry_run_unpacked_size() {
+    assert_eq!(
+        parse_dry_run_unpacked_size(
+            "these 2 paths will be fetched (12.50 MiB download, 60.25 MiB unpacked):\n  /nix/store/aaa-foo-debug\n"
+        ),
+        Some(63176704)
+    );
+    assert_eq!(
+        parse_dry_run_unpacked_size(
+            "this path will be fetched (0.05 MiB download, 0.20 MiB unpacked):\n"
+        ),
+        Some(209715)
+    );
+    assert_eq!(parse_dry_run_unpacked_size(""), None);
+}
+
+/// Returns how many bytes unprivileged users can still write on the filesystem of `path`
+fn available_space(path: &Path) -> anyhow::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .with_context(|| format!("{} contains a nul byte", path.display()))?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: c_path is a valid C string, and stat is written by statvfs when it succeeds
+    let stat = unsafe {
+        if libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("statvfs {}", path.display()));
+        }
+        stat.assume_init()
+    };
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[test]
+fn test_available_space() {
+    assert!(available_space(Path::new("/")).is_ok());
+    assert!(available_space(Path::new("/nonexistent/path")).is_err());
+}
+
+/// Fails if substituting this store path (of the store at `root`) would not fit on the store
+/// filesystem, according to `nix-store --realise --dry-run`, so that nix does not fail halfway
+/// through.
+///
+/// The check is skipped when its size cannot be determined.
+async fn check_disk_space(root: Option<&Path>, logical: &Path) -> anyhow::Result<()> {
+    let mut command = tokio::process::Command::from(nix_store_command());
+    command
+        .args(store_args(root))
+        .arg("--realise")
+        .arg("--dry-run")
+        .arg(logical);
+    let needed = match command_output_async(&mut command).await {
+        Ok(output) => match parse_dry_run_unpacked_size(&String::from_utf8_lossy(&output.stderr)) {
+            Some(needed) => needed,
+            None => return Ok(()),
+        },
+        Err(e) => {
+            tracing::debug!("running {:?}: {:#}", command, e);
+            return Ok(());
+        }
+    };
+    let store = physical(root, Path::new(store_dir()));
+    let available = match tokio::task::spawn_blocking(move || available_space(&store)).await? {
+        Ok(available) => available,
+        Err(e) => {
+            tracing::debug!("{:#}", e);
+            return Ok(());
+        }
+    };
+    if needed.saturating_add(DISK_SPACE_MARGIN) > available {
+        return Err(TemporaryFailure(format!(
+            "not enough disk space to substitute {}: it needs {} MiB, but only {} MiB are available in the store",
+            logical.display(),
+            needed / 1024 / 1024,
+            available / 1024 / 1024
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Whether this missing store path could be substituted again from a binary cache, according
+/// to `nix-store --realise --dry-run`.
+///
+/// If a binary cache could not be reached, the error is a [TemporaryFailure].
+pub async fn is_substitutable(storepath: &Path) -> anyhow::Result<bool> {
+    if is_guix() {
+        anyhow::bail!(
+            "cannot tell whether guix can substitute {}",
+            storepath.display()
+        );
+    }
+    let (root, logical) = split_store_root(storepath);
+    let mut command = tokio::process::Command::from(nix_store_command());
+    command
+        .args(store_args(root))
+        .arg("--realise")
+        .arg("--dry-run")
+        .arg(&logical);
+    let output = command_output_async(&mut command).await?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stderr.contains("will be fetched") {
+        return Ok(true);
+    }
+    if is_download_failure(&stderr) {
+        return Err(TemporaryFailure(format!(
+            "cannot tell whether {} can be substituted: a binary cache could not be reached",
+            storepath.display()
+        ))
+        .into());
+    }
+    Ok(false)
+}
 
 /// attempts have this store path exist in the store
 ///
 /// if the path already exists, do nothing
-/// otherwise runs `nix-store --realise` to download it from a binary cache.
+/// otherwise runs `nix copy --from` the binary cache set by [set_realise_from], if any, and
+/// then `nix-store --realise` (or `nix build` when `nix-store` is missing) to download it from a
+/// binary cache.
+///
+/// Paths of [extra stores](set_extra_stores) are realised in their store.
+///
+/// If a binary cache could not be reached, the store path would not fit on the store
+/// filesystem, or a command timed out (see [set_subprocess_timeout]), the error is a
+/// [TemporaryFailure]. Otherwise it contains the last error printed by nix.
+///
+/// At most [set_max_realisations] commands run at the same time, and they are killed if the
+/// returned future is dropped.
+///
+/// The output of the commands is recorded in the [realisation log](crate::realiselog) of the
+/// store path, and why it failed as its [failure](realiselog::LogWriter::fail).
 pub async fn realise(path: &Path) -> anyhow::Result<()> {
-    use tokio::fs::metadata;
-    use tokio::process::Command;
-    if metadata(path).await.is_ok() {
+    if is_present(path).await {
         return Ok(());
     };
-    let mut command = Command::new("nix-store");
-    command.arg("--realise").arg(path);
+    if is_read_only_store() {
+        anyhow::bail!("{} is not in the read-only store", path.display());
+    }
+    let log = realiselog::start(get_store_path(path).unwrap_or(path));
+    let result = realise_logged(path, &log).await;
+    if let Err(e) = &result {
+        log.fail(&format!("{:#}", e));
+    }
+    result
+}
+
+/// Does the work of [realise], recording the output of commands in `log`
+async fn realise_logged(path: &Path, log: &realiselog::LogWriter) -> anyhow::Result<()> {
+    use tokio::process::Command;
+    if is_guix() {
+        let _permit = realisation_slots()
+            .acquire()
+            .await
+            .context("waiting for a realisation slot")?;
+        let output = match tokio::time::timeout(subprocess_timeout(), guix_ensure_path(path)).await
+        {
+            Ok(output) => output?,
+            Err(_) => return Err(timed_out(&format!("guix repl for {}", path.display()))),
+        };
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        for line in stderr.lines() {
+            log.line(line);
+        }
+        tracing::debug!("guix repl printed: {}", stderr.trim());
+        if is_present(path).await {
+            return Ok(());
+        };
+        anyhow::bail!("realising {} failed: {}", path.display(), stderr.trim());
+    }
+    let (root, logical) = split_store_root(path);
+    if let Err(e) = check_disk_space(root, &logical).await {
+        log.line(&format!("{:#}", e));
+        return Err(e);
+    }
+    let mut download_failure = false;
+    // what nix printed last as an error
+    let mut error = None;
+    if let Some(url) = REALISE_FROM.get() {
+        let mut command = Command::from(nix_command());
+        command
+            .args(store_args(root))
+            .arg("copy")
+            .arg("--from")
+            .arg(url)
+            .arg(&logical);
+        tracing::info!("Running {:?}", &command);
+        match realisation_output(&mut command, log).await {
+            Ok(output) => {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                download_failure |= is_download_failure(&stderr);
+                error = last_error_line(&stderr).map(str::to_owned);
+            }
+            Err(e) if is_temporary(&e) => return Err(e),
+            Err(e) => tracing::info!("{:#}", e),
+        }
+        if is_present(path).await {
+            return Ok(());
+        };
+        tracing::debug!("could not copy {} from {}", path.display(), url);
+    }
+    let mut command = if new_cli_only() {
+        let mut command = Command::from(nix_command());
+        command
+            .args(store_args(root))
+            .arg("build")
+            .arg("--no-link")
+            .arg(&logical);
+        command
+    } else {
+        let mut command = Command::from(store_command("nix-store"));
+        command
+            .args(store_args(root))
+            .arg("--realise")
+            .arg(&logical);
+        command
+    };
     tracing::info!("Running {:?}", &command);
-    let _ = command.status().await;
-    if metadata(path).await.is_ok() {
+    match realisation_output(&mut command, log).await {
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            tracing::debug!("{:?} printed: {}", &command, stderr.trim());
+            download_failure |= is_download_failure(&stderr);
+            if let Some(line) = last_error_line(&stderr) {
+                error = Some(line.to_owned());
+            }
+        }
+        Err(e) if is_temporary(&e) => return Err(e),
+        Err(e) => error = Some(format!("{:#}", e)),
+    }
+    if is_present(path).await {
         return Ok(());
     };
-    anyhow::bail!("nix-store --realise {} failed", path.display());
+    if download_failure {
+        return Err(TemporaryFailure(format!(
+            "realising {} failed because a binary cache could not be reached",
+            path.display()
+        ))
+        .into());
+    }
+    match error {
+        Some(error) => anyhow::bail!("realising {} failed: {}", path.display(), error),
+        None => anyhow::bail!("realising {} failed", path.display()),
+    }
 }
 
 /// downloads a .drv file if necessary
@@ -50,28 +1200,105 @@ pub async fn realise(path: &Path) -> anyhow::Result<()> {
 /// otherwise runs `nix-store --realise` to download it from a binary cache.
 fn download_drv(path: &Path) -> anyhow::Result<()> {
     use std::fs::metadata;
-    use std::process::Command;
     if metadata(path).is_ok() {
         return Ok(());
     };
-    let mut command = Command::new("nix-store");
-    command.arg("--realise");
+    if is_read_only_store() {
+        anyhow::bail!("{} is not in the read-only store", path.display());
+    }
+    if is_guix() {
+        // guix substitutes outputs, not derivations
+        anyhow::bail!("{} is missing and cannot be downloaded", path.display());
+    }
+    let (root, logical) = split_store_root(path);
     // nix-store --realise foo.drv downloads the drv and its default output
     // we use the following trick to only download the drv: we ask for a non existing output
     // as the narinfo does not give the list of outputs, nix has to download the drv first, and
     // then fails to download the output
-    command.arg(path.with_extension("drv!outputdoesn0tex1st"));
+    let mut command = if new_cli_only() {
+        let mut command = nix_command();
+        command
+            .args(store_args(root))
+            .arg("build")
+            .arg("--no-link")
+            .arg(logical.with_extension("drv^outputdoesn0tex1st"));
+        command
+    } else {
+        let mut command = store_command("nix-store");
+        command
+            .args(store_args(root))
+            .arg("--realise")
+            .arg(logical.with_extension("drv!outputdoesn0tex1st"));
+        command
+    };
     tracing::info!("Running {:?}", &command);
-    let _ = command.status();
+    // this command always fails, as the output does not exist
+    let output = command_output(&mut command)?;
     if metadata(path).is_ok() {
         return Ok(());
     };
-    anyhow::bail!("nix-store --realise {} failed", path.display());
+    match last_error_line(&String::from_utf8_lossy(&output.stderr)) {
+        Some(error) => anyhow::bail!("downloading {} failed: {}", path.display(), error),
+        None => anyhow::bail!("downloading {} failed", path.display()),
+    }
 }
 
 /// Walks a store path and attempts to register everything that has a buildid in it.
 /// If offline is false, may try to download the .drv file from cache.
-pub fn index_store_path(storepath: &Path, sendto: Sender<Entry>, offline: bool) {
+///
+/// With [SymlinkPolicy::Register], the store paths symlinks point to are indexed as well,
+/// each at most once. With `siblings`, so are the other outputs of the deriver of `storepath`
+/// which are present, so that an executable and its debuginfo are registered together.
+///
+/// Entries are sent even on error, which happens when the deriver of `storepath` could not be
+/// queried: they may then lack their debuginfo and source, which indexing again later may find.
+pub fn index_store_path(
+    storepath: &Path,
+    sendto: Sender<Entry>,
+    offline: bool,
+    siblings: bool,
+) -> anyhow::Result<()> {
+    let mut visited = HashSet::new();
+    let mut todo = vec![storepath.to_owned()];
+    let mut result = Ok(());
+    while let Some(path) = todo.pop() {
+        if !visited.insert(path.clone()) {
+            continue;
+        }
+        let mut targets = BTreeSet::new();
+        let indexed = index_one_store_path(
+            &path,
+            &sendto,
+            offline,
+            siblings && path == storepath,
+            &mut targets,
+        );
+        if path == storepath {
+            result = indexed;
+        }
+        todo.extend(
+            targets
+                .into_iter()
+                .filter(|target| !visited.contains(target)),
+        );
+    }
+    result
+}
+
+/// Walks a store path and registers everything that has a buildid in it.
+///
+/// The store paths pointed to by symlinks are added to `also_index`, if
+/// [SymlinkPolicy::Register] is set, and so are the other outputs of the deriver which are
+/// present, if `siblings` is set.
+///
+/// Fails if the deriver could not be queried, see [index_store_path].
+fn index_one_store_path(
+    storepath: &Path,
+    sendto: &Sender<Entry>,
+    offline: bool,
+    siblings: bool,
+    also_index: &mut BTreeSet<PathBuf>,
+) -> anyhow::Result<()> {
     let span = tracing::info_span!("indexing", storepath=%storepath.display()).entered();
     if storepath
         .file_name()
@@ -79,14 +1306,19 @@ pub fn index_store_path(storepath: &Path, sendto: Sender<Entry>, offline: bool)
         .as_bytes()
         .ends_with(b".drv")
     {
-        return;
+        return Ok(());
     }
     if !storepath.is_dir() {
-        return;
+        return Ok(());
     }
-    let deriver_source = Lazy::new(|| match get_deriver(storepath) {
+    // logs a summary of repetitive messages when dropped
+    let mut sampler = LogSampler::new(storepath.display());
+    // why the deriver could not be queried
+    let deriver_error = std::cell::RefCell::new(None);
+    let deriver_source = Lazy::new(|| match timed_get_deriver(storepath) {
         Err(e) => {
             tracing::warn!("no deriver for {}: {:#}", storepath.display(), e);
+            *deriver_error.borrow_mut() = Some(e);
             (None, None)
         }
         Ok(None) => (None, None),
@@ -121,85 +1353,129 @@ pub fn index_store_path(storepath: &Path, sendto: Sender<Entry>, offline: bool)
             }
         }
     });
-    let storepath_os: &OsStr = storepath.as_ref();
-    if storepath_os.as_bytes().ends_with(b"-debug") {
-        let mut root = storepath.to_owned();
-        root.push("lib");
-        root.push("debug");
-        root.push(".build-id");
-        if !root.is_dir() {
-            return;
-        };
-        let readroot = match std::fs::read_dir(&root) {
-            Err(e) => {
-                tracing::warn!("could not list {}: {:#}", root.display(), e);
-                return;
+    if siblings {
+        if let (Some(deriver), _) = &*deriver_source {
+            match get_outputs(deriver) {
+                Ok(outputs) => also_index.extend(
+                    outputs
+                        .into_iter()
+                        .filter(|output| output != storepath && output.is_dir()),
+                ),
+                Err(e) => tracing::info!(
+                    "cannot list the outputs of {}, deriver of {}: {:#}",
+                    deriver.display(),
+                    storepath.display(),
+                    e
+                ),
             }
-            Ok(r) => r,
+        }
+    }
+    if is_debug_output(storepath) {
+        let send_debuginfo = |buildid: String, path: &Path| {
+            let (_, source) = &*deriver_source;
+            let entry = Entry {
+                debuginfo: path.to_str().map(|s| s.to_owned()),
+                // the size of a compressed file is not the size we serve
+                debuginfo_metadata: if is_compressed_debuginfo(path) {
+                    None
+                } else {
+                    file_metadata(path)
+                },
+                executable: None,
+                executable_metadata: None,
+                // registered with the executable
+                arch: None,
+                source: source.as_ref().and_then(|path| {
+                    path.as_ref()
+                        .and_then(|path| path.to_str())
+                        .map(|s| s.to_owned())
+                }),
+                buildid,
+            };
+            sendto
+                .blocking_send(entry)
+                .context("sending entry failed")
+                .or_warn();
         };
-        for mid in readroot {
-            let mid = match mid {
+        let mut registered = HashSet::new();
+        for file in walkdir::WalkDir::new(storepath) {
+            let file = match file {
                 Err(e) => {
-                    tracing::warn!("could not list {}: {:#}", root.display(), e);
+                    sampler.warn("errors listing files", || {
+                        format!("could not list {}: {:#}", storepath.display(), e)
+                    });
                     continue;
                 }
-                Ok(mid) => mid,
+                Ok(file) => file,
             };
-            if !mid.file_type().map(|x| x.is_dir()).unwrap_or(false) {
+            let path = file.path();
+            // files in lib/debug/.build-id may be symlinks to the actual debug files
+            if !path.is_file() {
                 continue;
-            };
-            let mid_path = mid.path();
-            let mid_name_os = mid.file_name();
-            let mid_name = match mid_name_os.to_str() {
-                None => continue,
-                Some(x) => x,
-            };
-            let read_mid = match std::fs::read_dir(&mid_path) {
-                Err(e) => {
-                    tracing::warn!("could not list {}: {:#}", mid_path.display(), e);
-                    continue;
-                }
-                Ok(r) => r,
-            };
-            for end in read_mid {
-                let end = match end {
+            }
+            let buildid = match path
+                .strip_prefix(storepath)
+                .ok()
+                .and_then(buildid_from_debug_file_path)
+            {
+                Some(buildid) => buildid,
+                // some debug outputs mirror the layout of the binaries instead, like
+                // lib/debug/usr/lib/libfoo.so.debug or lib/debug/usr/lib/libfoo.so.debug.xz
+                None if is_compressed_debuginfo(path) => match get_compressed_elf_info(path) {
                     Err(e) => {
-                        tracing::warn!("could not list {}: {:#}", mid_path.display(), e);
+                        sampler.info("compressed files whose buildid cannot be read", || {
+                            format!("cannot get buildid of {}: {:#}", path.display(), e)
+                        });
                         continue;
                     }
-                    Ok(end) => end,
-                };
-                if !end.file_type().map(|x| x.is_file()).unwrap_or(false) {
-                    continue;
-                };
-                let end_name_os = end.file_name();
-                let end_name = match end_name_os.to_str() {
-                    None => continue,
-                    Some(x) => x,
-                };
-                if !end_name.ends_with(".debug") {
-                    continue;
-                };
-                let buildid = format!(
-                    "{}{}",
-                    &mid_name,
-                    &end_name[..(end_name.len() - ".debug".len())]
-                );
-                let (_, source) = &*deriver_source;
-                let entry = Entry {
-                    debuginfo: end.path().to_str().map(|s| s.to_owned()),
-                    executable: None,
-                    source: source.as_ref().and_then(|path| {
-                        path.as_ref()
-                            .and_then(|path| path.to_str())
-                            .map(|s| s.to_owned())
-                    }),
-                    buildid,
-                };
-                sendto
-                    .blocking_send(entry)
-                    .context("sending entry failed")
-                    .or_warn();
+                    Ok(Some(info)) if info.has_debuginfo => {
+                        INDEXER.elf_file_parsed();
+                        info.buildid
+                    }
+                    Ok(_) => continue,
+                },
+                None => match get_elf_info(path) {
+                    Err(e) => {
+                        sampler.info("files whose buildid cannot be read", || {
+                            format!("cannot get buildid of {}: {:#}", path.display(), e)
+                        });
+                        continue;
+                    }
+                    Ok(Some(info)) if info.has_debuginfo => {
+                        INDEXER.elf_file_parsed();
+                        info.buildid
+                    }
+                    Ok(_) => continue,
+                },
+            };
+            if registered.insert(buildid.clone()) {
+                send_debuginfo(buildid, path);
+            }
+            // dwz supplementary files may be named after nothing, or be in another store path
+            if is_compressed_debuginfo(path) {
+                continue;
+            }
+            match get_debugaltlink(path) {
+                Err(e) => sampler.info("files whose debugaltlink cannot be read", || {
+                    format!("cannot get debugaltlink of {}: {:#}", path.display(), e)
+                }),
+                Ok(Some(link)) if !registered.contains(&link.buildid) => {
+                    match resolve_debugaltlink(path, &link.path, storepath) {
+                        // in another store path, it may not be present yet
+                        Some(alt) if alt.is_file() || !alt.starts_with(storepath) => {
+                            registered.insert(link.buildid.clone());
+                            send_debuginfo(link.buildid, &alt);
+                        }
+                        _ => sampler.info("files with a missing debugaltlink", || {
+                            format!(
+                                "{} refers to missing supplementary file {}",
+                                path.display(),
+                                link.path.display()
+                            )
+                        }),
+                    }
+                }
+                Ok(_) => (),
             }
         }
     } else {
@@ -222,46 +1498,79 @@ pub fn index_store_path(storepath: &Path, sendto: Sender<Entry>, offline: bool)
                 },
             }
         });
-        for file in walkdir::WalkDir::new(storepath) {
+        let filter = WALK_FILTER.get_or_init(WalkFilter::default);
+        for file in walkdir::WalkDir::new(storepath)
+            .follow_links(filter.symlinks == SymlinkPolicy::Follow)
+            .into_iter()
+            .filter_entry(|entry| filter.descend(storepath, entry))
+        {
             let file = match file {
                 Err(_) => continue,
                 Ok(file) => file,
             };
-            if !file.file_type().is_file() {
+            if filter.symlinks == SymlinkPolicy::Register && file.path_is_symlink() {
+                if let Some(target) = symlink_target_store_path(file.path()) {
+                    if target != storepath {
+                        also_index.insert(target);
+                    }
+                }
+                continue;
+            }
+            if !file.file_type().is_file() || !filter.examine(&file) {
                 continue;
             };
             let path = file.path();
-            let buildid = match get_buildid(path) {
+            let ElfInfo {
+                buildid,
+                go_buildid,
+                has_debuginfo,
+                arch,
+            } = match get_elf_info(path) {
                 Err(e) => {
-                    tracing::info!("cannot get buildid of {}: {:#}", path.display(), e);
+                    sampler.info("files whose buildid cannot be read", || {
+                        format!("cannot get buildid of {}: {:#}", path.display(), e)
+                    });
                     continue;
                 }
-                Ok(Some(buildid)) => buildid,
+                Ok(Some(info)) => info,
                 Ok(None) => continue,
             };
+            INDEXER.elf_file_parsed();
             let debuginfo = match &*debug_output {
                 None => None,
                 Some(storepath) => {
-                    let theoretical = debuginfo_path_for(&buildid, storepath.as_path());
+                    let theoretical = match debuginfo_path_for(&buildid, storepath.as_path()) {
+                        None => continue,
+                        Some(theoretical) => theoretical,
+                    };
                     if storepath.is_dir() {
                         // the store path is available, check the prediction
-                        if !theoretical.is_file() {
-                            tracing::warn!(
-                                "{} has buildid {}, and {} exists but not {}",
-                                path.display(),
-                                buildid,
-                                storepath.display(),
-                                theoretical.display()
-                            );
-                            None
-                        } else {
-                            Some(theoretical)
+                        let found = DEBUG_FILE_SUFFIXES
+                            .iter()
+                            .map(|suffix| theoretical.with_extension(&suffix[1..]))
+                            .find(|candidate| candidate.is_file());
+                        if found.is_none() {
+                            sampler.warn("files without the expected debuginfo", || {
+                                format!(
+                                    "{} has buildid {}, and {} exists but not {}",
+                                    path.display(),
+                                    buildid,
+                                    storepath.display(),
+                                    theoretical.display()
+                                )
+                            });
                         }
+                        found
                     } else {
                         Some(theoretical)
                     }
                 }
             };
+            // unstripped files are their own debuginfo
+            let debuginfo = match debuginfo {
+                None if has_debuginfo => Some(path.to_path_buf()),
+                debuginfo => debuginfo,
+            };
             let (_, source) = &*deriver_source;
             let entry = Entry {
                 buildid,
@@ -271,26 +1580,195 @@ pub fn index_store_path(storepath: &Path, sendto: Sender<Entry>, offline: bool)
                         .map(|s| s.to_owned())
                 }),
                 executable: path.to_str().map(|s| s.to_owned()),
+                executable_metadata: file_metadata(path),
+                debuginfo_metadata: debuginfo
+                    .as_deref()
+                    .filter(|path| !is_compressed_debuginfo(path))
+                    .and_then(file_metadata),
                 debuginfo: debuginfo.and_then(|path| path.to_str().map(|s| s.to_owned())),
+                arch,
             };
+            // Go tools may look the binary up by its Go build ID instead
+            if let Some(go_buildid) = go_buildid {
+                sendto
+                    .blocking_send(Entry {
+                        buildid: go_buildid,
+                        ..entry.clone()
+                    })
+                    .context("sending entry failed")
+                    .or_warn();
+            }
             sendto
                 .blocking_send(entry)
                 .context("sending entry failed")
                 .or_warn();
         }
     }
-    drop(span)
+    drop(span);
+    let error = deriver_error.borrow_mut().take();
+    match error {
+        Some(e) => Err(e.context(format!("querying the deriver of {}", storepath.display()))),
+        None => Ok(()),
+    }
+}
+
+#[test]
+fn test_index_debug_output_mirrored_layout() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("aaa-foo-debug");
+    let target = output.join("lib/debug/usr/bin/foo.debug");
+    std::fs::create_dir_all(target.parent().unwrap()).unwrap();
+    // test binaries are built with debug info
+    std::fs::copy(std::env::current_exe().unwrap(), &target).unwrap();
+    let buildid = get_buildid(&target).unwrap().unwrap();
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(10);
+    // entries are sent even if nix cannot tell the deriver of this fake store path
+    index_store_path(&output, sender, true, false).ok();
+    let entry = receiver.try_recv().unwrap();
+    assert_eq!(entry.buildid, buildid);
+    assert_eq!(entry.debuginfo.as_deref(), target.to_str());
+    assert_eq!(entry.executable, None);
+    assert!(receiver.try_recv().is_err());
+}
+
+/// The buildid of a debug file, according to its path in a debug output, like
+/// `lib/debug/.build-id/48/3bd7f7229bdb06462222e1e353e4f37e15c293.debug`.
+///
+/// The buildid is spread over all the directories below `.build-id`, which usually means the
+/// first byte, but not always. Case is ignored. Returns `None` if the path is not of this form.
+fn buildid_from_debug_file_path(path: &Path) -> Option<String> {
+    let mut components = path
+        .components()
+        .skip_while(|c| !c.as_os_str().as_bytes().eq_ignore_ascii_case(b".build-id"))
+        .skip(1)
+        .map(|c| c.as_os_str().to_str())
+        .collect::<Option<Vec<&str>>>()?;
+    let name = components.pop()?.to_ascii_lowercase();
+    let stem = DEBUG_FILE_SUFFIXES
+        .iter()
+        .find_map(|suffix| name.strip_suffix(suffix))?;
+    let mut buildid = components.concat().to_ascii_lowercase();
+    buildid.push_str(stem);
+    is_valid_buildid(&buildid).then_some(buildid)
+}
+
+#[test]
+fn test_buildid_from_debug_file_path() {
+    let buildid = Some("483bd7f7229bdb06462222e1e353e4f37e15c293".to_owned());
+    for path in [
+        "lib/debug/.build-id/48/3bd7f7229bdb06462222e1e353e4f37e15c293.debug",
+        "lib/debug/.build-id/48/3bd7f7229bdb06462222e1e353e4f37e15c293.debug.xz",
+        "lib/debug/.build-id/48/3b/d7f7229bdb06462222e1e353e4f37e15c293.debug",
+        "lib/debug/.Build-ID/48/3BD7F7229BDB06462222E1E353E4F37E15C293.DEBUG",
+        "lib/debug/.build-id/483bd7f7229bdb06462222e1e353e4f37e15c293.debug",
+        "usr/lib/debug/.build-id/48/3bd7f7229bdb06462222e1e353e4f37e15c293.debug",
+    ] {
+        assert_eq!(
+            buildid_from_debug_file_path(Path::new(path)),
+            buildid,
+            "{}",
+            path
+        );
+    }
+    for path in [
+        "lib/debug/usr/lib/libfoo.so.debug",
+        "lib/debug/.build-id/48/foo.debug",
+        "lib/debug/.build-id/48/3bd7f7229bdb06462222e1e353e4f37e15c293",
+        "lib/debug/.build-id/48/3bd7f7229bdb06462222e1e353e4f37e15c2.debug/x.debug",
+    ] {
+        assert_eq!(
+            buildid_from_debug_file_path(Path::new(path)),
+            None,
+            "{}",
+            path
+        );
+    }
+}
+
+/// Size and mtime of this file, if it exists
+fn file_metadata(path: &Path) -> Option<FileMetadata> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some(FileMetadata {
+        size: metadata.len(),
+        mtime: metadata.mtime(),
+    })
+}
+
+/// Possible suffixes of separate debuginfo files in debug outputs, uncompressed first.
+///
+/// Compressed files are decompressed when served.
+const DEBUG_FILE_SUFFIXES: &[&str] = &[".debug", ".debug.xz", ".debug.zst", ".debug.gz"];
+
+/// Whether this debuginfo file is compressed, and must be decompressed before being served
+pub fn is_compressed_debuginfo(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().as_bytes();
+    DEBUG_FILE_SUFFIXES[1..]
+        .iter()
+        .any(|suffix| name.ends_with(suffix.as_bytes()))
+}
+
+#[test]
+fn test_is_compressed_debuginfo() {
+    assert!(is_compressed_debuginfo(Path::new(
+        "/nix/store/aaa-foo-debug/lib/debug/.build-id/48/3bd7.debug.xz"
+    )));
+    assert!(!is_compressed_debuginfo(Path::new(
+        "/nix/store/aaa-foo-debug/lib/debug/.build-id/48/3bd7.debug"
+    )));
+    assert!(!is_compressed_debuginfo(Path::new(
+        "/nix/store/aaa-foo-src/foo.tar.xz"
+    )));
+}
+
+/// Whether this is a buildid as printed by readelf: a non-empty sequence of bytes in hexadecimal.
+///
+/// Buildids are usually 20 bytes long (sha1), but can have any length, for example 16 bytes
+/// (md5) or something set by `--build-id=0x...`.
+pub fn is_valid_buildid(buildid: &str) -> bool {
+    !buildid.is_empty() && buildid.len() % 2 == 0 && buildid.bytes().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Splits a buildid into the directory and the file name (without extension) it has in
+/// `lib/debug/.build-id`.
+///
+/// Returns None if this is not a valid buildid.
+pub fn split_buildid(buildid: &str) -> Option<(&str, &str)> {
+    if is_valid_buildid(buildid) {
+        Some(buildid.split_at(2))
+    } else {
+        None
+    }
+}
+
+#[test]
+fn test_split_buildid() {
+    assert_eq!(
+        split_buildid("483bd7f7229bdb06462222e1e353e4f37e15c293"),
+        Some(("48", "3bd7f7229bdb06462222e1e353e4f37e15c293"))
+    );
+    // md5
+    assert_eq!(
+        split_buildid("0123456789abcdef0123456789abcdef"),
+        Some(("01", "23456789abcdef0123456789abcdef"))
+    );
+    assert_eq!(split_buildid("ab"), Some(("ab", "")));
+    assert_eq!(split_buildid(""), None);
+    assert_eq!(split_buildid("abc"), None);
+    assert_eq!(split_buildid("é0"), None);
 }
 
 /// Return the path where separate debuginfo is to be found in a debug output for a buildid
-fn debuginfo_path_for(buildid: &str, debug_output: &Path) -> PathBuf {
+///
+/// Returns None if this is not a valid buildid.
+fn debuginfo_path_for(buildid: &str, debug_output: &Path) -> Option<PathBuf> {
+    let (dir, file) = split_buildid(buildid)?;
     let mut res = debug_output.to_path_buf();
     res.push("lib");
     res.push("debug");
     res.push(".build-id");
-    res.push(&buildid[..2]);
-    res.push(format!("{}.debug", &buildid[2..]));
-    res
+    res.push(dir);
+    res.push(format!("{}.debug", file));
+    Some(res)
 }
 
 /// Obtains the original deriver of a store path.
@@ -299,10 +1777,18 @@ fn debuginfo_path_for(buildid: &str, debug_output: &Path) -> PathBuf {
 ///
 /// The store path must exist.
 fn get_original_deriver(storepath: &Path) -> anyhow::Result<Option<PathBuf>> {
-    let mut cmd = std::process::Command::new("nix-store");
-    cmd.arg("--query").arg("--deriver").arg(storepath);
+    let (root, logical) = split_store_root(storepath);
+    if new_cli_only() {
+        let json = path_info(storepath)?;
+        return Ok(parse_path_info_deriver(&json, &logical).map(|deriver| physical(root, &deriver)));
+    }
+    let mut cmd = offline(store_command("nix-store"));
+    cmd.args(store_args(root))
+        .arg("--query")
+        .arg("--deriver")
+        .arg(&logical);
     tracing::debug!("Running {:?}", &cmd);
-    let out = cmd.output().with_context(|| format!("running {:?}", cmd))?;
+    let out = command_output(&mut cmd)?;
     if !out.status.success() {
         anyhow::bail!("{:?} failed: {}", cmd, String::from_utf8_lossy(&out.stderr));
     }
@@ -322,7 +1808,61 @@ fn get_original_deriver(storepath: &Path) -> anyhow::Result<Option<PathBuf>> {
         // nix returns `unknown-deriver` when it does not know
         anyhow::bail!("no deriver: {}", path.display());
     };
-    Ok(Some(path))
+    Ok(Some(physical(root, &path)))
+}
+
+/// Runs `nix path-info --json` on this store path, in its store.
+///
+/// The output refers to store paths by the path nix knows them by, see [split_store_root].
+fn path_info(storepath: &Path) -> anyhow::Result<serde_json::Value> {
+    let (root, logical) = split_store_root(storepath);
+    let mut cmd = offline(nix_command());
+    cmd.args(store_args(root))
+        .arg("path-info")
+        .arg("--json")
+        .arg(logical);
+    tracing::debug!("Running {:?}", &cmd);
+    let out = command_output(&mut cmd)?;
+    if !out.status.success() {
+        anyhow::bail!("{:?} failed: {}", cmd, String::from_utf8_lossy(&out.stderr));
+    }
+    serde_json::from_slice(&out.stdout).with_context(|| format!("parsing output of {:?}", cmd))
+}
+
+/// Returns the deriver of `storepath` according to this output of `nix path-info --json`
+fn parse_path_info_deriver(json: &serde_json::Value, storepath: &Path) -> Option<PathBuf> {
+    // nix < 2.19 returns a list of objects with a `path` field, more recent versions return
+    // an object whose keys are the paths
+    let info = match json {
+        serde_json::Value::Array(infos) => infos.iter().find(|info| {
+            info.get("path").and_then(|p| p.as_str()).map(Path::new) == Some(storepath)
+        }),
+        serde_json::Value::Object(infos) => infos
+            .iter()
+            .find(|(path, _)| Path::new(store_dir()).join(path) == storepath)
+            .map(|(_, info)| info),
+        _ => None,
+    }?;
+    let deriver = info.get("deriver")?.as_str()?;
+    Some(Path::new(store_dir()).join(deriver))
+}
+
+#[test]
+fn test_parse_path_info_deriver() {
+    let storepath = Path::new("/nix/store/bbb-hello-2.12");
+    let old = serde_json::json!([
+        {"path": "/nix/store/bbb-hello-2.12", "deriver": "/nix/store/aaa-hello-2.12.drv"}
+    ]);
+    let new = serde_json::json!({
+        "/nix/store/bbb-hello-2.12": {"deriver": "/nix/store/aaa-hello-2.12.drv"}
+    });
+    let unknown = serde_json::json!({
+        "/nix/store/bbb-hello-2.12": {"deriver": null}
+    });
+    let deriver = Some(PathBuf::from("/nix/store/aaa-hello-2.12.drv"));
+    assert_eq!(parse_path_info_deriver(&old, storepath), deriver);
+    assert_eq!(parse_path_info_deriver(&new, storepath), deriver);
+    assert_eq!(parse_path_info_deriver(&unknown, storepath), None);
 }
 
 /// Obtains a set of local derivers for a store path.
@@ -333,16 +1873,20 @@ fn get_original_deriver(storepath: &Path) -> anyhow::Result<Option<PathBuf>> {
 ///
 /// Fails if nix version is < 2.18
 fn get_valid_derivers(storepath: &Path) -> anyhow::Result<Vec<PathBuf>> {
-    let mut cmd = std::process::Command::new("nix-store");
-    cmd.arg("--query").arg("--valid-derivers").arg(storepath);
+    let (root, logical) = split_store_root(storepath);
+    let mut cmd = offline(store_command("nix-store"));
+    cmd.args(store_args(root))
+        .arg("--query")
+        .arg("--valid-derivers")
+        .arg(logical);
     tracing::debug!("Running {:?}", &cmd);
-    let out = cmd.output().with_context(|| format!("running {:?}", cmd))?;
+    let out = command_output(&mut cmd)?;
     if !out.status.success() {
         anyhow::bail!("{:?} failed: {}", cmd, String::from_utf8_lossy(&out.stderr));
     }
     let mut result = Vec::new();
     for line in out.stdout.split(|&c| c == b'\n') {
-        if line.len() != 0 {
+        if !line.is_empty() {
             let path = PathBuf::from(OsString::from_vec(line.to_owned()));
             if !path.is_absolute() {
                 // nix returns `unknown-deriver` when it does not know
@@ -352,18 +1896,38 @@ fn get_valid_derivers(storepath: &Path) -> anyhow::Result<Vec<PathBuf>> {
                     path.display()
                 );
             };
-            result.push(path)
+            result.push(physical(root, &path))
         }
     }
     Ok(result)
 }
 
+/// [get_deriver], recording how long it took in [INDEXER]
+fn timed_get_deriver(storepath: &Path) -> anyhow::Result<Option<PathBuf>> {
+    let start = std::time::Instant::now();
+    let result = get_deriver(storepath);
+    INDEXER.deriver_queried(start.elapsed());
+    result
+}
+
 /// Attempts to obtain any deriver for this store path, preferrably existing.
 ///
 /// Corresponds to `nix-store --query --deriver` or `nix-store --query --valid-derivers.
 ///
-/// The store path must exist.
+/// The store path must exist. Without nix, the deriver is unknown.
 fn get_deriver(storepath: &Path) -> anyhow::Result<Option<PathBuf>> {
+    if is_read_only_store() {
+        return Ok(None);
+    }
+    if is_guix() {
+        let derivers = guix_derivers(storepath)
+            .with_context(|| format!("getting derivers of {}", storepath.display()))?;
+        return Ok(derivers
+            .iter()
+            .find(|path| path.exists())
+            .or(derivers.first())
+            .cloned());
+    }
     if NIX_STORE_QUERY_VALID_DERIVERS_SUPPORTED.load(Ordering::SeqCst) {
         for path in get_valid_derivers(storepath)
             .with_context(|| format!("getting valid deriver for {}", storepath.display()))?
@@ -382,19 +1946,54 @@ fn get_deriver(storepath: &Path) -> anyhow::Result<Option<PathBuf>> {
         .with_context(|| format!("getting original deriver for {}", storepath.display()))
 }
 
-/// Checks that nix is installed.
+/// Checks that nix, or guix with the Guix [backend](crate::backend::set_backend), is
+/// installed.
 ///
 /// Also stores in global state whether some features only available in recent nix
 /// versions are available.
 ///
+/// If the store is read-only and there is neither nix db nor nix daemon, like in container
+/// images made of store paths, nix is not needed: the files in the store are served as they
+/// are, see [is_read_only_store].
+///
 /// Should be called on startup.
 pub fn detect_nix() -> anyhow::Result<()> {
+    if store_is_read_only()
+        && !Path::new(backend().db()).exists()
+        && !Path::new(backend().daemon_socket()).exists()
+    {
+        READ_ONLY_STORE.store(true, Ordering::SeqCst);
+        tracing::info!(
+            "{} is read-only and there is no db nor daemon, serving its content as is",
+            store_dir()
+        );
+        return Ok(());
+    }
+    let runs = |cmd: &str| {
+        store_command(cmd)
+            .arg("--version")
+            .output()
+            .is_ok_and(|out| out.status.success())
+    };
+    if is_guix() {
+        anyhow::ensure!(runs("guix"), "guix cannot be run");
+    } else if !runs("nix-store") {
+        anyhow::ensure!(runs("nix"), "neither nix-store nor nix can be run");
+        NIX_STORE_MISSING.store(true, Ordering::SeqCst);
+        tracing::info!("nix-store not found, using the nix command instead");
+    }
+    // rootless installs only have a chroot store
+    let test_dir = match extra_stores().first() {
+        Some(root) if !has_system_store() => root.join("nix/store"),
+        _ => PathBuf::from(store_dir()),
+    };
     let mut test_path = None;
-    for entry in Path::new("/nix/store")
+    for entry in test_dir
         .read_dir()
-        .context("listing directoy content of /nix/store")?
+        .with_context(|| format!("listing directoy content of {}", test_dir.display()))?
     {
-        let entry = entry.context("reading directory entry in /nix/store")?;
+        let entry =
+            entry.with_context(|| format!("reading directory entry in {}", test_dir.display()))?;
         if entry.file_name().as_bytes().starts_with(b".") {
             continue;
         }
@@ -403,40 +2002,369 @@ pub fn detect_nix() -> anyhow::Result<()> {
     }
     let test_path = match test_path {
         Some(test_path) => test_path,
-        None => anyhow::bail!("/nix/store is empty, did you really install nix?"),
+        None => anyhow::bail!(
+            "{} is empty, did you really install nix?",
+            test_dir.display()
+        ),
     };
-    if get_valid_derivers(&test_path).is_ok() {
+    if is_guix() {
+        guix_derivers(&test_path).with_context(|| {
+            format!(
+                "checking guix install by getting derivers of {}",
+                test_path.display()
+            )
+        })?;
+        return Ok(());
+    }
+    if !new_cli_only() && get_valid_derivers(&test_path).is_ok() {
         NIX_STORE_QUERY_VALID_DERIVERS_SUPPORTED.store(true, Ordering::SeqCst);
         tracing::info!("detected nix >= 2.18");
         return Ok(());
     }
-    let _ = get_original_deriver(&test_path).with_context(|| {
-        format!(
-            "checking nix install by getting deriver of {}",
-            test_path.display()
-        )
-    })?;
-    tracing::warn!("detected nix < 2.18, a more recent nix is required to obtain source files in some situations.");
-    Ok(())
+    let _ = get_original_deriver(&test_path).with_context(|| {
+        format!(
+            "checking nix install by getting deriver of {}",
+            test_path.display()
+        )
+    })?;
+    tracing::warn!("detected nix < 2.18, a more recent nix is required to obtain source files in some situations.");
+    Ok(())
+}
+
+/// The name and version of a package
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Package {
+    /// the name of the package, like `hello`
+    pub pname: String,
+    /// its version, like `2.12.1`, if any
+    pub version: Option<String>,
+    /// the platform it was cross-compiled for, like `aarch64-unknown-linux-gnu`, if it was
+    /// built with `pkgsCross`
+    pub target: Option<String>,
+}
+
+impl std::fmt::Display for Package {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.pname)?;
+        if let Some(target) = &self.target {
+            write!(f, "-{}", target)?;
+        }
+        match &self.version {
+            Some(version) => write!(f, "-{}", version),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Output names that are appended to the name of store paths, except for `out`
+const OUTPUT_NAMES: &[&str] = &[
+    "bin", "debug", "dev", "devdoc", "doc", "info", "lib", "man", "static",
+];
+
+/// Architectures that start the target triple of cross-compiled packages
+const TARGET_ARCHES: &[&str] = &[
+    "aarch64",
+    "arm",
+    "avr",
+    "i386",
+    "i486",
+    "i586",
+    "i686",
+    "loongarch64",
+    "m68k",
+    "microblaze",
+    "mips",
+    "or1k",
+    "powerpc",
+    "riscv32",
+    "riscv64",
+    "s390",
+    "sparc",
+    "vc4",
+    "wasm32",
+    "wasm64",
+    "x86_64",
+];
+
+/// Vendors of target triples, the second component
+const TARGET_VENDORS: &[&str] = &["apple", "none", "pc", "unknown", "w64"];
+
+/// Whether this looks like a target triple (or quadruple), like `aarch64-unknown-linux-gnu` or
+/// `x86_64-w64-mingw32`
+fn is_target_triple(name: &str) -> bool {
+    let parts: Vec<&str> = name.split('-').collect();
+    (3..=4).contains(&parts.len())
+        && TARGET_ARCHES.iter().any(|arch| parts[0].starts_with(arch))
+        && TARGET_VENDORS.contains(&parts[1])
+}
+
+/// Splits a derivation name into name and version like `builtins.parseDrvName`: the version
+/// starts after the first dash followed by something else than a letter.
+///
+/// `stdenv.mkDerivation` inserts the target triple between name and version when
+/// cross-compiling, like `hello-aarch64-unknown-linux-gnu-2.12.1`: it is split off the name.
+fn parse_drv_name(name: &str) -> Package {
+    let bytes = name.as_bytes();
+    let (pname, version) = match bytes.iter().enumerate().find(|&(i, &c)| {
+        c == b'-'
+            && bytes
+                .get(i + 1)
+                .is_some_and(|next| !next.is_ascii_alphabetic())
+    }) {
+        Some((i, _)) => (&name[..i], Some(name[i + 1..].to_owned())),
+        None => (name, None),
+    };
+    let split = pname
+        .match_indices('-')
+        .map(|(i, _)| (&pname[..i], &pname[i + 1..]))
+        .find(|(_, target)| is_target_triple(target));
+    let (pname, target) = match split {
+        Some((pname, target)) => (pname, Some(target.to_owned())),
+        None => (pname, None),
+    };
+    Package {
+        pname: pname.to_owned(),
+        version,
+        target,
+    }
+}
+
+/// Guesses the package of a file from the name of its store path.
+///
+/// For a derivation, this is exact. For an output, the name of the output is removed if it
+/// is a common one.
+pub fn package_from_store_path(path: &Path) -> Option<Package> {
+    let storepath = get_store_path(path)?;
+    let name = storepath.file_name()?.to_str()?;
+    // remove the hash
+    let (_, name) = name.split_once('-')?;
+    let name = match name.strip_suffix(".drv") {
+        Some(name) => name,
+        None => OUTPUT_NAMES
+            .iter()
+            .copied()
+            .chain(
+                DEBUG_OUTPUT_NAMES
+                    .get()
+                    .into_iter()
+                    .flatten()
+                    .map(String::as_str),
+            )
+            .find_map(|output| name.strip_suffix(output)?.strip_suffix('-'))
+            .unwrap_or(name),
+    };
+    Some(parse_drv_name(name))
+}
+
+#[test]
+fn test_package_from_store_path() {
+    let package = |pname: &str, version: Option<&str>| {
+        Some(Package {
+            pname: pname.to_owned(),
+            version: version.map(str::to_owned),
+            target: None,
+        })
+    };
+    assert_eq!(
+        package_from_store_path(Path::new(
+            "/nix/store/3cjr3wnrjzr7hkfgy3hmbm2ijrwy1z6h-hello-2.12.1/bin/hello"
+        )),
+        package("hello", Some("2.12.1"))
+    );
+    assert_eq!(
+        package_from_store_path(Path::new(
+            "/nix/store/3cjr3wnrjzr7hkfgy3hmbm2ijrwy1z6h-gcc-12.3.0-lib/lib/libstdc++.so"
+        )),
+        package("gcc", Some("12.3.0"))
+    );
+    assert_eq!(
+        package_from_store_path(Path::new(
+            "/nix/store/3cjr3wnrjzr7hkfgy3hmbm2ijrwy1z6h-xdg-utils-1.1.3.drv"
+        )),
+        package("xdg-utils", Some("1.1.3"))
+    );
+    assert_eq!(
+        package_from_store_path(Path::new(
+            "/nix/store/3cjr3wnrjzr7hkfgy3hmbm2ijrwy1z6h-source"
+        )),
+        package("source", None)
+    );
+    assert_eq!(package_from_store_path(Path::new("/usr/bin/ls")), None);
+    let cross = package_from_store_path(Path::new(
+        "/nix/store/3cjr3wnrjzr7hkfgy3hmbm2ijrwy1z6h-hello-aarch64-unknown-linux-gnu-2.12.1/bin/hello",
+    ))
+    .unwrap();
+    assert_eq!(cross.pname, "hello");
+    assert_eq!(cross.version.as_deref(), Some("2.12.1"));
+    assert_eq!(cross.target.as_deref(), Some("aarch64-unknown-linux-gnu"));
+    assert_eq!(cross.to_string(), "hello-aarch64-unknown-linux-gnu-2.12.1");
+    let mingw = package_from_store_path(Path::new(
+        "/nix/store/3cjr3wnrjzr7hkfgy3hmbm2ijrwy1z6h-zlib-x86_64-w64-mingw32-1.3.1-dev",
+    ))
+    .unwrap();
+    assert_eq!(mingw.pname, "zlib");
+    assert_eq!(mingw.target.as_deref(), Some("x86_64-w64-mingw32"));
+    // not a triple
+    assert_eq!(
+        package_from_store_path(Path::new(
+            "/nix/store/3cjr3wnrjzr7hkfgy3hmbm2ijrwy1z6h-arm-trusted-firmware-2.10"
+        ))
+        .unwrap()
+        .target,
+        None
+    );
+}
+
+/// Finds the package of this file from its deriver, or guesses it from its store path if the
+/// deriver is unknown.
+///
+/// Blocking.
+pub fn get_package(path: &Path) -> Option<Package> {
+    let storepath = get_store_path(path)?;
+    match get_deriver(storepath) {
+        Ok(Some(deriver)) => package_from_store_path(&deriver),
+        Ok(None) => package_from_store_path(storepath),
+        Err(e) => {
+            tracing::debug!("no deriver for {}: {:#}", storepath.display(), e);
+            package_from_store_path(storepath)
+        }
+    }
+}
+
+/// Lists all the valid store paths with `nix path-info --all`, in the store of this root if it
+/// is an [extra store](set_extra_stores).
+///
+/// Unlike reading the nix db, this works through the nix daemon when the nix db is only
+/// readable by root. In a [read-only store](is_read_only_store), the store directory is
+/// listed instead. With Guix, corresponds to `guix gc --list-live --list-dead`.
+pub fn list_store_paths(root: Option<&Path>) -> anyhow::Result<Vec<PathBuf>> {
+    if is_read_only_store() {
+        let store = physical(root, Path::new(store_dir()));
+        let mut result = Vec::new();
+        for entry in store
+            .read_dir()
+            .with_context(|| format!("listing {}", store.display()))?
+        {
+            let entry = entry.with_context(|| format!("listing {}", store.display()))?;
+            let name = entry.file_name();
+            if !name.as_bytes().starts_with(b".") && !name.as_bytes().ends_with(b".drv") {
+                result.push(entry.path());
+            }
+        }
+        return Ok(result);
+    }
+    if is_guix() {
+        return guix_list_store_paths();
+    }
+    let mut cmd = nix_command();
+    cmd.args(store_args(root)).arg("path-info").arg("--all");
+    tracing::debug!("Running {:?}", &cmd);
+    let out = command_output(&mut cmd)?;
+    if !out.status.success() {
+        anyhow::bail!("{:?} failed: {}", cmd, String::from_utf8_lossy(&out.stderr));
+    }
+    let mut result = Vec::new();
+    for line in out.stdout.split(|&c| c == b'\n') {
+        if !line.is_empty() {
+            let path = Path::new(OsStr::from_bytes(line));
+            if !path.starts_with(NIX_STORE) {
+                anyhow::bail!(
+                    "{:?} returned weird path {}",
+                    cmd,
+                    String::from_utf8_lossy(line)
+                );
+            };
+            result.push(physical(root, path))
+        }
+    }
+    Ok(result)
+}
+
+/// Obtains the closure of this store path, including itself.
+///
+/// Corresponds to `nix-store --query --requisites`, or `guix gc --requisites`
+///
+/// The store path must exist.
+pub fn get_closure(storepath: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    if is_guix() {
+        return guix_closure(storepath);
+    }
+    let (root, logical) = split_store_root(storepath);
+    let mut cmd = if new_cli_only() {
+        let mut cmd = offline(nix_command());
+        cmd.arg("path-info").arg("--recursive");
+        cmd
+    } else {
+        let mut cmd = offline(store_command("nix-store"));
+        cmd.arg("--query").arg("--requisites");
+        cmd
+    };
+    cmd.args(store_args(root)).arg(logical);
+    tracing::debug!("Running {:?}", &cmd);
+    let out = command_output(&mut cmd)?;
+    if !out.status.success() {
+        anyhow::bail!("{:?} failed: {}", cmd, String::from_utf8_lossy(&out.stderr));
+    }
+    let mut result = Vec::new();
+    for line in out.stdout.split(|&c| c == b'\n') {
+        if !line.is_empty() {
+            let path = PathBuf::from(OsString::from_vec(line.to_owned()));
+            if !path.is_absolute() {
+                anyhow::bail!(
+                    "{:?} returned weird path {}",
+                    cmd,
+                    String::from_utf8_lossy(line)
+                );
+            };
+            result.push(physical(root, &path))
+        }
+    }
+    Ok(result)
 }
 
-/// Obtains the debug output corresponding to this derivation
+/// Obtains the outputs of this derivation
 ///
 /// The derivation must exist.
-fn get_debug_output(drvpath: &Path) -> anyhow::Result<Option<PathBuf>> {
-    let mut cmd = std::process::Command::new("nix-store");
-    cmd.arg("--query").arg("--outputs").arg(drvpath);
+fn get_outputs(drvpath: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let (root, logical) = split_store_root(drvpath);
+    if new_cli_only() || is_guix() {
+        let json = show_derivations(&[drvpath.to_owned()])?;
+        let outputs = json
+            .as_object()
+            .into_iter()
+            .flat_map(|drvs| drvs.values())
+            .filter_map(|drv| drv.get("outputs").and_then(|o| o.as_object()))
+            .flat_map(|outputs| outputs.values())
+            .filter_map(|output| output.get("path").and_then(|p| p.as_str()))
+            .map(|path| physical(root, &Path::new(store_dir()).join(path)))
+            .collect();
+        return Ok(outputs);
+    }
+    let mut cmd = offline(store_command("nix-store"));
+    cmd.args(store_args(root))
+        .arg("--query")
+        .arg("--outputs")
+        .arg(logical);
     tracing::debug!("Running {:?}", &cmd);
-    let out = cmd.output().with_context(|| format!("running {:?}", cmd))?;
+    let out = command_output(&mut cmd)?;
     if !out.status.success() {
         anyhow::bail!("{:?} failed: {}", cmd, String::from_utf8_lossy(&out.stderr));
     }
-    for output in out.stdout.split(|&elt| elt == b'\n') {
-        if output.ends_with(b"-debug") {
-            return Ok(Some(PathBuf::from(OsString::from_vec(output.to_owned()))));
-        }
-    }
-    return Ok(None);
+    Ok(out
+        .stdout
+        .split(|&elt| elt == b'\n')
+        .filter(|output| !output.is_empty())
+        .map(|output| physical(root, Path::new(OsStr::from_bytes(output))))
+        .collect())
+}
+
+/// Obtains the debug output corresponding to this derivation, see [is_debug_output]
+///
+/// The derivation must exist.
+fn get_debug_output(drvpath: &Path) -> anyhow::Result<Option<PathBuf>> {
+    Ok(get_outputs(drvpath)?
+        .into_iter()
+        .find(|output| is_debug_output(output)))
 }
 
 /// Obtains the source store path corresponding to this derivation
@@ -445,10 +2373,30 @@ fn get_debug_output(drvpath: &Path) -> anyhow::Result<Option<PathBuf>> {
 ///
 /// Source is understood as `src = `, multiple sources or patches are not supported.
 fn get_source(drvpath: &Path) -> anyhow::Result<Option<PathBuf>> {
-    let mut cmd = std::process::Command::new("nix-store");
-    cmd.arg("--query").arg("--binding").arg("src").arg(drvpath);
+    let (root, logical) = split_store_root(drvpath);
+    if new_cli_only() || is_guix() {
+        let json = show_derivations(&[drvpath.to_owned()])?;
+        let source = json
+            .as_object()
+            .into_iter()
+            .flat_map(|drvs| drvs.values())
+            .find_map(|drv| drv.get("env")?.get("src")?.as_str());
+        return match source {
+            None => Ok(None),
+            Some(source) if Path::new(source).is_absolute() => {
+                Ok(Some(physical(root, Path::new(source))))
+            }
+            Some(source) => anyhow::bail!("weird source: {}", source),
+        };
+    }
+    let mut cmd = offline(store_command("nix-store"));
+    cmd.args(store_args(root))
+        .arg("--query")
+        .arg("--binding")
+        .arg("src")
+        .arg(logical);
     tracing::debug!("Running {:?}", &cmd);
-    let out = cmd.output().with_context(|| format!("running {:?}", cmd))?;
+    let out = command_output(&mut cmd)?;
     if !out.status.success() {
         if out
             .stderr
@@ -472,7 +2420,241 @@ fn get_source(drvpath: &Path) -> anyhow::Result<Option<PathBuf>> {
     if !path.is_absolute() {
         anyhow::bail!("weird source: {}", path.display());
     };
-    Ok(Some(path))
+    Ok(Some(physical(root, &path)))
+}
+
+/// The hash of the output of a fixed-output derivation, like a source fetched by `fetchurl`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FixedOutputHash {
+    /// the sha256 of the output, in lowercase hexadecimal
+    pub sha256: String,
+    /// whether this is the hash of the NAR serialisation of the output (`outputHashMode =
+    /// "recursive"`), or of the output as a flat file
+    pub recursive: bool,
+}
+
+/// Returns the sha256 of the fixed output `output` among the derivations described by this
+/// output of `nix derivation show`, if any.
+fn parse_fixed_output_hash(
+    json: &serde_json::Value,
+    output: &Path,
+) -> anyhow::Result<Option<FixedOutputHash>> {
+    let drvs = json
+        .as_object()
+        .context("nix derivation show did not return an object")?;
+    for drv in drvs.values() {
+        let outputs = match drv.get("outputs").and_then(|o| o.as_object()) {
+            Some(outputs) => outputs,
+            None => continue,
+        };
+        for out in outputs.values() {
+            let path = out.get("path").and_then(|p| p.as_str());
+            if path.map(|p| Path::new(store_dir()).join(p)).as_deref() != Some(output) {
+                continue;
+            }
+            let (hash, algo) = match (
+                out.get("hash").and_then(|h| h.as_str()),
+                out.get("hashAlgo").and_then(|h| h.as_str()),
+            ) {
+                (Some(hash), Some(algo)) => (hash, algo),
+                // not a fixed-output derivation
+                _ => return Ok(None),
+            };
+            // nix < 2.21 writes r:sha256 for recursive hashes, more recent versions write
+            // sha256 and `"method": "nar"`
+            let recursive =
+                algo.starts_with("r:") || out.get("method").and_then(|m| m.as_str()) == Some("nar");
+            if algo.trim_start_matches("r:") != "sha256" {
+                tracing::debug!("{} is hashed with {}, not sha256", output.display(), algo);
+                return Ok(None);
+            }
+            if hash.len() != 64 || !hash.bytes().all(|c| c.is_ascii_hexdigit()) {
+                tracing::debug!("unsupported hash format {} for {}", hash, output.display());
+                return Ok(None);
+            }
+            return Ok(Some(FixedOutputHash {
+                sha256: hash.to_ascii_lowercase(),
+                recursive,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+#[test]
+fn test_parse_fixed_output_hash() {
+    let hash = "0f1c1a4a06b3d9a4e44e8b7a5c8d2f0e6b5c3a2d1e0f9a8b7c6d5e4f3a2b1c0d";
+    let json = serde_json::json!({
+        "/nix/store/aaa-hello-2.12.tar.gz.drv": {
+            "outputs": {"out": {"path": "/nix/store/bbb-hello-2.12.tar.gz", "hash": hash, "hashAlgo": "sha256"}}
+        },
+        "/nix/store/ccc-source.drv": {
+            "outputs": {"out": {"path": "/nix/store/ddd-source", "hash": hash, "hashAlgo": "r:sha256"}}
+        },
+        "/nix/store/eee-hello-2.12.drv": {
+            "outputs": {"out": {"path": "/nix/store/fff-hello-2.12"}}
+        },
+    });
+    assert_eq!(
+        parse_fixed_output_hash(&json, Path::new("/nix/store/bbb-hello-2.12.tar.gz")).unwrap(),
+        Some(FixedOutputHash {
+            sha256: hash.to_owned(),
+            recursive: false
+        })
+    );
+    assert_eq!(
+        parse_fixed_output_hash(&json, Path::new("/nix/store/ddd-source")).unwrap(),
+        Some(FixedOutputHash {
+            sha256: hash.to_owned(),
+            recursive: true
+        })
+    );
+    assert_eq!(
+        parse_fixed_output_hash(&json, Path::new("/nix/store/fff-hello-2.12")).unwrap(),
+        None
+    );
+}
+
+/// Lists the outputs of the fixed-output derivations hashed recursively among the derivations
+/// described by this output of `nix derivation show`, as nix knows them: directories fetched by
+/// `fetchgit`, `fetchzip` and the like, as opposed to files fetched by `fetchurl`.
+fn parse_recursive_fixed_outputs(json: &serde_json::Value) -> Vec<PathBuf> {
+    let mut result: Vec<PathBuf> = json
+        .as_object()
+        .into_iter()
+        .flat_map(|drvs| drvs.values())
+        .filter_map(|drv| drv.get("outputs").and_then(|o| o.as_object()))
+        .flat_map(|outputs| outputs.values())
+        .filter(|out| {
+            let algo = out.get("hashAlgo").and_then(|h| h.as_str());
+            // see parse_fixed_output_hash
+            out.get("hash").is_some()
+                && (algo.is_some_and(|algo| algo.starts_with("r:"))
+                    || out.get("method").and_then(|m| m.as_str()) == Some("nar"))
+        })
+        .filter_map(|out| out.get("path").and_then(|p| p.as_str()))
+        .map(|path| Path::new(store_dir()).join(path))
+        .collect();
+    result.sort();
+    result
+}
+
+#[test]
+fn test_parse_recursive_fixed_outputs() {
+    let hash = "0f1c1a4a06b3d9a4e44e8b7a5c8d2f0e6b5c3a2d1e0f9a8b7c6d5e4f3a2b1c0d";
+    let json = serde_json::json!({
+        "/nix/store/aaa-hello-2.12.tar.gz.drv": {
+            "outputs": {"out": {"path": "/nix/store/bbb-hello-2.12.tar.gz", "hash": hash, "hashAlgo": "sha256"}}
+        },
+        "/nix/store/ccc-source.drv": {
+            "outputs": {"out": {"path": "/nix/store/ddd-source", "hash": hash, "hashAlgo": "r:sha256"}}
+        },
+        "/nix/store/ggg-submodule.drv": {
+            "outputs": {"out": {"path": "hhh-submodule", "hash": hash, "hashAlgo": "sha256", "method": "nar"}}
+        },
+        "/nix/store/eee-gcc.drv": {
+            "outputs": {"out": {"path": "/nix/store/fff-gcc"}}
+        },
+    });
+    assert_eq!(
+        parse_recursive_fixed_outputs(&json),
+        vec![
+            PathBuf::from("/nix/store/ddd-source"),
+            PathBuf::from("/nix/store/hhh-submodule")
+        ]
+    );
+}
+
+/// Runs `nix derivation show` on these derivations, in the store of the first one.
+///
+/// The output refers to store paths by the path nix knows them by, see [split_store_root].
+/// With Guix, the derivations are parsed instead.
+fn show_derivations(drvs: &[PathBuf]) -> anyhow::Result<serde_json::Value> {
+    if is_guix() {
+        return guix_show_derivations(drvs);
+    }
+    let root = drvs.first().and_then(|drv| split_store_root(drv).0);
+    let mut cmd = offline(nix_command());
+    cmd.args(store_args(root))
+        .arg("derivation")
+        .arg("show")
+        .args(drvs.iter().map(|drv| split_store_root(drv).1));
+    tracing::debug!("Running {:?}", &cmd);
+    let out = command_output(&mut cmd)?;
+    if !out.status.success() {
+        anyhow::bail!("{:?} failed: {}", cmd, String::from_utf8_lossy(&out.stderr));
+    }
+    serde_json::from_slice(&out.stdout).with_context(|| format!("parsing output of {:?}", cmd))
+}
+
+/// Returns the hash of `source`, the source of the derivation of `storepath`, if it is the
+/// output of a fixed-output derivation hashed with sha256.
+///
+/// `storepath` must exist, but not `source`. May download the deriver of `storepath`.
+///
+/// Blocking.
+pub fn get_source_hash(storepath: &Path, source: &Path) -> anyhow::Result<Option<FixedOutputHash>> {
+    match show_input_derivations(storepath)? {
+        None => Ok(None),
+        Some(json) => parse_fixed_output_hash(&json, &split_store_root(source).1),
+    }
+}
+
+/// Returns the other sources of the derivation of `storepath` than `source`: the directories
+/// fetched by `fetchgit`, `fetchFromGitHub` and the like among its inputs, like git submodules
+/// fetched separately and copied into the source tree during the build.
+///
+/// `storepath` must exist, but not the sources. May download the deriver of `storepath`.
+///
+/// Blocking.
+pub fn get_vendored_sources(storepath: &Path, source: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let json = match show_input_derivations(storepath)? {
+        None => return Ok(Vec::new()),
+        Some(json) => json,
+    };
+    let root = split_store_root(storepath).0;
+    let source = split_store_root(source).1;
+    Ok(parse_recursive_fixed_outputs(&json)
+        .into_iter()
+        .filter(|output| output != &source)
+        .map(|output| physical(root, &output))
+        .collect())
+}
+
+/// Returns the output of `nix derivation show` for the input derivations of the deriver of
+/// `storepath`, if it has any.
+///
+/// `storepath` must exist. May download the deriver of `storepath`.
+///
+/// Blocking.
+fn show_input_derivations(storepath: &Path) -> anyhow::Result<Option<serde_json::Value>> {
+    let deriver = match get_deriver(storepath)? {
+        None => return Ok(None),
+        Some(deriver) => deriver,
+    };
+    download_drv(&deriver).with_context(|| {
+        format!(
+            "downloading deriver {} of {}",
+            deriver.display(),
+            storepath.display()
+        )
+    })?;
+    let root = split_store_root(&deriver).0;
+    let json = show_derivations(std::slice::from_ref(&deriver))?;
+    let inputs: Vec<PathBuf> = json
+        .as_object()
+        .into_iter()
+        .flat_map(|drvs| drvs.values())
+        .filter_map(|drv| drv.get("inputDrvs").and_then(|i| i.as_object()))
+        .flat_map(|inputs| inputs.keys())
+        .map(|input| physical(root, &Path::new(store_dir()).join(input)))
+        .collect();
+    if inputs.is_empty() {
+        return Ok(None);
+    }
+    show_derivations(&inputs)
+        .with_context(|| format!("showing inputs of {}", deriver.display()))
+        .map(Some)
 }
 
 /// Where a source file might be
@@ -497,6 +2679,75 @@ impl SourceLocation {
             SourceLocation::File(path) => path.as_path(),
         }
     }
+
+    /// The path of the file relative to the root of the source `source`
+    fn relative_path(&self, source: &Path) -> Option<&Path> {
+        match self {
+            SourceLocation::Archive { member, .. } => {
+                Some(member.strip_prefix(".").unwrap_or(member))
+            }
+            SourceLocation::File(path) => path.strip_prefix(source).ok(),
+        }
+    }
+}
+
+/// What we need to know about an elf file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElfInfo {
+    /// The buildid, in hexadecimal. For Go binaries without GNU build ID, this is the Go build
+    /// ID.
+    pub buildid: String,
+    /// For Go binaries with a GNU build ID, the Go build ID, as an alternate key
+    pub go_buildid: Option<String>,
+    /// Whether the file contains DWARF debug info, ie. it was not stripped
+    pub has_debuginfo: bool,
+    /// The architecture of the file, like `x86_64` or `aarch64`, if known, see [arch_name]
+    pub arch: Option<String>,
+}
+
+/// The name of an architecture as recorded in the cache, like `x86_64`, `aarch64` or `i386`
+pub fn arch_name(arch: object::Architecture) -> Option<String> {
+    match arch {
+        object::Architecture::Unknown => None,
+        arch => Some(format!("{:?}", arch).to_ascii_lowercase()),
+    }
+}
+
+#[test]
+fn test_arch_name() {
+    assert_eq!(
+        arch_name(object::Architecture::X86_64).as_deref(),
+        Some("x86_64")
+    );
+    assert_eq!(
+        arch_name(object::Architecture::Aarch64).as_deref(),
+        Some("aarch64")
+    );
+    assert_eq!(
+        arch_name(object::Architecture::I386).as_deref(),
+        Some("i386")
+    );
+    assert_eq!(arch_name(object::Architecture::Unknown), None);
+}
+
+/// Turns common aliases of architecture names, like `amd64` or `arm64`, into the names of
+/// [arch_name]
+pub fn normalize_arch(arch: &str) -> String {
+    let arch = arch.to_ascii_lowercase();
+    match arch.as_str() {
+        "amd64" | "x86-64" | "x64" => "x86_64".to_owned(),
+        "arm64" => "aarch64".to_owned(),
+        "i686" | "i586" | "i486" | "x86" => "i386".to_owned(),
+        _ => arch,
+    }
+}
+
+#[test]
+fn test_normalize_arch() {
+    assert_eq!(normalize_arch("amd64"), "x86_64");
+    assert_eq!(normalize_arch("ARM64"), "aarch64");
+    assert_eq!(normalize_arch("i686"), "i386");
+    assert_eq!(normalize_arch("riscv64"), "riscv64");
 }
 
 /// Return the build id of this file.
@@ -504,8 +2755,34 @@ impl SourceLocation {
 /// If the file is not an executable returns Ok(None).
 /// Errors are only for errors returned from the fs.
 pub fn get_buildid(path: &Path) -> anyhow::Result<Option<String>> {
+    Ok(get_elf_info(path)?.map(|info| info.buildid))
+}
+
+/// Return the build id of this file, and other information relevant for indexing.
+///
+/// If the file is not an executable or has no buildid, returns Ok(None).
+/// Errors are only for errors returned from the fs.
+pub fn get_elf_info(path: &Path) -> anyhow::Result<Option<ElfInfo>> {
     let file = std::fs::File::open(path)
         .with_context(|| format!("opening {} to get its buildid", path.display()))?;
+    get_elf_info_of_file(file, path)
+}
+
+/// Like [get_elf_info] for compressed debuginfo (see [is_compressed_debuginfo]), which is
+/// decompressed to a temporary file first, as the notes holding the buildid can only be found
+/// from the section headers at the end of the file
+fn get_compressed_elf_info(path: &Path) -> anyhow::Result<Option<ElfInfo>> {
+    let input = std::fs::File::open(path)
+        .with_context(|| format!("opening {} to get its buildid", path.display()))?;
+    let mut output = tempfile::tempfile().context("creating temporary file")?;
+    compress_tools::uncompress_data(input, &mut output)
+        .with_context(|| format!("uncompressing {}", path.display()))?;
+    get_elf_info_of_file(output, path)
+}
+
+/// Does the work of [get_elf_info] on the open file of `path`, which is only used in error
+/// messages
+fn get_elf_info_of_file(file: std::fs::File, path: &Path) -> anyhow::Result<Option<ElfInfo>> {
     let reader = object::read::ReadCache::new(file);
     let object = match object::read::File::parse(&reader) {
         Err(_) => {
@@ -515,16 +2792,166 @@ pub fn get_buildid(path: &Path) -> anyhow::Result<Option<String>> {
         }
         Ok(o) => o,
     };
-    match object
+    let go_buildid = object
+        .section_by_name(".note.go.buildid")
+        .and_then(|section| section.data().ok())
+        .and_then(|data| go_buildid_from_note(data, object.is_little_endian()))
+        .map(|id| base16::encode_lower(&id));
+    let (buildid, go_buildid) = match object
         .build_id()
         .with_context(|| format!("parsing {} for buildid", path.display()))?
     {
-        None => Ok(None),
-        Some(data) => {
-            let buildid = base16::encode_lower(&data);
-            Ok(Some(buildid))
+        Some(data) if !data.is_empty() => (base16::encode_lower(&data), go_buildid),
+        _ => match go_buildid {
+            None => return Ok(None),
+            Some(go_buildid) => (go_buildid, None),
+        },
+    };
+    let has_debuginfo = [".debug_info", ".zdebug_info"].iter().any(|name| {
+        object.section_by_name(name).is_some_and(|section| {
+            section.size() > 0 && section.kind() != object::SectionKind::UninitializedData
+        })
+    });
+    Ok(Some(ElfInfo {
+        buildid,
+        go_buildid,
+        has_debuginfo,
+        arch: arch_name(object.architecture()),
+    }))
+}
+
+/// The supplementary debug file a debug file refers to with `.gnu_debugaltlink`, as created by
+/// `dwz -m`. The debugger needs it too, and looks it up by its buildid.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugAltLink {
+    /// where the supplementary file is, absolute or relative to the directory of the debug file
+    pub path: PathBuf,
+    /// the buildid of the supplementary file, in hexadecimal
+    pub buildid: String,
+}
+
+/// Returns the supplementary debug file this elf file refers to, if any.
+///
+/// Blocking.
+pub fn get_debugaltlink(path: &Path) -> anyhow::Result<Option<DebugAltLink>> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("opening {} to get its debugaltlink", path.display()))?;
+    let reader = object::read::ReadCache::new(file);
+    let object = match object::read::File::parse(&reader) {
+        Err(_) => return Ok(None),
+        Ok(o) => o,
+    };
+    let link = object
+        .gnu_debugaltlink()
+        .with_context(|| format!("parsing .gnu_debugaltlink of {}", path.display()))?;
+    Ok(match link {
+        Some((filename, buildid)) if !filename.is_empty() && !buildid.is_empty() => {
+            Some(DebugAltLink {
+                path: PathBuf::from(OsStr::from_bytes(filename)),
+                buildid: base16::encode_lower(&buildid),
+            })
+        }
+        _ => None,
+    })
+}
+
+/// Where the supplementary file `link` of the debug file `debug_file` is.
+///
+/// Returns `None` if it is outside of the store and of `storepath`, the store path being
+/// indexed, so that no file outside the store is ever served.
+fn resolve_debugaltlink(debug_file: &Path, link: &Path, storepath: &Path) -> Option<PathBuf> {
+    let mut resolved = PathBuf::new();
+    for component in debug_file.parent()?.join(link).components() {
+        match component {
+            std::path::Component::ParentDir => {
+                resolved.pop();
+            }
+            std::path::Component::CurDir => (),
+            component => resolved.push(component),
         }
     }
+    if resolved.starts_with(storepath) || get_store_path(&resolved).is_some() {
+        Some(resolved)
+    } else {
+        None
+    }
+}
+
+#[test]
+fn test_resolve_debugaltlink() {
+    let storepath = Path::new("/nix/store/aaa-foo-debug");
+    let debug_file = storepath.join("lib/debug/.build-id/48/3bd7.debug");
+    assert_eq!(
+        resolve_debugaltlink(&debug_file, Path::new("../../.dwz/foo.debug"), storepath),
+        Some(storepath.join("lib/debug/.dwz/foo.debug"))
+    );
+    assert_eq!(
+        resolve_debugaltlink(
+            &debug_file,
+            Path::new("/nix/store/bbb-bar-debug/lib/debug/.dwz/bar.debug"),
+            storepath
+        ),
+        Some(PathBuf::from(
+            "/nix/store/bbb-bar-debug/lib/debug/.dwz/bar.debug"
+        ))
+    );
+    assert_eq!(
+        resolve_debugaltlink(&debug_file, Path::new("/etc/shadow"), storepath),
+        None
+    );
+    assert_eq!(
+        resolve_debugaltlink(
+            &debug_file,
+            Path::new("../../../../../../etc/shadow"),
+            storepath
+        ),
+        None
+    );
+}
+
+/// The type of the elf note containing the Go build ID
+const GO_BUILDID_NOTE_TYPE: u32 = 4;
+
+/// Extracts the Go build ID, like `go tool buildid` prints it, from the content of the
+/// `.note.go.buildid` section of a Go binary.
+fn go_buildid_from_note(data: &[u8], little_endian: bool) -> Option<Vec<u8>> {
+    let word = |offset: usize| -> Option<u32> {
+        let bytes: [u8; 4] = data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    };
+    let namesz = word(0)? as usize;
+    let descsz = word(4)? as usize;
+    if word(8)? != GO_BUILDID_NOTE_TYPE || data.get(12..12 + namesz)? != b"Go\0\0" {
+        return None;
+    }
+    // the name is padded to 4 bytes
+    let start = 12 + namesz.div_ceil(4) * 4;
+    let id = data.get(start..start + descsz)?;
+    let id = match id.iter().position(|&c| c == 0) {
+        Some(end) => &id[..end],
+        None => id,
+    };
+    (!id.is_empty()).then(|| id.to_vec())
+}
+
+#[test]
+fn test_go_buildid_from_note() {
+    let id = b"abcdefgh/ijklmnop";
+    let mut note = Vec::new();
+    note.extend(4u32.to_le_bytes());
+    note.extend((id.len() as u32).to_le_bytes());
+    note.extend(GO_BUILDID_NOTE_TYPE.to_le_bytes());
+    note.extend(b"Go\0\0");
+    note.extend(id);
+    assert_eq!(go_buildid_from_note(&note, true).as_deref(), Some(&id[..]));
+    assert_eq!(go_buildid_from_note(&note, false), None);
+    assert_eq!(go_buildid_from_note(&note[..20], true), None);
+    note[12] = b'X';
+    assert_eq!(go_buildid_from_note(&note, true), None);
 }
 
 /// To remove references, gcc is patched to replace the hash part
@@ -540,7 +2967,7 @@ pub fn demangle(storepath: PathBuf) -> PathBuf {
     }
     let mut as_bytes = storepath.into_os_string().into_vec();
     let len = as_bytes.len();
-    let store_len = NIX_STORE.as_bytes().len();
+    let store_len = NIX_STORE.len();
     as_bytes[len.min(store_len + 1)..len.min(store_len + 1 + 32)].make_ascii_lowercase();
     OsString::from_vec(as_bytes).into()
 }
@@ -576,7 +3003,111 @@ fn test_demangle_non_storepaht() {
     );
 }
 
+/// Rules rewriting the beginning of requested source paths, longest first.
+///
+/// Set by [set_source_prefix_map].
+static SOURCE_PREFIX_MAP: OnceCell<Vec<(PathBuf, PathBuf)>> = OnceCell::new();
+
+/// Parses a `FROM=TO` rule of [set_source_prefix_map]
+fn parse_source_prefix_rule(spec: &str) -> anyhow::Result<(PathBuf, PathBuf)> {
+    match spec.split_once('=') {
+        Some((from, to)) if !from.is_empty() => Ok((PathBuf::from(from), PathBuf::from(to))),
+        _ => anyhow::bail!("source prefix map {:?} is not of the form FROM=TO", spec),
+    }
+}
+
+/// Makes source lookups treat requested paths starting with `FROM` as if they started with
+/// `TO`, for each of these `FROM=TO` rules. When several rules apply, the one with the longest
+/// `FROM` wins.
+///
+/// Should be called on startup.
+pub fn set_source_prefix_map(specs: &[String]) -> anyhow::Result<()> {
+    let mut rules = specs
+        .iter()
+        .map(|spec| parse_source_prefix_rule(spec))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    rules.sort_by_key(|(from, _)| std::cmp::Reverse(from.components().count()));
+    if SOURCE_PREFIX_MAP.set(rules).is_err() {
+        tracing::warn!("source prefix map was already set");
+    }
+    Ok(())
+}
+
+/// Rewrites the beginning of this requested source path with the first rule which applies
+fn remap_source_prefix(rules: &[(PathBuf, PathBuf)], request: &Path) -> PathBuf {
+    for (from, to) in rules {
+        if let Ok(rest) = request.strip_prefix(from) {
+            return to.join(rest);
+        }
+    }
+    request.to_owned()
+}
+
+#[test]
+fn test_remap_source_prefix() {
+    let rules = vec![
+        parse_source_prefix_rule("/usr/src/foo/lib=/build/libfoo").unwrap(),
+        parse_source_prefix_rule("/usr/src/foo=/build/foo-1.2").unwrap(),
+        parse_source_prefix_rule("/home/ci=").unwrap(),
+    ];
+    let remap = |request: &str| remap_source_prefix(&rules, Path::new(request));
+    assert_eq!(
+        remap("/usr/src/foo/src/main.c"),
+        PathBuf::from("/build/foo-1.2/src/main.c")
+    );
+    assert_eq!(
+        remap("/usr/src/foo/lib/foo.c"),
+        PathBuf::from("/build/libfoo/foo.c")
+    );
+    assert_eq!(remap("/home/ci/src/main.c"), PathBuf::from("src/main.c"));
+    assert_eq!(
+        remap("/usr/src/foobar/main.c"),
+        PathBuf::from("/usr/src/foobar/main.c")
+    );
+    assert!(parse_source_prefix_rule("/usr/src/foo").is_err());
+    assert!(parse_source_prefix_rule("=/build").is_err());
+}
+
+/// The paths relative to the root of the source that this path in the nix build directory may
+/// be: `/build/foo-1.2/src/main.c` is `src/main.c` in a source directory copied to the
+/// `sourceRoot` `/build/foo-1.2`, and `foo-1.2/src/main.c` in a tarball unpacked in `/build`.
+fn build_relative_paths(request: &Path) -> Vec<PathBuf> {
+    let in_build = match request.strip_prefix("/build") {
+        Ok(rest) => rest,
+        Err(_) => return Vec::new(),
+    };
+    let mut components = in_build.components();
+    components.next();
+    let in_source_root = components.as_path();
+    let mut result = Vec::new();
+    if !in_source_root.as_os_str().is_empty() {
+        result.push(in_source_root.to_owned());
+    }
+    result.push(in_build.to_owned());
+    result
+}
+
+#[test]
+fn test_build_relative_paths() {
+    assert_eq!(
+        build_relative_paths(Path::new("/build/foo-1.2/src/main.c")),
+        vec![
+            PathBuf::from("src/main.c"),
+            PathBuf::from("foo-1.2/src/main.c")
+        ]
+    );
+    assert_eq!(
+        build_relative_paths(Path::new("/build/main.c")),
+        vec![PathBuf::from("main.c")]
+    );
+    assert!(build_relative_paths(Path::new("/usr/src/foo/main.c")).is_empty());
+    assert!(build_relative_paths(Path::new("/builder/foo/main.c")).is_empty());
+}
+
 /// Attempts to find a file that matches the request in an existing source path.
+///
+/// Files which are symlinks to outside of the source are never returned, see
+/// [crate::confine].
 pub fn get_file_for_source(
     source: &Path,
     request: &Path,
@@ -586,10 +3117,48 @@ pub fn get_file_for_source(
         request.display(),
         source.display()
     );
+    let members = list_source(source)?;
+    let location = best_source_match(source, &members, request)?;
+    if let Some(location) = &location {
+        check_confined(source, location)?;
+    }
+    Ok(location)
+}
 
-    let target: Vec<&OsStr> = request.iter().collect();
-    // invariant: we only keep candidates which have same path as target for components i..
-    let mut candidates: Vec<_> = Vec::new();
+/// Fails if this is a file outside of `source` once symlinks are resolved
+fn check_confined(source: &Path, location: &SourceLocation) -> anyhow::Result<()> {
+    match location {
+        SourceLocation::File(path) => confine(source, path).map(|_| ()),
+        // only regular files are read from archives
+        SourceLocation::Archive { .. } => Ok(()),
+    }
+}
+
+/// Attempts to find files matching each of these requests in an existing source path.
+///
+/// Returns the requests which matched a file without ambiguity, with this file.
+pub fn get_files_for_source(
+    source: &Path,
+    requests: &[PathBuf],
+) -> anyhow::Result<Vec<(PathBuf, SourceLocation)>> {
+    let members = list_source(source)?;
+    let mut result = Vec::new();
+    for request in requests {
+        match best_source_match(source, &members, request) {
+            Ok(Some(location)) => match check_confined(source, &location) {
+                Ok(()) => result.push((request.clone(), location)),
+                Err(e) => tracing::warn!("{:#}", e),
+            },
+            Ok(None) => (),
+            Err(e) => tracing::debug!("{:#}", e),
+        }
+    }
+    Ok(result)
+}
+
+/// Lists the files in a source path, which is either a directory or an archive
+fn list_source(source: &Path) -> anyhow::Result<Vec<SourceLocation>> {
+    let mut members = Vec::new();
     let source_type = source
         .metadata()
         .with_context(|| format!("stat({})", source.display()))?;
@@ -600,30 +3169,55 @@ pub fn get_file_for_source(
                     tracing::warn!("failed to walk source {}: {:#}", source.display(), e);
                     continue;
                 }
-                Ok(f) => {
-                    if Some(&f.file_name()) == target.last() {
-                        candidates.push(SourceLocation::File(f.path().to_path_buf()));
-                    }
-                }
+                Ok(f) => members.push(SourceLocation::File(f.path().to_path_buf())),
             }
         }
     } else if source_type.is_file() {
-        let mut archive = std::fs::File::open(&source)
+        let mut archive = std::fs::File::open(source)
             .with_context(|| format!("opening source archive {}", source.display()))?;
         let member_list = compress_tools::list_archive_files(&mut archive)
             .with_context(|| format!("listing files in source archive {}", source.display()))?;
         for member in member_list {
-            if Path::new(&member).file_name().as_ref() == target.last() {
-                candidates.push(SourceLocation::Archive {
-                    archive: source.to_path_buf(),
-                    member: PathBuf::from(member),
-                });
-            }
+            members.push(SourceLocation::Archive {
+                archive: source.to_path_buf(),
+                member: PathBuf::from(member),
+            });
         }
     }
+    Ok(members)
+}
+
+/// Finds the file among `members` of `source` which matches the request best
+fn best_source_match(
+    source: &Path,
+    members: &[SourceLocation],
+    request: &Path,
+) -> anyhow::Result<Option<SourceLocation>> {
+    let rules = SOURCE_PREFIX_MAP
+        .get()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let request = &remap_source_prefix(rules, request);
+    let target: Vec<&OsStr> = request.iter().collect();
+    // invariant: we only keep candidates which have same path as target for components i..
+    let mut candidates: Vec<_> = members
+        .iter()
+        .filter(|member| member.member_path().file_name().as_ref() == target.last())
+        .cloned()
+        .collect();
     if candidates.len() < 2 {
         return Ok(candidates.pop());
     }
+    // the exact location in the source of a path in the build directory beats the heuristic
+    for relative in build_relative_paths(request) {
+        let mut exact: Vec<_> = candidates
+            .iter()
+            .filter(|candidate| candidate.relative_path(source) == Some(relative.as_path()))
+            .collect();
+        if exact.len() == 1 {
+            return Ok(exact.pop().cloned());
+        }
+    }
     let mut best_total_len = 0;
     let mut best_matching_len = 0;
     let mut best_candidates = Vec::new();
@@ -773,16 +3367,124 @@ fn get_file_for_source_ambiguous() {
     }
 }
 
-/// Turns a path in the store as its topmost parent in /nix/store
+#[test]
+fn get_file_for_source_source_root() {
+    let dir = make_test_source_path(vec!["src/main.c", "source/src/main.c"]);
+    let res = get_file_for_source(dir.path(), "/build/source/src/main.c".as_ref());
+    assert_eq!(
+        res.unwrap().unwrap(),
+        SourceLocation::File(dir.path().join("src/main.c"))
+    );
+    let res = get_file_for_source(dir.path(), "/build/foo/source/src/main.c".as_ref());
+    assert_eq!(
+        res.unwrap().unwrap(),
+        SourceLocation::File(dir.path().join("source/src/main.c"))
+    );
+}
+
+#[test]
+fn get_file_for_source_symlink_escape() {
+    let dir = make_test_source_path(vec!["source/src/main.c", "secret.c"]);
+    std::os::unix::fs::symlink("../../secret.c", dir.path().join("source/src/secret.c")).unwrap();
+    let source = dir.path().join("source");
+    assert!(get_file_for_source(&source, "/build/source/src/secret.c".as_ref()).is_err());
+    assert!(
+        get_file_for_source(&source, "/build/source/src/main.c".as_ref())
+            .unwrap()
+            .is_some()
+    );
+    let found = get_files_for_source(
+        &source,
+        &[
+            PathBuf::from("/build/source/src/main.c"),
+            PathBuf::from("/build/source/src/secret.c"),
+        ],
+    )
+    .unwrap();
+    assert_eq!(found.len(), 1);
+}
+
+#[test]
+fn get_files_for_source_several() {
+    let dir = make_test_source_path(vec![
+        "lib/foo.c",
+        "src/main.c",
+        "sysdeps/a/open.c",
+        "sysdeps/b/open.c",
+    ]);
+    let found = get_files_for_source(
+        dir.path(),
+        &[
+            PathBuf::from("/build/source/src/main.c"),
+            PathBuf::from("foo.c"),
+            PathBuf::from("open.c"),
+            PathBuf::from("missing.c"),
+        ],
+    )
+    .unwrap();
+    assert_eq!(
+        found,
+        vec![
+            (
+                PathBuf::from("/build/source/src/main.c"),
+                SourceLocation::File(dir.path().join("src/main.c"))
+            ),
+            (
+                PathBuf::from("foo.c"),
+                SourceLocation::File(dir.path().join("lib/foo.c"))
+            ),
+        ]
+    );
+}
+
+/// Returns the files in the store mapped by a process, from the content of its
+/// `/proc/<pid>/maps`, without duplicates
+pub fn mapped_store_files(maps: &str) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = Vec::new();
+    for line in maps.lines() {
+        // address, perms, offset, dev, inode, then the path, which may contain spaces
+        let path = match line.splitn(6, char::is_whitespace).nth(5) {
+            Some(path) => path,
+            None => continue,
+        };
+        let path = Path::new(path.trim_start());
+        if get_store_path(path).is_some() && !files.iter().any(|file| file == path) {
+            files.push(path.to_path_buf());
+        }
+    }
+    files
+}
+
+#[test]
+fn test_mapped_store_files() {
+    let maps = "\
+55d4c5a00000-55d4c5a02000 r--p 00000000 00:1f 1234    /nix/store/aaa-hello/bin/hello
+55d4c5a02000-55d4c5a03000 r-xp 00002000 00:1f 1234    /nix/store/aaa-hello/bin/hello
+55d4c6b21000-55d4c6b42000 rw-p 00000000 00:00 0       [heap]
+7f0e1c000000-7f0e1c028000 r--p 00000000 00:1f 5678    /nix/store/bbb-glibc/lib/libc.so.6
+7f0e1c400000-7f0e1c401000 r--p 00000000 00:1f 91      /home/user/lib/libfoo.so
+7f0e1c500000-7f0e1c501000 rw-p 00000000 00:00 0 
+";
+    assert_eq!(
+        mapped_store_files(maps),
+        vec![
+            PathBuf::from("/nix/store/aaa-hello/bin/hello"),
+            PathBuf::from("/nix/store/bbb-glibc/lib/libc.so.6"),
+        ]
+    );
+}
+
+/// Turns a path in the store as its topmost parent in /nix/store, or in the store of an
+/// [extra store](set_extra_stores)
 pub fn get_store_path(path: &Path) -> Option<&Path> {
     let mut ancestors = path.ancestors().peekable();
     while let Some(a) = ancestors.next() {
         match ancestors.peek() {
-            Some(p) if p.as_os_str() == "/nix/store" => return Some(a),
+            Some(p) if is_store_dir(p) => return Some(a),
             _ => (),
         }
     }
-    return None;
+    None
 }
 
 #[test]
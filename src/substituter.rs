@@ -13,19 +13,24 @@ use std::{
     io::{BufReader, Read},
     os::unix::prelude::OsStrExt,
     path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
 use async_recursion::async_recursion;
 use async_trait::async_trait;
 use futures_util::StreamExt;
+use once_cell::sync::OnceCell;
 use reqwest::StatusCode;
 use reqwest::Url;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tempfile::TempDir;
 use tokio::io::{AsyncWriteExt, BufWriter};
 
-use crate::store::{get_buildid, get_store_path};
+use crate::store::{
+    command_output_async, get_buildid, get_store_path, nix_store_command, split_buildid,
+};
 
 #[derive(Deserialize)]
 struct DebuginfoMetadata {
@@ -46,8 +51,8 @@ async fn magic(path: &Path) -> anyhow::Result<Vec<u8>> {
     Ok(res)
 }
 
-const NAR_MAGIC: &'static [u8] = b"\x0d\x00\x00\x00\x00\x00\x00\x00nix-archive-1";
-const ELF_MAGIC: &'static [u8] = b"\x7fELF";
+const NAR_MAGIC: &[u8] = b"\x0d\x00\x00\x00\x00\x00\x00\x00nix-archive-1";
+const ELF_MAGIC: &[u8] = b"\x7fELF";
 
 /// API to fetch debuginfo indices from substituters
 #[async_trait]
@@ -58,8 +63,112 @@ pub trait Substituter: Send + Sync {
     /// Returns None in case of missing file.
     async fn fetch(&self, path: &Path) -> anyhow::Result<Option<PathBuf>>;
 
+    /// Removes the file returned by [Substituter::fetch] for this path, if it was downloaded,
+    /// once it is not needed anymore
+    fn discard(&self, _path: &Path) {}
+
     /// the url used to construct this substituter
     fn url(&self) -> &str;
+
+    /// How well fetches from this substituter went so far
+    fn health(&self) -> Health {
+        Health {
+            url: self.url().to_owned(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Weight of the last fetch in the moving averages of [Health]. With [DEGRADED_ERROR_RATE], a
+/// binary cache which answered so far is deprioritised after 3 failures in a row.
+const HEALTH_SMOOTHING: f64 = 0.25;
+
+/// A binary cache failing at least this fraction of fetches lately is queried after the others
+const DEGRADED_ERROR_RATE: f64 = 0.5;
+
+/// A binary cache answering slower than this lately is queried after the others
+const DEGRADED_LATENCY: Duration = Duration::from_secs(5);
+
+/// Error rate and latency of a binary cache, as returned by [Substituter::health]
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Health {
+    /// the url of the binary cache
+    pub url: String,
+    /// how many fetches were attempted
+    pub fetches: u64,
+    /// how many of them failed
+    pub failures: u64,
+    /// moving average of the fraction of failed fetches
+    pub error_rate: f64,
+    /// moving average of how long the binary cache took to answer, in milliseconds, if it ever
+    /// answered
+    pub latency_ms: Option<f64>,
+    /// whether it is not contacted for now because it failed too many times in a row
+    pub circuit_open: bool,
+}
+
+impl Health {
+    /// Records the outcome of a fetch, and how long the binary cache took to answer if it did
+    fn record(&mut self, success: bool, latency: Option<Duration>) {
+        self.fetches += 1;
+        if !success {
+            self.failures += 1;
+        }
+        let failed = if success { 0. } else { 1. };
+        self.error_rate += HEALTH_SMOOTHING * (failed - self.error_rate);
+        if let Some(latency) = latency {
+            let latency = latency.as_secs_f64() * 1000.;
+            self.latency_ms = Some(match self.latency_ms {
+                None => latency,
+                Some(average) => average + HEALTH_SMOOTHING * (latency - average),
+            });
+        }
+    }
+
+    /// Whether this binary cache should be queried after the healthy ones
+    pub fn is_degraded(&self) -> bool {
+        self.circuit_open
+            || self.error_rate >= DEGRADED_ERROR_RATE
+            || self
+                .latency_ms
+                .is_some_and(|latency| latency > DEGRADED_LATENCY.as_secs_f64() * 1000.)
+    }
+}
+
+#[test]
+fn test_health() {
+    let mut health = Health::default();
+    assert!(!health.is_degraded());
+    health.record(true, Some(Duration::from_millis(100)));
+    assert_eq!(health.latency_ms, Some(100.));
+    for _ in 0..2 {
+        health.record(false, None);
+    }
+    assert!(!health.is_degraded());
+    health.record(false, None);
+    assert!(health.is_degraded());
+    assert_eq!(health.failures, 3);
+    // recovers after enough successes
+    for _ in 0..5 {
+        health.record(true, Some(Duration::from_millis(100)));
+    }
+    assert!(!health.is_degraded());
+    for _ in 0..20 {
+        health.record(true, Some(Duration::from_secs(10)));
+    }
+    assert!(health.is_degraded());
+    assert_eq!(health.fetches, 29);
+}
+
+/// Orders these substituters so that the degraded ones (see [Health::is_degraded]) come last,
+/// keeping the configured order otherwise.
+///
+/// Degraded substituters are still queried when the others do not have a file, so they are
+/// promoted again once they recover.
+pub fn by_health(substituters: &[Arc<dyn Substituter>]) -> Vec<Arc<dyn Substituter>> {
+    let mut result = substituters.to_vec();
+    result.sort_by_cached_key(|substituter| substituter.health().is_degraded());
+    result
 }
 
 /// returns a store path containing the requested debuginfo in
@@ -95,11 +204,14 @@ pub async fn fetch_debuginfo<T: Substituter + ?Sized>(
 ///
 /// returns a store path containing it
 #[async_recursion]
-async fn fetch_debuginfo_from<T: Substituter + ?Sized>(
+async fn fetch_debuginfo_from<T>(
     substituter: &T,
     path: &Path,
     max_redirects: usize,
-) -> anyhow::Result<Option<PathBuf>> {
+) -> anyhow::Result<Option<PathBuf>>
+where
+    T: Substituter + ?Sized,
+{
     tracing::debug!(
         "attempting to fetch {} from {}",
         path.display(),
@@ -137,14 +249,23 @@ async fn fetch_debuginfo_from<T: Substituter + ?Sized>(
                 ),
                 Some(x) => x,
             };
+            let (buildid_dir, buildid_file) = match split_buildid(&buildid) {
+                None => anyhow::bail!(
+                    "fetched elf file from {} in {} has invalid build id {}",
+                    path.display(),
+                    substituter.url(),
+                    buildid
+                ),
+                Some(x) => x,
+            };
             let dir = TempDir::new().context("tempdir")?;
             target = dir.path().join("target-nar");
             let mut parent = target.join("lib/debug/.build-id");
-            parent.push(&buildid[..2]);
+            parent.push(buildid_dir);
             tokio::fs::create_dir_all(parent.as_path())
                 .await
                 .with_context(|| format!("creating {}", parent.display()))?;
-            parent.push(format!("{}.debug", &buildid[2..]));
+            parent.push(format!("{}.debug", buildid_file));
             tokio::fs::copy(file.as_path(), parent.as_path())
                 .await
                 .context("copying debuginfo file")?;
@@ -212,21 +333,26 @@ async fn fetch_debuginfo_from<T: Substituter + ?Sized>(
             };
             // unpack the nar
             let fd = tokio::fs::File::open(nar_file).await?;
-            let mut cmd = tokio::process::Command::new("nix-store");
+            let mut cmd = tokio::process::Command::from(nix_store_command());
             cmd.arg("--restore");
             tempdir = tempfile::TempDir::new().context("tempdir")?;
             // FIXME: the indexer should probably not take the name of the store path into account
             target = tempdir.as_ref().join("nar-debug");
             cmd.arg(target.as_path());
             cmd.stdin(fd.into_std().await);
-            let status = cmd.status().await.with_context(|| {
+            let output = command_output_async(&mut cmd).await.with_context(|| {
                 format!(
                     "running nix-store --import to unpack nar from {} in {}",
                     nar_file.display(),
                     substituter.url()
                 )
             })?;
-            anyhow::ensure!(status.success(), "nix-store --import failed: {:?}", status);
+            anyhow::ensure!(
+                output.status.success(),
+                "nix-store --import failed: {:?}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            );
             anyhow::ensure!(
                 target.exists(),
                 "nix-store --import failed to create {}",
@@ -238,10 +364,12 @@ async fn fetch_debuginfo_from<T: Substituter + ?Sized>(
     };
 
     // add it to the store
-    let mut cmd = tokio::process::Command::new("nix-store");
+    let mut cmd = tokio::process::Command::from(nix_store_command());
     cmd.arg("--add");
     cmd.arg(dir_to_add);
-    let output = cmd.output().await.context("nix-store --add")?;
+    let output = command_output_async(&mut cmd)
+        .await
+        .context("nix-store --add")?;
     anyhow::ensure!(
         output.status.success(),
         "nix-store --add failed: {:?}: {}",
@@ -328,10 +456,11 @@ async fn file_substituter_from_url() {
         FileSubstituter::from_url("https://cache.nixos.rg").await,
         Ok(None)
     ));
-    assert!(matches!(
-        FileSubstituter::from_url(&format!("file://{}/doesnotexist", d.path().display())).await,
-        Err(_)
-    ));
+    assert!(
+        FileSubstituter::from_url(&format!("file://{}/doesnotexist", d.path().display()))
+            .await
+            .is_err()
+    );
     let ok = FileSubstituter::from_url(&format!(
         "file://{}/./?with_query_string=true",
         d.path().display()
@@ -354,6 +483,109 @@ async fn file_substituter_fetch() {
     assert_eq!(ok.fetch(Path::new("./file")).await.unwrap().unwrap(), path);
 }
 
+/// How [HttpSubstituter] retries failed requests, and stops contacting a binary cache which
+/// keeps failing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// how many times a request failing with a network error or a 5xx status is retried
+    pub retries: u32,
+    /// delay before the first retry, doubled for each following one
+    pub backoff: Duration,
+    /// after this many failed fetches in a row, the binary cache is not contacted for
+    /// `cooldown`. 0 disables this.
+    pub failure_threshold: u32,
+    /// how long a binary cache is not contacted after failing `failure_threshold` times
+    pub cooldown: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 2,
+            backoff: Duration::from_millis(200),
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(60),
+        }
+    }
+}
+
+/// The policy of all [HttpSubstituter]s
+///
+/// Set by [set_retry_policy].
+static RETRY_POLICY: OnceCell<RetryPolicy> = OnceCell::new();
+
+/// Makes [HttpSubstituter]s retry according to this policy.
+///
+/// Should be called on startup.
+pub fn set_retry_policy(policy: RetryPolicy) {
+    if RETRY_POLICY.set(policy).is_err() {
+        tracing::warn!("retry policy was already set");
+    }
+}
+
+/// Failures of a binary cache, to stop contacting it for a while when it keeps failing
+#[derive(Debug, Default)]
+struct CircuitBreaker {
+    /// how many fetches failed in a row
+    consecutive_failures: u32,
+    /// until when the binary cache is not contacted
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    /// Returns an error if the binary cache should not be contacted now
+    fn check(&mut self, now: Instant) -> anyhow::Result<()> {
+        match self.open_until {
+            Some(until) if now < until => anyhow::bail!(
+                "not contacted for {}s after {} failures in a row",
+                (until - now).as_secs(),
+                self.consecutive_failures
+            ),
+            Some(_) => {
+                // let one request through, a failure opens the circuit again
+                self.open_until = None;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Records the outcome of a fetch
+    fn record(&mut self, success: bool, policy: &RetryPolicy, now: Instant) {
+        if success {
+            self.consecutive_failures = 0;
+            return;
+        }
+        self.consecutive_failures += 1;
+        if policy.failure_threshold > 0 && self.consecutive_failures >= policy.failure_threshold {
+            self.open_until = Some(now + policy.cooldown);
+        }
+    }
+}
+
+#[test]
+fn test_circuit_breaker() {
+    let policy = RetryPolicy {
+        failure_threshold: 2,
+        cooldown: Duration::from_secs(10),
+        ..Default::default()
+    };
+    let mut breaker = CircuitBreaker::default();
+    let now = Instant::now();
+    breaker.record(false, &policy, now);
+    assert!(breaker.check(now).is_ok());
+    breaker.record(false, &policy, now);
+    assert!(breaker.check(now + Duration::from_secs(1)).is_err());
+    // half open after the cooldown
+    assert!(breaker.check(now + Duration::from_secs(11)).is_ok());
+    breaker.record(false, &policy, now + Duration::from_secs(11));
+    assert!(breaker.check(now + Duration::from_secs(12)).is_err());
+    assert!(breaker.check(now + Duration::from_secs(22)).is_ok());
+    breaker.record(true, &policy, now + Duration::from_secs(22));
+    breaker.record(false, &policy, now + Duration::from_secs(22));
+    assert!(breaker.check(now + Duration::from_secs(22)).is_ok());
+}
+
 /// A https:/// substituter
 #[derive(Debug)]
 pub struct HttpSubstituter {
@@ -364,6 +596,10 @@ pub struct HttpSubstituter {
     url: String,
     client: reqwest::Client,
     cache: TempDir,
+    // failures of this binary cache
+    breaker: Mutex<CircuitBreaker>,
+    // error rate and latency of this binary cache
+    health: Mutex<Health>,
 }
 
 impl HttpSubstituter {
@@ -385,15 +621,103 @@ impl HttpSubstituter {
         }
 
         let cache = TempDir::new().context("tempdir")?;
-        let client = reqwest::Client::new();
+        let client = crate::tls::client();
 
         Ok(Some(HttpSubstituter {
             http_url,
             url: url.to_owned(),
             cache,
             client,
+            breaker: Mutex::default(),
+            health: Mutex::new(Health {
+                url: url.to_owned(),
+                ..Default::default()
+            }),
         }))
     }
+
+    /// Records the outcome of a fetch in the circuit breaker and the health of this binary
+    /// cache, with how long it took to answer, if it did
+    fn record(&self, success: bool, latency: Option<Duration>) {
+        {
+            let mut health = self.health.lock().unwrap();
+            let was_degraded = health.is_degraded();
+            health.record(success, latency);
+            match (was_degraded, health.is_degraded()) {
+                (false, true) => tracing::warn!(
+                    "querying {} after other binary caches: error rate {:.0}%, latency {:.0}ms",
+                    self.url(),
+                    health.error_rate * 100.,
+                    health.latency_ms.unwrap_or_default()
+                ),
+                (true, false) => tracing::info!("{} is healthy again", self.url()),
+                _ => (),
+            }
+        }
+        let policy = RETRY_POLICY.get_or_init(RetryPolicy::default);
+        let mut breaker = self.breaker.lock().unwrap();
+        let was_open = breaker.open_until.is_some();
+        breaker.record(success, policy, Instant::now());
+        if !was_open && breaker.open_until.is_some() {
+            tracing::warn!(
+                "{} failed {} times in a row, not contacting it for {:?}",
+                self.url(),
+                breaker.consecutive_failures,
+                policy.cooldown
+            );
+        }
+    }
+
+    /// The url of this path relative to the root of the binary cache
+    fn url_of(&self, path: &Path) -> anyhow::Result<Url> {
+        let path_str = path
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("invalid path {}", path.display()))?;
+        self.http_url
+            .join(path_str)
+            .with_context(|| format!("cannot join {} to {}", path_str, &self.http_url))
+    }
+
+    /// The name of the file downloaded from this url in the cache
+    fn hash(url: &Url) -> u64 {
+        let mut hasher = DefaultHasher::default();
+        url.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Gets this url, retrying on network errors and 5xx statuses.
+    ///
+    /// Returns `None` if the file does not exist.
+    async fn send(&self, url: &Url) -> anyhow::Result<Option<reqwest::Response>> {
+        let policy = RETRY_POLICY.get_or_init(RetryPolicy::default);
+        let mut backoff = policy.backoff;
+        let mut attempt = 0;
+        loop {
+            let error = match self.client.get(url.as_str()).send().await {
+                Ok(r) if r.status() == StatusCode::NOT_FOUND => return Ok(None),
+                Err(e) if e.status() == Some(StatusCode::NOT_FOUND) => return Ok(None),
+                Ok(r) if r.status() == StatusCode::OK => return Ok(Some(r)),
+                Ok(r)
+                    if r.status().is_server_error()
+                        || r.status() == StatusCode::TOO_MANY_REQUESTS =>
+                {
+                    anyhow::anyhow!("{} returned status {}", url, r.status())
+                }
+                Ok(r) => {
+                    tracing::warn!("unexpected status {} for {}", r.status(), url);
+                    anyhow::bail!("{} returned status {}", url, r.status());
+                }
+                Err(e) => anyhow::Error::new(e),
+            };
+            if attempt >= policy.retries {
+                return Err(error);
+            }
+            attempt += 1;
+            tracing::debug!("{:#}, retrying {} in {:?}", error, url, backoff);
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
 }
 
 #[async_trait]
@@ -404,17 +728,8 @@ impl Substituter for HttpSubstituter {
             "substituter path {} should be relative",
             path.display()
         );
-        let path_str = path
-            .to_str()
-            .ok_or_else(|| anyhow::anyhow!("invalid path {}", path.display()))?;
-        let url = self
-            .http_url
-            .join(path_str)
-            .with_context(|| format!("cannot join {} to {}", path_str, &self.http_url))?;
-
-        let mut hasher = DefaultHasher::default();
-        url.hash(&mut hasher);
-        let hash = hasher.finish();
+        let url = self.url_of(path)?;
+        let hash = Self::hash(&url);
         let cache_path = self.cache.path().join(format!("{hash:x}"));
 
         if cache_path.exists() {
@@ -425,46 +740,58 @@ impl Substituter for HttpSubstituter {
         let fd = tokio::fs::File::create(&tmp).await.context("temp file")?;
         let mut write = BufWriter::new(fd);
 
+        self.breaker
+            .lock()
+            .unwrap()
+            .check(Instant::now())
+            .with_context(|| format!("skipping {}", self.url()))?;
         tracing::debug!("getting {}", &url);
-        let response = match self.client.get(url.as_str()).send().await {
-            Ok(r) if r.status() == StatusCode::NOT_FOUND => {
+        let start = Instant::now();
+        let response = self.send(&url).await;
+        let latency = start.elapsed();
+        let response = match response {
+            Ok(None) => {
+                self.record(true, Some(latency));
                 tracing::debug!("{} not found in {}", path.display(), self.url());
                 return Ok(None);
             }
-            Err(e) if e.status() == Some(StatusCode::NOT_FOUND) => {
-                tracing::debug!("{} not found in {}", path.display(), self.url());
-                return Ok(None);
-            }
-            Ok(r) if r.status() != StatusCode::OK => {
-                tracing::warn!("unexpected status {} for {}", r.status(), &url);
-                anyhow::bail!("{} returned status {}", self.url(), r.status());
+            Ok(Some(r)) => r,
+            Err(e) => {
+                self.record(false, None);
+                anyhow::bail!(
+                    "cannot fetch {} for {} in {}: {:#}",
+                    &url,
+                    path.display(),
+                    self.url(),
+                    e
+                )
             }
-            Ok(r) => r,
-            Err(e) => anyhow::bail!(
-                "cannot fetch {} for {} in {}: {:#}",
-                &url,
-                path.display(),
-                self.url(),
-                e
-            ),
         };
         let mut body = response.bytes_stream();
 
         while let Some(chunk) = body.next().await {
-            let chunk = chunk.with_context(|| {
-                format!(
-                    "downloading from {} for {} in {}",
-                    &url,
-                    path.display(),
-                    self.url()
-                )
-            })?;
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    self.record(false, Some(latency));
+                    return Err(e).with_context(|| {
+                        format!(
+                            "downloading from {} for {} in {}",
+                            &url,
+                            path.display(),
+                            self.url()
+                        )
+                    });
+                }
+            };
             write
                 .write_all(&chunk)
                 .await
                 .context("writing to tmp file")?;
         }
 
+        self.record(true, Some(latency));
+
         write.flush().await.context("writing to disk")?;
         write.into_inner().sync_data().await.context("syncing")?;
 
@@ -473,7 +800,25 @@ impl Substituter for HttpSubstituter {
         Ok(Some(cache_path))
     }
 
+    fn discard(&self, path: &Path) {
+        if let Ok(url) = self.url_of(path) {
+            let hash = Self::hash(&url);
+            std::fs::remove_file(self.cache.path().join(format!("{hash:x}"))).ok();
+        }
+    }
+
     fn url(&self) -> &str {
         &self.url
     }
+
+    fn health(&self) -> Health {
+        let mut health = self.health.lock().unwrap().clone();
+        health.circuit_open = self
+            .breaker
+            .lock()
+            .unwrap()
+            .open_until
+            .is_some_and(|until| Instant::now() < until);
+        health
+    }
 }
@@ -0,0 +1,295 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Fetching sources from the [Software Heritage](https://www.softwareheritage.org) archive.
+//!
+//! When the source of a derivation can neither be substituted nor refetched, typically because
+//! the upstream tarball disappeared years ago, Software Heritage may still have a copy.
+//! Sources fetched as a flat file (`fetchurl`) are looked up by the sha256 of their content.
+//! Sources fetched as a tree (`fetchFromGitHub`, `fetchgit`...) are looked up by the sha256 of
+//! their NAR serialisation, which Software Heritage records when archiving nixpkgs sources, and
+//! only the requested file is downloaded.
+
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::Context;
+use futures_util::StreamExt;
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use sha2::Digest;
+use tokio::io::{AsyncWriteExt, BufWriter};
+
+use crate::store::{get_file_for_source, FixedOutputHash, SourceLocation};
+
+/// The root of the API of Software Heritage
+const API: &str = "https://archive.softwareheritage.org/api/1";
+
+/// At most how many paths are tried in a source tree for a requested file. Anonymous access
+/// to the API is rate limited.
+const MAX_PATH_LOOKUPS: usize = 6;
+
+/// Response of `/extid/`
+#[derive(Deserialize)]
+struct ExtId {
+    /// the swhid of what has this extid, like `swh:1:dir:<sha1_git>`
+    target: String,
+}
+
+/// Response of `/directory/<sha1_git>/<path>/`
+#[derive(Deserialize)]
+struct DirectoryEntry {
+    /// `file`, `dir` or `rev`
+    #[serde(rename = "type")]
+    kind: String,
+    /// the sha1_git of the entry
+    target: String,
+}
+
+#[test]
+fn test_directory_entry() {
+    let entry: DirectoryEntry = serde_json::from_slice(
+        br#"{"dir_id":"d1","name":"main.c","type":"file","target":"6c4b2f","perms":33188}"#,
+    )
+    .unwrap();
+    assert_eq!(entry.kind, "file");
+    assert_eq!(entry.target, "6c4b2f");
+}
+
+/// A client of the Software Heritage API
+pub struct SoftwareHeritage {
+    client: reqwest::Client,
+    /// where downloaded files are kept
+    cache_dir: PathBuf,
+}
+
+/// The candidate paths of the requested file relative to the root of the source, most
+/// specific first.
+///
+/// The requested path is where the file was at build time, like `/build/source/src/main.c`,
+/// so the root of the source is one of its ancestors.
+fn candidate_paths(request: &Path) -> Vec<String> {
+    let components: Vec<&OsStr> = request
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(c) => Some(c),
+            _ => None,
+        })
+        .collect();
+    (0..components.len())
+        .filter_map(|i| {
+            let suffix: Vec<&str> = components[i..]
+                .iter()
+                .map(|c| c.to_str())
+                .collect::<Option<_>>()?;
+            Some(suffix.join("/"))
+        })
+        .take(MAX_PATH_LOOKUPS)
+        .collect()
+}
+
+#[test]
+fn test_candidate_paths() {
+    assert_eq!(
+        candidate_paths(Path::new("/build/source/src/main.c")),
+        vec![
+            "build/source/src/main.c",
+            "source/src/main.c",
+            "src/main.c",
+            "main.c"
+        ]
+    );
+    assert_eq!(candidate_paths(Path::new("/")), Vec::<String>::new());
+}
+
+impl SoftwareHeritage {
+    /// A client keeping downloaded files in `cache_dir`
+    pub fn new(cache_dir: PathBuf) -> Self {
+        SoftwareHeritage {
+            client: crate::tls::client(),
+            cache_dir,
+        }
+    }
+
+    /// Gets `API/path`, returning `None` if it does not exist
+    async fn get(&self, path: &str) -> anyhow::Result<Option<reqwest::Response>> {
+        let url = format!("{}/{}", API, path);
+        tracing::debug!("getting {}", &url);
+        let response = self
+            .client
+            .get(&url)
+            .send()
+            .await
+            .with_context(|| format!("fetching {}", &url))?;
+        match response.status() {
+            StatusCode::OK => Ok(Some(response)),
+            StatusCode::NOT_FOUND => Ok(None),
+            StatusCode::TOO_MANY_REQUESTS => {
+                anyhow::bail!("rate limited by Software Heritage while fetching {}", &url)
+            }
+            status => anyhow::bail!("{} returned status {}", &url, status),
+        }
+    }
+
+    /// Gets `API/path` as json, returning `None` if it does not exist
+    async fn get_json<T: DeserializeOwned>(&self, path: &str) -> anyhow::Result<Option<T>> {
+        match self.get(path).await? {
+            None => Ok(None),
+            Some(response) => {
+                let body = response
+                    .bytes()
+                    .await
+                    .with_context(|| format!("reading response of {}", path))?;
+                Ok(Some(serde_json::from_slice(&body).with_context(|| {
+                    format!("parsing response of {}", path)
+                })?))
+            }
+        }
+    }
+
+    /// Downloads `API/path` to `target`, unless it already exists.
+    ///
+    /// If `sha256` is set, the download is checked against it. Returns false if the file
+    /// does not exist.
+    async fn download(
+        &self,
+        path: &str,
+        target: &Path,
+        sha256: Option<&str>,
+    ) -> anyhow::Result<bool> {
+        if target.exists() {
+            return Ok(true);
+        }
+        let response = match self.get(path).await? {
+            None => return Ok(false),
+            Some(response) => response,
+        };
+        let dir = target.parent().context("download target has no parent")?;
+        tokio::fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("creating {}", dir.display()))?;
+        let tmp = tempfile::Builder::new()
+            .prefix(".download")
+            .tempfile_in(dir)
+            .context("creating temporary file")?
+            .into_temp_path();
+        let fd = tokio::fs::File::create(&tmp).await.context("temp file")?;
+        let mut write = BufWriter::new(fd);
+        let mut hasher = sha2::Sha256::new();
+        let mut body = response.bytes_stream();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.with_context(|| format!("downloading {}", path))?;
+            hasher.update(&chunk);
+            write
+                .write_all(&chunk)
+                .await
+                .context("writing to tmp file")?;
+        }
+        write.flush().await.context("writing to disk")?;
+        if let Some(sha256) = sha256 {
+            let actual = base16::encode_lower(&hasher.finalize());
+            anyhow::ensure!(
+                actual == sha256,
+                "{} has sha256 {} instead of {}",
+                path,
+                actual,
+                sha256
+            );
+        }
+        tmp.persist(target).context("renaming temp file")?;
+        Ok(true)
+    }
+
+    /// Looks for the file `request` in the source with this hash.
+    pub async fn fetch_source(
+        &self,
+        hash: &FixedOutputHash,
+        request: &Path,
+    ) -> anyhow::Result<Option<SourceLocation>> {
+        if hash.recursive {
+            self.fetch_from_tree(&hash.sha256, request).await
+        } else {
+            self.fetch_from_archive(&hash.sha256, request).await
+        }
+    }
+
+    /// Downloads the source archive with this sha256 and looks for `request` in it
+    async fn fetch_from_archive(
+        &self,
+        sha256: &str,
+        request: &Path,
+    ) -> anyhow::Result<Option<SourceLocation>> {
+        let archive = self.cache_dir.join("content").join(sha256);
+        let path = format!("content/sha256:{}/raw/", sha256);
+        if !self.download(&path, &archive, Some(sha256)).await? {
+            tracing::debug!("source with sha256 {} is not archived", sha256);
+            return Ok(None);
+        }
+        tracing::info!("using source with sha256 {} from Software Heritage", sha256);
+        let request = request.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let source = match crate::sourcecache::extracted(&archive) {
+                Ok(dir) => dir,
+                Err(e) => {
+                    tracing::debug!("cannot extract {}: {:#}", archive.display(), e);
+                    archive
+                }
+            };
+            get_file_for_source(&source, &request)
+        })
+        .await?
+    }
+
+    /// Finds the source tree with this NAR sha256 and downloads the file `request` from it
+    async fn fetch_from_tree(
+        &self,
+        nar_sha256: &str,
+        request: &Path,
+    ) -> anyhow::Result<Option<SourceLocation>> {
+        let extid: ExtId = match self
+            .get_json(&format!("extid/nar-sha256/hex:{}/", nar_sha256))
+            .await?
+        {
+            None => {
+                tracing::debug!("source with NAR sha256 {} is not archived", nar_sha256);
+                return Ok(None);
+            }
+            Some(extid) => extid,
+        };
+        let directory = match extid.target.strip_prefix("swh:1:dir:") {
+            Some(directory) => directory.to_owned(),
+            None => anyhow::bail!("unexpected target {} for a source tree", extid.target),
+        };
+        for candidate in candidate_paths(request) {
+            let entry = match self
+                .get_json::<DirectoryEntry>(&format!("directory/{}/{}/", directory, candidate))
+                .await?
+            {
+                // the target is used as a directory name
+                Some(entry)
+                    if entry.kind == "file"
+                        && entry.target.bytes().all(|c| c.is_ascii_hexdigit()) =>
+                {
+                    entry
+                }
+                _ => continue,
+            };
+            let name = request
+                .file_name()
+                .context("requested source has no name")?;
+            let target = self.cache_dir.join("files").join(&entry.target).join(name);
+            let path = format!("content/sha1_git:{}/raw/", entry.target);
+            if self.download(&path, &target, None).await? {
+                tracing::info!(
+                    "downloaded {} of source tree {} from Software Heritage",
+                    candidate,
+                    directory
+                );
+                return Ok(Some(SourceLocation::File(target)));
+            }
+        }
+        Ok(None)
+    }
+}
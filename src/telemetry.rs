@@ -0,0 +1,615 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Counters describing the throughput of indexation, independently of requests, the requests
+//! of each endpoint, and optionally the requests of each client.
+//!
+//! Indexation happens in blocking threads deep in [crate::store], so counters are global.
+//! All but the counters of clients are also exposed on `/metrics` for Prometheus, see
+//! [prometheus].
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use axum::extract::{ConnectInfo, MatchedPath, Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use hashlink::LruCache;
+use http::header::CONTENT_LENGTH;
+use http::HeaderName;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+/// Over how many seconds [IndexerTelemetry::paths_per_second] is averaged
+const RATE_WINDOW_SECS: usize = 60;
+
+/// Counters updated during indexation
+pub struct IndexerTelemetry {
+    /// when the process started, to bucket completion times
+    start: Instant,
+    /// store paths completely indexed
+    paths_indexed: AtomicU64,
+    /// elf files whose buildid was read
+    elf_files_parsed: AtomicU64,
+    /// entries written to the cache
+    entries_registered: AtomicU64,
+    /// number of calls to `nix-store --query` for derivers
+    deriver_queries: AtomicU64,
+    /// total duration of these calls
+    deriver_query_micros: AtomicU64,
+    /// store paths scheduled for indexation but not indexed yet
+    queued_paths: AtomicI64,
+    /// number of paths indexed during each of the last seconds, as (second since start, count)
+    recent: Mutex<[(u64, u64); RATE_WINDOW_SECS]>,
+}
+
+/// The global indexation counters
+pub static INDEXER: Lazy<IndexerTelemetry> = Lazy::new(|| IndexerTelemetry {
+    start: Instant::now(),
+    paths_indexed: AtomicU64::new(0),
+    elf_files_parsed: AtomicU64::new(0),
+    entries_registered: AtomicU64::new(0),
+    deriver_queries: AtomicU64::new(0),
+    deriver_query_micros: AtomicU64::new(0),
+    queued_paths: AtomicI64::new(0),
+    recent: Mutex::new([(0, 0); RATE_WINDOW_SECS]),
+});
+
+/// Indexation counters at some point in time, as reported by `/admin/stats`
+#[derive(Debug, Clone, Serialize)]
+pub struct IndexerSnapshot {
+    /// store paths indexed since startup
+    pub paths_indexed: u64,
+    /// store paths indexed per second, over the last minute
+    pub paths_per_second: f64,
+    /// elf files whose buildid was read since startup
+    pub elf_files_parsed: u64,
+    /// entries written to the cache since startup
+    pub entries_registered: u64,
+    /// queries of the deriver of store paths since startup
+    pub deriver_queries: u64,
+    /// mean duration of these queries, in milliseconds
+    pub mean_deriver_query_ms: f64,
+    /// store paths scheduled for indexation but not indexed yet
+    pub queue_depth: u64,
+    /// store paths registered in the nix db but not indexed yet, if known
+    pub backlog: Option<u64>,
+    /// how long ago the oldest of these paths was registered, in seconds, if known
+    pub backlog_age_secs: Option<u64>,
+    /// when the last indexed store path was registered, in seconds since the epoch, if known
+    pub watermark: Option<i64>,
+    /// why background indexation is paused, if it is
+    pub paused: Option<String>,
+}
+
+impl IndexerTelemetry {
+    /// Records that this many store paths were scheduled for indexation
+    pub fn queued(&self, n: usize) {
+        self.queued_paths.fetch_add(n as i64, Ordering::Relaxed);
+    }
+
+    /// Records that a store path scheduled with [IndexerTelemetry::queued] was indexed
+    pub fn path_indexed(&self) {
+        self.queued_paths.fetch_sub(1, Ordering::Relaxed);
+        self.paths_indexed.fetch_add(1, Ordering::Relaxed);
+        let second = self.start.elapsed().as_secs();
+        let mut recent = self.recent.lock().unwrap();
+        let bucket = &mut recent[second as usize % RATE_WINDOW_SECS];
+        if bucket.0 != second {
+            *bucket = (second, 0);
+        }
+        bucket.1 += 1;
+    }
+
+    /// Records that the buildid of an elf file was read
+    pub fn elf_file_parsed(&self) {
+        self.elf_files_parsed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that this many entries were written to the cache
+    pub fn entries_registered(&self, n: usize) {
+        self.entries_registered
+            .fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Records that querying a deriver took that long
+    pub fn deriver_queried(&self, duration: Duration) {
+        self.deriver_queries.fetch_add(1, Ordering::Relaxed);
+        self.deriver_query_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the current value of the counters. The backlog is left unknown.
+    pub fn snapshot(&self) -> IndexerSnapshot {
+        let now = self.start.elapsed().as_secs();
+        let recent: u64 = self
+            .recent
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(second, _)| now.saturating_sub(*second) < RATE_WINDOW_SECS as u64)
+            .map(|(_, count)| count)
+            .sum();
+        let window = (now + 1).min(RATE_WINDOW_SECS as u64);
+        let deriver_queries = self.deriver_queries.load(Ordering::Relaxed);
+        let deriver_query_micros = self.deriver_query_micros.load(Ordering::Relaxed);
+        IndexerSnapshot {
+            paths_indexed: self.paths_indexed.load(Ordering::Relaxed),
+            paths_per_second: recent as f64 / window as f64,
+            elf_files_parsed: self.elf_files_parsed.load(Ordering::Relaxed),
+            entries_registered: self.entries_registered.load(Ordering::Relaxed),
+            deriver_queries,
+            mean_deriver_query_ms: if deriver_queries == 0 {
+                0.
+            } else {
+                deriver_query_micros as f64 / deriver_queries as f64 / 1000.
+            },
+            queue_depth: self.queued_paths.load(Ordering::Relaxed).max(0) as u64,
+            backlog: None,
+            backlog_age_secs: None,
+            watermark: None,
+            paused: None,
+        }
+    }
+}
+
+#[test]
+fn test_snapshot() {
+    let telemetry = IndexerTelemetry {
+        start: Instant::now(),
+        paths_indexed: AtomicU64::new(0),
+        elf_files_parsed: AtomicU64::new(0),
+        entries_registered: AtomicU64::new(0),
+        deriver_queries: AtomicU64::new(0),
+        deriver_query_micros: AtomicU64::new(0),
+        queued_paths: AtomicI64::new(0),
+        recent: Mutex::new([(0, 0); RATE_WINDOW_SECS]),
+    };
+    telemetry.queued(3);
+    telemetry.path_indexed();
+    telemetry.path_indexed();
+    telemetry.deriver_queried(Duration::from_millis(2));
+    telemetry.deriver_queried(Duration::from_millis(4));
+    telemetry.entries_registered(5);
+    let snapshot = telemetry.snapshot();
+    assert_eq!(snapshot.paths_indexed, 2);
+    assert_eq!(snapshot.queue_depth, 1);
+    assert!(snapshot.paths_per_second > 0.);
+    assert_eq!(snapshot.deriver_queries, 2);
+    assert!((snapshot.mean_deriver_query_ms - 3.).abs() < 0.01);
+    assert_eq!(snapshot.entries_registered, 5);
+}
+
+/// Upper bounds of the buckets of the histogram of request durations, in seconds
+const DURATION_BUCKETS: [f64; 10] = [0.005, 0.01, 0.05, 0.1, 0.5, 1., 5., 10., 30., 60.];
+
+/// Requests to an endpoint
+#[derive(Debug, Clone, Default)]
+struct EndpointCounters {
+    /// number of requests, by status code
+    statuses: BTreeMap<u16, u64>,
+    /// number of requests which took at most each of [DURATION_BUCKETS]
+    buckets: [u64; DURATION_BUCKETS.len()],
+    /// number of requests
+    count: u64,
+    /// total time spent answering, in seconds
+    seconds: f64,
+}
+
+/// Counters updated while answering requests
+#[derive(Default)]
+pub struct ServerTelemetry {
+    /// by route, like `/buildid/:buildid/debuginfo`
+    endpoints: Mutex<BTreeMap<String, EndpointCounters>>,
+    /// files served, by kind: `debuginfo`, `executable`, `source`
+    served: Mutex<BTreeMap<&'static str, u64>>,
+    /// debuginfo requests for buildids which were not found
+    misses: AtomicU64,
+    /// store paths which could not be realised
+    realise_failures: AtomicU64,
+}
+
+/// The global request counters
+pub static SERVER: Lazy<ServerTelemetry> = Lazy::new(ServerTelemetry::default);
+
+impl ServerTelemetry {
+    /// Records that a file of this kind was served
+    pub fn served(&self, kind: &'static str) {
+        *self.served.lock().unwrap().entry(kind).or_default() += 1;
+    }
+
+    /// Records that the debuginfo of a buildid was not found
+    pub fn missed(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that a store path could not be realised
+    pub fn realise_failed(&self) {
+        self.realise_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a request to this endpoint
+    fn record(&self, endpoint: &str, status: u16, duration: Duration) {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        if !endpoints.contains_key(endpoint) {
+            endpoints.insert(endpoint.to_owned(), EndpointCounters::default());
+        }
+        let counters = endpoints.get_mut(endpoint).unwrap();
+        *counters.statuses.entry(status).or_default() += 1;
+        let seconds = duration.as_secs_f64();
+        for (bucket, bound) in counters.buckets.iter_mut().zip(DURATION_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        counters.count += 1;
+        counters.seconds += seconds;
+    }
+}
+
+/// Middleware recording requests in [SERVER]
+pub async fn record_metrics(request: Request, next: Next) -> Response {
+    let endpoint = match request.extensions().get::<MatchedPath>() {
+        Some(path) => path.as_str().to_owned(),
+        // do not create a time series for each unknown url
+        None => "unknown".to_owned(),
+    };
+    let start = Instant::now();
+    let response = next.run(request).await;
+    SERVER.record(&endpoint, response.status().as_u16(), start.elapsed());
+    response
+}
+
+/// Escapes a label value for the Prometheus text format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Renders the counters of [SERVER] and of this snapshot of [INDEXER] in the Prometheus text
+/// format, as served on `/metrics`.
+///
+/// Reference: <https://prometheus.io/docs/instrumenting/exposition_formats/>
+pub fn prometheus(server: &ServerTelemetry, indexer: &IndexerSnapshot) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+        let _ = writeln!(out, "# HELP nixseparatedebuginfod_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE nixseparatedebuginfod_{} {}", name, kind);
+        for (suffix, value) in samples {
+            let _ = writeln!(out, "nixseparatedebuginfod_{}{} {}", name, suffix, value);
+        }
+    };
+    let endpoints = server.endpoints.lock().unwrap().clone();
+    let mut requests = Vec::new();
+    let mut durations = Vec::new();
+    for (endpoint, counters) in &endpoints {
+        let endpoint = escape_label(endpoint);
+        for (status, count) in &counters.statuses {
+            requests.push((
+                format!("{{endpoint=\"{}\",status=\"{}\"}}", endpoint, status),
+                count.to_string(),
+            ));
+        }
+        for (count, bound) in counters.buckets.iter().zip(DURATION_BUCKETS) {
+            durations.push((
+                format!("_bucket{{endpoint=\"{}\",le=\"{}\"}}", endpoint, bound),
+                count.to_string(),
+            ));
+        }
+        durations.push((
+            format!("_bucket{{endpoint=\"{}\",le=\"+Inf\"}}", endpoint),
+            counters.count.to_string(),
+        ));
+        durations.push((
+            format!("_sum{{endpoint=\"{}\"}}", endpoint),
+            counters.seconds.to_string(),
+        ));
+        durations.push((
+            format!("_count{{endpoint=\"{}\"}}", endpoint),
+            counters.count.to_string(),
+        ));
+    }
+    metric(
+        "requests_total",
+        "counter",
+        "Requests answered, by endpoint and status code",
+        requests,
+    );
+    metric(
+        "request_duration_seconds",
+        "histogram",
+        "Time spent answering requests, by endpoint",
+        durations,
+    );
+    let served = server.served.lock().unwrap().clone();
+    metric(
+        "files_served_total",
+        "counter",
+        "Files found for requests, by kind",
+        served
+            .iter()
+            .map(|(kind, count)| (format!("{{kind=\"{}\"}}", kind), count.to_string()))
+            .collect(),
+    );
+    let simple = |value: String| vec![(String::new(), value)];
+    metric(
+        "misses_total",
+        "counter",
+        "Debuginfo requests for buildids which were not found",
+        simple(server.misses.load(Ordering::Relaxed).to_string()),
+    );
+    metric(
+        "realise_failures_total",
+        "counter",
+        "Store paths which could not be realised",
+        simple(server.realise_failures.load(Ordering::Relaxed).to_string()),
+    );
+    metric(
+        "paths_indexed_total",
+        "counter",
+        "Store paths indexed",
+        simple(indexer.paths_indexed.to_string()),
+    );
+    metric(
+        "elf_files_parsed_total",
+        "counter",
+        "Elf files whose buildid was read",
+        simple(indexer.elf_files_parsed.to_string()),
+    );
+    metric(
+        "entries_registered_total",
+        "counter",
+        "Entries written to the cache",
+        simple(indexer.entries_registered.to_string()),
+    );
+    metric(
+        "queued_paths",
+        "gauge",
+        "Store paths scheduled for indexation but not indexed yet",
+        simple(indexer.queue_depth.to_string()),
+    );
+    metric(
+        "indexing_paused",
+        "gauge",
+        "Whether background indexation is paused",
+        simple(u8::from(indexer.paused.is_some()).to_string()),
+    );
+    if let Some(backlog) = indexer.backlog {
+        metric(
+            "backlog_paths",
+            "gauge",
+            "Store paths of the nix db not indexed yet",
+            simple(backlog.to_string()),
+        );
+    }
+    if let Some(age) = indexer.backlog_age_secs {
+        metric(
+            "backlog_age_seconds",
+            "gauge",
+            "How long ago the oldest store path not indexed yet was registered",
+            simple(age.to_string()),
+        );
+    }
+    if let Some(watermark) = indexer.watermark {
+        metric(
+            "registration_time_watermark_seconds",
+            "gauge",
+            "When the last indexed store path was registered in the nix db, since the epoch",
+            simple(watermark.to_string()),
+        );
+    }
+    out
+}
+
+#[test]
+fn test_prometheus() {
+    let server = ServerTelemetry::default();
+    server.record(
+        "/buildid/:buildid/debuginfo",
+        200,
+        Duration::from_millis(20),
+    );
+    server.record("/buildid/:buildid/debuginfo", 404, Duration::from_secs(2));
+    server.served("debuginfo");
+    server.missed();
+    let indexer = IndexerSnapshot {
+        paths_indexed: 3,
+        paths_per_second: 0.,
+        elf_files_parsed: 4,
+        entries_registered: 5,
+        deriver_queries: 0,
+        mean_deriver_query_ms: 0.,
+        queue_depth: 0,
+        backlog: None,
+        backlog_age_secs: None,
+        watermark: Some(1700000000),
+        paused: None,
+    };
+    let text = prometheus(&server, &indexer);
+    for line in [
+        "# TYPE nixseparatedebuginfod_requests_total counter",
+        "nixseparatedebuginfod_requests_total{endpoint=\"/buildid/:buildid/debuginfo\",status=\"404\"} 1",
+        "nixseparatedebuginfod_request_duration_seconds_bucket{endpoint=\"/buildid/:buildid/debuginfo\",le=\"0.05\"} 1",
+        "nixseparatedebuginfod_request_duration_seconds_bucket{endpoint=\"/buildid/:buildid/debuginfo\",le=\"5\"} 2",
+        "nixseparatedebuginfod_request_duration_seconds_count{endpoint=\"/buildid/:buildid/debuginfo\"} 2",
+        "nixseparatedebuginfod_files_served_total{kind=\"debuginfo\"} 1",
+        "nixseparatedebuginfod_misses_total 1",
+        "nixseparatedebuginfod_entries_registered_total 5",
+        "nixseparatedebuginfod_registration_time_watermark_seconds 1700000000",
+    ] {
+        assert!(text.lines().any(|l| l == line), "{} not in {}", line, text);
+    }
+    assert!(!text.contains("backlog_paths"));
+    assert_eq!(escape_label("a\"b"), "a\\\"b");
+}
+
+/// How many clients [ClientUsage] remembers, forgetting the least recently seen ones
+const MAX_CLIENTS: usize = 1000;
+
+/// Requests of a client, as reported by `/admin/stats`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ClientCounters {
+    /// the address or header value identifying the client
+    pub client: String,
+    /// number of requests
+    pub requests: u64,
+    /// number of requests answered with a 4xx status
+    pub client_errors: u64,
+    /// number of requests answered with a 5xx status
+    pub server_errors: u64,
+    /// bytes served, when their size was known in advance
+    pub bytes: u64,
+    /// total time spent answering, in milliseconds
+    pub duration_ms: u64,
+    /// when the last request was received, in seconds since the epoch
+    pub last_request: u64,
+}
+
+/// Counters of requests per client, for operators of shared servers to see who generates load
+#[derive(Clone)]
+pub struct ClientUsage {
+    /// identify clients by this header instead of their address, when behind a reverse proxy
+    header: Option<HeaderName>,
+    /// counters by client
+    clients: Arc<Mutex<LruCache<String, ClientCounters>>>,
+}
+
+impl ClientUsage {
+    /// Counts requests per client, identified by their address or by this header
+    pub fn new(header: Option<HeaderName>) -> Self {
+        ClientUsage {
+            header,
+            clients: Arc::new(Mutex::new(LruCache::new(MAX_CLIENTS))),
+        }
+    }
+
+    /// Returns who sent this request
+    fn client(&self, request: &Request) -> String {
+        if let Some(header) = &self.header {
+            // X-Forwarded-For may contain a list of proxies, the client comes first
+            let value = request
+                .headers()
+                .get(header)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.split(',').next())
+                .map(str::trim)
+                .filter(|value| !value.is_empty());
+            if let Some(value) = value {
+                return value.to_owned();
+            }
+        }
+        match request.extensions().get::<ConnectInfo<SocketAddr>>() {
+            Some(ConnectInfo(address)) => address.ip().to_string(),
+            None => "unknown".to_owned(),
+        }
+    }
+
+    /// Records a request of this client
+    fn record(&self, client: String, response: &Response, duration: Duration, time: u64) {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.get(&client).is_none() {
+            clients.insert(
+                client.clone(),
+                ClientCounters {
+                    client: client.clone(),
+                    ..Default::default()
+                },
+            );
+        }
+        let counters = clients.get_mut(&client).unwrap();
+        counters.requests += 1;
+        if response.status().is_client_error() {
+            counters.client_errors += 1;
+        }
+        if response.status().is_server_error() {
+            counters.server_errors += 1;
+        }
+        counters.bytes += response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(0);
+        counters.duration_ms += duration.as_millis() as u64;
+        counters.last_request = time;
+    }
+
+    /// Returns the counters of all remembered clients, most active first
+    pub fn snapshot(&self) -> Vec<ClientCounters> {
+        let mut result: Vec<ClientCounters> = self
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, counters)| counters.clone())
+            .collect();
+        result.sort_by(|a, b| b.requests.cmp(&a.requests).then(a.client.cmp(&b.client)));
+        result
+    }
+}
+
+/// Middleware recording requests in a [ClientUsage], if enabled
+pub async fn count_client_requests(
+    State(usage): State<Option<ClientUsage>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let usage = match usage {
+        None => return next.run(request).await,
+        Some(usage) => usage,
+    };
+    let client = usage.client(&request);
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let start = Instant::now();
+    let response = next.run(request).await;
+    usage.record(client, &response, start.elapsed(), time);
+    response
+}
+
+#[test]
+fn test_client_usage() {
+    let usage = ClientUsage::new(Some(HeaderName::from_static("x-forwarded-for")));
+    let request = |forwarded: Option<&str>| {
+        let mut request = Request::new(axum::body::Body::empty());
+        if let Some(forwarded) = forwarded {
+            request
+                .headers_mut()
+                .insert("x-forwarded-for", forwarded.parse().unwrap());
+        }
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1234))));
+        request
+    };
+    assert_eq!(
+        usage.client(&request(Some("10.0.0.1, 10.0.0.2"))),
+        "10.0.0.1"
+    );
+    assert_eq!(usage.client(&request(None)), "127.0.0.1");
+    let mut response = Response::new(axum::body::Body::empty());
+    response
+        .headers_mut()
+        .insert(CONTENT_LENGTH, "10".parse().unwrap());
+    usage.record("a".to_owned(), &response, Duration::from_millis(3), 1);
+    usage.record("a".to_owned(), &response, Duration::from_millis(3), 2);
+    *response.status_mut() = http::StatusCode::NOT_FOUND;
+    usage.record("b".to_owned(), &response, Duration::from_millis(1), 3);
+    let snapshot = usage.snapshot();
+    assert_eq!(snapshot.len(), 2);
+    assert_eq!(snapshot[0].client, "a");
+    assert_eq!(snapshot[0].requests, 2);
+    assert_eq!(snapshot[0].bytes, 20);
+    assert_eq!(snapshot[0].duration_ms, 6);
+    assert_eq!(snapshot[0].last_request, 2);
+    assert_eq!(snapshot[1].client_errors, 1);
+}
@@ -0,0 +1,216 @@
+// SPDX-FileCopyrightText: 2023 Guillaume Girol <symphorien+git@xlumurb.eu>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Pausing background indexation while the machine is busy.
+//!
+//! The initial indexation of a large store walks every file of every store path, and would
+//! otherwise compete with the builds of the user. Before indexing each store path in the
+//! background, [wait] waits until the load, memory pressure and power source are below the
+//! thresholds set with [set_thresholds]. Indexation for requests is never paused.
+
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::{Lazy, OnceCell};
+
+/// When to pause background indexation
+#[derive(Debug, Clone, Default)]
+pub struct Thresholds {
+    /// pause when the load average over the last minute exceeds this
+    pub max_load: Option<f64>,
+    /// pause when tasks were stalled waiting for memory more than this percentage of the last
+    /// 10 seconds, according to `/proc/pressure/memory`
+    pub max_memory_pressure: Option<f64>,
+    /// pause when running on battery
+    pub pause_on_battery: bool,
+}
+
+/// How often thresholds are checked again while paused
+const PAUSED_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// For how long the outcome of a check is trusted, so that indexing thousands of small store
+/// paths does not read `/proc` thousands of times
+const CHECK_VALIDITY: Duration = Duration::from_secs(1);
+
+/// Thresholds set by [set_thresholds]
+static THRESHOLDS: OnceCell<Thresholds> = OnceCell::new();
+
+/// When thresholds were last found not to be exceeded
+static LAST_CHECK: Lazy<tokio::sync::Mutex<Option<Instant>>> =
+    Lazy::new(|| tokio::sync::Mutex::new(None));
+
+/// Why indexation is paused, if it is
+static PAUSED: Mutex<Option<String>> = Mutex::new(None);
+
+/// Sets when background indexation is paused. Nothing is paused until this is called.
+///
+/// Panics if called twice.
+pub fn set_thresholds(thresholds: Thresholds) {
+    THRESHOLDS
+        .set(thresholds)
+        .expect("set_thresholds called twice")
+}
+
+/// Why background indexation is currently paused, if it is
+pub fn paused() -> Option<String> {
+    PAUSED.lock().unwrap().clone()
+}
+
+/// Waits until no threshold is exceeded.
+///
+/// All callers wait together, so pausing and resuming is only logged once.
+pub async fn wait() {
+    let thresholds = match THRESHOLDS.get() {
+        None => return,
+        Some(thresholds) => thresholds,
+    };
+    let mut last_check = LAST_CHECK.lock().await;
+    if last_check.is_some_and(|last| last.elapsed() < CHECK_VALIDITY) {
+        return;
+    }
+    let mut paused_since = None;
+    loop {
+        let reason = tokio::task::spawn_blocking(|| thresholds.exceeded())
+            .await
+            .unwrap_or(None);
+        match reason {
+            None => break,
+            Some(reason) => {
+                if paused_since.is_none() {
+                    tracing::info!("pausing background indexation: {}", reason);
+                    paused_since = Some(Instant::now());
+                }
+                *PAUSED.lock().unwrap() = Some(reason);
+                tokio::time::sleep(PAUSED_CHECK_INTERVAL).await;
+            }
+        }
+    }
+    if let Some(since) = paused_since {
+        tracing::info!(
+            "resuming background indexation after {}s",
+            since.elapsed().as_secs()
+        );
+        *PAUSED.lock().unwrap() = None;
+    }
+    *last_check = Some(Instant::now());
+}
+
+impl Thresholds {
+    /// Returns which threshold is exceeded, if any.
+    ///
+    /// What cannot be read, like `/proc/pressure` on old kernels, is considered below its
+    /// threshold.
+    ///
+    /// Blocking.
+    fn exceeded(&self) -> Option<String> {
+        if let Some(max_load) = self.max_load {
+            if let Some(load) = std::fs::read_to_string("/proc/loadavg")
+                .ok()
+                .and_then(|loadavg| parse_loadavg(&loadavg))
+            {
+                if load > max_load {
+                    return Some(format!("load is {} (more than {})", load, max_load));
+                }
+            }
+        }
+        if let Some(max_pressure) = self.max_memory_pressure {
+            if let Some(pressure) = std::fs::read_to_string("/proc/pressure/memory")
+                .ok()
+                .and_then(|pressure| parse_memory_pressure(&pressure))
+            {
+                if pressure > max_pressure {
+                    return Some(format!(
+                        "memory pressure is {}% (more than {}%)",
+                        pressure, max_pressure
+                    ));
+                }
+            }
+        }
+        if self.pause_on_battery && on_battery(Path::new("/sys/class/power_supply")) {
+            return Some("running on battery".to_owned());
+        }
+        None
+    }
+}
+
+/// Parses the load average over the last minute from the content of `/proc/loadavg`
+fn parse_loadavg(loadavg: &str) -> Option<f64> {
+    loadavg.split_whitespace().next()?.parse().ok()
+}
+
+#[test]
+fn test_parse_loadavg() {
+    assert_eq!(parse_loadavg("3.52 2.10 1.05 4/1203 48613\n"), Some(3.52));
+    assert_eq!(parse_loadavg(""), None);
+}
+
+/// Parses the percentage of the last 10 seconds during which some tasks were stalled waiting
+/// for memory, from the content of `/proc/pressure/memory`
+fn parse_memory_pressure(pressure: &str) -> Option<f64> {
+    let line = pressure.lines().find(|line| line.starts_with("some "))?;
+    line.split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))?
+        .parse()
+        .ok()
+}
+
+#[test]
+fn test_parse_memory_pressure() {
+    assert_eq!(
+        parse_memory_pressure(
+            "some avg10=12.50 avg60=3.00 avg300=0.61 total=1234\nfull avg10=1.00 avg60=0.00 avg300=0.00 total=42\n"
+        ),
+        Some(12.5)
+    );
+    assert_eq!(parse_memory_pressure("garbage"), None);
+}
+
+/// Whether the machine runs on battery, according to the power supplies in this directory
+/// (`/sys/class/power_supply`): no mains supply is online, and a battery is discharging.
+///
+/// Blocking.
+fn on_battery(power_supply: &Path) -> bool {
+    let entries = match std::fs::read_dir(power_supply) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    let read = |dir: &Path, name: &str| {
+        std::fs::read_to_string(dir.join(name))
+            .map(|content| content.trim().to_owned())
+            .unwrap_or_default()
+    };
+    let mut discharging = false;
+    for entry in entries.flatten() {
+        let dir = entry.path();
+        match read(&dir, "type").as_str() {
+            "Mains" if read(&dir, "online") == "1" => return false,
+            "Battery" if read(&dir, "status") == "Discharging" => discharging = true,
+            _ => (),
+        }
+    }
+    discharging
+}
+
+#[test]
+fn test_on_battery() {
+    let dir = tempfile::tempdir().unwrap();
+    let supply = |name: &str, files: &[(&str, &str)]| {
+        let path = dir.path().join(name);
+        std::fs::create_dir_all(&path).unwrap();
+        for (file, content) in files {
+            std::fs::write(path.join(file), format!("{}\n", content)).unwrap();
+        }
+    };
+    assert!(!on_battery(dir.path()));
+    supply("BAT0", &[("type", "Battery"), ("status", "Discharging")]);
+    assert!(on_battery(dir.path()));
+    supply("AC", &[("type", "Mains"), ("online", "1")]);
+    assert!(!on_battery(dir.path()));
+    supply("AC", &[("type", "Mains"), ("online", "0")]);
+    assert!(on_battery(dir.path()));
+    supply("BAT0", &[("type", "Battery"), ("status", "Charging")]);
+    assert!(!on_battery(dir.path()));
+    assert!(!on_battery(&dir.path().join("missing")));
+}
@@ -461,3 +461,23 @@ fn test_cache_invalidation() {
 
     server.kill().unwrap();
 }
+
+#[test]
+fn test_http2() {
+    let t = tempfile::tempdir().unwrap();
+
+    let (port, mut server) = spawn_server(&t, None);
+
+    // h2c with prior knowledge
+    let client = reqwest::blocking::Client::builder()
+        .http2_prior_knowledge()
+        .build()
+        .unwrap();
+    let response = client
+        .get(format!("http://127.0.0.1:{port}/readyz"))
+        .send()
+        .unwrap();
+    assert_eq!(response.version(), reqwest::Version::HTTP_2);
+
+    server.kill().unwrap();
+}